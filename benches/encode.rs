@@ -0,0 +1,90 @@
+// Writing frames with `Connection::write_frame`, which encodes every frame
+// into a single reused buffer, against writing them piecewise with a
+// `String` per frame, the way frames used to be written.
+//
+// Run with `cargo bench --bench encode`. Both write to `tokio::io::sink`
+// through a `BufWriter` and flush after every frame.
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use learn_tokio_frame::{Connection, Frame};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    runtime::Runtime,
+};
+
+const FRAMES: usize = 1_000;
+
+// A `write_u8` for the type byte and a `format!` for the rest of the frame.
+async fn write_piecewise<W: AsyncWrite + Unpin>(stream: &mut BufWriter<W>, frame: &Frame) {
+    match frame {
+        Frame::Addition(x, y) => {
+            stream.write_u8(b'+').await.unwrap();
+            let operands = format!("{}:{}\r\n", x, y);
+            stream.write_all(operands.as_bytes()).await.unwrap();
+        }
+        Frame::Sum(operands) => {
+            stream.write_u8(b'+').await.unwrap();
+            let operands: Vec<_> = operands.iter().map(u64::to_string).collect();
+            stream
+                .write_all(operands.join(":").as_bytes())
+                .await
+                .unwrap();
+            stream.write_all(b"\r\n").await.unwrap();
+        }
+        Frame::OpResult(r) => {
+            stream.write_u8(b'=').await.unwrap();
+            stream.write_u64(*r).await.unwrap();
+        }
+        _ => unreachable!(),
+    }
+    stream.flush().await.unwrap();
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let frames = [
+        ("addition", Frame::Addition(123_456, 654_321)),
+        ("sum", Frame::Sum((1..=16).map(|n| n * 1_000_003).collect())),
+        ("op_result", Frame::OpResult(u64::MAX)),
+    ];
+
+    for (name, frame) in &frames {
+        let mut group = c.benchmark_group(format!("write_{}", name));
+        group.throughput(Throughput::Elements(FRAMES as u64));
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter("piecewise"),
+            frame,
+            |b, frame| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        let mut stream = BufWriter::new(tokio::io::sink());
+                        for _ in 0..FRAMES {
+                            write_piecewise(&mut stream, black_box(frame)).await;
+                        }
+                    })
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::from_parameter("single_buffer"),
+            frame,
+            |b, frame| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        let stream = tokio::io::join(tokio::io::empty(), tokio::io::sink());
+                        let mut connection = Connection::new(stream);
+                        for _ in 0..FRAMES {
+                            connection.write_frame(black_box(frame)).await.unwrap();
+                        }
+                    })
+                })
+            },
+        );
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_encode);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+// Reading large `Array` frames with different read buffer configurations.
+//
+// Run with `cargo bench --bench read_buffer`. Every iteration reads the
+// same encoded frames from a fresh `Connection`, so the time includes
+// growing the read buffer from its initial capacity.
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use learn_tokio_frame::{
+    connection::{BufferGrowth, ConnectionConfig},
+    Connection, Frame,
+};
+use tokio::{io::AsyncWriteExt, runtime::Runtime};
+
+// Encoded `Array` of `len` results, followed by a `Ping`.
+fn encoded_array(len: usize) -> Vec<u8> {
+    let mut encoded = Frame::Array(vec![Frame::OpResult(42); len])
+        .to_vec()
+        .unwrap();
+    encoded.extend_from_slice(&Frame::Ping.to_vec().unwrap());
+    encoded
+}
+
+async fn read_all(encoded: &[u8], config: ConnectionConfig) {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let mut connection = Connection::with_config(server, config);
+
+    let write = async {
+        client.write_all(encoded).await.unwrap();
+        drop(client);
+    };
+    let read = async {
+        while let Some(frame) = connection.read_frame().await.unwrap() {
+            black_box(frame);
+        }
+    };
+    tokio::join!(write, read);
+}
+
+fn bench_read_buffer(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let configs = [
+        ("double", BufferGrowth::Double, 4 * 1024),
+        ("linear_4k", BufferGrowth::Linear(4 * 1024), 4 * 1024),
+        ("linear_64k", BufferGrowth::Linear(64 * 1024), 4 * 1024),
+        ("presized", BufferGrowth::Double, 1024 * 1024),
+    ];
+
+    for len in [1_000, 10_000, 50_000] {
+        let encoded = encoded_array(len);
+        let mut group = c.benchmark_group(format!("array_{}", len));
+        group.throughput(Throughput::Bytes(encoded.len() as u64));
+
+        for (name, growth, capacity) in configs {
+            let config = ConnectionConfig {
+                read_buffer_capacity: capacity,
+                max_read_buffer_capacity: capacity,
+                read_buffer_growth: growth,
+                ..ConnectionConfig::default()
+            };
+            group.bench_with_input(BenchmarkId::from_parameter(name), &config, |b, config| {
+                b.iter(|| runtime.block_on(read_all(&encoded, config.clone())))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_read_buffer);
+criterion_main!(benches);
@@ -0,0 +1,74 @@
+// Sending a batch of frames with a flush per frame, with `feed_frame` and
+// a single `flush`, and with `write_frames`.
+//
+// Run with `cargo bench --bench write_frames`. The frames go over a
+// loopback TCP connection, so every flush is a system call, and a task
+// on the other end discards whatever it reads.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use learn_tokio_frame::{Connection, Frame};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+};
+
+async fn connect() -> Connection {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (mut peer, _) = listener.accept().await.unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        while peer.read(&mut buf).await.unwrap() > 0 {}
+    });
+    Connection::new(stream)
+}
+
+fn bench_write_frames(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut connection = runtime.block_on(connect());
+
+    for len in [10, 100, 1_000] {
+        let frames: Vec<_> = (0..len).map(|n| Frame::Addition(n, n)).collect();
+        let mut group = c.benchmark_group(format!("frames_{}", len));
+        group.throughput(Throughput::Elements(len));
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter("write_frame"),
+            &frames,
+            |b, frames| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        for frame in frames {
+                            connection.write_frame(frame).await.unwrap();
+                        }
+                    })
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::from_parameter("feed_frame"),
+            &frames,
+            |b, frames| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        for frame in frames {
+                            connection.feed_frame(frame).await.unwrap();
+                        }
+                        connection.flush().await.unwrap();
+                    })
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::from_parameter("write_frames"),
+            &frames,
+            |b, frames| b.iter(|| runtime.block_on(connection.write_frames(frames)).unwrap()),
+        );
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_write_frames);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+// Serve the calculator on a Unix domain socket and send it a request.
+//
+// Run with `cargo run --example unix_socket [path]`, the socket defaults
+// to `calculator.sock` in the temporary directory.
+#[cfg(unix)]
+#[tokio::main]
+async fn main() -> learn_tokio_frame::Result<()> {
+    use learn_tokio_frame::{server::Server, Client, Frame};
+    use tokio::net::UnixListener;
+
+    let path = match std::env::args().nth(1) {
+        Some(path) => path.into(),
+        None => std::env::temp_dir().join("calculator.sock"),
+    };
+
+    // Binding fails while a socket file of an earlier run is still there.
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    tokio::spawn(Server::builder().build_unix(listener).run());
+
+    let mut client = Client::connect_unix(&path).await?;
+    let response = client.call(&Frame::Multiplication(6, 7)).await?;
+    println!("Server Response: {}", response);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("Unix domain sockets are not supported on this platform");
+}
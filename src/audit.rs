@@ -0,0 +1,438 @@
+use std::{
+    fmt::{self, Write as _},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    iter,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::Frame;
+
+// Lines that may be pending before further lines are dropped, see
+// `AuditLog::with_capacity`.
+const DEFAULT_CAPACITY: usize = 4096;
+
+// Records every request a server answers, set with
+// `server::Builder::audit_log`. Each request is one line of JSON:
+//
+//   {"timestamp_ms":1700000000000,"peer":"127.0.0.1:50000","client":null,
+//    "operation":"Addition","request":"ADD 1 2","response":"RESULT 3",
+//    "latency_us":42,"outcome":"ok"}
+//
+// A request answered with an error frame has the outcome `error` and an
+// `error_code`. Unlike `OpLog` failed requests are recorded as well: the
+// outcome `failed` and an `error` for a request that closed the connection,
+// timed out or whose response could not be written, `unanswered` for a
+// frame that has no response, e.g. an error reported by the peer.
+//
+// Lines are handed to a thread that owns the writer, so neither a slow
+// disk nor a blocking `Write` of the embedder blocks a handler. The writer
+// is flushed whenever no more lines are pending. Lines that do not fit
+// in the queue, or that fail to be written, are dropped and counted, see
+// `dropped`. Writing is attempted again with the next line.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    lines: mpsc::SyncSender<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+// A file that is rotated once it grows beyond `max_bytes`: `path` is
+// renamed to `path.1`, `path.1` to `path.2` and so on, keeping at most
+// `max_files` old files, and writing continues in a new `path`.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+// How a request ended, see `AuditLog::record`.
+#[derive(Clone, Copy)]
+pub(crate) enum Outcome<'a> {
+    // Answered with the frame, an error frame is the outcome `error`.
+    Answered(&'a Frame),
+
+    // The frame needs no answer.
+    Unanswered,
+
+    // The connection was closed with the error, or failed while writing
+    // the response.
+    Failed(Option<&'a Frame>, &'a dyn fmt::Display),
+}
+
+impl AuditLog {
+    // Write the audit log to `writer`. The writer thread ends once every
+    // `AuditLog` clone is dropped.
+    pub fn new(writer: impl Write + Send + 'static) -> AuditLog {
+        AuditLog::with_capacity(writer, DEFAULT_CAPACITY)
+    }
+
+    // Like `new`, with at most `lines` lines waiting to be written.
+    pub fn with_capacity(writer: impl Write + Send + 'static, lines: usize) -> AuditLog {
+        let (lines, rx) = mpsc::sync_channel(lines);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let counter = dropped.clone();
+        thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || write_lines(writer, rx, &counter))
+            .expect("failed to spawn the audit log thread");
+        AuditLog { lines, dropped }
+    }
+
+    // Write the audit log to `path`, see `RotatingFile`.
+    pub fn open(path: impl AsRef<Path>, max_bytes: u64, max_files: usize) -> io::Result<AuditLog> {
+        Ok(AuditLog::new(RotatingFile::open(
+            path, max_bytes, max_files,
+        )?))
+    }
+
+    // Record `request` that ended with `outcome` after `latency`.
+    pub(crate) fn record(
+        &self,
+        peer: &impl fmt::Display,
+        client: Option<&str>,
+        request: &Frame,
+        outcome: Outcome,
+        latency: Duration,
+    ) {
+        let mut line = String::new();
+        // Writing to a `String` never fails.
+        write_event(&mut line, peer, client, request, outcome, latency).unwrap();
+        // The request itself was still answered, a line that does not
+        // fit is dropped rather than waiting for the writer.
+        if self.lines.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Number of lines that were not written, because too many lines were
+    // pending or writing them failed.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn write_event(
+    line: &mut String,
+    peer: &impl fmt::Display,
+    client: Option<&str>,
+    request: &Frame,
+    outcome: Outcome,
+    latency: Duration,
+) -> fmt::Result {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+
+    write!(line, "{{\"timestamp_ms\":{},\"peer\":", timestamp)?;
+    write_string(line, &peer.to_string())?;
+    line.push_str(",\"client\":");
+    match client {
+        Some(client) => write_string(line, client)?,
+        None => line.push_str("null"),
+    }
+    write!(line, ",\"operation\":\"{}\",\"request\":", request.kind())?;
+    write_string(line, &request.to_string())?;
+    line.push_str(",\"response\":");
+    let response = match outcome {
+        Outcome::Answered(response) | Outcome::Failed(Some(response), _) => Some(response),
+        Outcome::Unanswered | Outcome::Failed(None, _) => None,
+    };
+    match response {
+        Some(response) => write_string(line, &response.to_string())?,
+        None => line.push_str("null"),
+    }
+    write!(line, ",\"latency_us\":{}", latency.as_micros())?;
+
+    match outcome {
+        Outcome::Answered(response) => {
+            // A tagged request succeeds or fails with the request inside it.
+            let mut inner = response;
+            while let Frame::Tagged(_, response) = inner {
+                inner = response;
+            }
+            match inner {
+                Frame::Error(code, _) => write!(
+                    line,
+                    ",\"outcome\":\"error\",\"error_code\":\"{:?}\"}}",
+                    code
+                )?,
+                _ => line.push_str(",\"outcome\":\"ok\"}"),
+            }
+        }
+        Outcome::Unanswered => line.push_str(",\"outcome\":\"unanswered\"}"),
+        Outcome::Failed(_, err) => {
+            line.push_str(",\"outcome\":\"failed\",\"error\":");
+            write_string(line, &err.to_string())?;
+            line.push('}');
+        }
+    }
+    line.push('\n');
+    Ok(())
+}
+
+// `value` as a JSON string.
+fn write_string(line: &mut String, value: &str) -> fmt::Result {
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if c.is_control() => write!(line, "\\u{:04x}", c as u32)?,
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+    Ok(())
+}
+
+fn write_lines(mut writer: impl Write, lines: mpsc::Receiver<String>, dropped: &AtomicU64) {
+    let mut failing = false;
+    while let Ok(line) = lines.recv() {
+        // The lines after a failed one are dropped until the next flush,
+        // they would fail the same way.
+        let mut written = Ok(());
+        for line in iter::once(line).chain(lines.try_iter()) {
+            written = written.and_then(|_| writer.write_all(line.as_bytes()));
+            if written.is_err() {
+                dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        match written.and_then(|_| writer.flush()) {
+            Ok(()) if failing => {
+                tracing::info!("writing the audit log again");
+                failing = false;
+            }
+            Ok(()) => {}
+            // Logged once, not for every line until it recovers.
+            Err(err) if !failing => {
+                tracing::error!(%err, "failed writing the audit log, dropping lines");
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+impl RotatingFile {
+    // Open `path` for appending, creating it if needed.
+    pub fn open(
+        path: impl AsRef<Path>,
+        max_bytes: u64,
+        max_files: usize,
+    ) -> io::Result<RotatingFile> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            len,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+
+        // Renaming replaces the oldest file, without old files there is
+        // nothing to keep.
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                match fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            // Gone after a rotation that failed to open the new file.
+            match fs::rename(&self.path, rotated(1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        self.reopen()
+    }
+
+    // Continue in a newly opened `path`, e.g. after it was removed.
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.len = self.file.metadata()?.len();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    // A write that does not fit is started in a new file, every line of
+    // the audit log ends up whole in one file.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.len > 0 && self.len + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        // The file is opened again once, in case it is the file that
+        // broke rather than the disk.
+        let written = match self.file.write(buf) {
+            Ok(written) => written,
+            Err(_) => {
+                self.reopen()?;
+                self.file.write(buf)?
+            }
+        };
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[test]
+fn test_event_line() {
+    use crate::frame::{ErrorCode, Tag};
+
+    let peer = "127.0.0.1:5000";
+    let mut line = String::new();
+    let request = Frame::Identify("say \"hi\"".to_string());
+    let latency = Duration::from_micros(42);
+    let outcome = Outcome::Answered(&request);
+    write_event(&mut line, &peer, Some("a\nb"), &request, outcome, latency).unwrap();
+    assert!(line.ends_with(
+        "\"peer\":\"127.0.0.1:5000\",\"client\":\"a\\nb\",\"operation\":\"Identify\",\
+         \"request\":\"IDENTIFY say \\\"hi\\\"\",\"response\":\"IDENTIFY say \\\"hi\\\"\",\
+         \"latency_us\":42,\"outcome\":\"ok\"}\n"
+    ));
+
+    let mut line = String::new();
+    let tag = Tag { id: 1, priority: 0 };
+    let request = Frame::Tagged(tag, Box::new(Frame::Subtraction(1, 2)));
+    let error = Frame::Error(ErrorCode::Underflow, "arithmetic underflow".to_string());
+    let response = Frame::Tagged(tag, Box::new(error));
+    let outcome = Outcome::Answered(&response);
+    write_event(&mut line, &peer, None, &request, outcome, latency).unwrap();
+    assert!(line.contains("\"client\":null,\"operation\":\"Tagged\""));
+    assert!(line.ends_with("\"outcome\":\"error\",\"error_code\":\"Underflow\"}\n"));
+
+    let mut line = String::new();
+    let outcome = Outcome::Failed(None, &"request timed out");
+    write_event(&mut line, &peer, None, &request, outcome, latency).unwrap();
+    assert!(line.ends_with(
+        "\"response\":null,\"latency_us\":42,\"outcome\":\"failed\",\
+         \"error\":\"request timed out\"}\n"
+    ));
+}
+
+#[test]
+fn test_rotating_file() {
+    let dir = std::env::temp_dir().join(format!("learn-tokio-frame-audit-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("audit.log");
+
+    let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+    for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+        file.write_all(line.as_bytes()).unwrap();
+    }
+    file.flush().unwrap();
+
+    let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+    assert_eq!("fourth\n", read("audit.log"));
+    assert_eq!("third\n", read("audit.log.1"));
+    assert_eq!("second\n", read("audit.log.2"));
+    assert!(!dir.join("audit.log.3").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_dropped_lines() {
+    use std::sync::{Condvar, Mutex};
+
+    #[derive(Default)]
+    struct Writes {
+        released: bool,
+        lines: Vec<String>,
+    }
+
+    // Blocks the writer thread in its first write until released, fails
+    // the third write and succeeds afterwards.
+    #[derive(Clone, Default)]
+    struct Stalled(Arc<(Mutex<Writes>, Condvar)>);
+    impl Write for Stalled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let (state, changed) = &*self.0;
+            let mut state = state.lock().unwrap();
+            state.lines.push(String::from_utf8_lossy(buf).into_owned());
+            changed.notify_all();
+            state = changed.wait_while(state, |state| !state.released).unwrap();
+            if state.lines.len() == 3 {
+                return Err(io::Error::other("disk full"));
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let writer = Stalled::default();
+    let audit = AuditLog::with_capacity(writer.clone(), 1);
+    let (state, changed) = &*writer.0;
+    let wait_for = |writes: usize| {
+        let state = state.lock().unwrap();
+        drop(changed.wait_while(state, |state| state.lines.len() < writes));
+    };
+    let record = |n: u64| {
+        let request = Frame::Factorial(n);
+        let outcome = Outcome::Answered(&request);
+        audit.record(&"peer", None, &request, outcome, Duration::ZERO);
+    };
+
+    // The writer holds the first line, the second is queued and the third
+    // does not fit.
+    record(1);
+    wait_for(1);
+    record(2);
+    record(3);
+    assert_eq!(1, audit.dropped());
+
+    state.lock().unwrap().released = true;
+    changed.notify_all();
+    wait_for(2);
+
+    // Writing the next line fails, the one after it is written again.
+    record(4);
+    wait_for(3);
+    while audit.dropped() < 2 {
+        thread::yield_now();
+    }
+    record(5);
+    wait_for(4);
+
+    let lines = state.lock().unwrap().lines.clone();
+    for (line, n) in lines.iter().zip([1, 2, 4, 5]) {
+        assert!(
+            line.contains(&format!("\"request\":\"FACT {}\"", n)),
+            "{}",
+            line
+        );
+    }
+    assert_eq!(2, audit.dropped());
+}
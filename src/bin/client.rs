@@ -1,42 +1,17 @@
-use tokio::net::TcpStream;
-
-use learn_tokio_frame::{Connection, Frame};
-
-pub struct Client {
-    connection: Connection,
-}
-
-impl Client {
-    pub async fn connect() -> Client {
-        let socket = TcpStream::connect("127.0.0.1:8080").await.unwrap();
-
-        let connection = Connection::new(socket);
-
-        Client { connection }
-    }
-
-    pub async fn addition(&mut self) -> learn_tokio_frame::Result<Frame> {
-        let frame = Frame::Addition(10, 32);
-
-        self.connection.write_frame(&frame).await;
-
-        let response = self.connection.read_frame().await?;
-
-        match response {
-            Some(frame) => {
-                println!("Server Response: {:#?}", &frame);
-                Ok(frame)
-            }
-            None => {
-                println!("Failed to get a response");
-                Err("No response".into())
-            }
-        }
-    }
-}
+use learn_tokio_frame::Client;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
-pub async fn main() {
-    let mut c = Client::connect().await;
-    c.addition().await;
+pub async fn main() -> learn_tokio_frame::Result<()> {
+    // Log at `info` unless `RUST_LOG` says otherwise, e.g.
+    // `RUST_LOG=learn_tokio_frame=debug` to see every request.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let mut c = Client::connect("127.0.0.1:8080").await?;
+    c.addition().await?;
+    Ok(())
 }
@@ -0,0 +1,21 @@
+use tokio::net::TcpListener;
+
+use learn_tokio_frame::proxy::Proxy;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Log at `info` unless `RUST_LOG` says otherwise, e.g.
+    // `RUST_LOG=learn_tokio_frame=debug` to see every request.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let listener = TcpListener::bind("127.0.0.1:8081").await?;
+    let upstream = "127.0.0.1:8080".parse()?;
+
+    Proxy::new(listener, upstream).run().await?;
+    Ok(())
+}
@@ -1,9 +1,225 @@
-use tokio::net::TcpListener;
+// The calculator server.
+//
+// Options are read from a TOML file, environment variables and command
+// line flags, each overriding the ones before it:
+//
+//     bind = "0.0.0.0:8080"      CALCULATOR_BIND              --bind
+//     max_connections = 250      CALCULATOR_MAX_CONNECTIONS   --max-connections
+//     idle_timeout = 300         CALCULATOR_IDLE_TIMEOUT      --idle-timeout
+//     write_timeout = 10         CALCULATOR_WRITE_TIMEOUT     --write-timeout
+//     drain_timeout = 30         CALCULATOR_DRAIN_TIMEOUT     --drain-timeout
+//     log = "debug"              CALCULATOR_LOG               --log
+//     audit_log = "audit.log"    CALCULATOR_AUDIT_LOG         --audit-log
+//     admin_token = "secret"     CALCULATOR_ADMIN_TOKEN       --admin-token
+//
+// `bind` takes several addresses separated by commas, e.g.
+// `0.0.0.0:8080,[::]:8080` for IPv4 and IPv6 clients. Timeouts are in
+// seconds. The file is given with `--config` or `CALCULATOR_CONFIG`,
+// without one only the other two are used. Without a log level `RUST_LOG`
+// is used, `info` if that is unset as well. The audit log is rotated at
+// `AUDIT_LOG_MAX_BYTES`, keeping `AUDIT_LOG_MAX_FILES` old files. Without
+// an admin token admin frames are rejected, other users of the host can
+// see flags, so it is better set in the file or the environment.
+use std::{collections::HashMap, error::Error, time::Duration};
+
+use learn_tokio_frame::{audit::AuditLog, server::Server};
+use tracing_subscriber::EnvFilter;
+
+// Options that can be set, as named in the config file.
+const OPTIONS: [&str; 8] = [
+    "bind",
+    "max_connections",
+    "idle_timeout",
+    "write_timeout",
+    "drain_timeout",
+    "log",
+    "audit_log",
+    "admin_token",
+];
+
+const AUDIT_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const AUDIT_LOG_MAX_FILES: usize = 5;
 
 #[tokio::main]
-pub async fn main() -> learn_tokio_frame::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:8080").await.unwrap();
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    let env = env_vars()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = options(&env, &args)?;
+
+    let filter = match options.get("log") {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    tracing_subscriber::fmt().with_env_filter(filter).init();
 
-    learn_tokio_frame::server::run(listener).await;
+    let mut builder = Server::builder();
+    if let Some(max) = options.get("max_connections") {
+        builder = builder.max_connections(parse(max, "max_connections")?);
+    }
+    if let Some(secs) = options.get("idle_timeout") {
+        builder = builder.idle_timeout(seconds(secs, "idle_timeout")?);
+    }
+    if let Some(secs) = options.get("write_timeout") {
+        builder = builder.write_timeout(seconds(secs, "write_timeout")?);
+    }
+    if let Some(secs) = options.get("drain_timeout") {
+        builder = builder.drain_timeout(seconds(secs, "drain_timeout")?);
+    }
+    if let Some(path) = options.get("audit_log") {
+        let audit = AuditLog::open(path, AUDIT_LOG_MAX_BYTES, AUDIT_LOG_MAX_FILES)
+            .map_err(|err| format!("can not open audit log {}: {}", path, err))?;
+        builder = builder.audit_log(audit);
+    }
+    if let Some(token) = options.get("admin_token") {
+        builder = builder.admin_token(token.as_str());
+    }
+    let bind = options.get("bind").map_or("127.0.0.1:8080", String::as_str);
+    let addrs = bind
+        .split(',')
+        .map(|addr| parse(addr.trim(), "bind"))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    builder
+        .bind_all(&addrs)?
+        .run_until(tokio::signal::ctrl_c())
+        .await;
     Ok(())
 }
+
+// The options set by the config file, the environment `env` and the
+// command line `args`, later ones overriding earlier ones.
+fn options(
+    env: &HashMap<String, String>,
+    args: &[String],
+) -> Result<HashMap<&'static str, String>, Box<dyn Error>> {
+    let flags = flags(args)?;
+    let mut options = HashMap::new();
+
+    let path = flags.get("config").or_else(|| env.get("CALCULATOR_CONFIG"));
+    if let Some(path) = path {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("can not read config file {}: {}", path, err))?;
+        options.extend(file_options(&contents)?);
+    }
+
+    for option in OPTIONS {
+        if let Some(value) = env.get(&env_var(option)) {
+            options.insert(option, value.clone());
+        }
+    }
+
+    for option in OPTIONS {
+        if let Some(value) = flags.get(option) {
+            options.insert(option, value.clone());
+        }
+    }
+    Ok(options)
+}
+
+// The environment variables of the options, others are never read, they
+// may not even be valid UTF-8.
+fn env_vars() -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let mut env = HashMap::new();
+    let names = std::iter::once("CALCULATOR_CONFIG".to_string()).chain(OPTIONS.map(env_var));
+    for name in names {
+        if let Some(value) = std::env::var_os(&name) {
+            let value = value
+                .into_string()
+                .map_err(|_| format!("{} is not valid UTF-8", name))?;
+            env.insert(name, value);
+        }
+    }
+    Ok(env)
+}
+
+// The environment variable of `option`, e.g. `CALCULATOR_BIND`.
+fn env_var(option: &str) -> String {
+    format!("CALCULATOR_{}", option.to_uppercase())
+}
+
+// `--name value` pairs of the command line, `name` as in the config file.
+fn flags(args: &[String]) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let mut flags = HashMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let name = match arg.strip_prefix("--") {
+            Some(name) => name.replace('-', "_"),
+            None => return Err(format!("unexpected argument {}", arg).into()),
+        };
+        if name != "config" && !OPTIONS.contains(&name.as_str()) {
+            return Err(format!("unknown flag {}", arg).into());
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        flags.insert(name, value.clone());
+    }
+    Ok(flags)
+}
+
+fn file_options(contents: &str) -> Result<HashMap<&'static str, String>, Box<dyn Error>> {
+    let table: toml::Table = contents
+        .parse()
+        .map_err(|err| format!("invalid config file: {}", err))?;
+
+    let mut options = HashMap::new();
+    for (key, value) in table {
+        let option = OPTIONS
+            .iter()
+            .find(|option| **option == key)
+            .ok_or_else(|| format!("unknown option {} in config file", key))?;
+        let value = match value {
+            toml::Value::String(value) => value,
+            toml::Value::Integer(value) => value.to_string(),
+            other => return Err(format!("invalid value for {}: {}", key, other).into()),
+        };
+        options.insert(*option, value);
+    }
+    Ok(options)
+}
+
+fn parse<T>(value: &str, option: &str) -> Result<T, Box<dyn Error>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|err| format!("invalid value for {}: {}: {}", option, value, err).into())
+}
+
+fn seconds(value: &str, option: &str) -> Result<Duration, Box<dyn Error>> {
+    parse(value, option).map(Duration::from_secs)
+}
+
+#[test]
+fn test_options_precedence() {
+    let path = std::env::temp_dir().join(format!("calculator-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        "bind = \"0.0.0.0:9000\"\nmax_connections = 10\nidle_timeout = 60\n",
+    )
+    .unwrap();
+
+    let env = HashMap::from([
+        ("CALCULATOR_CONFIG".to_string(), path.display().to_string()),
+        ("CALCULATOR_MAX_CONNECTIONS".to_string(), "20".to_string()),
+        ("CALCULATOR_LOG".to_string(), "debug".to_string()),
+    ]);
+    let args = ["--max-connections", "30", "--write-timeout", "5"].map(String::from);
+    let options = options(&env, &args).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let expected = HashMap::from([
+        ("bind", "0.0.0.0:9000"),
+        ("max_connections", "30"),
+        ("idle_timeout", "60"),
+        ("write_timeout", "5"),
+        ("log", "debug"),
+    ]);
+    let options: HashMap<_, _> = options.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    assert_eq!(expected, options);
+
+    assert!(file_options("threads = 4").is_err());
+    assert!(flags(&["--bind".to_string()]).is_err());
+}
@@ -0,0 +1,162 @@
+// Binary encoding of frames, see `connection::Encoding::Binary`.
+//
+// Every frame is sent as a big endian `u32` length, the number of bytes
+// that follow, then an opcode byte and the payload:
+//
+//   opcode 1 to 4: Addition, Subtraction, Multiplication and Modulo,
+//                  the two operands as big endian `u64`s.
+//   opcode 5:      OpResult, the result as a big endian `u64`.
+//   opcode 0:      any other frame, in its text encoding.
+//
+// The length delimits a frame without looking at its payload, so an
+// invalid frame is skipped exactly.
+use std::io::Cursor;
+
+use tokio_util::bytes::{Buf, BufMut, BytesMut};
+
+use crate::{
+    frame::{Error, ParseConfig},
+    Frame,
+};
+
+const TEXT: u8 = 0;
+const ADDITION: u8 = 1;
+const SUBTRACTION: u8 = 2;
+const MULTIPLICATION: u8 = 3;
+const MODULO: u8 = 4;
+const OP_RESULT: u8 = 5;
+
+// Advance `src` past one frame, `Incomplete` if it is not fully buffered.
+pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
+    let len = get_len(src)?;
+    if src.remaining() < len {
+        return Err(Error::Incomplete);
+    }
+    src.advance(len);
+    Ok(())
+}
+
+// Parse a frame that passed `check`.
+pub fn parse(src: &mut Cursor<&[u8]>, config: &ParseConfig) -> Result<Frame, Error> {
+    let len = get_len(src)?;
+    if src.remaining() < len {
+        return Err(Error::Incomplete);
+    }
+    let start = src.position() as usize;
+    let mut payload = Cursor::new(&src.get_ref()[start..start + len]);
+    src.advance(len);
+
+    if !payload.has_remaining() {
+        return Err(Error::InvalidLength);
+    }
+    let opcode = payload.get_u8();
+    let operands = match opcode {
+        TEXT => {
+            let mut text = Cursor::new(&payload.get_ref()[1..]);
+            let frame = Frame::parse_with(&mut text, config)?;
+            if text.has_remaining() {
+                return Err(Error::InvalidLength);
+            }
+            return Ok(frame);
+        }
+        OP_RESULT => 1,
+        ADDITION..=MODULO => 2,
+        _ => return Err(Error::InvalidTypeByte(opcode)),
+    };
+    if payload.remaining() != operands * 8 {
+        return Err(Error::InvalidLength);
+    }
+
+    let frame = match opcode {
+        ADDITION => Frame::Addition(payload.get_u64(), payload.get_u64()),
+        SUBTRACTION => Frame::Subtraction(payload.get_u64(), payload.get_u64()),
+        MULTIPLICATION => Frame::Multiplication(payload.get_u64(), payload.get_u64()),
+        MODULO => Frame::Modulo(payload.get_u64(), payload.get_u64()),
+        _ => Frame::OpResult(payload.get_u64()),
+    };
+    Ok(frame)
+}
+
+// Append the encoding of `frame` to `dst`. The frame is encoded in place
+// and its length filled in afterwards.
+pub fn encode(frame: &Frame, dst: &mut BytesMut) -> Result<(), crate::Error> {
+    let start = dst.len();
+    dst.put_u32(0);
+    let encoded = match frame {
+        Frame::Addition(x, y) => encode_operands(dst, ADDITION, &[*x, *y]),
+        Frame::Subtraction(x, y) => encode_operands(dst, SUBTRACTION, &[*x, *y]),
+        Frame::Multiplication(x, y) => encode_operands(dst, MULTIPLICATION, &[*x, *y]),
+        Frame::Modulo(x, y) => encode_operands(dst, MODULO, &[*x, *y]),
+        Frame::OpResult(r) => encode_operands(dst, OP_RESULT, &[*r]),
+        _ => {
+            dst.put_u8(TEXT);
+            frame.encode(dst)
+        }
+    }
+    .and_then(|()| {
+        u32::try_from(dst.len() - start - 4)
+            .map_err(|_| crate::Error::Encode("(binary) frame is too large".to_string()))
+    });
+
+    match encoded {
+        Ok(len) => {
+            dst[start..start + 4].copy_from_slice(&len.to_be_bytes());
+            Ok(())
+        }
+        Err(err) => {
+            dst.truncate(start);
+            Err(err)
+        }
+    }
+}
+
+fn encode_operands(dst: &mut BytesMut, opcode: u8, operands: &[u64]) -> crate::Result<()> {
+    dst.put_u8(opcode);
+    for operand in operands {
+        dst.put_u64(*operand);
+    }
+    Ok(())
+}
+
+fn get_len(src: &mut Cursor<&[u8]>) -> Result<usize, Error> {
+    if src.remaining() < 4 {
+        return Err(Error::Incomplete);
+    }
+    Ok(src.get_u32() as usize)
+}
+
+#[test]
+fn test_parse_binary() {
+    let mut buf = vec![0, 0, 0, 17, ADDITION];
+    buf.extend_from_slice(&u64::MAX.to_be_bytes());
+    buf.extend_from_slice(&7u64.to_be_bytes());
+    buf.extend_from_slice(b"\0\0\0\x04\0p\r\n");
+
+    let mut cursor = Cursor::new(&buf[..]);
+    check(&mut cursor).unwrap();
+    check(&mut cursor).unwrap();
+    assert_eq!(buf.len() as u64, cursor.position());
+
+    let mut cursor = Cursor::new(&buf[..]);
+    let config = ParseConfig::default();
+    assert!(matches!(
+        parse(&mut cursor, &config),
+        Ok(Frame::Addition(u64::MAX, 7))
+    ));
+    assert!(matches!(parse(&mut cursor, &config), Ok(Frame::Ping)));
+
+    let mut cursor = Cursor::new(&buf[..20]);
+    assert!(matches!(check(&mut cursor), Err(Error::Incomplete)));
+
+    for buf in [
+        &b"\0\0\0\0"[..],
+        b"\0\0\0\x02\x05\0",
+        b"\0\0\0\x01\x09",
+        b"\0\0\0\x07\0p\r\np\r\n",
+    ] {
+        let mut cursor = Cursor::new(buf);
+        assert!(parse(&mut cursor, &config).is_err(), "{:?}", buf);
+        // An invalid frame is still skipped as a whole.
+        assert_eq!(buf.len() as u64, cursor.position());
+    }
+}
@@ -0,0 +1,135 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+// Guards calls to something that can fail repeatedly, e.g. a downstream
+// service.
+//
+// The breaker starts `Closed` and lets every call through. After
+// `failure_threshold` consecutive failures it becomes `Open` and rejects
+// calls until `cooldown` has passed. The first call after the cooldown
+// moves it to `HalfOpen` and is let through as a trial, its outcome
+// either closes the breaker again or re-opens it for another cooldown.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+
+    // Consecutive failures seen while closed.
+    failures: u32,
+
+    // When the breaker last opened.
+    opened_at: Instant,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold,
+            cooldown,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                failures: 0,
+                opened_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.inner.lock().unwrap().state
+    }
+
+    // Returns `true` if a call may go ahead. Every allowed call has to be
+    // followed by `record_success` or `record_failure`.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            State::Closed => true,
+            State::Open if inner.opened_at.elapsed() >= self.cooldown => {
+                inner.state = State::HalfOpen;
+                true
+            }
+            // Only the single trial call is let through while half-open.
+            State::Open | State::HalfOpen => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = State::Closed;
+        inner.failures = 0;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            State::Closed => {
+                inner.failures += 1;
+                if inner.failures >= self.failure_threshold {
+                    inner.state = State::Open;
+                    inner.opened_at = Instant::now();
+                }
+            }
+            State::HalfOpen => {
+                inner.state = State::Open;
+                inner.opened_at = Instant::now();
+            }
+            State::Open => {}
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_breaker_states() {
+    let breaker = CircuitBreaker::new(3, Duration::from_secs(10));
+
+    // Failures below the threshold, interrupted by a success, keep it closed.
+    for _ in 0..2 {
+        assert!(breaker.allow());
+        breaker.record_failure();
+    }
+    assert!(breaker.allow());
+    breaker.record_success();
+    assert_eq!(State::Closed, breaker.state());
+
+    for _ in 0..3 {
+        assert!(breaker.allow());
+        breaker.record_failure();
+    }
+    assert_eq!(State::Open, breaker.state());
+    assert!(!breaker.allow());
+
+    tokio::time::advance(Duration::from_secs(5)).await;
+    assert!(!breaker.allow());
+
+    // Cooldown passed, a single trial call goes through and fails.
+    tokio::time::advance(Duration::from_secs(5)).await;
+    assert!(breaker.allow());
+    assert_eq!(State::HalfOpen, breaker.state());
+    assert!(!breaker.allow());
+    breaker.record_failure();
+    assert_eq!(State::Open, breaker.state());
+    assert!(!breaker.allow());
+
+    // The next trial succeeds and the breaker recovers.
+    tokio::time::advance(Duration::from_secs(10)).await;
+    assert!(breaker.allow());
+    breaker.record_success();
+    assert_eq!(State::Closed, breaker.state());
+    assert!(breaker.allow());
+}
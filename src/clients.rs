@@ -0,0 +1,390 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    path::Path,
+};
+
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    connection,
+    frame::{ErrorCode, ServerStats, Token, PROTOCOL_VERSION},
+    Connection, Frame,
+};
+
+#[derive(Debug)]
+pub struct Client<T = TcpStream> {
+    connection: Connection<T>,
+
+    // Protocol version accepted by the server.
+    version: u32,
+}
+
+// A request the server answered with `Frame::Error`.
+#[derive(Debug, PartialEq)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl std::error::Error for ServerError {}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "server error {:?}: {}", self.code, self.message)
+    }
+}
+
+impl Client<TcpStream> {
+    // Connect and negotiate the latest protocol version this crate
+    // implements.
+    pub async fn connect<T: ToSocketAddrs>(addr: T) -> crate::Result<Client> {
+        Client::connect_with_version(addr, PROTOCOL_VERSION).await
+    }
+
+    // Connect and offer `version` with a `Frame::Hello`. A server that
+    // does not accept it answers with an error frame, returned as a
+    // `ServerError`.
+    pub async fn connect_with_version<T: ToSocketAddrs>(
+        addr: T,
+        version: u32,
+    ) -> crate::Result<Client> {
+        let socket = TcpStream::connect(addr).await?;
+        Client::handshake(socket, version).await
+    }
+}
+
+#[cfg(unix)]
+impl Client<UnixStream> {
+    // Like `connect`, to a server listening on the Unix domain socket at
+    // `path`, see `server::Builder::build_unix`.
+    pub async fn connect_unix(path: impl AsRef<Path>) -> crate::Result<Client<UnixStream>> {
+        let socket = UnixStream::connect(path).await?;
+        Client::handshake(socket, PROTOCOL_VERSION).await
+    }
+}
+
+impl<T> Client<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // Offer `version` with a `Frame::Hello` on an already connected
+    // stream, like `connect_with_version`.
+    pub async fn handshake(stream: T, version: u32) -> crate::Result<Client<T>> {
+        let mut connection = Connection::new(stream);
+        connection.write_frame(&Frame::Hello(version)).await?;
+        let version = match connection.read_frame().await? {
+            // The server picks a version no later than the offered one.
+            Some(Frame::Hello(accepted)) if accepted <= version => accepted,
+            // A later version than the offered one is unexpected as well.
+            Some(Frame::Error(code, message)) => return Err(ServerError { code, message }.into()),
+            Some(frame) => return Err(crate::Error::UnexpectedResponse(frame)),
+            None => return Err(crate::Error::ConnectionClosed),
+        };
+
+        Ok(Client {
+            connection,
+            version,
+        })
+    }
+
+    // The protocol version negotiated when connecting.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // Counters of the underlying connection, including the handshake.
+    pub fn stats(&self) -> connection::Stats {
+        self.connection.stats()
+    }
+
+    pub async fn addition(&mut self) -> crate::Result<Frame> {
+        let frame = Frame::Addition(10, 32);
+
+        self.connection.write_frame(&frame).await?;
+
+        let response = self.connection.read_frame().await?;
+
+        match response {
+            Some(Frame::Error(code, message)) => Err(ServerError { code, message }.into()),
+            Some(frame) => {
+                tracing::info!(response = %frame, "server response");
+                Ok(frame)
+            }
+            None => {
+                tracing::warn!("connection closed without a response");
+                Err(crate::Error::ConnectionClosed)
+            }
+        }
+    }
+
+    // Send a request frame and wait for its response.
+    pub async fn request(&mut self, frame: &Frame) -> crate::Result<Frame> {
+        self.connection.write_frame(frame).await?;
+
+        match self.connection.read_frame().await? {
+            Some(response) => Ok(response),
+            None => Err(crate::Error::ConnectionClosed),
+        }
+    }
+
+    // Like `request`, an error frame is returned as a `ServerError`.
+    pub async fn call(&mut self, frame: &Frame) -> crate::Result<Frame> {
+        match self.request(frame).await? {
+            Frame::Error(code, message) => Err(ServerError { code, message }.into()),
+            response => Ok(response),
+        }
+    }
+
+    // Statistics of the server, `token` is its admin token.
+    pub async fn server_stats(&mut self, token: &str) -> crate::Result<ServerStats> {
+        match self.call(&Frame::Stats(token.to_string())).await? {
+            Frame::StatsInfo(stats) => Ok(stats),
+            frame => Err(crate::Error::UnexpectedResponse(frame)),
+        }
+    }
+
+    // Shut the server down, `token` is its admin token. Returns once the
+    // server started shutting down.
+    pub async fn shutdown_server(&mut self, token: &str) -> crate::Result<()> {
+        match self.call(&Frame::Shutdown(token.to_string())).await? {
+            Frame::Shutdown(_) => Ok(()),
+            frame => Err(crate::Error::UnexpectedResponse(frame)),
+        }
+    }
+
+    // Send an arithmetic frame and check the result of the server against
+    // `Frame::eval`. An error frame from the server is returned as an error.
+    pub async fn verify(&mut self, frame: Frame) -> crate::Result<bool> {
+        let expected = frame.eval();
+
+        match self.call(&frame).await? {
+            Frame::OpResult(result) => Ok(expected == Some(result)),
+            frame => Err(crate::Error::UnexpectedResponse(frame)),
+        }
+    }
+}
+
+// A `Client` that answers repeated requests from a local cache.
+//
+// Operations are pure, so the response to a request only depends on the
+// request. Up to `capacity` responses are kept, the least recently used
+// one is evicted first. Only operations are cached, requests that depend
+// on the connection, e.g. registers, always go to the server.
+pub struct CachingClient<T = TcpStream> {
+    client: Client<T>,
+    capacity: usize,
+    cache_errors: bool,
+    responses: HashMap<Vec<u8>, Frame>,
+
+    // Keys of `responses`, the most recently used last.
+    recency: VecDeque<Vec<u8>>,
+}
+
+impl<T> CachingClient<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(client: Client<T>, capacity: usize) -> CachingClient<T> {
+        CachingClient {
+            client,
+            capacity,
+            cache_errors: false,
+            responses: HashMap::new(),
+            recency: VecDeque::new(),
+        }
+    }
+
+    // Also cache error responses. Off by default, so a failed request is
+    // retried by the server.
+    pub fn set_cache_errors(&mut self, cache_errors: bool) {
+        self.cache_errors = cache_errors;
+    }
+
+    pub async fn request(&mut self, frame: &Frame) -> crate::Result<Frame> {
+        let key = match cache_key(frame)? {
+            Some(key) => key,
+            None => return self.client.request(frame).await,
+        };
+
+        if let Some(response) = self.responses.get(&key) {
+            let response = response.clone();
+            self.touch(&key);
+            return Ok(response);
+        }
+
+        let response = self.client.request(frame).await?;
+        if self.capacity > 0 && (self.cache_errors || !matches!(response, Frame::Error(..))) {
+            if self.responses.len() == self.capacity {
+                if let Some(oldest) = self.recency.pop_front() {
+                    self.responses.remove(&oldest);
+                }
+            }
+            self.responses.insert(key.clone(), response.clone());
+            self.recency.push_back(key);
+        }
+        Ok(response)
+    }
+
+    // Mark `key` as the most recently used.
+    fn touch(&mut self, key: &[u8]) {
+        if let Some(i) = self.recency.iter().position(|used| used == key) {
+            let key = self.recency.remove(i).unwrap();
+            self.recency.push_back(key);
+        }
+    }
+}
+
+// The encoding of the normalized request, `None` if the response can not
+// be cached. The operands of commutative operations are ordered, so `2+3`
+// and `3+2` share an entry. Only where the order can not change the
+// answer, a product with a zero overflows or not depending on whether the
+// zero comes first.
+fn cache_key(frame: &Frame) -> crate::Result<Option<Vec<u8>>> {
+    let normalized = match frame {
+        Frame::Addition(x, y) => Frame::Addition(*x.min(y), *x.max(y)),
+        Frame::Multiplication(x, y) => Frame::Multiplication(*x.min(y), *x.max(y)),
+        Frame::Gcd(x, y) => Frame::Gcd(*x.min(y), *x.max(y)),
+        Frame::Lcm(x, y) => Frame::Lcm(*x.min(y), *x.max(y)),
+        Frame::Product(operands) if operands.contains(&0) => frame.clone(),
+        Frame::Sum(operands) | Frame::Product(operands) => {
+            let mut sorted = operands.clone();
+            sorted.sort_unstable();
+            match frame {
+                Frame::Sum(_) => Frame::Sum(sorted),
+                _ => Frame::Product(sorted),
+            }
+        }
+        Frame::Aggregate(function, operands) => {
+            let mut sorted = operands.clone();
+            sorted.sort_unstable();
+            Frame::Aggregate(*function, sorted)
+        }
+        Frame::Subtraction(..)
+        | Frame::Modulo(..)
+        | Frame::Division(..)
+        | Frame::Pow(..)
+        | Frame::Factorial(_)
+        | Frame::Signed(..)
+        | Frame::Float(..)
+        | Frame::Decimal(..)
+        | Frame::Expr(_)
+        | Frame::Sort(_) => frame.clone(),
+        // A result reference depends on the other elements of an array.
+        Frame::Rpn(tokens) if !tokens.iter().any(|token| matches!(token, Token::Ref(_))) => {
+            frame.clone()
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(normalized.to_vec()?))
+}
+
+#[test]
+fn test_cache_key() {
+    let key = |frame: Frame| cache_key(&frame).unwrap().unwrap();
+
+    assert_eq!(
+        key(Frame::Sum(vec![3, 1, 2])),
+        key(Frame::Sum(vec![1, 2, 3]))
+    );
+    assert_eq!(
+        key(Frame::Product(vec![4, 2, 3])),
+        key(Frame::Product(vec![2, 3, 4]))
+    );
+
+    // The first overflows before the zero is reached, the second is zero.
+    assert_ne!(
+        key(Frame::Product(vec![1 << 32, 1 << 32, 0])),
+        key(Frame::Product(vec![0, 1 << 32, 1 << 32]))
+    );
+}
+
+#[tokio::test]
+async fn test_verify_random_operations() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // Recover so the server answers failed operations with an error frame.
+    let config = crate::server::ServerConfig {
+        recover_on_protocol_error: true,
+        ..Default::default()
+    };
+    tokio::spawn(crate::server::Server::with_config(listener, config).run());
+
+    let mut client = Client::connect(addr).await.unwrap();
+
+    // xorshift, enough to spread the operands without a dependency.
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    for _ in 0..200 {
+        // Operands stay below 2^32 so no operation overflows.
+        let (x, y) = (next() >> 32, next() >> 32);
+        let frame = match next() % 3 {
+            0 => Frame::Addition(x, y),
+            1 => Frame::Subtraction(x.max(y), x.min(y)),
+            _ => Frame::Multiplication(x, y),
+        };
+        assert!(client.verify(frame.clone()).await.unwrap(), "{:?}", frame);
+    }
+
+    let err = client.verify(Frame::Subtraction(1, 2)).await.unwrap_err();
+    let crate::Error::Server(err) = err else {
+        panic!("unexpected error {:?}", err);
+    };
+    assert_eq!(ErrorCode::Underflow, err.code);
+    assert_eq!("arithmetic underflow", err.message);
+}
+
+#[tokio::test]
+async fn test_caching_client_serves_repeated_requests() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = crate::server::ServerConfig {
+        recover_on_protocol_error: true,
+        ..Default::default()
+    };
+    let server = crate::server::Server::with_config(listener, config);
+    let handle = server.handle();
+    tokio::spawn(server.run());
+
+    let mut client = CachingClient::new(Client::connect(addr).await.unwrap(), 2);
+
+    let response = client.request(&Frame::Addition(2, 3)).await.unwrap();
+    assert!(matches!(response, Frame::OpResult(5)));
+    assert_eq!(1, handle.requests_served());
+
+    // Same request with the operands swapped, answered from the cache.
+    let response = client.request(&Frame::Addition(3, 2)).await.unwrap();
+    assert!(matches!(response, Frame::OpResult(5)));
+    assert_eq!(1, handle.requests_served());
+
+    // Errors are not cached by default.
+    for served in [2, 3] {
+        let response = client.request(&Frame::Subtraction(1, 2)).await.unwrap();
+        assert!(matches!(response, Frame::Error(..)));
+        assert_eq!(served, handle.requests_served());
+    }
+
+    // `2+3` is the least recently used entry and is evicted.
+    client.request(&Frame::Multiplication(6, 7)).await.unwrap();
+    client.request(&Frame::Multiplication(7, 7)).await.unwrap();
+    assert_eq!(5, handle.requests_served());
+    client.request(&Frame::Addition(2, 3)).await.unwrap();
+    assert_eq!(6, handle.requests_served());
+
+    // Pings are never cached.
+    client.request(&Frame::Ping).await.unwrap();
+    client.request(&Frame::Ping).await.unwrap();
+    assert_eq!(8, handle.requests_served());
+}
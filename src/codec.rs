@@ -0,0 +1,323 @@
+// Encoding and decoding of frames for `tokio_util::codec`.
+//
+// `FrameCodec` turns a byte stream into frames and back, so any
+// `AsyncRead + AsyncWrite` can be used as a `Stream` and `Sink` of frames
+// with `Framed`. `Connection` uses the same codec for its own buffers.
+use std::io::Cursor;
+
+use tokio_util::{
+    bytes::{Buf, BufMut, BytesMut},
+    codec::{Decoder, Encoder},
+};
+
+use crate::{
+    binary,
+    connection::{Encoding, DEFAULT_MAX_FRAME_LEN},
+    frame::{self, Frame, ParseConfig},
+};
+
+// Length of the checksum that follows a frame, see `FrameCodec::set_checksum`.
+const CHECKSUM_LEN: usize = 4;
+
+#[derive(Clone, Debug)]
+pub struct FrameCodec {
+    // Options used when decoding frames.
+    parse_config: ParseConfig,
+
+    // How frames are encoded on the wire, both directions use the same.
+    encoding: Encoding,
+
+    // Decoding fails once this many bytes are buffered without a complete
+    // frame, so a peer can not make the read buffer grow forever.
+    max_frame_len: usize,
+
+    // Follow every frame with its CRC32, see `set_checksum`.
+    checksum: bool,
+}
+
+impl FrameCodec {
+    pub fn new() -> FrameCodec {
+        FrameCodec::with_parse_config(ParseConfig::default())
+    }
+
+    pub fn with_encoding(encoding: Encoding) -> FrameCodec {
+        FrameCodec {
+            encoding,
+            ..FrameCodec::new()
+        }
+    }
+
+    pub fn with_parse_config(parse_config: ParseConfig) -> FrameCodec {
+        FrameCodec {
+            parse_config,
+            encoding: Encoding::Text,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            checksum: false,
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    // Set the longest frame `decode` accepts. Once more bytes are buffered
+    // without completing a frame it fails with
+    // `frame::Error::FrameTooLarge`, the stream can not be decoded any
+    // further.
+    pub fn set_max_frame_len(&mut self, bytes: usize) {
+        self.max_frame_len = bytes;
+    }
+
+    // Follow every frame with the CRC32 of its encoding, as a big endian
+    // `u32`, and require the same of the frames that are decoded. A frame
+    // whose checksum does not match is dropped and decoding fails with
+    // `frame::Error::ChecksumMismatch`. Off by default, both peers have to
+    // agree on it.
+    pub fn set_checksum(&mut self, enabled: bool) {
+        self.checksum = enabled;
+    }
+
+    // Append the encoding of `frame` to `dst`, like `Encoder::encode` but
+    // without taking ownership of the frame. A frame that can not be
+    // encoded leaves `dst` as it was.
+    pub fn encode_frame(&self, frame: &Frame, dst: &mut BytesMut) -> crate::Result<()> {
+        let start = dst.len();
+        match self.encoding {
+            Encoding::Text => frame.encode(dst)?,
+            Encoding::Binary => binary::encode(frame, dst)?,
+        }
+        if self.checksum {
+            let checksum = crc32fast::hash(&dst[start..]);
+            dst.put_u32(checksum);
+        }
+        Ok(())
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> FrameCodec {
+        FrameCodec::new()
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = crate::Error;
+
+    // Decode the frame at the start of `buffer`, `Ok(None)` if it is not
+    // fully buffered yet. An invalid frame is removed from the buffer, so
+    // the next call continues with the frame after it.
+    fn decode(&mut self, buffer: &mut BytesMut) -> crate::Result<Option<Frame>> {
+        let decoded = decode(buffer, &self.parse_config, self.encoding, self.checksum)?;
+
+        // Every complete frame was consumed, so the buffer only holds the
+        // start of the next one.
+        if decoded.is_none() && buffer.len() > self.max_frame_len {
+            return Err(frame::Error::FrameTooLarge(self.max_frame_len).into());
+        }
+        Ok(decoded)
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = crate::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> crate::Result<()> {
+        self.encode_frame(&frame, dst)
+    }
+}
+
+fn decode(
+    buffer: &mut BytesMut,
+    config: &ParseConfig,
+    encoding: Encoding,
+    checksum: bool,
+) -> crate::Result<Option<Frame>> {
+    use frame::Error::Incomplete;
+
+    // Cursor is used to track the current location in the buffer.
+    let mut buf = Cursor::new(&buffer[..]);
+
+    // Check if enough data has been buffered to  parse a single frame.
+    // If enough data is not present we can skip allocating.
+    let checked = match encoding {
+        Encoding::Text => Frame::check_with(&mut buf, config),
+        Encoding::Binary => binary::check(&mut buf),
+    };
+    match checked {
+        Ok(_) => {
+            // `check` function will advance the cursor until the end of the
+            // frame. Since the cursor has position set to zero before
+            // `Frame::check` was called, we get the length of the frame
+            // by checking the cursor position.
+            let len = buf.position() as usize;
+
+            // The checksum follows the frame, it has to be buffered as well.
+            let trailer = if checksum { CHECKSUM_LEN } else { 0 };
+            if buffer.len() < len + trailer {
+                return Ok(None);
+            }
+
+            // We have enough data in the buffer to parse the frame. Parse
+            // it, if the encoded frame is invalid an error is returned.
+            let frame = if checksum && !checksum_matches(&buffer[..len + trailer]) {
+                Err(frame::Error::ChecksumMismatch)
+            } else {
+                let mut buf = Cursor::new(&buffer[..len]);
+                match encoding {
+                    Encoding::Text => Frame::parse_with(&mut buf, config),
+                    Encoding::Binary => binary::parse(&mut buf, config),
+                }
+            };
+
+            // The frame is discarded whether it parsed or not, so an invalid
+            // frame does not prevent reading the frames after it.
+            // Calling advance will discard the data.
+            buffer.advance(len + trailer);
+
+            // Return parsed frame.
+            Ok(Some(frame?))
+        }
+        Err(Incomplete) => Ok(None),
+
+        Err(e) => {
+            // The frame can not be delimited, skip everything up to the end
+            // of the current line instead, and the checksum after it.
+            let len = match memchr::memmem::find(&buffer[..], b"\r\n") {
+                Some(i) if checksum => i + 2 + CHECKSUM_LEN,
+                Some(i) => i + 2,
+                None => buffer.len(),
+            };
+            // The checksum is not buffered yet, the error is reported once
+            // it is, so none of it is left to be decoded as the next frame.
+            if len > buffer.len() {
+                return Ok(None);
+            }
+            buffer.advance(len);
+
+            Err(e.into())
+        }
+    }
+}
+
+// Whether the last `CHECKSUM_LEN` bytes of `frame` are the CRC32 of the
+// bytes before them.
+fn checksum_matches(frame: &[u8]) -> bool {
+    let (frame, checksum) = frame.split_at(frame.len() - CHECKSUM_LEN);
+    crc32fast::hash(frame).to_be_bytes() == checksum
+}
+
+#[tokio::test]
+async fn test_framed_round_trip() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    for encoding in [Encoding::Text, Encoding::Binary] {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = Framed::new(client, FrameCodec::with_encoding(encoding));
+        let mut server = Framed::new(server, FrameCodec::with_encoding(encoding));
+
+        let frames = [
+            Frame::Addition(1, 2),
+            Frame::Echo(b"\r\n".to_vec()),
+            Frame::Array(vec![Frame::Ping, Frame::OpResult(u64::MAX)]),
+        ];
+        for frame in &frames {
+            client.feed(frame.clone()).await.unwrap();
+        }
+        client.flush().await.unwrap();
+        drop(client);
+
+        for frame in &frames {
+            let received = server.next().await.unwrap().unwrap();
+            assert_eq!(format!("{:?}", frame), format!("{:?}", received));
+        }
+        assert!(server.next().await.is_none());
+    }
+}
+
+#[test]
+fn test_decode_errors() {
+    let mut codec = FrameCodec::new();
+
+    // An invalid frame is skipped, the frame after it still decodes.
+    let mut buffer = BytesMut::from(&b"?\r\np\r\n"[..]);
+    let err = codec.decode(&mut buffer).unwrap_err();
+    assert!(matches!(
+        err,
+        crate::Error::Protocol(frame::Error::InvalidTypeByte(b'?'))
+    ));
+    assert!(matches!(
+        codec.decode(&mut buffer).unwrap(),
+        Some(Frame::Ping)
+    ));
+    assert!(codec.decode(&mut buffer).unwrap().is_none());
+
+    codec.set_max_frame_len(8);
+    let mut buffer = BytesMut::from(&b"x1+2+3+4"[..]);
+    assert!(codec.decode(&mut buffer).unwrap().is_none());
+    buffer.extend_from_slice(b"+");
+    let err = codec.decode(&mut buffer).unwrap_err();
+    assert!(matches!(
+        err,
+        crate::Error::Protocol(frame::Error::FrameTooLarge(8))
+    ));
+}
+
+#[test]
+fn test_decode_long_operand() {
+    let mut codec = FrameCodec::new();
+
+    // The digits are rejected before a terminator or the frame length
+    // limit is reached.
+    let mut buffer = BytesMut::from(&b"+1:"[..]);
+    buffer.extend_from_slice(&[b'9'; 64]);
+    let err = codec.decode(&mut buffer).unwrap_err();
+    assert!(matches!(
+        err,
+        crate::Error::Protocol(frame::Error::OperandTooLong)
+    ));
+    assert!(buffer.is_empty());
+
+    // Operands within the limit are still buffered until complete.
+    let mut buffer = BytesMut::from(&b"+1:99999"[..]);
+    assert!(codec.decode(&mut buffer).unwrap().is_none());
+    buffer.extend_from_slice(b"\r\n");
+    assert!(matches!(
+        codec.decode(&mut buffer).unwrap(),
+        Some(Frame::Addition(1, 99999))
+    ));
+}
+
+#[test]
+fn test_decode_corrupted_type_byte_with_checksum() {
+    let mut codec = FrameCodec::new();
+    codec.set_checksum(true);
+
+    let mut buffer = BytesMut::new();
+    codec.encode_frame(&Frame::Ping, &mut buffer).unwrap();
+    codec
+        .encode_frame(&Frame::Addition(1, 2), &mut buffer)
+        .unwrap();
+    buffer[0] = b'?';
+
+    // The corrupted frame is skipped with its checksum, only once the
+    // checksum is buffered.
+    let mut partial = BytesMut::from(&buffer[..4]);
+    assert!(codec.decode(&mut partial).unwrap().is_none());
+
+    let err = codec.decode(&mut buffer).unwrap_err();
+    assert!(matches!(
+        err,
+        crate::Error::Protocol(frame::Error::InvalidTypeByte(b'?'))
+    ));
+    assert!(matches!(
+        codec.decode(&mut buffer).unwrap(),
+        Some(Frame::Addition(1, 2))
+    ));
+    assert!(buffer.is_empty());
+}
@@ -1,15 +1,27 @@
-use crate::frame::{self, Frame};
+use crate::{
+    codec::FrameCodec,
+    frame::{Frame, ParseConfig},
+};
 
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
-    net::TcpStream,
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    time,
 };
 
-use std::io::{self, Cursor, ErrorKind};
-use tokio_util::bytes::{Buf, BytesMut};
+use std::{fmt, future::Future, io::ErrorKind, time::Duration};
+#[cfg(test)]
+use tokio_util::bytes::Buf;
+use tokio_util::{bytes::BytesMut, codec::Decoder};
 
 // Send and recieve `Frame` values from a remte peer.
 //
+// Any `AsyncRead + AsyncWrite` stream can carry the frames, e.g. a
+// `TcpStream`, a Unix socket, a TLS stream or a `tokio::io::duplex` pipe.
+//
 // To read frames, `Connection` uses an internal buffer, which is
 // filled up until there are enough bytes to create a full frame.
 //
@@ -17,144 +29,1617 @@ use tokio_util::bytes::{Buf, BytesMut};
 // buffer. The contents of the write buffer are then written to
 // the socket.
 #[derive(Debug)]
-pub struct Connection {
-    // The `TcpStream` is decorated with a `BufWriter`, which provides
-    // write level buffering.
-    stream: BufWriter<TcpStream>,
+pub struct Connection<T = TcpStream> {
+    // The stream is decorated with a `BufWriter`, which provides write
+    // level buffering.
+    stream: BufWriter<T>,
 
     // The buffer for reading frames.
-    buffer: BytesMut,
+    buffer: ReadBuffer,
+
+    // Decodes frames from `buffer` and encodes the frames that are sent.
+    codec: FrameCodec,
+
+    // Every frame is encoded here first and written with a single
+    // `write_all`, the allocation is reused for the next frame.
+    encoded: BytesMut,
+
+    // `feed_frame` flushes once more than this many bytes are buffered.
+    flush_threshold: usize,
+
+    read_timeout: Option<Duration>,
+
+    write_timeout: Option<Duration>,
+
+    stats: Stats,
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
-        Connection {
-            stream: BufWriter::new(stream),
+// Default for `Connection::set_max_frame_len`.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1024 * 1024;
+
+// Options for `Connection::with_config`.
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    // Wire format, both peers have to use the same.
+    pub encoding: Encoding,
+
+    // Options used when decoding frames.
+    pub parse_config: ParseConfig,
 
+    // See `Connection::set_max_frame_len`.
+    pub max_frame_len: usize,
+
+    // See `Connection::set_checksum`.
+    pub checksum: bool,
+
+    // See `Connection::set_flush_threshold`.
+    pub flush_threshold: usize,
+
+    // Initial capacity of the read buffer.
+    pub read_buffer_capacity: usize,
+
+    // Largest capacity the read buffer keeps. It still grows beyond this
+    // to hold a larger frame, up to `max_frame_len`, but is shrunk back to
+    // `read_buffer_capacity` once that frame was read, so a single large
+    // frame does not hold on to its memory for the rest of the connection.
+    pub max_read_buffer_capacity: usize,
+
+    // How the read buffer grows while a frame does not fit.
+    pub read_buffer_growth: BufferGrowth,
+
+    // `read_frame` fails with `Timeout::Read` when no complete frame
+    // was read within this long, including the time waiting for the frame
+    // to start. Bytes of a partially received frame stay buffered, so
+    // reading can be retried.
+    pub read_timeout: Option<Duration>,
+
+    // Writing and flushing frames fails with `Timeout::Write` when
+    // the peer does not accept the bytes within this long. What was
+    // written of the frame is unknown, the connection should be closed.
+    pub write_timeout: Option<Duration>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            encoding: Encoding::Text,
+            parse_config: ParseConfig::default(),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            checksum: false,
+            // Same as the capacity of the `BufWriter`.
+            flush_threshold: 8 * 1024,
             // Default 4KB read buffer, this is ok for our
             // use case.
-            buffer: BytesMut::with_capacity(4 * 1024),
+            read_buffer_capacity: 4 * 1024,
+            max_read_buffer_capacity: 64 * 1024,
+            read_buffer_growth: BufferGrowth::Double,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+}
+
+// How the read buffer of a `Connection` grows when it is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BufferGrowth {
+    // Double the capacity, a large frame takes few reallocations.
+    #[default]
+    Double,
+
+    // Add this many bytes to the capacity, the buffer is never much
+    // larger than the frame, at the cost of more reallocations.
+    Linear(usize),
+}
+
+// The read buffer of a connection, grown and shrunk as configured in
+// `ConnectionConfig`.
+#[derive(Debug)]
+struct ReadBuffer {
+    bytes: BytesMut,
+
+    // Size of the allocation behind `bytes`, `BytesMut::capacity` does not
+    // count the bytes that were consumed from its front.
+    allocated: usize,
+
+    capacity: usize,
+
+    max_capacity: usize,
+
+    growth: BufferGrowth,
+}
+
+// Counters of a `Connection`, see `Connection::stats`.
+//
+// Bytes are counted as they go through the socket, including those of
+// frames that are still buffered. A frame that fails to decode counts as
+// a parse error, not as a frame read.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub bytes_read: u64,
+
+    pub bytes_written: u64,
+
+    pub frames_read: u64,
+
+    pub frames_written: u64,
+
+    pub parse_errors: u64,
+}
+
+// The timeout a `Connection` ran into, see `crate::Error::Timeout`.
+#[derive(Debug, PartialEq)]
+pub enum Timeout {
+    // No frame was read within the read timeout.
+    Read(Duration),
+
+    // A frame could not be written within the write timeout.
+    Write(Duration),
+}
+
+// The wire format of a `Connection`, both peers have to use the same.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    // The text protocol described in `frame`.
+    #[default]
+    Text,
+
+    // Length prefixed frames, see `binary`. Arithmetic frames are sent
+    // as fixed size binary values instead of decimal text.
+    Binary,
+}
+
+// The read side of a `Connection` after `Connection::split` or
+// `Connection::into_split`.
+//
+// Owns the read buffer, so any bytes that were buffered before the
+// split (including a partially received frame) are still available.
+#[derive(Debug)]
+pub struct FrameReader<R = OwnedReadHalf> {
+    stream: R,
+
+    buffer: ReadBuffer,
+
+    codec: FrameCodec,
+
+    read_timeout: Option<Duration>,
+
+    stats: Stats,
+}
+
+// The write side of a `Connection` after `Connection::split` or
+// `Connection::into_split`.
+#[derive(Debug)]
+pub struct FrameWriter<W = OwnedWriteHalf> {
+    stream: BufWriter<W>,
+
+    codec: FrameCodec,
+
+    encoded: BytesMut,
+
+    flush_threshold: usize,
+
+    write_timeout: Option<Duration>,
+
+    stats: Stats,
+}
+
+impl std::error::Error for Timeout {}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Timeout::Read(timeout) => write!(fmt, "no frame read within {:?}", timeout),
+            Timeout::Write(timeout) => write!(fmt, "frame not written within {:?}", timeout),
         }
     }
+}
+
+impl<T> Connection<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: T) -> Self {
+        Connection::with_config(stream, ConnectionConfig::default())
+    }
+
+    pub fn with_encoding(stream: T, encoding: Encoding) -> Self {
+        Connection::with_config(
+            stream,
+            ConnectionConfig {
+                encoding,
+                ..ConnectionConfig::default()
+            },
+        )
+    }
+
+    pub fn with_parse_config(stream: T, parse_config: ParseConfig) -> Self {
+        Connection::with_config(
+            stream,
+            ConnectionConfig {
+                parse_config,
+                ..ConnectionConfig::default()
+            },
+        )
+    }
+
+    pub fn with_codec(stream: T, codec: FrameCodec) -> Self {
+        Connection {
+            codec,
+            ..Connection::new(stream)
+        }
+    }
+
+    pub fn with_config(stream: T, config: ConnectionConfig) -> Self {
+        let buffer = ReadBuffer::new(&config);
+        let mut codec = FrameCodec::with_parse_config(config.parse_config);
+        codec.set_encoding(config.encoding);
+        codec.set_max_frame_len(config.max_frame_len);
+        codec.set_checksum(config.checksum);
+
+        Connection {
+            stream: BufWriter::new(stream),
+
+            buffer,
+
+            codec,
+
+            encoded: BytesMut::new(),
+
+            flush_threshold: config.flush_threshold,
+
+            read_timeout: config.read_timeout,
+
+            write_timeout: config.write_timeout,
+
+            stats: Stats::default(),
+        }
+    }
+
+    // Set how many encoded bytes `feed_frame` may buffer before it flushes
+    // them, regardless of when `flush` is called.
+    pub fn set_flush_threshold(&mut self, bytes: usize) {
+        self.flush_threshold = bytes;
+    }
+
+    // Set the longest frame `read_frame` accepts. Once more bytes are
+    // buffered without completing a frame it returns
+    // `frame::Error::FrameTooLarge`, the connection should then be closed.
+    pub fn set_max_frame_len(&mut self, bytes: usize) {
+        self.codec.set_max_frame_len(bytes);
+    }
+
+    // Follow every frame with the CRC32 of its encoding, as a big endian
+    // `u32`, and require the same of the frames that are read. A frame
+    // whose checksum does not match is dropped and reading fails with
+    // `frame::Error::ChecksumMismatch`. Off by default, both peers have to
+    // agree on it.
+    pub fn set_checksum(&mut self, enabled: bool) {
+        self.codec.set_checksum(enabled);
+    }
 
     // Tries to parse the frame, if the buffer does not contain
     // enough data , `Ok(None)` is returned. If there is an
     // invalid frame and Err is returned.
     pub fn parse_frame(&mut self) -> crate::Result<Option<Frame>> {
-        use frame::Error::Incomplete;
-        // Cursor is used to track the current location in the buffer.
-        let mut buf = Cursor::new(&self.buffer[..]);
-
-        // Check if enough data has been buffered to  parse a single frame.
-        // If enough data is not present we can skip allocating.
-        match Frame::check(&mut buf) {
-            Ok(_) => {
-                // `check` function will advance the cursor until the end of the
-                // frame. Since the cursor has position set to zero before
-                // `Frame::check` was called, we get the length of the frame
-                // by checking the cursor position.
-                let len = buf.position() as usize;
-
-                // We have enough data in the buffer to parse the frame.
-                // lets' reset the position and call `Frame::parse`
-                buf.set_position(0);
-
-                // Parse the frame, if the encoded frame is invalid an
-                // error is returned.
-                let frame = Frame::parse(&mut buf)?;
-
-                // Parsing the frame succeded, let discard the parsed data.
-                // Calling advance will discard the data.
-                self.buffer.advance(len);
-
-                // Return parsed frame.
-                Ok(Some(frame))
-            }
-            Err(Incomplete) => Ok(None),
-
-            Err(e) => Err(e.into()),
-        }
+        decode(&mut self.buffer, &mut self.codec, &mut self.stats)
     }
 
+    // Read the next frame, `Ok(None)` once the peer closed the stream
+    // between frames.
+    //
+    // Cancel safe: the future can be dropped while it waits, e.g. when it
+    // loses a `tokio::select!` against a shutdown signal, without losing
+    // any bytes. Everything read so far stays in the read buffer, and a
+    // frame is only taken out of it by the poll that returns the frame.
     pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
-        loop {
-            if let Some(frame) = self.parse_frame()? {
-                return Ok(Some(frame));
+        let read = read_frame(
+            &mut self.stream,
+            &mut self.buffer,
+            &mut self.codec,
+            &mut self.stats,
+        );
+        with_timeout(self.read_timeout, Timeout::Read, read).await
+    }
+
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<(), crate::Error> {
+        let write = async {
+            self.stats.bytes_written +=
+                feed_encoded(&mut self.stream, frame, &self.codec, &mut self.encoded).await?;
+            self.stats.frames_written += 1;
+            flush(&mut self.stream).await
+        };
+        with_timeout(self.write_timeout, Timeout::Write, write).await
+    }
+
+    // Write `frames` in order with a single flush at the end, rather than
+    // one per frame as `write_frame` does. The write buffer is still
+    // written out whenever it fills up. When a frame fails to encode, the
+    // frames before it stay buffered, as with `feed_frame`.
+    pub async fn write_frames(&mut self, frames: &[Frame]) -> Result<(), crate::Error> {
+        let write = async {
+            for frame in frames {
+                self.stats.bytes_written +=
+                    feed_encoded(&mut self.stream, frame, &self.codec, &mut self.encoded).await?;
+                self.stats.frames_written += 1;
             }
+            flush(&mut self.stream).await
+        };
+        with_timeout(self.write_timeout, Timeout::Write, write).await
+    }
+
+    // Encode the frame into the write buffer without flushing it, so
+    // several frames can be sent with a single `flush`. To bound the memory
+    // used by pending frames, the buffer is flushed anyway once it holds
+    // more than the flush threshold.
+    pub async fn feed_frame(&mut self, frame: &Frame) -> Result<(), crate::Error> {
+        let feed = async {
+            self.stats.bytes_written +=
+                feed_encoded(&mut self.stream, frame, &self.codec, &mut self.encoded).await?;
+            self.stats.frames_written += 1;
 
-            // There is not enough data to read a frame. Attempt to
-            // read more data from the socket.
-            //
-            // `0` returned means end of the stream.
-            if 0 == self
-                .stream
-                .read_buf(&mut self.buffer)
-                .await
-                .map_or(Err("failed to read from socket".to_string()), |v| Ok(v))?
-            {
-                // The remote closed the connection. For this to be a clean shutdown
-                // no data should be in the buffer. If there is data, that means
-                // the peer closed the socket while sending the frame.
-                if self.buffer.is_empty() {
-                    return Ok(None);
-                } else {
-                    return Err("connection reset by peer".into());
-                }
+            if self.stream.buffer().len() > self.flush_threshold {
+                flush(&mut self.stream).await?;
             }
-        }
+            Ok(())
+        };
+        with_timeout(self.write_timeout, Timeout::Write, feed).await
     }
 
-    // TODO: cleanup and refactor the internal of each match arm
+    // Write all buffered frames to the socket.
+    pub async fn flush(&mut self) -> Result<(), crate::Error> {
+        let flush = flush(&mut self.stream);
+        with_timeout(self.write_timeout, Timeout::Write, flush).await
+    }
+
+    // Flush the buffered frames and shut down the write side of the
+    // stream, the peer then reads the end of the stream once it has read
+    // every frame. Frames can still be read, e.g. the responses to the
+    // requests that were sent, but no more frames can be written.
+    pub async fn shutdown(&mut self) -> Result<(), crate::Error> {
+        let shutdown = shutdown(&mut self.stream);
+        with_timeout(self.write_timeout, Timeout::Write, shutdown).await
+    }
+
+    // Bytes and frames read and written so far, e.g. to report the
+    // throughput of the connection.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    // Returns the underlying stream together with the bytes that were
+    // read from it but not consumed as a frame yet.
+    //
+    // Frames that were fed but not flushed are dropped.
+    pub fn into_inner(self) -> (T, BytesMut) {
+        (self.stream.into_inner(), self.buffer.bytes)
+    }
+
+    // Split the connection into a reader and a writer that can be used
+    // from different tasks, e.g. to push frames to the peer while waiting
+    // for its requests.
+    //
+    // The read buffer moves to the `FrameReader` as is, so bytes of a
+    // frame that was only partially received before the split are not
+    // lost. Frames that were fed but not flushed are dropped. The stream
+    // is closed once both halves are dropped.
+    pub fn split(self) -> (FrameReader<io::ReadHalf<T>>, FrameWriter<io::WriteHalf<T>>) {
+        self.split_with(io::split)
+    }
+
+    fn split_with<R, W, F>(self, split: F) -> (FrameReader<R>, FrameWriter<W>)
+    where
+        W: AsyncWrite,
+        F: FnOnce(T) -> (R, W),
+    {
+        let (read, write) = split(self.stream.into_inner());
+
+        let reader = FrameReader {
+            stream: read,
+            buffer: self.buffer,
+            codec: self.codec.clone(),
+            read_timeout: self.read_timeout,
+            stats: self.stats,
+        };
+        let writer = FrameWriter {
+            stream: BufWriter::new(write),
+            codec: self.codec,
+            encoded: self.encoded,
+            flush_threshold: self.flush_threshold,
+            write_timeout: self.write_timeout,
+            stats: self.stats,
+        };
+
+        (reader, writer)
+    }
+}
+
+impl Connection<TcpStream> {
+    // Like `split`, but without the lock shared by the halves of `split`.
+    // Dropping the writer shuts down the write side of the socket, so the
+    // peer reads the end of the stream while the reader still works.
+    pub fn into_split(self) -> (FrameReader, FrameWriter) {
+        self.split_with(TcpStream::into_split)
+    }
+}
+
+impl<R> FrameReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn parse_frame(&mut self) -> crate::Result<Option<Frame>> {
+        decode(&mut self.buffer, &mut self.codec, &mut self.stats)
+    }
+
+    // Cancel safe, see `Connection::read_frame`.
+    pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        let read = read_frame(
+            &mut self.stream,
+            &mut self.buffer,
+            &mut self.codec,
+            &mut self.stats,
+        );
+        with_timeout(self.read_timeout, Timeout::Read, read).await
+    }
+
+    // The counters of the connection as of the split, with those of the
+    // frames read since added. Only the read counters change.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+impl<W> FrameWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
     pub async fn write_frame(&mut self, frame: &Frame) -> Result<(), crate::Error> {
-        match frame {
-            Frame::Addition(x, y) => {
-                self.stream.write_u8(b'+').await.map_or(
-                    Err::<(), crate::Error>("(+) failed to write byte".into()),
-                    |v| Ok(v),
-                )?;
-                let data = format!("{}:{}\r\n", x, y);
-                self.stream.write_all(data.as_bytes()).await.map_or(
-                    Err::<(), crate::Error>("(+) failed to write all bytes".into()),
-                    |v| Ok(v),
-                )?;
-            }
-            Frame::Subtraction(x, y) => {
-                self.stream.write_u8(b'-').await.map_or(
-                    Err::<(), crate::Error>("(-) failed to write byte".into()),
-                    |v| Ok(v),
-                )?;
-                let data = format!("{}:{}\r\n", x, y);
-                self.stream.write_all(data.as_bytes()).await.map_or(
-                    Err::<(), crate::Error>("(-) failed to write all bytes".into()),
-                    |v| Ok(v),
-                )?;
+        let write = async {
+            self.stats.bytes_written +=
+                feed_encoded(&mut self.stream, frame, &self.codec, &mut self.encoded).await?;
+            self.stats.frames_written += 1;
+            flush(&mut self.stream).await
+        };
+        with_timeout(self.write_timeout, Timeout::Write, write).await
+    }
+
+    // See `Connection::write_frames`.
+    pub async fn write_frames(&mut self, frames: &[Frame]) -> Result<(), crate::Error> {
+        let write = async {
+            for frame in frames {
+                self.stats.bytes_written +=
+                    feed_encoded(&mut self.stream, frame, &self.codec, &mut self.encoded).await?;
+                self.stats.frames_written += 1;
             }
-            Frame::Multiplication(x, y) => {
-                self.stream.write_u8(b'*').await.map_or(
-                    Err::<(), crate::Error>("(*) failed to write byte".into()),
-                    |v| Ok(v),
-                )?;
-                let data = format!("{}:{}\r\n", x, y);
-                self.stream.write_all(data.as_bytes()).await.map_or(
-                    Err::<(), crate::Error>("(*) failed to write all bytes".into()),
-                    |v| Ok(v),
-                )?;
+            flush(&mut self.stream).await
+        };
+        with_timeout(self.write_timeout, Timeout::Write, write).await
+    }
+
+    // See `Connection::feed_frame`.
+    pub async fn feed_frame(&mut self, frame: &Frame) -> Result<(), crate::Error> {
+        let feed = async {
+            self.stats.bytes_written +=
+                feed_encoded(&mut self.stream, frame, &self.codec, &mut self.encoded).await?;
+            self.stats.frames_written += 1;
+
+            if self.stream.buffer().len() > self.flush_threshold {
+                flush(&mut self.stream).await?;
             }
-            Frame::OpResult(r) => {
-                self.stream.write_u8(b'=').await.map_or(
-                    Err::<(), crate::Error>("(=) failed to write all bytes".into()),
-                    |v| Ok(v),
-                )?;
-                self.stream.write_u64(*r).await.map_or(
-                    Err::<(), crate::Error>("(=) failed to write all bytes".into()),
-                    |v| Ok(v),
-                )?;
+            Ok(())
+        };
+        with_timeout(self.write_timeout, Timeout::Write, feed).await
+    }
+
+    pub async fn flush(&mut self) -> Result<(), crate::Error> {
+        let flush = flush(&mut self.stream);
+        with_timeout(self.write_timeout, Timeout::Write, flush).await
+    }
+
+    // See `Connection::shutdown`.
+    pub async fn shutdown(&mut self) -> Result<(), crate::Error> {
+        let shutdown = shutdown(&mut self.stream);
+        with_timeout(self.write_timeout, Timeout::Write, shutdown).await
+    }
+
+    // Like `FrameReader::stats`, only the write counters change.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+// Run `future`, failing with `error` when it does not complete within
+// `timeout`.
+async fn with_timeout<F, U>(
+    timeout: Option<Duration>,
+    error: fn(Duration) -> Timeout,
+    future: F,
+) -> crate::Result<U>
+where
+    F: Future<Output = crate::Result<U>>,
+{
+    match timeout {
+        Some(timeout) => time::timeout(timeout, future)
+            .await
+            .map_err(|_| error(timeout))?,
+        None => future.await,
+    }
+}
+
+impl ReadBuffer {
+    fn new(config: &ConnectionConfig) -> ReadBuffer {
+        let bytes = BytesMut::with_capacity(config.read_buffer_capacity);
+        ReadBuffer {
+            allocated: bytes.capacity(),
+            bytes,
+            capacity: config.read_buffer_capacity,
+            max_capacity: config.max_read_buffer_capacity,
+            growth: config.read_buffer_growth,
+        }
+    }
+
+    // Make room for at least one more byte. Space of consumed bytes is
+    // reused when there is enough of it, otherwise the buffer grows.
+    fn reserve(&mut self) {
+        let len = self.bytes.len();
+        if self.bytes.capacity() > len {
+            return;
+        }
+        if self.allocated > len && self.bytes.try_reclaim(self.allocated - len) {
+            return;
+        }
+        let target = match self.growth {
+            BufferGrowth::Double => len.saturating_mul(2),
+            BufferGrowth::Linear(step) => len.saturating_add(step),
+        };
+        self.reallocate(target.max(self.capacity).max(len + 1));
+    }
+
+    // Give back the memory of a frame larger than the maximum capacity,
+    // once it was consumed.
+    fn shrink(&mut self) {
+        if self.allocated > self.max_capacity && self.bytes.len() <= self.capacity {
+            self.reallocate(self.capacity);
+        }
+    }
+
+    fn reallocate(&mut self, capacity: usize) {
+        let mut bytes = BytesMut::with_capacity(capacity);
+        bytes.extend_from_slice(&self.bytes);
+        self.allocated = bytes.capacity();
+        self.bytes = bytes;
+    }
+}
+
+// Decode a frame from `buffer`, counting it in `stats`.
+fn decode(
+    buffer: &mut ReadBuffer,
+    codec: &mut FrameCodec,
+    stats: &mut Stats,
+) -> crate::Result<Option<Frame>> {
+    match codec.decode(&mut buffer.bytes) {
+        Ok(Some(frame)) => {
+            stats.frames_read += 1;
+            Ok(Some(frame))
+        }
+        Ok(None) => Ok(None),
+        Err(err) => {
+            stats.parse_errors += 1;
+            Err(err)
+        }
+    }
+}
+
+// Every frame is decoded synchronously from `buffer`, the only point at
+// which this waits is `fill`.
+async fn read_frame<R>(
+    stream: &mut R,
+    buffer: &mut ReadBuffer,
+    codec: &mut FrameCodec,
+    stats: &mut Stats,
+) -> crate::Result<Option<Frame>>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        if let Some(frame) = decode(buffer, codec, stats)? {
+            buffer.shrink();
+            return Ok(Some(frame));
+        }
+
+        // There is not enough data to read a frame. Attempt to
+        // read more data from the socket.
+        //
+        // `0` returned means end of the stream.
+        let read = fill(stream, buffer).await?;
+        stats.bytes_read += read as u64;
+        if 0 == read {
+            // The remote closed the connection. For this to be a clean shutdown
+            // no data should be in the buffer. If there is data, that means
+            // the peer closed the socket while sending the frame.
+            if buffer.bytes.is_empty() {
+                return Ok(None);
+            } else {
+                return Err(crate::Error::ConnectionReset);
             }
         }
+    }
+}
+
+// Read more bytes from `stream` into `buffer`.
+//
+// `read_buf` only appends to the buffer in the poll that completes it, so
+// when the future is dropped while it waits nothing was read, and
+// everything read before is still in the buffer.
+async fn fill<R>(stream: &mut R, buffer: &mut ReadBuffer) -> io::Result<usize>
+where
+    R: AsyncRead + Unpin,
+{
+    buffer.reserve();
+    stream.read_buf(&mut buffer.bytes).await
+}
+
+// Encode `frame` into `buf` and write it to `stream` at once, the caller
+// is responsible for flushing. Returns the number of bytes written.
+async fn feed_encoded<W>(
+    stream: &mut W,
+    frame: &Frame,
+    codec: &FrameCodec,
+    buf: &mut BytesMut,
+) -> crate::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    buf.clear();
+    codec.encode_frame(frame, buf)?;
+    stream.write_all(buf).await?;
+    Ok(buf.len() as u64)
+}
+
+// Flush `stream`, retrying transient `Interrupted` errors. Any other
+// error is returned as is, so the caller can inspect its kind.
+async fn flush<W>(stream: &mut W) -> Result<(), crate::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
         // write the encoded frame to socket
-        self.stream.flush().await.map_or(
-            Err(Box::new(io::Error::new(ErrorKind::Other, "oh no!"))),
-            |v| Ok(v),
+        match stream.flush().await {
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+// Flush `stream` and shut down its write side.
+async fn shutdown<W>(stream: &mut BufWriter<W>) -> Result<(), crate::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    flush(stream).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_into_split_keeps_buffered_bytes() {
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut peer = TcpStream::connect(addr).await.unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+    let mut connection = Connection::new(socket);
+
+    // Send only the first half of the frame, and let the connection
+    // buffer it without being able to complete a frame.
+    peer.write_all(b"+12:").await.unwrap();
+    let read = tokio::time::timeout(Duration::from_millis(100), connection.read_frame()).await;
+    assert!(read.is_err());
+    assert_eq!(&connection.buffer.bytes[..], b"+12:");
+
+    let (mut read_half, _write_half) = connection.into_split();
+
+    peer.write_all(b"30\r\n").await.unwrap();
+    match read_half.read_frame().await.unwrap() {
+        Some(Frame::Addition(12, 30)) => {}
+        other => panic!("unexpected frame {:?}", other),
+    }
+    assert!(read_half.buffer.bytes.is_empty());
+}
+
+#[tokio::test]
+async fn test_into_inner_returns_leftover_bytes() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut peer = TcpStream::connect(addr).await.unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+    let mut connection = Connection::new(socket);
+
+    peer.write_all(b"+1:2\r\n+3:").await.unwrap();
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::Addition(1, 2)) => {}
+        other => panic!("unexpected frame {:?}", other),
+    }
+
+    let (mut stream, leftover) = connection.into_inner();
+    peer.write_all(b"4\r\n").await.unwrap();
+    peer.shutdown().await.unwrap();
+
+    // Whatever was not buffered yet is still readable from the stream,
+    // together the two hold the rest of the data exactly once.
+    let mut rest = leftover.to_vec();
+    stream.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(b"+3:4\r\n", &rest[..]);
+}
+
+#[tokio::test]
+async fn test_feed_frame_flushes_over_threshold() {
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut peer = TcpStream::connect(addr).await.unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+    let mut connection = Connection::new(socket);
+    connection.set_flush_threshold(64);
+
+    // Each frame is 9 bytes, the eighth frame crosses the threshold.
+    for _ in 0..7 {
+        connection.feed_frame(&Frame::OpResult(1)).await.unwrap();
+    }
+    let mut received = vec![0; 128];
+    let read = tokio::time::timeout(Duration::from_millis(100), peer.read(&mut received)).await;
+    assert!(read.is_err(), "frames below the threshold were flushed");
+
+    for _ in 0..3 {
+        connection.feed_frame(&Frame::OpResult(1)).await.unwrap();
+    }
+    // Flushed automatically, before any explicit `flush`.
+    let mut received = vec![0; 72];
+    peer.read_exact(&mut received).await.unwrap();
+
+    connection.flush().await.unwrap();
+    let mut received = vec![0; 18];
+    peer.read_exact(&mut received).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_max_frame_len() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut peer = TcpStream::connect(addr).await.unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+    let mut connection = Connection::new(socket);
+    connection.set_max_frame_len(64);
+
+    // Frames up to the limit are read as usual.
+    let expr = format!("x{}\r\n", "1+".repeat(30) + "1");
+    assert_eq!(64, expr.len());
+    peer.write_all(expr.as_bytes()).await.unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::Expr(_))
+    ));
+
+    // A line that never ends fails once it is longer than the limit,
+    // instead of waiting for the rest of it.
+    peer.write_all(&[b'x'; 65]).await.unwrap();
+    let err = connection.read_frame().await.unwrap_err();
+    assert!(matches!(
+        err,
+        crate::Error::Protocol(crate::frame::Error::FrameTooLarge(64))
+    ));
+}
+
+#[tokio::test]
+async fn test_checksum() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut peer = Connection::with_encoding(stream, Encoding::Binary);
+    let (socket, _) = listener.accept().await.unwrap();
+    let mut connection = Connection::with_encoding(socket, Encoding::Binary);
+    peer.set_checksum(true);
+    connection.set_checksum(true);
+
+    peer.write_frame(&Frame::Addition(1, 2)).await.unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::Addition(1, 2))
+    ));
+
+    // Flip a bit of the first operand, the checksum no longer matches.
+    let mut codec = FrameCodec::with_encoding(Encoding::Binary);
+    codec.set_checksum(true);
+    let mut encoded = BytesMut::new();
+    codec
+        .encode_frame(&Frame::Addition(1, 2), &mut encoded)
+        .unwrap();
+    encoded[12] ^= 1;
+    peer.stream.write_all(&encoded).await.unwrap();
+    peer.write_frame(&Frame::Ping).await.unwrap();
+
+    let err = connection.read_frame().await.unwrap_err();
+    assert!(matches!(
+        err,
+        crate::Error::Protocol(crate::frame::Error::ChecksumMismatch)
+    ));
+    // Only the corrupted frame is dropped.
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::Ping)
+    ));
+
+    // A text frame is checked the same way, the checksum follows the
+    // line terminator.
+    let mut codec = FrameCodec::new();
+    codec.set_checksum(true);
+    let mut encoded = BytesMut::new();
+    codec.encode_frame(&Frame::Ping, &mut encoded).unwrap();
+    assert_eq!(7, encoded.len());
+    let mut buffer = BytesMut::from(&encoded[..5]);
+    assert!(codec.decode(&mut buffer).unwrap().is_none());
+    buffer.extend_from_slice(&encoded[5..]);
+    assert!(matches!(
+        codec.decode(&mut buffer).unwrap(),
+        Some(Frame::Ping)
+    ));
+}
+
+// Writer that fails its first flush with `error_kind` and records
+// everything that is written to it and how often it was flushed.
+#[cfg(test)]
+#[derive(Default)]
+struct FailingFlush {
+    error_kind: Option<ErrorKind>,
+    written: Vec<u8>,
+    flushes: usize,
+}
+
+#[cfg(test)]
+impl AsyncWrite for FailingFlush {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.written.extend_from_slice(buf);
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.flushes += 1;
+        match self.error_kind.take() {
+            Some(kind) => std::task::Poll::Ready(Err(kind.into())),
+            None => std::task::Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_flush_retries_interrupted() {
+    let mut stream = BufWriter::new(FailingFlush {
+        error_kind: Some(ErrorKind::Interrupted),
+        ..FailingFlush::default()
+    });
+
+    feed_encoded(
+        &mut stream,
+        &Frame::Ping,
+        &FrameCodec::new(),
+        &mut BytesMut::new(),
+    )
+    .await
+    .unwrap();
+    flush(&mut stream).await.unwrap();
+    assert_eq!(b"p\r\n", &stream.get_ref().written[..]);
+}
+
+#[tokio::test]
+async fn test_flush_returns_io_error() {
+    let mut stream = FailingFlush {
+        error_kind: Some(ErrorKind::BrokenPipe),
+        ..FailingFlush::default()
+    };
+
+    let err = flush(&mut stream).await.unwrap_err();
+    assert!(matches!(err, crate::Error::Io(err) if err.kind() == ErrorKind::BrokenPipe));
+}
+
+// The echo payload is length delimited, every byte value has to
+// survive the round trip, including `\r`, `\n`, `:` and null bytes.
+#[tokio::test]
+async fn test_echo_round_trip_all_bytes() {
+    let payload: Vec<u8> = (0..=255).collect();
+
+    let mut encoded = Vec::new();
+    feed_encoded(
+        &mut encoded,
+        &Frame::Echo(payload.clone()),
+        &FrameCodec::new(),
+        &mut BytesMut::new(),
+    )
+    .await
+    .unwrap();
+
+    let mut buffer = BytesMut::from(&encoded[..]);
+    match FrameCodec::with_encoding(Encoding::Text)
+        .decode(&mut buffer)
+        .unwrap()
+    {
+        Some(Frame::Echo(echoed)) => assert_eq!(payload, echoed),
+        other => panic!("unexpected frame {:?}", other),
+    }
+    assert!(buffer.is_empty());
+}
+
+#[tokio::test]
+async fn test_op_result_round_trip() {
+    // The result bytes are never scanned for a line terminator, a result
+    // whose encoding contains `\r\n` reads back as is.
+    let results = [0, 0x0d0a, 0x0d0a_0d0a_0d0a_0d0a, u64::MAX];
+    let mut encoded = Vec::new();
+    for result in results {
+        feed_encoded(
+            &mut encoded,
+            &Frame::OpResult(result),
+            &FrameCodec::new(),
+            &mut BytesMut::new(),
+        )
+        .await
+        .unwrap();
+    }
+    assert_eq!(9 * results.len(), encoded.len());
+
+    let mut buffer = BytesMut::from(&encoded[..]);
+    for result in results {
+        match FrameCodec::with_encoding(Encoding::Text)
+            .decode(&mut buffer)
+            .unwrap()
+        {
+            Some(Frame::OpResult(r)) => assert_eq!(result, r),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+    assert!(buffer.is_empty());
+}
+
+#[tokio::test]
+async fn test_signed_round_trip() {
+    use crate::frame::Operator;
+
+    let frames = [
+        Frame::Signed(Operator::Sub, -3, -5),
+        Frame::Signed(Operator::Mul, i64::MIN, i64::MAX),
+        Frame::SignedResult(-12),
+    ];
+    let mut encoded = Vec::new();
+    for frame in &frames {
+        feed_encoded(
+            &mut encoded,
+            frame,
+            &FrameCodec::new(),
+            &mut BytesMut::new(),
+        )
+        .await
+        .unwrap();
+    }
+
+    let mut buffer = BytesMut::from(&encoded[..]);
+    for frame in &frames {
+        let parsed = FrameCodec::with_encoding(Encoding::Text)
+            .decode(&mut buffer)
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", Some(frame)),
+            format!("{:?}", parsed.as_ref())
+        );
+    }
+    assert!(buffer.is_empty());
+}
+
+#[tokio::test]
+async fn test_float_round_trip() {
+    use crate::frame::Operator;
+
+    let frames = [
+        Frame::Float(Operator::Add, 1.5, -0.25),
+        Frame::Float(Operator::Mul, 1e300, 0.1),
+        Frame::FloatResult(-1e-10),
+    ];
+    let mut encoded = Vec::new();
+    for frame in &frames {
+        feed_encoded(
+            &mut encoded,
+            frame,
+            &FrameCodec::new(),
+            &mut BytesMut::new(),
+        )
+        .await
+        .unwrap();
+    }
+
+    let mut buffer = BytesMut::from(&encoded[..]);
+    for frame in &frames {
+        let parsed = FrameCodec::with_encoding(Encoding::Text)
+            .decode(&mut buffer)
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", Some(frame)),
+            format!("{:?}", parsed.as_ref())
+        );
+    }
+    assert!(buffer.is_empty());
+}
+
+#[cfg(feature = "bignum")]
+#[tokio::test]
+async fn test_big_round_trip() {
+    use crate::frame::Operator;
+    use num_bigint::BigUint;
+
+    let big = BigUint::from(u64::MAX).pow(8);
+    let frames = [
+        Frame::Big(Operator::Add, big.clone(), 0u32.into()),
+        Frame::BigResult(&big * &big),
+    ];
+    let mut encoded = Vec::new();
+    for frame in &frames {
+        feed_encoded(
+            &mut encoded,
+            frame,
+            &FrameCodec::new(),
+            &mut BytesMut::new(),
+        )
+        .await
+        .unwrap();
+    }
+
+    let mut buffer = BytesMut::from(&encoded[..]);
+    for frame in &frames {
+        let parsed = FrameCodec::with_encoding(Encoding::Text)
+            .decode(&mut buffer)
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", Some(frame)),
+            format!("{:?}", parsed.as_ref())
+        );
+    }
+    assert!(buffer.is_empty());
+}
+
+#[tokio::test]
+async fn test_array_round_trip() {
+    let frames = [
+        Frame::Array(vec![]),
+        Frame::Array(vec![
+            Frame::Addition(1, 2),
+            Frame::OpResult(7),
+            Frame::Echo(b"\r\n".to_vec()),
+            Frame::Array(vec![Frame::Ping, Frame::Sort(vec![3, 1])]),
+        ]),
+    ];
+    let mut encoded = Vec::new();
+    for frame in &frames {
+        feed_encoded(
+            &mut encoded,
+            frame,
+            &FrameCodec::new(),
+            &mut BytesMut::new(),
         )
+        .await
+        .unwrap();
+    }
+
+    let mut buffer = BytesMut::from(&encoded[..]);
+    for frame in &frames {
+        let parsed = FrameCodec::with_encoding(Encoding::Text)
+            .decode(&mut buffer)
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", Some(frame)),
+            format!("{:?}", parsed.as_ref())
+        );
+    }
+    assert!(buffer.is_empty());
+}
+
+#[tokio::test]
+async fn test_variadic_round_trip() {
+    let frames = [
+        Frame::Sum(vec![1, 2, 3, 4]),
+        Frame::Product(vec![u64::MAX, 0, 7]),
+    ];
+    let mut encoded = Vec::new();
+    for frame in &frames {
+        feed_encoded(
+            &mut encoded,
+            frame,
+            &FrameCodec::new(),
+            &mut BytesMut::new(),
+        )
+        .await
+        .unwrap();
+    }
+
+    let mut buffer = BytesMut::from(&encoded[..]);
+    for frame in &frames {
+        let parsed = FrameCodec::with_encoding(Encoding::Text)
+            .decode(&mut buffer)
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", Some(frame)),
+            format!("{:?}", parsed.as_ref())
+        );
+    }
+    assert!(buffer.is_empty());
+
+    assert!(feed_encoded(
+        &mut Vec::new(),
+        &Frame::Sum(vec![1, 2]),
+        &FrameCodec::new(),
+        &mut BytesMut::new()
+    )
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_tagged_round_trip() {
+    use crate::frame::Tag;
+
+    let tag = Tag {
+        id: u64::MAX,
+        priority: 3,
+    };
+    let frames = [
+        Frame::Tagged(tag, Box::new(Frame::Addition(1, 2))),
+        Frame::Tagged(tag, Box::new(Frame::OpResult(3))),
+        Frame::Tagged(tag, Box::new(Frame::Array(vec![Frame::Pong]))),
+    ];
+    let mut encoded = Vec::new();
+    for frame in &frames {
+        feed_encoded(
+            &mut encoded,
+            frame,
+            &FrameCodec::new(),
+            &mut BytesMut::new(),
+        )
+        .await
+        .unwrap();
+    }
+
+    let mut buffer = BytesMut::from(&encoded[..]);
+    for frame in &frames {
+        let parsed = FrameCodec::with_encoding(Encoding::Text)
+            .decode(&mut buffer)
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", Some(frame)),
+            format!("{:?}", parsed.as_ref())
+        );
     }
+    assert!(buffer.is_empty());
+}
+
+// Every frame decodes to the same value in both encodings.
+#[tokio::test]
+async fn test_binary_parity_with_text() {
+    use crate::frame::{Operator, Tag};
+
+    let frames = [
+        Frame::Addition(u64::MAX, 0),
+        Frame::Subtraction(3, 1),
+        Frame::Multiplication(6, 7),
+        Frame::Modulo(7, 0),
+        Frame::Division(7, 2),
+        Frame::OpResult(42),
+        Frame::Signed(Operator::Sub, -3, 5),
+        Frame::Sum(vec![1, 2, 3]),
+        Frame::Tree(
+            Operator::Mul,
+            vec![
+                Frame::OpResult(2),
+                Frame::Tree(Operator::Add, vec![Frame::Division(9, 2)]),
+            ],
+        ),
+        Frame::Echo(b"\r\n\0".to_vec()),
+        Frame::Tagged(
+            Tag { id: 1, priority: 2 },
+            Box::new(Frame::Array(vec![Frame::Ping, Frame::Pong])),
+        ),
+    ];
+
+    for encoding in [Encoding::Text, Encoding::Binary] {
+        let mut encoded = Vec::new();
+        for frame in &frames {
+            feed_encoded(
+                &mut encoded,
+                frame,
+                &FrameCodec::with_encoding(encoding),
+                &mut BytesMut::new(),
+            )
+            .await
+            .unwrap();
+        }
+
+        let mut buffer = BytesMut::from(&encoded[..]);
+        for frame in &frames {
+            let parsed = FrameCodec::with_encoding(encoding)
+                .decode(&mut buffer)
+                .unwrap();
+            assert_eq!(
+                format!("{:?}", Some(frame)),
+                format!("{:?}", parsed.as_ref())
+            );
+        }
+        assert!(buffer.is_empty());
+    }
+
+    // Operands take 8 bytes each, regardless of their value.
+    let mut encoded = BytesMut::new();
+    crate::binary::encode(&Frame::Addition(u64::MAX, 0), &mut encoded).unwrap();
+    assert_eq!(21, encoded.len());
+}
+
+// Encoding of a fixed set of frames, checked against
+// `testdata/frames.golden` to catch accidental changes to the wire format.
+// Run the test with `UPDATE_GOLDEN=1` to accept an intended change.
+#[tokio::test]
+async fn test_wire_format_golden() {
+    let frames = [
+        Frame::Addition(10, 32),
+        Frame::Subtraction(u64::MAX, 0),
+        Frame::Multiplication(0, 7),
+        Frame::OpResult(42),
+        Frame::Version,
+        Frame::VersionInfo("0.1.0".to_string()),
+        Frame::Error(
+            crate::frame::ErrorCode::Overflow,
+            "arithmetic overflow".to_string(),
+        ),
+    ];
+
+    let mut encoded = Vec::new();
+    for frame in &frames {
+        feed_encoded(
+            &mut encoded,
+            frame,
+            &FrameCodec::new(),
+            &mut BytesMut::new(),
+        )
+        .await
+        .unwrap();
+    }
+
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/frames.golden");
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(path, &encoded).unwrap();
+    }
+    let golden = std::fs::read(path).unwrap();
+    assert!(
+        golden == encoded,
+        "wire format changed, expected {:?} got {:?}. If the change is intended \
+         update the golden file by running the test with UPDATE_GOLDEN=1",
+        String::from_utf8_lossy(&golden),
+        String::from_utf8_lossy(&encoded),
+    );
+}
+
+#[tokio::test]
+async fn test_duplex_stream() {
+    let (client, server) = tokio::io::duplex(64);
+    let mut client = Connection::new(client);
+    let mut server = Connection::with_encoding(server, Encoding::Text);
+
+    client.write_frame(&Frame::Addition(1, 2)).await.unwrap();
+    assert!(matches!(
+        server.read_frame().await.unwrap(),
+        Some(Frame::Addition(1, 2))
+    ));
+    server.write_frame(&Frame::OpResult(3)).await.unwrap();
+    assert!(matches!(
+        client.read_frame().await.unwrap(),
+        Some(Frame::OpResult(3))
+    ));
+
+    drop(client);
+    assert!(server.read_frame().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_write_frames_flushes_once() {
+    let stream = tokio::io::join(tokio::io::empty(), FailingFlush::default());
+    let mut connection = Connection::new(stream);
+    let frames: Vec<_> = (0..1000).map(|n| Frame::Addition(n, n)).collect();
+    connection.write_frames(&frames).await.unwrap();
+
+    let (_, writer) = connection.into_inner().0.into_inner();
+    assert_eq!(1, writer.flushes);
+    let mut buffer = BytesMut::from(&writer.written[..]);
+    for n in 0..1000 {
+        let frame = FrameCodec::new().decode(&mut buffer).unwrap();
+        assert!(matches!(frame, Some(Frame::Addition(x, y)) if x == n && y == n));
+    }
+    assert!(buffer.is_empty());
+}
+
+#[tokio::test]
+async fn test_shutdown_drains_responses() {
+    let (client, server) = tokio::io::duplex(64);
+    let mut client = Connection::new(client);
+    let mut server = Connection::new(server);
+
+    client.feed_frame(&Frame::Addition(1, 2)).await.unwrap();
+    client.feed_frame(&Frame::Addition(3, 4)).await.unwrap();
+    client.shutdown().await.unwrap();
+    assert!(client.write_frame(&Frame::Ping).await.is_err());
+
+    // The server reads the buffered requests, then the end of the stream,
+    // and can still answer them.
+    let mut results = Vec::new();
+    while let Some(frame) = server.read_frame().await.unwrap() {
+        let Frame::Addition(x, y) = frame else {
+            panic!("unexpected frame {:?}", frame);
+        };
+        results.push(Frame::OpResult(x + y));
+    }
+    server.write_frames(&results).await.unwrap();
+    server.shutdown().await.unwrap();
+
+    assert!(matches!(
+        client.read_frame().await.unwrap(),
+        Some(Frame::OpResult(3))
+    ));
+    assert!(matches!(
+        client.read_frame().await.unwrap(),
+        Some(Frame::OpResult(7))
+    ));
+    assert!(client.read_frame().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_read_frame_is_cancel_safe() {
+    let (mut client, server) = tokio::io::duplex(64);
+    let mut server = Connection::new(server);
+    let frames = [
+        Frame::Addition(12, 34),
+        Frame::Echo(b"\r\n".to_vec()),
+        Frame::Sum(vec![1, 2, 3]),
+        Frame::Ping,
+    ];
+    let mut encoded = BytesMut::new();
+    for frame in &frames {
+        frame.encode(&mut encoded).unwrap();
+    }
+
+    // Every byte arrives on its own, and every read that has to wait for
+    // the next one is cancelled.
+    let (shutdown, mut shutdown_rx) = tokio::sync::watch::channel(());
+    let mut received = Vec::new();
+    let mut cancelled = 0;
+    for byte in encoded.iter() {
+        client.write_all(&[*byte]).await.unwrap();
+        shutdown.send(()).unwrap();
+        tokio::select! {
+            biased;
+            frame = server.read_frame() => received.push(frame.unwrap().unwrap()),
+            _ = shutdown_rx.changed() => cancelled += 1,
+        }
+    }
+    assert_eq!(encoded.len() - frames.len(), cancelled);
+    assert_eq!(format!("{:?}", frames), format!("{:?}", received));
+}
+
+#[tokio::test]
+async fn test_stats() {
+    let (client, server) = tokio::io::duplex(64);
+    let mut client = Connection::new(client);
+    let mut server = Connection::new(server);
+
+    // `+1:2\r\n` and `p\r\n`.
+    client.feed_frame(&Frame::Addition(1, 2)).await.unwrap();
+    client.write_frame(&Frame::Ping).await.unwrap();
+    assert!(server.read_frame().await.unwrap().is_some());
+    assert!(server.read_frame().await.unwrap().is_some());
+    let written = Stats {
+        bytes_written: 9,
+        frames_written: 2,
+        ..Stats::default()
+    };
+    assert_eq!(written, client.stats());
+    let read = Stats {
+        bytes_read: 9,
+        frames_read: 2,
+        ..Stats::default()
+    };
+    assert_eq!(read, server.stats());
+
+    client.write_frame(&Frame::Ping).await.unwrap();
+    let (_, mut client_writer) = client.split();
+    client_writer.write_frame(&Frame::Ping).await.unwrap();
+    assert_eq!(4, client_writer.stats().frames_written);
+
+    // An invalid frame counts as a parse error.
+    let (mut server_reader, _) = server.split();
+    assert!(server_reader.read_frame().await.unwrap().is_some());
+    assert!(server_reader.read_frame().await.unwrap().is_some());
+    let (mut raw, server) = tokio::io::duplex(64);
+    let mut server = Connection::new(server);
+    raw.write_all(b"?\r\n").await.unwrap();
+    assert!(server.read_frame().await.is_err());
+    assert_eq!(1, server.stats().parse_errors);
+    assert_eq!(4, server_reader.stats().frames_read);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_timeouts() {
+    let (client, server) = tokio::io::duplex(16);
+    let config = ConnectionConfig {
+        read_timeout: Some(Duration::from_secs(1)),
+        write_timeout: Some(Duration::from_secs(2)),
+        ..ConnectionConfig::default()
+    };
+    let mut client = Connection::with_config(client, config.clone());
+    let mut server = Connection::with_config(server, config);
+
+    // A stalled peer fails the read instead of blocking it forever, the
+    // partial frame stays buffered.
+    client.stream.write_all(b"+1:").await.unwrap();
+    client.stream.flush().await.unwrap();
+    let err = server.read_frame().await.unwrap_err();
+    assert!(matches!(
+        err,
+        crate::Error::Timeout(Timeout::Read(timeout)) if timeout == Duration::from_secs(1)
+    ));
+    client.stream.write_all(b"2\r\n").await.unwrap();
+    client.stream.flush().await.unwrap();
+    assert!(matches!(
+        server.read_frame().await.unwrap(),
+        Some(Frame::Addition(1, 2))
+    ));
+
+    // The pipe only holds 16 bytes, the rest is never read.
+    let err = server
+        .write_frame(&Frame::Echo(vec![0; 64]))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        crate::Error::Timeout(Timeout::Write(timeout)) if timeout == Duration::from_secs(2)
+    ));
+}
+
+#[test]
+fn test_read_buffer_growth() {
+    let config = ConnectionConfig {
+        read_buffer_capacity: 16,
+        max_read_buffer_capacity: 64,
+        read_buffer_growth: BufferGrowth::Linear(32),
+        ..ConnectionConfig::default()
+    };
+    let mut buffer = ReadBuffer::new(&config);
+    let allocated = |buffer: &ReadBuffer| (buffer.allocated, buffer.bytes.capacity());
+
+    // Room left, nothing to do.
+    buffer.bytes.extend_from_slice(&[0; 8]);
+    buffer.reserve();
+    assert_eq!(16, buffer.allocated);
+
+    buffer.bytes.extend_from_slice(&[0; 8]);
+    buffer.reserve();
+    assert_eq!((48, 48), allocated(&buffer));
+    buffer.bytes.extend_from_slice(&[0; 32]);
+    buffer.reserve();
+    assert_eq!((80, 80), allocated(&buffer));
+
+    // Still holding more than the initial capacity, kept.
+    buffer.bytes.advance(24);
+    buffer.shrink();
+    assert_eq!(80, buffer.allocated);
+
+    buffer.bytes.advance(16);
+    buffer.shrink();
+    assert_eq!((16, 16), allocated(&buffer));
+    assert_eq!(8, buffer.bytes.len());
+
+    // Consumed bytes are reused before the buffer grows.
+    buffer.bytes.extend_from_slice(&[0; 8]);
+    buffer.bytes.advance(12);
+    buffer.reserve();
+    assert_eq!(16, buffer.allocated);
+    assert_eq!(4, buffer.bytes.len());
+
+    let mut buffer = ReadBuffer::new(&ConnectionConfig {
+        read_buffer_capacity: 16,
+        ..ConnectionConfig::default()
+    });
+    buffer.bytes.extend_from_slice(&[0; 16]);
+    buffer.reserve();
+    assert_eq!(32, buffer.allocated);
+}
+
+#[tokio::test]
+async fn test_large_array_shrinks_read_buffer() {
+    let (client, server) = tokio::io::duplex(1024);
+    let mut client = Connection::new(client);
+    let mut server = Connection::with_config(
+        server,
+        ConnectionConfig {
+            read_buffer_capacity: 64,
+            max_read_buffer_capacity: 1024,
+            ..ConnectionConfig::default()
+        },
+    );
+
+    let array = Frame::Array(vec![Frame::OpResult(7); 1000]);
+    tokio::spawn(async move {
+        client.write_frame(&array).await.unwrap();
+        client.write_frame(&Frame::Ping).await.unwrap();
+    });
+
+    match server.read_frame().await.unwrap() {
+        Some(Frame::Array(frames)) => assert_eq!(1000, frames.len()),
+        other => panic!("unexpected frame {:?}", other),
+    }
+    assert!(server.buffer.allocated <= 1024);
+    assert!(matches!(
+        server.read_frame().await.unwrap(),
+        Some(Frame::Ping)
+    ));
+}
+
+#[tokio::test]
+async fn test_split_halves_used_concurrently() {
+    let (client, server) = tokio::io::duplex(64);
+    let mut client = Connection::new(client);
+    let (mut reader, mut writer) = Connection::new(server).split();
+    client.stream.write_all(b"+1:").await.unwrap();
+    client.stream.flush().await.unwrap();
+
+    // The writer pushes frames from its own task, while the reader waits
+    // for requests.
+    let push = tokio::spawn(async move {
+        for i in 0..3 {
+            writer.feed_frame(&Frame::OpResult(i)).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+    });
+    let read = tokio::spawn(async move { reader.read_frame().await.unwrap() });
+
+    for i in 0..3 {
+        match client.read_frame().await.unwrap() {
+            Some(Frame::OpResult(r)) => assert_eq!(i, r),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+    push.await.unwrap();
+
+    client.stream.write_all(b"2\r\n").await.unwrap();
+    client.stream.flush().await.unwrap();
+    assert!(matches!(read.await.unwrap(), Some(Frame::Addition(1, 2))));
 }
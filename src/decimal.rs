@@ -0,0 +1,222 @@
+// Fixed point decimal numbers, see `Frame::Decimal`.
+//
+// A `Decimal` is a whole number of units of `10^-scale`, e.g. `12.50` is
+// 1250 units with a scale of 2. Unlike `f64` every decimal fraction is
+// represented exactly, so sums of amounts of money do not drift. An
+// operation whose result does not fit is an error, it is never rounded.
+use std::fmt;
+
+// Most digits after the decimal point, `10^MAX_SCALE` fits in an `i64`.
+pub const MAX_SCALE: u32 = 18;
+
+// The scale is part of the value, `12.5` and `12.50` are different
+// decimals of the same magnitude.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "Fields")
+)]
+pub struct Decimal {
+    units: i64,
+    scale: u32,
+}
+
+// The fields of a deserialized `Decimal`, the scale is checked before it
+// becomes one.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct Fields {
+    units: i64,
+    scale: u32,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<Fields> for Decimal {
+    type Error = &'static str;
+
+    fn try_from(fields: Fields) -> Result<Decimal, &'static str> {
+        Decimal::new(fields.units, fields.scale).ok_or("decimal scale larger than MAX_SCALE")
+    }
+}
+
+impl Decimal {
+    // `units * 10^-scale`, `None` if the scale is larger than `MAX_SCALE`.
+    pub fn new(units: i64, scale: u32) -> Option<Decimal> {
+        (scale <= MAX_SCALE).then_some(Decimal { units, scale })
+    }
+
+    pub fn units(&self) -> i64 {
+        self.units
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    // Parse digits with an optional leading `-` and an optional fraction,
+    // e.g. `-12.50`. The scale is the number of digits of the fraction.
+    pub fn parse(src: &[u8]) -> Option<Decimal> {
+        let (negative, digits) = match src.strip_prefix(b"-") {
+            Some(digits) => (true, digits),
+            None => (false, src),
+        };
+        let (whole, fraction) = match memchr::memchr(b'.', digits) {
+            Some(i) => (&digits[..i], Some(&digits[i + 1..])),
+            None => (digits, None),
+        };
+        let is_digits = |part: &[u8]| !part.is_empty() && part.iter().all(u8::is_ascii_digit);
+        if !is_digits(whole) || !fraction.is_none_or(is_digits) {
+            return None;
+        }
+        let fraction = fraction.unwrap_or_default();
+
+        // Accumulated with the final sign, so `i64::MIN` units parse.
+        let mut units = 0i64;
+        for digit in whole.iter().chain(fraction) {
+            let digit = i64::from(digit - b'0');
+            units = units.checked_mul(10)?;
+            units = if negative {
+                units.checked_sub(digit)?
+            } else {
+                units.checked_add(digit)?
+            };
+        }
+        Decimal::new(units, u32::try_from(fraction.len()).ok()?)
+    }
+
+    // The result has the larger scale of the two operands.
+    pub fn checked_add(self, other: Decimal) -> Option<Decimal> {
+        let scale = self.scale.max(other.scale);
+        let units = self.rescale(scale)?.checked_add(other.rescale(scale)?)?;
+        Decimal::new(units, scale)
+    }
+
+    // The result has the larger scale of the two operands.
+    pub fn checked_sub(self, other: Decimal) -> Option<Decimal> {
+        let scale = self.scale.max(other.scale);
+        let units = self.rescale(scale)?.checked_sub(other.rescale(scale)?)?;
+        Decimal::new(units, scale)
+    }
+
+    // The result has the sum of the scales of the operands, `None` if that
+    // is larger than `MAX_SCALE`.
+    pub fn checked_mul(self, other: Decimal) -> Option<Decimal> {
+        let units = self.units.checked_mul(other.units)?;
+        Decimal::new(units, self.scale + other.scale)
+    }
+
+    // Whether the magnitude of the decimal is at most `max`.
+    pub fn abs_le(&self, max: u64) -> bool {
+        u128::from(self.units.unsigned_abs()) <= u128::from(max) * 10u128.pow(self.scale)
+    }
+
+    // The units of the same value with a scale of `scale`, which is not
+    // smaller than the current scale.
+    fn rescale(&self, scale: u32) -> Option<i64> {
+        self.units
+            .checked_mul(10i64.checked_pow(scale - self.scale)?)
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.units < 0 {
+            "-".fmt(fmt)?;
+        }
+        let units = self.units.unsigned_abs();
+        if self.scale == 0 {
+            return write!(fmt, "{}", units);
+        }
+        let one = 10u64.pow(self.scale);
+        write!(
+            fmt,
+            "{}.{:0width$}",
+            units / one,
+            units % one,
+            width = self.scale as usize
+        )
+    }
+}
+
+#[test]
+fn test_parse_decimal() {
+    let cases = [
+        (&b"12.50"[..], Some((1250, 2))),
+        (b"-0.05", Some((-5, 2))),
+        (b"7", Some((7, 0))),
+        (b"-9223372036854775808", Some((i64::MIN, 0))),
+        (b"9223372036854775808", None),
+        (b"0.000000000000000001", Some((1, 18))),
+        (b"0.0000000000000000001", None),
+        (b"", None),
+        (b"-", None),
+        (b".5", None),
+        (b"5.", None),
+        (b"1.2.3", None),
+        (b"+1", None),
+        (b"1e3", None),
+    ];
+    for (src, expected) in cases {
+        let parsed = Decimal::parse(src).map(|decimal| (decimal.units(), decimal.scale()));
+        assert_eq!(expected, parsed, "{:?}", src);
+    }
+
+    for src in ["12.50", "-0.05", "7", "0.000000000000000001", "-12"] {
+        assert_eq!(src, Decimal::parse(src.as_bytes()).unwrap().to_string());
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_deserialize_decimal() {
+    let decimal: Decimal = serde_json::from_str(r#"{"units":1250,"scale":2}"#).unwrap();
+    assert_eq!("12.50", decimal.to_string());
+    assert_eq!(
+        decimal,
+        serde_json::from_str(&serde_json::to_string(&decimal).unwrap()).unwrap()
+    );
+
+    let err = serde_json::from_str::<Decimal>(r#"{"units":1,"scale":19}"#).unwrap_err();
+    assert!(err.to_string().contains("MAX_SCALE"), "{}", err);
+}
+
+#[test]
+fn test_decimal_arithmetic() {
+    let decimal = |src: &str| Decimal::parse(src.as_bytes()).unwrap();
+    let show = |result: Option<Decimal>| result.map(|decimal| decimal.to_string());
+
+    // 0.1 + 0.2 is exact, unlike with `f64`.
+    assert_eq!(
+        Some("0.3".to_string()),
+        show(decimal("0.1").checked_add(decimal("0.2")))
+    );
+    assert_eq!(
+        Some("12.75".to_string()),
+        show(decimal("12.5").checked_add(decimal("0.25")))
+    );
+    assert_eq!(
+        Some("-7.50".to_string()),
+        show(decimal("5").checked_sub(decimal("12.50")))
+    );
+    assert_eq!(
+        Some("3.7500".to_string()),
+        show(decimal("1.50").checked_mul(decimal("2.50")))
+    );
+    assert_eq!(
+        None,
+        decimal("9223372036854775807").checked_add(decimal("1"))
+    );
+    // The larger scale does not fit.
+    assert_eq!(
+        None,
+        decimal("922337203685477581").checked_add(decimal("0.01"))
+    );
+    assert_eq!(
+        None,
+        decimal("0.000000001").checked_mul(decimal("0.0000000001"))
+    );
+
+    assert!(decimal("-10.00").abs_le(10));
+    assert!(!decimal("10.01").abs_le(10));
+}
@@ -0,0 +1,149 @@
+use std::{fmt, io};
+
+use crate::{connection, frame, server::ComputeError, Frame, ServerError};
+
+// Errors of connections, clients and servers.
+//
+// The variants tell apart what went wrong, e.g. a client can retry after
+// `Io` or `Timeout`, while `Protocol` means the peer does not speak the
+// protocol and `Server` that it refused the request.
+#[derive(Debug)]
+pub enum Error {
+    // Reading from or writing to the stream failed.
+    Io(io::Error),
+
+    // The peer sent bytes that do not decode to a frame.
+    Protocol(frame::Error),
+
+    // The peer closed the stream in the middle of a frame.
+    ConnectionReset,
+
+    // The peer closed the stream while a response was expected.
+    ConnectionClosed,
+
+    // A read or write did not complete within the timeout of the
+    // connection.
+    Timeout(connection::Timeout),
+
+    // A frame that can not be encoded, e.g. a variadic operation with
+    // fewer than three operands.
+    Encode(String),
+
+    // A request the server can not answer and did not recover from, see
+    // `ServerConfig::recover_on_protocol_error`.
+    Compute(ComputeError),
+
+    // The server answered a request with `Frame::Error`.
+    Server(ServerError),
+
+    // The server answered with a frame that does not fit the request.
+    UnexpectedResponse(Frame),
+
+    // Errors of the transports around the protocol, e.g. TLS or
+    // WebSocket.
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    pub(crate) fn other(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+        Error::Other(err.into())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Protocol(err) => Some(err),
+            Error::Timeout(err) => Some(err),
+            Error::Compute(err) => Some(err),
+            Error::Server(err) => Some(err),
+            Error::Other(err) => Some(err.as_ref()),
+            Error::ConnectionReset
+            | Error::ConnectionClosed
+            | Error::Encode(_)
+            | Error::UnexpectedResponse(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => err.fmt(fmt),
+            Error::Protocol(err) => err.fmt(fmt),
+            Error::ConnectionReset => "connection reset by peer".fmt(fmt),
+            Error::ConnectionClosed => "connection closed by peer".fmt(fmt),
+            Error::Timeout(err) => err.fmt(fmt),
+            Error::Encode(message) => write!(fmt, "frame can not be encoded: {}", message),
+            Error::Compute(err) => err.fmt(fmt),
+            Error::Server(err) => err.fmt(fmt),
+            Error::UnexpectedResponse(frame) => write!(fmt, "unexpected response {:?}", frame),
+            Error::Other(err) => err.fmt(fmt),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(src: io::Error) -> Error {
+        Error::Io(src)
+    }
+}
+
+impl From<frame::Error> for Error {
+    fn from(src: frame::Error) -> Error {
+        Error::Protocol(src)
+    }
+}
+
+impl From<connection::Timeout> for Error {
+    fn from(src: connection::Timeout) -> Error {
+        Error::Timeout(src)
+    }
+}
+
+impl From<ComputeError> for Error {
+    fn from(src: ComputeError) -> Error {
+        Error::Compute(src)
+    }
+}
+
+impl From<ServerError> for Error {
+    fn from(src: ServerError) -> Error {
+        Error::Server(src)
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(src: tokio_tungstenite::tungstenite::Error) -> Error {
+        Error::other(src)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<tokio_rustls::rustls::Error> for Error {
+    fn from(src: tokio_rustls::rustls::Error) -> Error {
+        Error::other(src)
+    }
+}
+
+// Writing into a `BytesMut` never fails, see `Frame::encode`.
+impl From<fmt::Error> for Error {
+    fn from(src: fmt::Error) -> Error {
+        Error::Encode(src.to_string())
+    }
+}
+
+#[test]
+fn test_source() {
+    use std::error::Error as _;
+
+    let err = Error::from(io::Error::from(io::ErrorKind::BrokenPipe));
+    let source = err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+    assert_eq!(io::ErrorKind::BrokenPipe, source.kind());
+
+    let err = Error::from(frame::Error::Incomplete);
+    assert!(matches!(err, Error::Protocol(frame::Error::Incomplete)));
+    assert!(Error::ConnectionReset.source().is_none());
+}
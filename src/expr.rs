@@ -0,0 +1,149 @@
+// Evaluation of infix expressions, see `Frame::Expr`.
+//
+// An expression is made of decimal `u64` operands, the binary operators
+// `+`, `-`, `*`, `/` and `%`, and parentheses. `*`, `/` and `%` bind
+// tighter than `+` and `-`, operators of the same precedence are applied
+// left to right, so "2+3*4" is 14 and "10-4-3" is 3. Division rounds
+// down. Spaces are ignored.
+use crate::server::ComputeError;
+
+// Parentheses nested deeper than this are rejected, so an expression
+// can not exhaust the stack of the evaluator.
+const MAX_DEPTH: usize = 64;
+
+pub fn eval(expr: &str) -> Result<u64, ComputeError> {
+    let mut parser = Parser {
+        src: expr.as_bytes(),
+        pos: 0,
+        depth: 0,
+    };
+    let value = parser.sum()?;
+    match parser.peek() {
+        None => Ok(value),
+        Some(_) => Err(ComputeError::InvalidSyntax),
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    // sum := product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<u64, ComputeError> {
+        let mut value = self.product()?;
+        loop {
+            match self.peek() {
+                Some(b'+') => {
+                    self.pos += 1;
+                    let rhs = self.product()?;
+                    value = value.checked_add(rhs).ok_or(ComputeError::Overflow)?;
+                }
+                Some(b'-') => {
+                    self.pos += 1;
+                    let rhs = self.product()?;
+                    value = value.checked_sub(rhs).ok_or(ComputeError::Underflow)?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    // product := atom (('*' | '/' | '%') atom)*
+    fn product(&mut self) -> Result<u64, ComputeError> {
+        let mut value = self.atom()?;
+        loop {
+            match self.peek() {
+                Some(b'*') => {
+                    self.pos += 1;
+                    let rhs = self.atom()?;
+                    value = value.checked_mul(rhs).ok_or(ComputeError::Overflow)?;
+                }
+                Some(b'/') => {
+                    self.pos += 1;
+                    let rhs = self.atom()?;
+                    value = value.checked_div(rhs).ok_or(ComputeError::DivisionByZero)?;
+                }
+                Some(b'%') => {
+                    self.pos += 1;
+                    let rhs = self.atom()?;
+                    value = value.checked_rem(rhs).ok_or(ComputeError::DivisionByZero)?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    // atom := number | '(' sum ')'
+    fn atom(&mut self) -> Result<u64, ComputeError> {
+        match self.peek() {
+            Some(b'(') => {
+                if self.depth == MAX_DEPTH {
+                    return Err(ComputeError::InvalidSyntax);
+                }
+                self.pos += 1;
+                self.depth += 1;
+                let value = self.sum()?;
+                self.depth -= 1;
+                match self.peek() {
+                    Some(b')') => {
+                        self.pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(ComputeError::InvalidSyntax),
+                }
+            }
+            Some(b'0'..=b'9') => {
+                let start = self.pos;
+                while matches!(self.src.get(self.pos), Some(b'0'..=b'9')) {
+                    self.pos += 1;
+                }
+                // Digits only, the number is too large when `atoi` fails.
+                atoi::atoi(&self.src[start..self.pos]).ok_or(ComputeError::Overflow)
+            }
+            _ => Err(ComputeError::InvalidSyntax),
+        }
+    }
+
+    // The next byte that is not a space, the position is moved to it.
+    fn peek(&mut self) -> Option<u8> {
+        while self.src.get(self.pos) == Some(&b' ') {
+            self.pos += 1;
+        }
+        self.src.get(self.pos).copied()
+    }
+}
+
+#[test]
+fn test_eval() {
+    let cases = [
+        ("2+3*4", Ok(14)),
+        ("(2+3)*4", Ok(20)),
+        ("10-4-3", Ok(3)),
+        ("17 % 5 * 2", Ok(4)),
+        (" ( ( 7 ) ) ", Ok(7)),
+        ("18446744073709551615", Ok(u64::MAX)),
+        ("18446744073709551616", Err(ComputeError::Overflow)),
+        ("18446744073709551615+1", Err(ComputeError::Overflow)),
+        ("1-2", Err(ComputeError::Underflow)),
+        ("1%0", Err(ComputeError::DivisionByZero)),
+        ("", Err(ComputeError::InvalidSyntax)),
+        ("2+", Err(ComputeError::InvalidSyntax)),
+        ("(2+3", Err(ComputeError::InvalidSyntax)),
+        ("2 3", Err(ComputeError::InvalidSyntax)),
+        ("-2", Err(ComputeError::InvalidSyntax)),
+        ("7/2*2", Ok(6)),
+        ("1/0", Err(ComputeError::DivisionByZero)),
+        ("2^3", Err(ComputeError::InvalidSyntax)),
+    ];
+    for (expr, expected) in cases {
+        assert_eq!(expected, eval(expr), "{:?}", expr);
+    }
+
+    let nested = format!("{}1{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+    assert_eq!(Ok(1), eval(&nested));
+    let nested = format!("({})", nested);
+    assert_eq!(Err(ComputeError::InvalidSyntax), eval(&nested));
+}
@@ -12,6 +12,9 @@
 // The end of the payload is represented by
 // `\r\n`
 //
+// Addition and Multiplication also take more than two operands,
+// "{num1}:{num2}:...:{numN}\r\n", e.g. "+1:2:3:4\r\n" adds all four.
+//
 // Similarly to encode the Subtraction operation the following
 // bytes are sent.
 // `-` followed by "{num1}:{num2}\r\n"
@@ -26,180 +29,2988 @@
 // The end of the payload is represented by
 // `\r\n`
 //
-use std::{fmt, io::Cursor, u64};
+// Similarly to encode the Modulo operation, the remainder of
+// num1 divided by num2, the following bytes are sent.
+// `%` followed by "{num1}:{num2}\r\n"
+// num1 and num2 are numbers represented by `u64`.
+// The end of the payload is represented by
+// `\r\n`
+//
+// Similarly to encode the Division operation, the quotient of num1
+// divided by num2 rounded down, the following bytes are sent.
+// `/` followed by "{num1}:{num2}\r\n"
+// num1 and num2 are numbers represented by `u64`.
+// The end of the payload is represented by
+// `\r\n`
+//
+// Exponentiation, num1 raised to the power of num2, is sent as `^`
+// followed by "{num1}:{num2}\r\n" and the factorial of num as `F`
+// followed by "{num}\r\n". The operands are numbers represented by `u64`.
+//
+// The greatest common divisor of num1 and num2 is sent as `g` followed
+// by "{num1}:{num2}\r\n" and their least common multiple as `l`
+// followed by "{num1}:{num2}\r\n". The gcd of 0 and 0 is 0, so is the
+// lcm of 0 and any number.
+//
+// Operands are decimal unless prefixed with `0b` (binary), `0o`
+// (octal) or `0x` (hexadecimal), e.g. `+0b1010:0xF\r\n` adds
+// 10 and 15. Each operand picks its own base.
+//
+// Signed operations are sent as `i` followed by the operator, one of
+// `+`, `-` and `*`, and "{num1}:{num2}\r\n", where num1 and num2 are
+// numbers represented by `i64` with an optional leading `-`, e.g.
+// "i*-3:4\r\n" multiplies -3 by 4. The result is sent as `i=`
+// followed by "{result}\r\n".
+//
+// Floating point operations are sent the same way with `f`, the
+// operands are decimal numbers with an optional fraction, e.g.
+// "f+1.5:-0.25\r\n", and the result is sent as `f=` followed by
+// "{result}\r\n".
+//
+// Fixed point operations, e.g. on amounts of money, are sent the same way
+// with `d`, e.g. "d+12.50:-0.25\r\n", and the result is sent as `d=`
+// followed by "{result}\r\n". Unlike floating point operands, the digits
+// after the decimal point are kept exactly, see `Decimal`. A sum has as
+// many as the operand with the most, a product as many as both together.
+//
+// With the `bignum` feature, operands of any size are sent as `n`
+// followed by the operator, one of `+`, `-` and `*`, and
+// "{num1}:{num2}\r\n", where num1 and num2 are decimal numbers, e.g.
+// "n*18446744073709551616:2\r\n". The result is sent as `n=` followed
+// by "{result}\r\n".
+//
+// Results are sent as `=` followed by the result encoded as a
+// big endian `u64` (8 bytes), there is no terminator.
+//
+// Liveness is checked with `p` followed by `\r\n`, which is
+// answered with `P` followed by `\r\n`.
+//
+// A client can name itself with `I` followed by "{name}\r\n".
+// The server echoes the frame to acknowledge it.
+//
+// To query the server version the client sends `v` followed
+// by `\r\n`. The server replies with `V` followed by
+// "{version}\r\n", where version is the crate version.
+//
+// Errors are reported with `!` followed by "{code}:{message}\r\n",
+// where code is one of the `ErrorCode` values.
+//
+// A postfix (RPN) expression is sent as `r` followed by the
+// space separated tokens and `\r\n`, e.g. "r 3 4 + 2 *\r\n"
+// computes (3 + 4) * 2. Tokens are operands or one of the
+// operators `+`, `-` and `*`. Inside an array, `$N` stands for the
+// result of the element at index N, e.g. "r $0 4 *\r\n" multiplies
+// the result of the first element by 4.
+//
+// A large array of requests can be streamed instead, as `[`
+// followed by "{count}\r\n" and then the `count` request frames
+// one by one. The server answers with the same header and one
+// response per element, as the elements arrive.
+//
+// An expression tree is sent as `t` followed by the operator, one of
+// `+`, `-` and `*`, and "{count}\r\n", then the `count` operand frames.
+// Operands are operation frames, results (`=`) or trees themselves, the
+// operator is applied to their results from left to right, e.g.
+// "t*2\r\n+1:2\r\nt-2\r\n+3:4\r\n%9:4\r\n" computes (1+2)*((3+4)-(9%4)).
+// Arrays, tags and trees nest at most `MAX_NESTING_DEPTH` levels deep.
+//
+// An infix expression is sent as `x` followed by "{expression}\r\n",
+// e.g. "x2+3*4\r\n". It may use `+`, `-`, `*`, `/`, `%` and parentheses,
+// with the usual precedence, and is answered with its result.
+//
+// A list of operands is sorted with `s` followed by a space and the
+// colon separated operands "{n1}:{n2}:...\r\n", e.g. "s 3:1:2\r\n".
+// The list may be empty. The server answers with an array of
+// results holding the operands in ascending order.
+//
+// The minimum, maximum or mean of a list of operands is computed with
+// `a` followed by the function, `<` (minimum), `>` (maximum) or `~`
+// (mean, rounded down), and the colon separated operands
+// "{n1}:{n2}:...\r\n", e.g. "a~1:2:4\r\n" is 2. The list may be empty,
+// which the server answers with an error.
+//
+// The protocol version is negotiated with `h` followed by
+// "{version}\r\n", the server answers with the same frame holding the
+// highest version both sides support. Frames introduced by a later
+// version are rejected for the rest of the connection, see
+// `Frame::min_version`. A version the server does not accept is
+// answered with an error frame and the connection is closed.
+//
+// Every connection has a set of named registers. `S` followed by
+// "{name}:{value}\r\n" sets a register and is answered with the value,
+// `G` followed by "{name}\r\n" reads it back. `W` followed by
+// "{slot}\r\n" saves a snapshot of all registers under the slot name
+// and `L` followed by "{slot}\r\n" restores it, both are echoed.
+//
+// Every connection also has an accumulator, a register without a name
+// that starts at 0. `A` followed by the operator, one of `+`, `-` and `*`,
+// and "{num}\r\n" applies the operator to the accumulator and num, e.g.
+// "A+5\r\n" adds 5 to it, and is answered with the new value. `R`
+// followed by `\r\n` reads the accumulator. A failed operation leaves
+// the accumulator unchanged.
+//
+// Registers can be used as operands with `o` followed by the operator, one
+// of `+`, `-` and `*`, and "{a}:{b}\r\n", where a and b are numbers or
+// register names, e.g. "Sx:42\r\n" followed by "o+x:8\r\n" is 50. An
+// operand starting with a digit is a number, so registers whose name
+// starts with a digit can not be used as operands.
+//
+// Operators manage a server with admin frames, which carry the admin token
+// of the server. `k` followed by "{token}\r\n" asks for the statistics of
+// the server, answered with `K` followed by
+// "{active}:{accepted}:{served}:{errors}\r\n", the connections open and
+// accepted so far, the requests served and the frames that could not be
+// decoded. `q` followed by "{token}\r\n" shuts the server down, see
+// `Server::run_until`, and is echoed. A frame with the wrong token fails
+// like any invalid request, with `ErrorCode::Unauthorized`.
+//
+// A request can be tagged with `@` followed by "{id}:{priority}\r\n"
+// and then the request frame. The response carries the same tag, so it
+// can be matched with its request. When the server handles requests
+// concurrently, queued requests with a higher priority (0 to 255) are
+// handled first.
+//
+// Arbitrary bytes are echoed with `e` followed by "{len}\r\n" and
+// then exactly `len` payload bytes, there is no terminator. The
+// payload is never scanned, so it may contain any byte value.
+// The server answers with the same frame.
+//
+// An array of frames is sent as `#` followed by "{count}\r\n"
+// and then exactly `count` encoded frames. The array ends
+// with its last element, there is no separate terminator.
+// The server answers with an array holding the response to
+// every element, in order.
+//
+// With the `compression` feature, a frame can be sent compressed as `z`
+// followed by "{len}\r\n" and then exactly `len` bytes, the encoding of
+// the frame compressed with deflate. Large arrays are mostly digits and
+// shrink a lot. Only a whole frame is compressed, a `z` frame is never
+// nested in another frame. The server answers with a compressed response.
+//
+use std::{
+    fmt::{self, Write},
+    io::Cursor,
+};
 
 use atoi::atoi;
-use tokio_util::bytes::Buf;
+
+use crate::decimal::Decimal;
+#[cfg(feature = "bignum")]
+use num_bigint::BigUint;
+use tokio_util::bytes::{Buf, BufMut, BytesMut};
 
 // A frame for our own protocol.
+//
+// With the `serde` feature frames can also be serialized with serde, e.g.
+// to store requests as JSON. That format is independent of the wire format.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Frame {
     Addition(u64, u64),
     Subtraction(u64, u64),
     Multiplication(u64, u64),
+    Modulo(u64, u64),
+    Division(u64, u64),
+    Pow(u64, u64),
+    Factorial(u64),
+    Gcd(u64, u64),
+    Lcm(u64, u64),
+    // Addition and Multiplication of more than two operands.
+    Sum(Vec<u64>),
+    Product(Vec<u64>),
+    Signed(Operator, i64, i64),
+    SignedResult(i64),
+    Float(Operator, f64, f64),
+    FloatResult(f64),
+    Decimal(Operator, Decimal, Decimal),
+    DecimalResult(Decimal),
+    #[cfg(feature = "bignum")]
+    Big(Operator, BigUint, BigUint),
+    #[cfg(feature = "bignum")]
+    BigResult(BigUint),
     OpResult(u64),
+    Ping,
+    Pong,
+    Identify(String),
+    Version,
+    VersionInfo(String),
+    Rpn(Vec<Token>),
+    Expr(String),
+    ArrayStart(u64),
+    Array(Vec<Frame>),
+    Error(ErrorCode, String),
+    Echo(Vec<u8>),
+    Sort(Vec<u64>),
+    Aggregate(Aggregate, Vec<u64>),
+    Set(String, u64),
+    Get(String),
+    Save(String),
+    Restore(String),
+    // Apply the operator to the accumulator of the connection and the
+    // operand, see `Frame::Result`.
+    Accumulate(Operator, u64),
+    // The value of the accumulator.
+    Result,
+    // Operation on registers of the connection or numbers.
+    Named(Operator, Operand, Operand),
+    // Admin frames holding the admin token, never shown by `Display`.
+    Stats(String),
+    StatsInfo(ServerStats),
+    Shutdown(String),
+    Hello(u32),
+    Tagged(Tag, Box<Frame>),
+    Tree(Operator, Vec<Frame>),
+    #[cfg(feature = "compression")]
+    Compressed(Box<Frame>),
+}
+
+// Identifies a request and its response, see `Frame::Tagged`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tag {
+    pub id: u64,
+    pub priority: u8,
+}
+
+// Identifies the kind of failure reported by `Frame::Error`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ErrorCode {
+    // The request could not be decoded.
+    Protocol = 1,
+
+    // The frame is not a request the server answers.
+    UnexpectedFrame = 2,
+
+    // The result of the operation does not fit in a `u64`.
+    Overflow = 3,
+
+    // The result of the operation would be negative.
+    Underflow = 4,
+
+    // An operand is larger than the server allows.
+    OperandLimit = 5,
+
+    // A postfix expression is malformed.
+    InvalidExpression = 6,
+
+    // The server closes the connection because the session expired.
+    SessionExpired = 7,
+
+    // The response would be larger than the server allows.
+    ResponseTooLarge = 8,
+
+    // The register or snapshot does not exist.
+    UnknownName = 9,
+
+    // The frame is not part of the negotiated protocol version.
+    UnsupportedVersion = 10,
+
+    // The divisor of the operation is zero.
+    DivisionByZero = 11,
+
+    // The operation needs at least one operand.
+    EmptyOperands = 12,
+
+    // The server closes the connection because the client sent nothing
+    // for too long.
+    IdleTimeout = 13,
+
+    // The server closes the connection because it is shutting down.
+    ShuttingDown = 14,
+
+    // The client sends requests faster than its rate limit, the request
+    // was dropped.
+    SlowDown = 15,
+
+    // The server has as many connections open as it serves and closes the
+    // new connection, see `ServerConfig::reject_when_busy`.
+    ServerBusy = 16,
+
+    // The server closes the connection because a request took longer than
+    // its request timeout.
+    RequestTimeout = 17,
+
+    // An admin frame with a token other than the admin token of the
+    // server, or the server has none.
+    Unauthorized = 18,
+
+    // The server can not compute the request right now, e.g. because its
+    // blocking thread pool keeps failing. The request may be retried later.
+    Unavailable = 19,
+
+    // A register or snapshot beyond the limits of the server.
+    RegisterLimit = 20,
+
+    // A register or snapshot name that does not parse back.
+    InvalidName = 21,
+}
+
+// Operator of a `Frame::Signed` operation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
 }
 
-#[derive(Debug)]
+// Function of a `Frame::Aggregate`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Aggregate {
+    Min,
+    Max,
+    Mean,
+}
+
+// A token of a postfix expression.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Token {
+    Number(u64),
+    // Result of an earlier element of the same array.
+    Ref(usize),
+    Add,
+    Sub,
+    Mul,
+}
+
+// Counters of a server, the answer to a `Frame::Stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServerStats {
+    pub connections_active: u64,
+    pub connections_accepted: u64,
+    pub requests_served: u64,
+    pub protocol_errors: u64,
+}
+
+// An operand of a `Frame::Named`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Operand {
+    Number(u64),
+    // The value of the register with this name.
+    Name(String),
+}
+
+// Why a frame could not be decoded.
+#[derive(Debug, PartialEq)]
 pub enum Error {
     // Not enough data is available
     Incomplete,
 
-    // Non supported encoding.
-    ErrMessage(crate::Error),
+    // The type byte does not start any known frame.
+    InvalidTypeByte(u8),
+
+    // The operator of an operation, e.g. of `Frame::Signed`, is not one the
+    // frame supports.
+    InvalidOperator(u8),
+
+    // An operand is not a number of the expected kind, e.g. it has an
+    // invalid digit or does not fit its type.
+    InvalidOperand,
+
+    // An operand has more digits than `ParseConfig` allows.
+    OperandTooLong,
+
+    // The frame has more operands than it takes, or than `ParseConfig`
+    // allows in a list.
+    TooManyOperands,
+
+    // The `:` between two parts of the frame, or the line terminator, is
+    // missing.
+    MissingDelimiter,
+
+    // A field other than an operand is invalid, e.g. a client name that
+    // is not UTF-8. Holds the name of the field.
+    InvalidField(&'static str),
+
+    // A frame that has no payload, e.g. `Frame::Ping`, has one.
+    UnexpectedPayload,
+
+    // An expression tree without operands.
+    EmptyTree,
+
+    // Frames are nested deeper than `MAX_NESTING_DEPTH`.
+    NestingTooDeep,
+
+    // A frame is nested in a frame that can not hold it, e.g. a tagged
+    // frame in a tagged frame.
+    InvalidNesting,
+
+    // The length of a length delimited frame does not match its contents.
+    InvalidLength,
+
+    // A `Frame::Compressed` that can not be decompressed.
+    InvalidCompression,
+
+    // A `Frame::Compressed` is longer than this once decompressed, see
+    // `ParseConfig::max_decompressed_len`.
+    DecompressedTooLarge(usize),
+
+    // More bytes than the maximum frame length were buffered without
+    // completing a frame. The connection can not be resynchronized.
+    FrameTooLarge(usize),
+
+    // The checksum that follows a frame does not match its bytes, see
+    // `Connection::set_checksum`.
+    ChecksumMismatch,
 }
 
-impl std::error::Error for Error {}
+// Options that control how strictly frames are decoded.
+#[derive(Clone, Debug)]
+pub struct ParseConfig {
+    // Only accept the canonical encoding of operands, `7` is
+    // accepted while `007` is rejected.
+    pub strict: bool,
 
-impl fmt::Display for Error {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Error::Incomplete => "stream ended early".fmt(fmt),
-            Error::ErrMessage(err) => err.fmt(fmt),
+    // Maximum number of decimal digits in an operand. Operands with a
+    // base prefix may use as many digits as a number of the same
+    // magnitude needs in their base.
+    pub max_operand_digits: usize,
+
+    // Maximum number of operands in a frame that takes a list of
+    // operands, e.g. `Frame::Sort`.
+    pub max_operands: usize,
+
+    // Maximum number of digits in an operand of `Frame::Big`. A result
+    // may have twice as many digits.
+    #[cfg(feature = "bignum")]
+    pub max_big_operand_digits: usize,
+
+    // Maximum length of a `Frame::Compressed` frame once it is
+    // decompressed, the limit of the compressed length does not bound it.
+    #[cfg(feature = "compression")]
+    pub max_decompressed_len: usize,
+}
+
+impl Default for ParseConfig {
+    fn default() -> ParseConfig {
+        ParseConfig {
+            strict: false,
+            // Enough for `u64::MAX`.
+            max_operand_digits: 20,
+            max_operands: 1024,
+            #[cfg(feature = "bignum")]
+            max_big_operand_digits: 1024,
+            #[cfg(feature = "compression")]
+            max_decompressed_len: 1024 * 1024,
         }
     }
 }
 
-impl Frame {
-    pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
-        match get_u8(src)? {
-            b'+' => {
-                get_line(src)?;
+// A readable rendering for logs, e.g. `ADD 10 32` or `RESULT 42`. Unlike
+// the wire format it is not meant to be parsed back.
+impl fmt::Display for Frame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Frame::Addition(x, y) => write!(fmt, "ADD {} {}", x, y),
+            Frame::Subtraction(x, y) => write!(fmt, "SUB {} {}", x, y),
+            Frame::Multiplication(x, y) => write!(fmt, "MUL {} {}", x, y),
+            Frame::Modulo(x, y) => write!(fmt, "MOD {} {}", x, y),
+            Frame::Division(x, y) => write!(fmt, "DIV {} {}", x, y),
+            Frame::Pow(x, y) => write!(fmt, "POW {} {}", x, y),
+            Frame::Factorial(n) => write!(fmt, "FACT {}", n),
+            Frame::Gcd(x, y) => write!(fmt, "GCD {} {}", x, y),
+            Frame::Lcm(x, y) => write!(fmt, "LCM {} {}", x, y),
+            Frame::Sum(operands) => write_list(fmt, "ADD", operands),
+            Frame::Product(operands) => write_list(fmt, "MUL", operands),
+            Frame::Signed(op, x, y) => write!(fmt, "{} {} {}", op.name(), x, y),
+            Frame::Float(op, x, y) => write!(fmt, "{} {} {}", op.name(), x, y),
+            #[cfg(feature = "bignum")]
+            Frame::Big(op, x, y) => write!(fmt, "{} {} {}", op.name(), x, y),
+            Frame::OpResult(r) => write!(fmt, "RESULT {}", r),
+            Frame::SignedResult(r) => write!(fmt, "RESULT {}", r),
+            Frame::FloatResult(r) => write!(fmt, "RESULT {}", r),
+            Frame::Decimal(op, x, y) => write!(fmt, "{} {} {}", op.name(), x, y),
+            Frame::DecimalResult(r) => write!(fmt, "RESULT {}", r),
+            #[cfg(feature = "bignum")]
+            Frame::BigResult(r) => write!(fmt, "RESULT {}", r),
+            Frame::Ping => "PING".fmt(fmt),
+            Frame::Pong => "PONG".fmt(fmt),
+            Frame::Identify(name) => write!(fmt, "IDENTIFY {}", name),
+            Frame::Version => "VERSION".fmt(fmt),
+            Frame::VersionInfo(version) => write!(fmt, "VERSION {}", version),
+            Frame::Rpn(tokens) => {
+                "RPN".fmt(fmt)?;
+                for token in tokens {
+                    write!(fmt, " {}", token)?;
+                }
                 Ok(())
             }
-            b'-' => {
-                get_line(src)?;
-                Ok(())
+            Frame::Expr(expr) => write!(fmt, "EXPR {}", expr),
+            Frame::ArrayStart(count) => write!(fmt, "ARRAY {} ...", count),
+            Frame::Array(frames) => {
+                "ARRAY [".fmt(fmt)?;
+                write_frames(fmt, frames)?;
+                "]".fmt(fmt)
             }
-            b'*' => {
-                get_line(src)?;
-                Ok(())
+            Frame::Error(code, message) => write!(fmt, "ERROR {:?} {}", code, message),
+            Frame::Echo(payload) => write!(fmt, "ECHO {} bytes", payload.len()),
+            Frame::Sort(operands) => write_list(fmt, "SORT", operands),
+            Frame::Aggregate(function, operands) => write_list(fmt, function.name(), operands),
+            Frame::Set(name, value) => write!(fmt, "SET {} {}", name, value),
+            Frame::Get(name) => write!(fmt, "GET {}", name),
+            Frame::Save(slot) => write!(fmt, "SAVE {}", slot),
+            Frame::Restore(slot) => write!(fmt, "RESTORE {}", slot),
+            Frame::Accumulate(op, x) => write!(fmt, "ACC {} {}", op.name(), x),
+            Frame::Result => "ACC".fmt(fmt),
+            Frame::Named(op, x, y) => write!(fmt, "{} {} {}", op.name(), x, y),
+            Frame::Stats(_) => "STATS".fmt(fmt),
+            Frame::StatsInfo(stats) => write!(
+                fmt,
+                "STATS active={} accepted={} served={} protocol_errors={}",
+                stats.connections_active,
+                stats.connections_accepted,
+                stats.requests_served,
+                stats.protocol_errors
+            ),
+            Frame::Shutdown(_) => "SHUTDOWN".fmt(fmt),
+            Frame::Hello(version) => write!(fmt, "HELLO {}", version),
+            Frame::Tagged(tag, frame) => write!(fmt, "#{} {}", tag.id, frame),
+            Frame::Tree(op, operands) => {
+                write!(fmt, "{} (", op.name())?;
+                write_frames(fmt, operands)?;
+                ")".fmt(fmt)
             }
-            default => Err(format!("protocol error, invalid type byte {}", default).into()),
+            #[cfg(feature = "compression")]
+            Frame::Compressed(frame) => write!(fmt, "COMPRESSED {}", frame),
         }
     }
+}
 
-    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
-        match get_u8(src)? {
-            b'+' => {
-                let first_opereand = get_first_operand(src)?;
-                let second_operand = get_second_operand(src)?;
-                Ok(Frame::Addition(first_opereand, second_operand))
-            }
-            b'-' => {
-                let first_opereand = get_first_operand(src)?;
-                let second_operand = get_second_operand(src)?;
-                Ok(Frame::Subtraction(first_opereand, second_operand))
-            }
-            b'*' => {
-                let first_opereand = get_first_operand(src)?;
-                let second_operand = get_second_operand(src)?;
-                Ok(Frame::Multiplication(first_opereand, second_operand))
-            }
-            _ => !unimplemented!(),
-        }
+fn write_list(fmt: &mut fmt::Formatter, name: &str, operands: &[u64]) -> fmt::Result {
+    fmt.write_str(name)?;
+    for operand in operands {
+        write!(fmt, " {}", operand)?;
     }
+    Ok(())
 }
 
-fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
-    if !src.has_remaining() {
-        return Err(Error::Incomplete);
+fn write_frames(fmt: &mut fmt::Formatter, frames: &[Frame]) -> fmt::Result {
+    for (i, frame) in frames.iter().enumerate() {
+        if i > 0 {
+            fmt.write_str(", ")?;
+        }
+        write!(fmt, "{}", frame)?;
     }
-    Ok(src.get_u8())
+    Ok(())
 }
 
-fn get_first_operand(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
-    let start = src.position() as usize;
-
-    // last byte position
-    let end = src.get_ref().len() - 1;
-
-    for i in start..end {
-        if src.get_ref()[i] == b':' {
-            // set the position to `:`
-            src.set_position((i + 1) as u64);
-            let fbytes = &src.get_ref()[start..i];
-            return atoi::<u64>(fbytes)
-                .map_or(Err("Protocol error, invalid frame".into()), |v| Ok(v));
+impl Operator {
+    // Name of the operation, as shown by the `Display` of `Frame`.
+    fn name(&self) -> &'static str {
+        match self {
+            Operator::Add => "ADD",
+            Operator::Sub => "SUB",
+            Operator::Mul => "MUL",
         }
     }
-    return Err("Protocol error, invalid frame".into());
 }
 
-fn get_second_operand(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
-    let start = src.position() as usize;
-
-    // last byte position
-    let end = src.get_ref().len() - 1;
-
-    for i in start..end {
-        if src.get_ref()[i] == b'\r' && src.get_ref()[i + 1] == b'\n' {
-            // set the position to `\n`
-            src.set_position((i + 2) as u64);
-            let fbytes = &src.get_ref()[start..i];
-            return atoi::<u64>(fbytes)
-                .map_or(Err("Protocol error, invalid frame".into()), |v| Ok(v));
+impl Aggregate {
+    fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Min => "MIN",
+            Aggregate::Max => "MAX",
+            Aggregate::Mean => "MEAN",
         }
     }
-    return Err("Protocol error, invalid frame".into());
 }
 
-// Find line terminating character = `<` `>`
-fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
-    let start = src.position() as usize;
+impl fmt::Display for Aggregate {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Aggregate::Min => "<".fmt(fmt),
+            Aggregate::Max => ">".fmt(fmt),
+            Aggregate::Mean => "~".fmt(fmt),
+        }
+    }
+}
 
-    // last byte position
-    let end = src.get_ref().len() - 1;
+impl fmt::Display for Operator {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operator::Add => "+".fmt(fmt),
+            Operator::Sub => "-".fmt(fmt),
+            Operator::Mul => "*".fmt(fmt),
+        }
+    }
+}
 
-    // Search for the termination pattern
-    for i in start..end {
-        if src.get_ref()[i] == b'\r' && src.get_ref()[i + 1] == b'\n' {
-            // update the position after `\n`
-            src.set_position((i + 2) as u64);
+impl fmt::Display for Token {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(n) => n.fmt(fmt),
+            Token::Ref(index) => write!(fmt, "${}", index),
+            Token::Add => "+".fmt(fmt),
+            Token::Sub => "-".fmt(fmt),
+            Token::Mul => "*".fmt(fmt),
+        }
+    }
+}
 
-            return Ok(&src.get_ref()[start..i]);
+impl fmt::Display for Operand {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Number(n) => n.fmt(fmt),
+            Operand::Name(name) => name.fmt(fmt),
         }
     }
-    Err(Error::Incomplete)
 }
 
-impl From<String> for Error {
-    fn from(src: String) -> Error {
-        Error::ErrMessage(src.into())
+impl ErrorCode {
+    fn from_u64(code: u64) -> Option<ErrorCode> {
+        let code = match code {
+            1 => ErrorCode::Protocol,
+            2 => ErrorCode::UnexpectedFrame,
+            3 => ErrorCode::Overflow,
+            4 => ErrorCode::Underflow,
+            5 => ErrorCode::OperandLimit,
+            6 => ErrorCode::InvalidExpression,
+            7 => ErrorCode::SessionExpired,
+            8 => ErrorCode::ResponseTooLarge,
+            9 => ErrorCode::UnknownName,
+            10 => ErrorCode::UnsupportedVersion,
+            11 => ErrorCode::DivisionByZero,
+            12 => ErrorCode::EmptyOperands,
+            13 => ErrorCode::IdleTimeout,
+            14 => ErrorCode::ShuttingDown,
+            15 => ErrorCode::SlowDown,
+            16 => ErrorCode::ServerBusy,
+            17 => ErrorCode::RequestTimeout,
+            18 => ErrorCode::Unauthorized,
+            19 => ErrorCode::Unavailable,
+            20 => ErrorCode::RegisterLimit,
+            21 => ErrorCode::InvalidName,
+            _ => return None,
+        };
+        Some(code)
     }
 }
 
-impl From<&str> for Error {
-    fn from(src: &str) -> Error {
-        src.to_string().into()
+impl Error {
+    // The code reported to the peer for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::OperandTooLong | Error::TooManyOperands => ErrorCode::OperandLimit,
+            _ => ErrorCode::Protocol,
+        }
     }
 }
-#[test]
-fn test_get_operands() {
-    let buf = &b"123:456\r\n"[..];
-    let mut cursor = Cursor::new(buf);
-    let first = get_first_operand(&mut cursor);
 
-    assert_eq!(123, first.unwrap());
-    let second = get_second_operand(&mut cursor);
+impl std::error::Error for Error {}
 
-    assert_eq!(456, second.unwrap());
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Incomplete => "stream ended early".fmt(fmt),
+            Error::InvalidTypeByte(byte) => {
+                write!(fmt, "protocol error, invalid type byte {}", byte)
+            }
+            Error::InvalidOperator(byte) => {
+                write!(fmt, "protocol error, invalid operator {:?}", *byte as char)
+            }
+            Error::InvalidOperand => "protocol error, invalid operand".fmt(fmt),
+            Error::OperandTooLong => "protocol error, operand exceeds the digit limit".fmt(fmt),
+            Error::TooManyOperands => "protocol error, too many operands".fmt(fmt),
+            Error::MissingDelimiter => "protocol error, missing delimiter".fmt(fmt),
+            Error::InvalidField(field) => write!(fmt, "protocol error, invalid {}", field),
+            Error::UnexpectedPayload => "protocol error, unexpected payload".fmt(fmt),
+            Error::EmptyTree => "protocol error, empty expression tree".fmt(fmt),
+            Error::NestingTooDeep => "protocol error, frames nested too deeply".fmt(fmt),
+            Error::InvalidNesting => "protocol error, frame can not be nested here".fmt(fmt),
+            Error::InvalidLength => "protocol error, length does not match the frame".fmt(fmt),
+            Error::InvalidCompression => "protocol error, invalid compressed frame".fmt(fmt),
+            Error::DecompressedTooLarge(max) => write!(
+                fmt,
+                "protocol error, compressed frame is longer than {} bytes",
+                max
+            ),
+            Error::FrameTooLarge(max) => {
+                write!(fmt, "protocol error, frame is longer than {} bytes", max)
+            }
+            Error::ChecksumMismatch => "protocol error, checksum mismatch".fmt(fmt),
+        }
+    }
 }
 
-#[test]
-fn test_parse() {
-    let buf = &b"+123:456\r\n"[..];
+// Frames nested deeper inside arrays, tags and trees are rejected.
+pub const MAX_NESTING_DEPTH: usize = 32;
 
-    let mut cursor = Cursor::new(buf);
-    let frame = Frame::parse(&mut cursor);
-    assert!(frame.is_ok());
-}
+// Highest protocol version this crate implements.
+pub const PROTOCOL_VERSION: u32 = 2;
 
-#[test]
-fn test_parse_fail() {
-    let buf = &b"+123456\r\n"[..];
+impl Frame {
+    // The protocol version that introduced the frame.
+    pub fn min_version(&self) -> u32 {
+        match self {
+            Frame::Addition(..)
+            | Frame::Subtraction(..)
+            | Frame::Multiplication(..)
+            | Frame::OpResult(_)
+            | Frame::Ping
+            | Frame::Pong
+            | Frame::Identify(_)
+            | Frame::Version
+            | Frame::VersionInfo(_)
+            | Frame::Error(..)
+            | Frame::Hello(_) => 1,
+            Frame::Modulo(..)
+            | Frame::Division(..)
+            | Frame::Pow(..)
+            | Frame::Factorial(_)
+            | Frame::Gcd(..)
+            | Frame::Lcm(..)
+            | Frame::Sum(_)
+            | Frame::Product(_)
+            | Frame::Signed(..)
+            | Frame::SignedResult(_)
+            | Frame::Float(..)
+            | Frame::FloatResult(_)
+            | Frame::Decimal(..)
+            | Frame::DecimalResult(_)
+            | Frame::Rpn(_)
+            | Frame::Expr(_)
+            | Frame::ArrayStart(_)
+            | Frame::Array(_)
+            | Frame::Echo(_)
+            | Frame::Sort(_)
+            | Frame::Aggregate(..)
+            | Frame::Tagged(..)
+            | Frame::Tree(..)
+            | Frame::Set(..)
+            | Frame::Get(_)
+            | Frame::Save(_)
+            | Frame::Restore(_)
+            | Frame::Accumulate(..)
+            | Frame::Result
+            | Frame::Named(..)
+            | Frame::Stats(_)
+            | Frame::StatsInfo(_)
+            | Frame::Shutdown(_) => 2,
+            #[cfg(feature = "bignum")]
+            Frame::Big(..) | Frame::BigResult(_) => 2,
+            #[cfg(feature = "compression")]
+            Frame::Compressed(_) => 2,
+        }
+    }
 
-    let mut cursor = Cursor::new(buf);
-    let frame = Frame::parse(&mut cursor);
-    assert!(frame.is_err());
+    // Name of the variant, e.g. for logging the type of a request without
+    // its operands.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Frame::Addition(..) => "Addition",
+            Frame::Subtraction(..) => "Subtraction",
+            Frame::Multiplication(..) => "Multiplication",
+            Frame::Modulo(..) => "Modulo",
+            Frame::Division(..) => "Division",
+            Frame::Pow(..) => "Pow",
+            Frame::Factorial(_) => "Factorial",
+            Frame::Gcd(..) => "Gcd",
+            Frame::Lcm(..) => "Lcm",
+            Frame::Sum(_) => "Sum",
+            Frame::Product(_) => "Product",
+            Frame::Signed(..) => "Signed",
+            Frame::SignedResult(_) => "SignedResult",
+            Frame::Float(..) => "Float",
+            Frame::FloatResult(_) => "FloatResult",
+            Frame::Decimal(..) => "Decimal",
+            Frame::DecimalResult(_) => "DecimalResult",
+            #[cfg(feature = "bignum")]
+            Frame::Big(..) => "Big",
+            #[cfg(feature = "bignum")]
+            Frame::BigResult(_) => "BigResult",
+            Frame::OpResult(_) => "OpResult",
+            Frame::Ping => "Ping",
+            Frame::Pong => "Pong",
+            Frame::Identify(_) => "Identify",
+            Frame::Version => "Version",
+            Frame::VersionInfo(_) => "VersionInfo",
+            Frame::Rpn(_) => "Rpn",
+            Frame::Expr(_) => "Expr",
+            Frame::ArrayStart(_) => "ArrayStart",
+            Frame::Array(_) => "Array",
+            Frame::Error(..) => "Error",
+            Frame::Echo(_) => "Echo",
+            Frame::Sort(_) => "Sort",
+            Frame::Aggregate(..) => "Aggregate",
+            Frame::Set(..) => "Set",
+            Frame::Get(_) => "Get",
+            Frame::Save(_) => "Save",
+            Frame::Restore(_) => "Restore",
+            Frame::Accumulate(..) => "Accumulate",
+            Frame::Result => "Result",
+            Frame::Named(..) => "Named",
+            Frame::Stats(_) => "Stats",
+            Frame::StatsInfo(_) => "StatsInfo",
+            Frame::Shutdown(_) => "Shutdown",
+            Frame::Hello(_) => "Hello",
+            Frame::Tagged(..) => "Tagged",
+            Frame::Tree(..) => "Tree",
+            #[cfg(feature = "compression")]
+            Frame::Compressed(_) => "Compressed",
+        }
+    }
+
+    // Compute the result of an arithmetic frame locally, `None` if the
+    // frame is not arithmetic or the result does not fit in a `u64`.
+    pub fn eval(&self) -> Option<u64> {
+        match self {
+            Frame::Addition(x, y) => x.checked_add(*y),
+            Frame::Subtraction(x, y) => x.checked_sub(*y),
+            Frame::Multiplication(x, y) => x.checked_mul(*y),
+            Frame::Modulo(x, y) => x.checked_rem(*y),
+            Frame::Division(x, y) => x.checked_div(*y),
+            Frame::Pow(x, y) => checked_pow(*x, *y),
+            // Stops at the first overflow, at 21! at the latest.
+            Frame::Factorial(n) => (2..=*n).try_fold(1u64, |product, x| product.checked_mul(x)),
+            Frame::Gcd(x, y) => Some(gcd(*x, *y)),
+            Frame::Lcm(0, _) | Frame::Lcm(_, 0) => Some(0),
+            Frame::Lcm(x, y) => (x / gcd(*x, *y)).checked_mul(*y),
+            Frame::Aggregate(Aggregate::Min, operands) => operands.iter().min().copied(),
+            Frame::Aggregate(Aggregate::Max, operands) => operands.iter().max().copied(),
+            Frame::Aggregate(Aggregate::Mean, operands) if operands.is_empty() => None,
+            // The sum of the operands may not fit in a `u64`, their mean does.
+            Frame::Aggregate(Aggregate::Mean, operands) => {
+                let sum: u128 = operands.iter().map(|x| *x as u128).sum();
+                Some((sum / operands.len() as u128) as u64)
+            }
+            Frame::Sum(operands) => operands.iter().try_fold(0u64, |sum, x| sum.checked_add(*x)),
+            Frame::Product(operands) => operands
+                .iter()
+                .try_fold(1u64, |product, x| product.checked_mul(*x)),
+            _ => None,
+        }
+    }
+
+    pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
+        Frame::check_with(src, &ParseConfig::default())
+    }
+
+    // Like `check`, operands that are longer than `config` allows fail the
+    // check before their line is complete.
+    pub fn check_with(src: &mut Cursor<&[u8]>, config: &ParseConfig) -> Result<(), Error> {
+        Frame::check_nested(src, config, 0)
+    }
+
+    // `depth` is the number of frames the frame is nested in.
+    fn check_nested(
+        src: &mut Cursor<&[u8]>,
+        config: &ParseConfig,
+        depth: usize,
+    ) -> Result<(), Error> {
+        if depth > MAX_NESTING_DEPTH {
+            return Err(Error::NestingTooDeep);
+        }
+        match get_u8(src)? {
+            b'+' => {
+                get_operand_line(src, config)?;
+                Ok(())
+            }
+            b'-' => {
+                get_operand_line(src, config)?;
+                Ok(())
+            }
+            b'*' => {
+                get_operand_line(src, config)?;
+                Ok(())
+            }
+            b'%' | b'/' | b'^' | b'F' | b'g' | b'l' | b'i' => {
+                get_operand_line(src, config)?;
+                Ok(())
+            }
+            b'f' | b'd' => {
+                get_line(src)?;
+                Ok(())
+            }
+            #[cfg(feature = "bignum")]
+            b'n' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b'=' => {
+                skip(src, 8)?;
+                Ok(())
+            }
+            b'p' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b'P' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b'A' => {
+                get_operand_line(src, config)?;
+                Ok(())
+            }
+            b'I' | b'S' | b'G' | b'W' | b'L' | b'R' | b'o' | b'k' | b'K' | b'q' | b'h' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b'v' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b'V' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b'!' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b'r' | b'x' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b's' | b'a' => {
+                get_operand_line(src, config)?;
+                Ok(())
+            }
+            b'[' => {
+                get_count(src)?;
+                Ok(())
+            }
+            b'@' => {
+                get_line(src)?;
+                Frame::check_nested(src, config, depth + 1)
+            }
+            b'e' => {
+                let len = get_length(src)?;
+                // The whole payload has to be buffered before `parse` runs.
+                skip(src, len)?;
+                Ok(())
+            }
+            b'#' => {
+                let count = get_count(src)?;
+                // Every element has to be fully buffered, a missing
+                // element surfaces as `Incomplete` from the element check.
+                for _ in 0..count {
+                    Frame::check_nested(src, config, depth + 1)?;
+                }
+                Ok(())
+            }
+            b't' => {
+                get_u8(src)?;
+                for _ in 0..get_count(src)? {
+                    Frame::check_nested(src, config, depth + 1)?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "compression")]
+            b'z' => {
+                let len = get_length(src)?;
+                skip(src, len)?;
+                Ok(())
+            }
+            default => Err(Error::InvalidTypeByte(default)),
+        }
+    }
+
+    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+        Frame::parse_with(src, &ParseConfig::default())
+    }
+
+    pub fn parse_with(src: &mut Cursor<&[u8]>, config: &ParseConfig) -> Result<Frame, Error> {
+        Frame::parse_nested(src, config, 0)
+    }
+
+    fn parse_nested(
+        src: &mut Cursor<&[u8]>,
+        config: &ParseConfig,
+        depth: usize,
+    ) -> Result<Frame, Error> {
+        if depth > MAX_NESTING_DEPTH {
+            return Err(Error::NestingTooDeep);
+        }
+        match get_u8(src)? {
+            b'+' => {
+                if let Some(operands) = get_variadic(src, config)? {
+                    return Ok(Frame::Sum(operands));
+                }
+                let first_opereand = get_first_operand(src, config)?;
+                let second_operand = get_second_operand(src, config)?;
+                Ok(Frame::Addition(first_opereand, second_operand))
+            }
+            b'-' => {
+                let first_opereand = get_first_operand(src, config)?;
+                let second_operand = get_second_operand(src, config)?;
+                Ok(Frame::Subtraction(first_opereand, second_operand))
+            }
+            b'*' => {
+                if let Some(operands) = get_variadic(src, config)? {
+                    return Ok(Frame::Product(operands));
+                }
+                let first_opereand = get_first_operand(src, config)?;
+                let second_operand = get_second_operand(src, config)?;
+                Ok(Frame::Multiplication(first_opereand, second_operand))
+            }
+            b'%' => {
+                let first_opereand = get_first_operand(src, config)?;
+                let second_operand = get_second_operand(src, config)?;
+                Ok(Frame::Modulo(first_opereand, second_operand))
+            }
+            b'/' => {
+                let first_opereand = get_first_operand(src, config)?;
+                let second_operand = get_second_operand(src, config)?;
+                Ok(Frame::Division(first_opereand, second_operand))
+            }
+            b'^' => {
+                let first_opereand = get_first_operand(src, config)?;
+                let second_operand = get_second_operand(src, config)?;
+                Ok(Frame::Pow(first_opereand, second_operand))
+            }
+            b'F' => Ok(Frame::Factorial(get_second_operand(src, config)?)),
+            b'g' => {
+                let first_opereand = get_first_operand(src, config)?;
+                let second_operand = get_second_operand(src, config)?;
+                Ok(Frame::Gcd(first_opereand, second_operand))
+            }
+            b'l' => {
+                let first_opereand = get_first_operand(src, config)?;
+                let second_operand = get_second_operand(src, config)?;
+                Ok(Frame::Lcm(first_opereand, second_operand))
+            }
+            b'i' => {
+                let op = match get_u8(src)? {
+                    b'+' => Operator::Add,
+                    b'-' => Operator::Sub,
+                    b'*' => Operator::Mul,
+                    b'=' => {
+                        let result = get_signed(src, config, get_second_operand)?;
+                        return Ok(Frame::SignedResult(result));
+                    }
+                    op => return Err(Error::InvalidOperator(op)),
+                };
+                let first_opereand = get_signed(src, config, get_first_operand)?;
+                let second_operand = get_signed(src, config, get_second_operand)?;
+                Ok(Frame::Signed(op, first_opereand, second_operand))
+            }
+            b'f' => {
+                let op = match get_u8(src)? {
+                    b'+' => Operator::Add,
+                    b'-' => Operator::Sub,
+                    b'*' => Operator::Mul,
+                    b'=' => return Ok(Frame::FloatResult(get_float(get_line(src)?)?)),
+                    op => return Err(Error::InvalidOperator(op)),
+                };
+                let line = get_line(src)?;
+                let (x, y) = match memchr::memchr(b':', line) {
+                    Some(i) => (&line[..i], &line[i + 1..]),
+                    None => return Err(Error::MissingDelimiter),
+                };
+                Ok(Frame::Float(op, get_float(x)?, get_float(y)?))
+            }
+            b'd' => {
+                let op = match get_u8(src)? {
+                    b'+' => Operator::Add,
+                    b'-' => Operator::Sub,
+                    b'*' => Operator::Mul,
+                    b'=' => return Ok(Frame::DecimalResult(get_decimal(get_line(src)?)?)),
+                    op => return Err(Error::InvalidOperator(op)),
+                };
+                let line = get_line(src)?;
+                let (x, y) = match memchr::memchr(b':', line) {
+                    Some(i) => (&line[..i], &line[i + 1..]),
+                    None => return Err(Error::MissingDelimiter),
+                };
+                Ok(Frame::Decimal(op, get_decimal(x)?, get_decimal(y)?))
+            }
+            #[cfg(feature = "bignum")]
+            b'n' => {
+                let max_digits = config.max_big_operand_digits;
+                let op = match get_u8(src)? {
+                    b'+' => Operator::Add,
+                    b'-' => Operator::Sub,
+                    b'*' => Operator::Mul,
+                    b'=' => {
+                        let result = get_big(get_line(src)?, 2 * max_digits)?;
+                        return Ok(Frame::BigResult(result));
+                    }
+                    op => return Err(Error::InvalidOperator(op)),
+                };
+                let line = get_line(src)?;
+                let (x, y) = match memchr::memchr(b':', line) {
+                    Some(i) => (&line[..i], &line[i + 1..]),
+                    None => return Err(Error::MissingDelimiter),
+                };
+                Ok(Frame::Big(
+                    op,
+                    get_big(x, max_digits)?,
+                    get_big(y, max_digits)?,
+                ))
+            }
+            b'=' => {
+                if src.remaining() < 8 {
+                    return Err(Error::Incomplete);
+                }
+                Ok(Frame::OpResult(src.get_u64()))
+            }
+            b'p' => {
+                if !get_line(src)?.is_empty() {
+                    return Err(Error::UnexpectedPayload);
+                }
+                Ok(Frame::Ping)
+            }
+            b'P' => {
+                if !get_line(src)?.is_empty() {
+                    return Err(Error::UnexpectedPayload);
+                }
+                Ok(Frame::Pong)
+            }
+            b'I' => {
+                let name = String::from_utf8(get_line(src)?.to_vec())
+                    .map_err(|_| Error::InvalidField("client name"))?;
+                if name.is_empty() {
+                    return Err(Error::InvalidField("client name"));
+                }
+                Ok(Frame::Identify(name))
+            }
+            b'S' => {
+                let line = get_line(src)?;
+                let (name, value) = match memchr::memchr(b':', line) {
+                    Some(i) => (&line[..i], &line[i + 1..]),
+                    None => return Err(Error::MissingDelimiter),
+                };
+                let value = get_operand(value, config)?;
+                Ok(Frame::Set(get_name(name)?, value))
+            }
+            b'h' => {
+                let version = parse_digits(get_line(src)?, 10)
+                    .and_then(|version| u32::try_from(version).ok())
+                    .filter(|version| *version > 0)
+                    .ok_or(Error::InvalidField("protocol version"))?;
+                Ok(Frame::Hello(version))
+            }
+            b'G' => Ok(Frame::Get(get_name(get_line(src)?)?)),
+            b'W' => Ok(Frame::Save(get_name(get_line(src)?)?)),
+            b'L' => Ok(Frame::Restore(get_name(get_line(src)?)?)),
+            b'A' => {
+                let op = match get_u8(src)? {
+                    b'+' => Operator::Add,
+                    b'-' => Operator::Sub,
+                    b'*' => Operator::Mul,
+                    op => return Err(Error::InvalidOperator(op)),
+                };
+                Ok(Frame::Accumulate(op, get_second_operand(src, config)?))
+            }
+            b'R' => {
+                if !get_line(src)?.is_empty() {
+                    return Err(Error::UnexpectedPayload);
+                }
+                Ok(Frame::Result)
+            }
+            b'o' => {
+                let op = match get_u8(src)? {
+                    b'+' => Operator::Add,
+                    b'-' => Operator::Sub,
+                    b'*' => Operator::Mul,
+                    op => return Err(Error::InvalidOperator(op)),
+                };
+                let line = get_line(src)?;
+                let (x, y) = match memchr::memchr(b':', line) {
+                    Some(i) => (&line[..i], &line[i + 1..]),
+                    None => return Err(Error::MissingDelimiter),
+                };
+                if y.contains(&b':') {
+                    return Err(Error::TooManyOperands);
+                }
+                Ok(Frame::Named(
+                    op,
+                    get_named_operand(x, config)?,
+                    get_named_operand(y, config)?,
+                ))
+            }
+            b'k' => Ok(Frame::Stats(get_admin_token(get_line(src)?)?)),
+            b'q' => Ok(Frame::Shutdown(get_admin_token(get_line(src)?)?)),
+            b'K' => {
+                let mut counters = get_line(src)?
+                    .split(|b| *b == b':')
+                    .map(|counter| parse_digits(counter, 10));
+                let mut next = || {
+                    counters
+                        .next()
+                        .flatten()
+                        .ok_or(Error::InvalidField("server stats"))
+                };
+                let stats = ServerStats {
+                    connections_active: next()?,
+                    connections_accepted: next()?,
+                    requests_served: next()?,
+                    protocol_errors: next()?,
+                };
+                if counters.next().is_some() {
+                    return Err(Error::TooManyOperands);
+                }
+                Ok(Frame::StatsInfo(stats))
+            }
+            b'v' => {
+                if !get_line(src)?.is_empty() {
+                    return Err(Error::UnexpectedPayload);
+                }
+                Ok(Frame::Version)
+            }
+            b'V' => {
+                let version = String::from_utf8(get_line(src)?.to_vec())
+                    .map_err(|_| Error::InvalidField("version"))?;
+                Ok(Frame::VersionInfo(version))
+            }
+            b'!' => {
+                let line = get_line(src)?;
+                let (code, message) = match memchr::memchr(b':', line) {
+                    Some(i) => (&line[..i], &line[i + 1..]),
+                    None => return Err(Error::MissingDelimiter),
+                };
+                let code = parse_digits(code, 10)
+                    .and_then(ErrorCode::from_u64)
+                    .ok_or(Error::InvalidField("error code"))?;
+                let message = String::from_utf8(message.to_vec())
+                    .map_err(|_| Error::InvalidField("error message"))?;
+                Ok(Frame::Error(code, message))
+            }
+            b'r' => {
+                let tokens = get_line(src)?
+                    .split(|&byte| byte == b' ')
+                    .filter(|token| !token.is_empty())
+                    .map(|token| get_token(token, config))
+                    .collect::<Result<_, _>>()?;
+                Ok(Frame::Rpn(tokens))
+            }
+            b's' => Ok(Frame::Sort(get_operand_list(src, config)?)),
+            b'a' => {
+                let function = match get_u8(src)? {
+                    b'<' => Aggregate::Min,
+                    b'>' => Aggregate::Max,
+                    b'~' => Aggregate::Mean,
+                    op => return Err(Error::InvalidOperator(op)),
+                };
+                Ok(Frame::Aggregate(function, get_operand_list(src, config)?))
+            }
+            b'x' => {
+                let expr = String::from_utf8(get_line(src)?.to_vec())
+                    .map_err(|_| Error::InvalidField("expression"))?;
+                Ok(Frame::Expr(expr))
+            }
+            b'[' => Ok(Frame::ArrayStart(get_count(src)?)),
+            b'@' => {
+                let tag = get_tag(src)?;
+                match Frame::parse_nested(src, config, depth + 1)? {
+                    Frame::Tagged(..) => Err(Error::InvalidNesting),
+                    frame => Ok(Frame::Tagged(tag, Box::new(frame))),
+                }
+            }
+            b'e' => {
+                let len = get_length(src)?;
+                let start = src.position() as usize;
+                skip(src, len)?;
+                Ok(Frame::Echo(src.get_ref()[start..start + len].to_vec()))
+            }
+            b'#' => {
+                let count = get_count(src)?;
+                let mut frames = Vec::new();
+                for _ in 0..count {
+                    frames.push(Frame::parse_nested(src, config, depth + 1)?);
+                }
+                Ok(Frame::Array(frames))
+            }
+            b't' => {
+                let op = match get_u8(src)? {
+                    b'+' => Operator::Add,
+                    b'-' => Operator::Sub,
+                    b'*' => Operator::Mul,
+                    op => return Err(Error::InvalidOperator(op)),
+                };
+                let count = get_count(src)?;
+                if count == 0 {
+                    return Err(Error::EmptyTree);
+                }
+                let mut operands = Vec::new();
+                for _ in 0..count {
+                    operands.push(Frame::parse_nested(src, config, depth + 1)?);
+                }
+                Ok(Frame::Tree(op, operands))
+            }
+            #[cfg(feature = "compression")]
+            b'z' => {
+                if depth > 0 {
+                    return Err(Error::InvalidNesting);
+                }
+                let len = get_length(src)?;
+                let start = src.position() as usize;
+                skip(src, len)?;
+                let decompressed = decompress(
+                    &src.get_ref()[start..start + len],
+                    config.max_decompressed_len,
+                )?;
+
+                let mut inner = Cursor::new(&decompressed[..]);
+                let frame = match Frame::parse_nested(&mut inner, config, depth + 1) {
+                    Err(Error::Incomplete) => return Err(Error::InvalidLength),
+                    frame => frame?,
+                };
+                if inner.has_remaining() {
+                    return Err(Error::InvalidLength);
+                }
+                Ok(Frame::Compressed(Box::new(frame)))
+            }
+            default => Err(Error::InvalidTypeByte(default)),
+        }
+    }
+
+    // Append the encoding of the frame to `buf`.
+    //
+    // A frame that can not be represented on the wire is an error, and
+    // `buf` is left as it was. So is a frame that encodes to nothing, a
+    // peer could not tell it was sent at all.
+    pub fn encode(&self, buf: &mut BytesMut) -> crate::Result<()> {
+        encode_checked(self, buf, Frame::encode_into)
+    }
+
+    // The encoding of the frame, see `encode`.
+    pub fn to_vec(&self) -> crate::Result<Vec<u8>> {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf)?;
+        Ok(buf.to_vec())
+    }
+
+    fn encode_into(&self, buf: &mut BytesMut) -> crate::Result<()> {
+        match self {
+            Frame::Addition(x, y) => put_line(buf, b"+", &[*x, *y]),
+            // Fewer than three operands would be read back as a different frame.
+            Frame::Sum(operands) | Frame::Product(operands) if operands.len() < 3 => {
+                return Err(crate::Error::Encode(
+                    "(+) variadic operations need at least three operands".to_string(),
+                ));
+            }
+            Frame::Sum(operands) | Frame::Product(operands) => {
+                let op = if matches!(self, Frame::Sum(_)) {
+                    b"+"
+                } else {
+                    b"*"
+                };
+                put_line(buf, op, operands);
+            }
+            Frame::Subtraction(x, y) => put_line(buf, b"-", &[*x, *y]),
+            Frame::Multiplication(x, y) => put_line(buf, b"*", &[*x, *y]),
+            Frame::Modulo(x, y) => put_line(buf, b"%", &[*x, *y]),
+            Frame::Division(x, y) => put_line(buf, b"/", &[*x, *y]),
+            Frame::Pow(x, y) => put_line(buf, b"^", &[*x, *y]),
+            Frame::Factorial(n) => put_line(buf, b"F", &[*n]),
+            Frame::Gcd(x, y) => put_line(buf, b"g", &[*x, *y]),
+            Frame::Lcm(x, y) => put_line(buf, b"l", &[*x, *y]),
+            Frame::Signed(op, x, y) => write!(buf, "i{}{}:{}\r\n", op, x, y)?,
+            Frame::SignedResult(r) => write!(buf, "i={}\r\n", r)?,
+            // `Display` of `f64` never uses an exponent and round trips.
+            Frame::Float(op, x, y) => write!(buf, "f{}{}:{}\r\n", op, x, y)?,
+            Frame::FloatResult(r) => write!(buf, "f={}\r\n", r)?,
+            Frame::Decimal(op, x, y) => write!(buf, "d{}{}:{}\r\n", op, x, y)?,
+            Frame::DecimalResult(r) => write!(buf, "d={}\r\n", r)?,
+            #[cfg(feature = "bignum")]
+            Frame::Big(op, x, y) => write!(buf, "n{}{}:{}\r\n", op, x, y)?,
+            #[cfg(feature = "bignum")]
+            Frame::BigResult(r) => write!(buf, "n={}\r\n", r)?,
+            Frame::OpResult(r) => {
+                buf.put_u8(b'=');
+                buf.put_u64(*r);
+            }
+            Frame::Ping => buf.put_slice(b"p\r\n"),
+            Frame::Pong => buf.put_slice(b"P\r\n"),
+            Frame::Expr(expr) => {
+                if expr.contains(['\r', '\n']) {
+                    return Err(crate::Error::Encode(
+                        "(x) expression contains a line break".to_string(),
+                    ));
+                }
+                write!(buf, "x{}\r\n", expr)?;
+            }
+            Frame::Identify(name) => write!(buf, "I{}\r\n", name)?,
+            Frame::Set(name, value) => write!(buf, "S{}:{}\r\n", check_name(name)?, value)?,
+            Frame::Get(name) => write!(buf, "G{}\r\n", check_name(name)?)?,
+            Frame::Save(slot) => write!(buf, "W{}\r\n", check_name(slot)?)?,
+            Frame::Restore(slot) => write!(buf, "L{}\r\n", check_name(slot)?)?,
+            Frame::Accumulate(op, x) => write!(buf, "A{}{}\r\n", op, x)?,
+            Frame::Result => buf.put_slice(b"R\r\n"),
+            Frame::Named(op, x, y) => {
+                for operand in [x, y] {
+                    if let Operand::Name(name) = operand {
+                        check_name(name)?;
+                    }
+                }
+                write!(buf, "o{}{}:{}\r\n", op, x, y)?;
+            }
+            Frame::Stats(token) => write!(buf, "k{}\r\n", token)?,
+            Frame::StatsInfo(stats) => write!(
+                buf,
+                "K{}:{}:{}:{}\r\n",
+                stats.connections_active,
+                stats.connections_accepted,
+                stats.requests_served,
+                stats.protocol_errors
+            )?,
+            Frame::Shutdown(token) => write!(buf, "q{}\r\n", token)?,
+            Frame::Hello(version) => write!(buf, "h{}\r\n", version)?,
+            Frame::Version => buf.put_slice(b"v\r\n"),
+            Frame::VersionInfo(version) => write!(buf, "V{}\r\n", version)?,
+            Frame::Error(code, message) => write!(buf, "!{}:{}\r\n", *code as u16, message)?,
+            Frame::Rpn(tokens) => {
+                buf.put_u8(b'r');
+                for token in tokens {
+                    write!(buf, " {}", token)?;
+                }
+                buf.put_slice(b"\r\n");
+            }
+            Frame::ArrayStart(count) => write!(buf, "[{}\r\n", count)?,
+            Frame::Echo(payload) => {
+                write!(buf, "e{}\r\n", payload.len())?;
+                buf.put_slice(payload);
+            }
+            Frame::Sort(operands) => {
+                put_line(buf, b"s ", operands);
+            }
+            Frame::Aggregate(function, operands) => {
+                write!(buf, "a{}", function)?;
+                put_line(buf, b"", operands);
+            }
+            Frame::Tagged(tag, frame) => {
+                write!(buf, "@{}:{}\r\n", tag.id, tag.priority)?;
+                frame.encode_into(buf)?;
+            }
+            Frame::Tree(op, operands) => {
+                write!(buf, "t{}{}\r\n", op, operands.len())?;
+                for operand in operands {
+                    operand.encode_into(buf)?;
+                }
+            }
+            Frame::Array(frames) => {
+                write!(buf, "#{}\r\n", frames.len())?;
+                for frame in frames {
+                    frame.encode_into(buf)?;
+                }
+            }
+            #[cfg(feature = "compression")]
+            Frame::Compressed(frame) => {
+                let compressed = compress(&frame.to_vec()?)?;
+                write!(buf, "z{}\r\n", compressed.len())?;
+                buf.put_slice(&compressed);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Append the encoding `encode` writes for `frame`, see `Frame::encode`.
+fn encode_checked<F>(frame: &Frame, buf: &mut BytesMut, encode: F) -> crate::Result<()>
+where
+    F: FnOnce(&Frame, &mut BytesMut) -> crate::Result<()>,
+{
+    let start = buf.len();
+    let encoded = encode(frame, buf).and_then(|()| {
+        if buf.len() == start {
+            return Err(crate::Error::Encode(format!(
+                "frame {:?} encoded to zero bytes",
+                frame
+            )));
+        }
+        Ok(())
+    });
+    if encoded.is_err() {
+        buf.truncate(start);
+    }
+    encoded
+}
+
+// Write `prefix` and the decimal `operands` separated by `:` and end the
+// line, without going through `fmt` or a `String`.
+fn put_line(buf: &mut BytesMut, prefix: &[u8], operands: &[u64]) {
+    buf.put_slice(prefix);
+    for (i, operand) in operands.iter().enumerate() {
+        if i > 0 {
+            buf.put_u8(b':');
+        }
+        put_decimal(buf, *operand);
+    }
+    buf.put_slice(b"\r\n");
+}
+
+fn put_decimal(buf: &mut BytesMut, mut n: u64) {
+    // `u64::MAX` has 20 digits.
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    buf.put_slice(&digits[start..]);
+}
+
+#[cfg(feature = "compression")]
+fn compress(src: &[u8]) -> crate::Result<Vec<u8>> {
+    use flate2::{write::DeflateEncoder, Compression};
+    use std::io::Write;
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(src)?;
+    Ok(encoder.finish()?)
+}
+
+// Decompress `src`, failing once the result is longer than `max_len`
+// instead of decompressing all of it.
+#[cfg(feature = "compression")]
+fn decompress(src: &[u8], max_len: usize) -> Result<Vec<u8>, Error> {
+    use flate2::read::DeflateDecoder;
+    use std::io::Read;
+
+    let mut decompressed = Vec::new();
+    DeflateDecoder::new(src)
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|_| Error::InvalidCompression)?;
+    if decompressed.len() > max_len {
+        return Err(Error::DecompressedTooLarge(max_len));
+    }
+    Ok(decompressed)
+}
+
+// `x` to the power of `y`, `None` if the result does not fit in a `u64`.
+fn checked_pow(x: u64, y: u64) -> Option<u64> {
+    match (x, y) {
+        // The only bases that do not overflow for every exponent that
+        // does not fit in a `u32`.
+        (_, 0) => Some(1),
+        (0 | 1, _) => Some(x),
+        _ => x.checked_pow(u32::try_from(y).ok()?),
+    }
+}
+
+// Euclid's algorithm, `gcd(x, 0)` is `x`.
+fn gcd(mut x: u64, mut y: u64) -> u64 {
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    x
+}
+
+fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+    Ok(src.get_u8())
+}
+
+fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+    src.advance(n);
+    Ok(())
+}
+
+fn get_first_operand(src: &mut Cursor<&[u8]>, config: &ParseConfig) -> Result<u64, Error> {
+    let start = src.position() as usize;
+    let buf = *src.get_ref();
+
+    // Only search for the separator where a valid operand can end.
+    let limit = start + operand_len_limit(&buf[start..], config);
+    let window = buf.len().min(limit + 1);
+
+    match memchr::memchr2(b':', b'\r', &buf[start..window]).map(|i| start + i) {
+        Some(i) if buf[i] == b':' => {
+            // set the position after `:`
+            src.set_position((i + 1) as u64);
+            let fbytes = &buf[start..i];
+            get_operand_value(fbytes, config)
+        }
+        // The separator has to come before the end of the line, a `\r`
+        // can only be the start of the terminator.
+        Some(i) => match buf.get(i + 1) {
+            Some(b'\n') => Err(Error::MissingDelimiter),
+            _ => Err(Error::InvalidOperand),
+        },
+        None if window > limit => Err(Error::OperandTooLong),
+        None => Err(Error::MissingDelimiter),
+    }
+}
+
+fn get_second_operand(src: &mut Cursor<&[u8]>, config: &ParseConfig) -> Result<u64, Error> {
+    let start = src.position() as usize;
+    let buf = *src.get_ref();
+
+    // Only search for the terminator where a valid operand can end.
+    let window = buf
+        .len()
+        .min(start + operand_len_limit(&buf[start..], config) + 2);
+
+    match find_crlf(&buf[..window], start) {
+        Some(i) => {
+            // set the position after `\n`
+            src.set_position((i + 2) as u64);
+            let fbytes = &buf[start..i];
+            if fbytes.contains(&b':') {
+                return Err(Error::TooManyOperands);
+            }
+            if fbytes.contains(&b'\r') {
+                return Err(Error::InvalidOperand);
+            }
+            get_operand_value(fbytes, config)
+        }
+        None if window < buf.len() => Err(Error::OperandTooLong),
+        None => Err(Error::MissingDelimiter),
+    }
+}
+
+// Read a signed operand, an optional `-` followed by an operand that is
+// read with `read`, so the same limits apply to the digits.
+fn get_signed<F>(src: &mut Cursor<&[u8]>, config: &ParseConfig, read: F) -> Result<i64, Error>
+where
+    F: FnOnce(&mut Cursor<&[u8]>, &ParseConfig) -> Result<u64, Error>,
+{
+    let negative = src.chunk().first() == Some(&b'-');
+    if negative {
+        src.advance(1);
+    }
+
+    let magnitude = read(src, config)?;
+    let value = if negative {
+        0i64.checked_sub_unsigned(magnitude)
+    } else {
+        i64::try_from(magnitude).ok()
+    };
+    value.ok_or(Error::InvalidOperand)
+}
+
+// A decimal number with an optional `-` and an optional fraction, e.g.
+// `-12.5`. Exponents, infinities and NaN are not accepted.
+fn get_float(operand: &[u8]) -> Result<f64, Error> {
+    let digits = operand.strip_prefix(b"-").unwrap_or(operand);
+    let (whole, fraction) = match memchr::memchr(b'.', digits) {
+        Some(i) => (&digits[..i], Some(&digits[i + 1..])),
+        None => (digits, None),
+    };
+    let is_digits = |part: &[u8]| !part.is_empty() && part.iter().all(u8::is_ascii_digit);
+    if !is_digits(whole) || !fraction.is_none_or(is_digits) {
+        return Err(Error::InvalidOperand);
+    }
+
+    // Only ASCII digits, `-` and `.` are left, the operand is valid UTF-8.
+    std::str::from_utf8(operand)
+        .ok()
+        .and_then(|operand| operand.parse().ok())
+        .ok_or(Error::InvalidOperand)
+}
+
+fn get_decimal(operand: &[u8]) -> Result<Decimal, Error> {
+    Decimal::parse(operand).ok_or(Error::InvalidOperand)
+}
+
+// A decimal number of at most `max_digits` digits.
+#[cfg(feature = "bignum")]
+fn get_big(operand: &[u8], max_digits: usize) -> Result<BigUint, Error> {
+    if operand.len() > max_digits {
+        return Err(Error::OperandTooLong);
+    }
+    // `parse_bytes` accepts `_` separators, only plain digits are valid.
+    if operand.is_empty() || !operand.iter().all(u8::is_ascii_digit) {
+        return Err(Error::InvalidOperand);
+    }
+    BigUint::parse_bytes(operand, 10).ok_or(Error::InvalidOperand)
+}
+
+// Returns the base selected by the prefix of an operand and the length
+// of the prefix.
+fn operand_radix(fbytes: &[u8]) -> (u32, usize) {
+    match fbytes {
+        [b'0', b'b', ..] => (2, 2),
+        [b'0', b'o', ..] => (8, 2),
+        [b'0', b'x', ..] => (16, 2),
+        _ => (10, 0),
+    }
+}
+
+// Maximum length of the operand starting at `fbytes[0]`, including its
+// base prefix. `max_operand_digits` is given in decimal digits, so it is
+// scaled to the number of digits the same magnitude takes in the base.
+fn operand_len_limit(fbytes: &[u8], config: &ParseConfig) -> usize {
+    let (radix, prefix) = operand_radix(fbytes);
+    let digits = config.max_operand_digits as f64 * 10f64.ln() / (radix as f64).ln();
+    prefix + digits.ceil() as usize
+}
+
+// Convert the digits of an operand into its value, honouring an
+// optional base prefix.
+fn get_operand_value(fbytes: &[u8], config: &ParseConfig) -> Result<u64, Error> {
+    let (radix, prefix) = operand_radix(fbytes);
+    let digits = &fbytes[prefix..];
+
+    if config.strict && digits.len() > 1 && digits[0] == b'0' {
+        return Err(Error::InvalidOperand);
+    }
+    parse_digits(digits, radix).ok_or(Error::InvalidOperand)
+}
+
+// A register or snapshot name, see `is_valid_name`.
+fn get_name(name: &[u8]) -> Result<String, Error> {
+    match std::str::from_utf8(name) {
+        Ok(name) if is_valid_name(name) => Ok(name.to_string()),
+        _ => Err(Error::InvalidField("name")),
+    }
+}
+
+// Whether `name` can name a register or snapshot. It is not empty, does
+// not start with a digit, which would make it a number in `Frame::Named`,
+// and holds no delimiter.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.starts_with(|c: char| c.is_ascii_digit())
+        && !name.is_empty()
+        && !name.contains([':', '\r', '\n'])
+}
+
+// `name` if it is valid, a name that would not parse back otherwise.
+fn check_name(name: &str) -> crate::Result<&str> {
+    if !is_valid_name(name) {
+        return Err(crate::Error::Encode(format!("invalid name {:?}", name)));
+    }
+    Ok(name)
+}
+
+// An admin token, it is never empty.
+fn get_admin_token(token: &[u8]) -> Result<String, Error> {
+    if token.is_empty() {
+        return Err(Error::InvalidField("admin token"));
+    }
+    String::from_utf8(token.to_vec()).map_err(|_| Error::InvalidField("admin token"))
+}
+
+// A number if `operand` starts with a digit, a register name otherwise.
+fn get_named_operand(operand: &[u8], config: &ParseConfig) -> Result<Operand, Error> {
+    match operand.first() {
+        Some(first) if first.is_ascii_digit() => Ok(Operand::Number(get_operand(operand, config)?)),
+        _ => Ok(Operand::Name(get_name(operand)?)),
+    }
+}
+
+// A single operand, bounded by the digit limit.
+fn get_operand(operand: &[u8], config: &ParseConfig) -> Result<u64, Error> {
+    if operand.len() > operand_len_limit(operand, config) {
+        return Err(Error::OperandTooLong);
+    }
+    get_operand_value(operand, config)
+}
+
+// The operands of a line with more than two colon separated operands.
+// `None` leaves the cursor in place, the line is parsed as a two operand
+// frame.
+fn get_variadic(src: &mut Cursor<&[u8]>, config: &ParseConfig) -> Result<Option<Vec<u64>>, Error> {
+    let start = src.position();
+    let line = get_line(src)?;
+    if memchr::memchr_iter(b':', line).count() < 2 {
+        src.set_position(start);
+        return Ok(None);
+    }
+
+    let mut operands = Vec::new();
+    for operand in line.split(|&byte| byte == b':') {
+        if operands.len() == config.max_operands {
+            return Err(Error::TooManyOperands);
+        }
+        operands.push(get_operand(operand, config)?);
+    }
+    Ok(Some(operands))
+}
+
+// Read a line of colon separated operands, preceded by a space.
+fn get_operand_list(src: &mut Cursor<&[u8]>, config: &ParseConfig) -> Result<Vec<u64>, Error> {
+    let line = get_line(src)?;
+    let line = line.strip_prefix(b" ").unwrap_or(line);
+    if line.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut operands = Vec::new();
+    for operand in line.split(|&byte| byte == b':') {
+        if operands.len() == config.max_operands {
+            return Err(Error::TooManyOperands);
+        }
+        operands.push(get_operand(operand, config)?);
+    }
+    Ok(operands)
+}
+
+fn get_token(token: &[u8], config: &ParseConfig) -> Result<Token, Error> {
+    match token {
+        b"+" => Ok(Token::Add),
+        b"-" => Ok(Token::Sub),
+        b"*" => Ok(Token::Mul),
+        [b'$', index @ ..] => parse_digits(index, 10)
+            .and_then(|index| usize::try_from(index).ok())
+            .map(Token::Ref)
+            .ok_or(Error::InvalidOperand),
+        _ if token.len() > operand_len_limit(token, config) => Err(Error::OperandTooLong),
+        _ => get_operand_value(token, config).map(Token::Number),
+    }
+}
+
+// Every byte has to be a valid digit for `radix`, `None` is returned for
+// invalid digits, an empty slice or a value that does not fit a `u64`.
+fn parse_digits(digits: &[u8], radix: u32) -> Option<u64> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |value, &byte| {
+        let digit = (byte as char).to_digit(radix)?;
+        value.checked_mul(radix as u64)?.checked_add(digit as u64)
+    })
+}
+
+// Read the element count of an array, the whole line must be digits.
+fn get_count(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
+    let line = get_line(src)?;
+    if line.is_empty() || !line.iter().all(u8::is_ascii_digit) {
+        return Err(Error::InvalidField("count"));
+    }
+    atoi::<u64>(line).ok_or(Error::InvalidField("count"))
+}
+
+// Read the "{id}:{priority}" line of a tagged frame.
+fn get_tag(src: &mut Cursor<&[u8]>) -> Result<Tag, Error> {
+    let line = get_line(src)?;
+    let (id, priority) = match memchr::memchr(b':', line) {
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => return Err(Error::MissingDelimiter),
+    };
+    let id = parse_digits(id, 10).ok_or(Error::InvalidField("tag id"))?;
+    let priority = parse_digits(priority, 10)
+        .and_then(|priority| u8::try_from(priority).ok())
+        .ok_or(Error::InvalidField("tag priority"))?;
+    Ok(Tag { id, priority })
+}
+
+// Read the payload length of an echo frame, the whole line must be digits.
+fn get_length(src: &mut Cursor<&[u8]>) -> Result<usize, Error> {
+    let line = get_line(src)?;
+    if line.is_empty() || !line.iter().all(u8::is_ascii_digit) {
+        return Err(Error::InvalidField("length"));
+    }
+    atoi::<usize>(line).ok_or(Error::InvalidField("length"))
+}
+
+// Find line terminating character = `\r` `\n`
+fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+
+    match find_crlf(src.get_ref(), start) {
+        Some(i) => {
+            // update the position after `\n`
+            src.set_position((i + 2) as u64);
+
+            Ok(&src.get_ref()[start..i])
+        }
+        None => Err(Error::Incomplete),
+    }
+}
+
+// Like `get_line` for a line of operands. Fails as soon as a run of digits
+// is longer than an operand may be, rather than waiting for a terminator
+// that may never come.
+fn get_operand_line<'a>(
+    src: &mut Cursor<&'a [u8]>,
+    config: &ParseConfig,
+) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+    let buf = *src.get_ref();
+    let end = find_crlf(buf, start).unwrap_or(buf.len());
+
+    let too_long = buf[start..end]
+        .split(|byte| !byte.is_ascii_alphanumeric())
+        .any(|digits| digits.len() > operand_len_limit(digits, config));
+    if too_long {
+        return Err(Error::OperandTooLong);
+    }
+    get_line(src)
+}
+
+// Returns the index of the `\r` of the first `\r\n` at or after `start`.
+// A `\r` that is not followed by `\n` is skipped.
+fn find_crlf(buf: &[u8], start: usize) -> Option<usize> {
+    let mut from = start;
+
+    while let Some(i) = memchr::memchr(b'\r', buf.get(from..)?) {
+        let i = from + i;
+        if buf.get(i + 1) == Some(&b'\n') {
+            return Some(i);
+        }
+        from = i + 1;
+    }
+    None
+}
+#[test]
+fn test_get_operands() {
+    let buf = &b"123:456\r\n"[..];
+    let mut cursor = Cursor::new(buf);
+    let first = get_first_operand(&mut cursor, &ParseConfig::default());
+
+    assert_eq!(123, first.unwrap());
+    let second = get_second_operand(&mut cursor, &ParseConfig::default());
+
+    assert_eq!(456, second.unwrap());
+}
+
+#[test]
+fn test_parse() {
+    let buf = &b"+123:456\r\n"[..];
+
+    let mut cursor = Cursor::new(buf);
+    let frame = Frame::parse(&mut cursor);
+    assert!(frame.is_ok());
+}
+
+#[test]
+fn test_parse_fail() {
+    let buf = &b"+123456\r\n"[..];
+
+    let mut cursor = Cursor::new(buf);
+    let frame = Frame::parse(&mut cursor);
+    assert!(frame.is_err());
+}
+
+#[test]
+fn test_parse_op_result() {
+    let buf = &b"=\x00\x00\x00\x00\x00\x00\x00\x2a"[..];
+    let mut cursor = Cursor::new(buf);
+    Frame::check(&mut cursor).unwrap();
+    assert_eq!(9, cursor.position());
+
+    cursor.set_position(0);
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::OpResult(42))));
+
+    let mut cursor = Cursor::new(&buf[..5]);
+    assert!(matches!(Frame::check(&mut cursor), Err(Error::Incomplete)));
+}
+
+#[test]
+fn test_parse_version() {
+    let buf = &b"v\r\n"[..];
+    let mut cursor = Cursor::new(buf);
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Version)));
+
+    let buf = &b"V0.1.0\r\n"[..];
+    let mut cursor = Cursor::new(buf);
+    match Frame::parse(&mut cursor) {
+        Ok(Frame::VersionInfo(version)) => assert_eq!("0.1.0", version),
+        other => panic!("unexpected frame {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_array_consumes_exact_bytes() {
+    let buf = &b"#2\r\n+1:2\r\n*3:4\r\n-5:6\r\n"[..];
+    let mut cursor = Cursor::new(buf);
+    Frame::check(&mut cursor).unwrap();
+    let len = cursor.position();
+
+    cursor.set_position(0);
+    match Frame::parse(&mut cursor) {
+        Ok(Frame::Array(frames)) => {
+            assert!(matches!(
+                frames[..],
+                [Frame::Addition(1, 2), Frame::Multiplication(3, 4)]
+            ));
+        }
+        other => panic!("unexpected frame {:?}", other),
+    }
+    // The trailing subtraction belongs to the next frame.
+    assert_eq!(len, cursor.position());
+    assert_eq!(b"-5:6\r\n", &buf[len as usize..]);
+}
+
+#[test]
+fn test_check_array_missing_elements() {
+    let buf = &b"#3\r\n+1:2\r\n*3:4\r\n"[..];
+    let mut cursor = Cursor::new(buf);
+    assert!(matches!(Frame::check(&mut cursor), Err(Error::Incomplete)));
+}
+
+#[test]
+fn test_parse_array_malformed_element() {
+    let buf = &b"#2\r\n+1:2\r\n?3:4\r\n"[..];
+    let mut cursor = Cursor::new(buf);
+    assert!(matches!(
+        Frame::check(&mut cursor),
+        Err(Error::InvalidTypeByte(b'?'))
+    ));
+
+    let buf = &b"#2\r\n+1:2\r\n*34\r\n"[..];
+    let mut cursor = Cursor::new(buf);
+    assert!(Frame::check(&mut cursor).is_ok());
+    cursor.set_position(0);
+    assert!(Frame::parse(&mut cursor).is_err());
+}
+
+#[test]
+fn test_find_crlf() {
+    assert_eq!(Some(3), find_crlf(b"123\r\n", 0));
+    // Terminator ending exactly at the end of the buffer.
+    assert_eq!(Some(1), find_crlf(b"1\r\n", 1));
+    assert_eq!(Some(0), find_crlf(b"\r\n", 0));
+    // A lone `\r` is not a terminator.
+    assert_eq!(Some(3), find_crlf(b"1\r2\r\n", 0));
+    assert_eq!(None, find_crlf(b"123\r", 0));
+    assert_eq!(None, find_crlf(b"", 0));
+    // Out of range start positions are not found instead of panicking.
+    assert_eq!(None, find_crlf(b"1\r\n", 4));
+}
+
+#[test]
+fn test_line_and_operand_agree() {
+    for buf in [&b"1:2\r\n"[..], b"1:23\r\n", b"1:2\r3\r\n"] {
+        let mut line = Cursor::new(buf);
+        line.set_position(2);
+        let mut operand = line.clone();
+
+        let expected = find_crlf(buf, 2).unwrap();
+        assert_eq!(&buf[2..expected], get_line(&mut line).unwrap());
+        let _ = get_second_operand(&mut operand, &ParseConfig::default());
+        assert_eq!(line.position(), operand.position());
+        assert_eq!(expected as u64 + 2, line.position());
+    }
+}
+
+#[test]
+fn test_parse_leading_zeros() {
+    let strict = ParseConfig {
+        strict: true,
+        ..Default::default()
+    };
+    let buf = &b"+007:1\r\n"[..];
+
+    let mut cursor = Cursor::new(buf);
+    assert!(Frame::parse_with(&mut cursor, &strict).is_err());
+
+    let mut cursor = Cursor::new(buf);
+    assert!(matches!(
+        Frame::parse_with(&mut cursor, &ParseConfig::default()),
+        Ok(Frame::Addition(7, 1))
+    ));
+
+    // A single zero is the canonical encoding of zero.
+    let mut cursor = Cursor::new(&b"+0:10\r\n"[..]);
+    assert!(matches!(
+        Frame::parse_with(&mut cursor, &strict),
+        Ok(Frame::Addition(0, 10))
+    ));
+    let mut cursor = Cursor::new(&b"+1:010\r\n"[..]);
+    assert!(Frame::parse_with(&mut cursor, &strict).is_err());
+}
+
+#[test]
+fn test_parse_operand_bases() {
+    let mut cursor = Cursor::new(&b"+0b1010:0xF\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Addition(10, 15))
+    ));
+
+    let mut cursor = Cursor::new(&b"*0o17:42\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Multiplication(15, 42))
+    ));
+
+    let mut cursor = Cursor::new(&b"-0xffffffffffffffff:0b0\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Subtraction(u64::MAX, 0))
+    ));
+
+    // Digits that are invalid for the selected base, a missing value and
+    // a value that does not fit a `u64`.
+    for buf in [
+        &b"+0b102:1\r\n"[..],
+        b"+0o8:1\r\n",
+        b"+1:0xg\r\n",
+        b"+0x:1\r\n",
+        b"+1:0x10000000000000000\r\n",
+        b"+1a:1\r\n",
+    ] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_parse_operand_digit_limit() {
+    let digits = "9".repeat(10_000);
+    for buf in [
+        format!("+{}:1\r\n", digits),
+        format!("+1:{}\r\n", digits),
+        format!("+0b{}:1\r\n", "1".repeat(100)),
+    ] {
+        let mut cursor = Cursor::new(buf.as_bytes());
+        assert!(matches!(
+            Frame::parse(&mut cursor),
+            Err(Error::OperandTooLong)
+        ));
+    }
+
+    // `u64::MAX` is within the limit in every base.
+    let buf = format!("+{}:0b{:b}\r\n", u64::MAX, u64::MAX);
+    let mut cursor = Cursor::new(buf.as_bytes());
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Addition(u64::MAX, u64::MAX))
+    ));
+    let buf = format!("*0o{:o}:0x{:x}\r\n", u64::MAX, u64::MAX);
+    let mut cursor = Cursor::new(buf.as_bytes());
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Multiplication(u64::MAX, u64::MAX))
+    ));
+
+    let config = ParseConfig {
+        max_operand_digits: 2,
+        ..Default::default()
+    };
+    let mut cursor = Cursor::new(&b"+99:100\r\n"[..]);
+    assert!(Frame::parse_with(&mut cursor, &config).is_err());
+}
+
+#[test]
+fn test_parse_rpn() {
+    let mut cursor = Cursor::new(&b"r 3 4 + 2 *\r\n"[..]);
+    match Frame::parse(&mut cursor) {
+        Ok(Frame::Rpn(tokens)) => assert_eq!(
+            vec![
+                Token::Number(3),
+                Token::Number(4),
+                Token::Add,
+                Token::Number(2),
+                Token::Mul
+            ],
+            tokens
+        ),
+        other => panic!("unexpected frame {:?}", other),
+    }
+
+    let mut cursor = Cursor::new(&b"r $0 4 *\r\n"[..]);
+    match Frame::parse(&mut cursor) {
+        Ok(Frame::Rpn(tokens)) => {
+            assert_eq!(vec![Token::Ref(0), Token::Number(4), Token::Mul], tokens)
+        }
+        other => panic!("unexpected frame {:?}", other),
+    }
+
+    for buf in [
+        &b"r 3 4 /\r\n"[..],
+        b"r 3 4+\r\n",
+        b"r 3 x +\r\n",
+        b"r $ 4 *\r\n",
+        b"r $0x1 4 *\r\n",
+    ] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_parse_error() {
+    let mut cursor = Cursor::new(&b"!3:arithmetic overflow\r\n"[..]);
+    match Frame::parse(&mut cursor) {
+        Ok(Frame::Error(ErrorCode::Overflow, message)) => {
+            assert_eq!("arithmetic overflow", message)
+        }
+        other => panic!("unexpected frame {:?}", other),
+    }
+
+    for buf in [&b"!overflow\r\n"[..], b"!99:unknown\r\n", b"!:no code\r\n"] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_parse_stray_delimiters() {
+    let cases = [
+        (&b"+12\r34:5\r\n"[..], Error::InvalidOperand),
+        (b"+12:3\r4\r\n", Error::InvalidOperand),
+        (b"-1:2:3\r\n", Error::TooManyOperands),
+        (b"+12\r\n", Error::MissingDelimiter),
+        // The separator of the next frame is not used.
+        (b"+12\r\n+3:4\r\n", Error::MissingDelimiter),
+        (b"+:1\r\n", Error::InvalidOperand),
+    ];
+
+    for (buf, expected) in cases {
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(
+            Err(expected),
+            Frame::parse(&mut cursor).map(|_| ()),
+            "{:?}",
+            buf
+        );
+    }
+}
+
+#[test]
+fn test_parse_errors() {
+    let cases = [
+        (&b"i/1:2\r\n"[..], Error::InvalidOperator(b'/')),
+        (b"+1:2x\r\n", Error::InvalidOperand),
+        (b"i+1:99999999999999999999\r\n", Error::InvalidOperand),
+        (b"p1\r\n", Error::UnexpectedPayload),
+        (b"I\r\n", Error::InvalidField("client name")),
+        (b"!99:unknown\r\n", Error::InvalidField("error code")),
+        (b"@1:2\r\n@3:4\r\np\r\n", Error::InvalidNesting),
+        (b"t+0\r\n", Error::EmptyTree),
+        (b"?\r\n", Error::InvalidTypeByte(b'?')),
+    ];
+    for (buf, expected) in cases {
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(
+            Err(expected),
+            Frame::parse(&mut cursor).map(|_| ()),
+            "{:?}",
+            buf
+        );
+    }
+
+    assert_eq!(ErrorCode::OperandLimit, Error::OperandTooLong.code());
+    assert_eq!(ErrorCode::Protocol, Error::MissingDelimiter.code());
+}
+
+#[test]
+fn test_check_echo_needs_full_payload() {
+    // The payload contains a `\r\n`, only the declared length ends it.
+    let buf = &b"e5\r\na\r\nb"[..];
+    let mut cursor = Cursor::new(buf);
+    assert!(matches!(Frame::check(&mut cursor), Err(Error::Incomplete)));
+
+    let buf = &b"e5\r\na\r\nbc"[..];
+    let mut cursor = Cursor::new(buf);
+    Frame::check(&mut cursor).unwrap();
+    assert_eq!(buf.len() as u64, cursor.position());
+
+    cursor.set_position(0);
+    match Frame::parse(&mut cursor) {
+        Ok(Frame::Echo(payload)) => assert_eq!(b"a\r\nbc", &payload[..]),
+        other => panic!("unexpected frame {:?}", other),
+    }
+
+    for buf in [&b"e\r\n"[..], b"e-1\r\n", b"e1x\r\na"] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::check(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_parse_sort() {
+    let mut cursor = Cursor::new(&b"s 3:1:2\r\n"[..]);
+    match Frame::parse(&mut cursor) {
+        Ok(Frame::Sort(operands)) => assert_eq!(vec![3, 1, 2], operands),
+        other => panic!("unexpected frame {:?}", other),
+    }
+
+    for buf in [&b"s\r\n"[..], b"s \r\n"] {
+        let mut cursor = Cursor::new(buf);
+        match Frame::parse(&mut cursor) {
+            Ok(Frame::Sort(operands)) => assert!(operands.is_empty()),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+
+    let config = ParseConfig {
+        max_operands: 2,
+        ..Default::default()
+    };
+    let mut cursor = Cursor::new(&b"s 3:1\r\n"[..]);
+    assert!(Frame::parse_with(&mut cursor, &config).is_ok());
+    let mut cursor = Cursor::new(&b"s 3:1:2\r\n"[..]);
+    assert!(Frame::parse_with(&mut cursor, &config).is_err());
+
+    for buf in [&b"s 3::2\r\n"[..], b"s 3:x\r\n", b"s 3:1:\r\n"] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_parse_tagged() {
+    let buf = &b"@7:200\r\n+1:2\r\n"[..];
+    let mut cursor = Cursor::new(buf);
+    Frame::check(&mut cursor).unwrap();
+    assert_eq!(buf.len() as u64, cursor.position());
+
+    cursor.set_position(0);
+    match Frame::parse(&mut cursor) {
+        Ok(Frame::Tagged(tag, frame)) => {
+            assert_eq!(
+                Tag {
+                    id: 7,
+                    priority: 200
+                },
+                tag
+            );
+            assert!(matches!(*frame, Frame::Addition(1, 2)));
+        }
+        other => panic!("unexpected frame {:?}", other),
+    }
+
+    let mut cursor = Cursor::new(&b"@7:200\r\n+1:2"[..]);
+    assert!(matches!(Frame::check(&mut cursor), Err(Error::Incomplete)));
+
+    for buf in [
+        &b"@7\r\np\r\n"[..],
+        b"@7:256\r\np\r\n",
+        b"@x:1\r\np\r\n",
+        b"@1:1\r\n@2:1\r\np\r\n",
+    ] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_parse_registers() {
+    let mut cursor = Cursor::new(&b"Sx:0x2A\r\nGx\r\nWbefore\r\nLbefore\r\n"[..]);
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Set(name, 42)) if name == "x"));
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Get(name)) if name == "x"));
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Save(slot)) if slot == "before"));
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Restore(slot)) if slot == "before"));
+
+    for buf in [
+        &b"Sx\r\n"[..],
+        b"S:1\r\n",
+        b"Sx:y\r\n",
+        b"S1x:1\r\n",
+        b"G\r\n",
+        b"G1x\r\n",
+        b"Gx:y\r\n",
+        b"W\r\n",
+    ] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+
+    // Names that would not parse back are not written.
+    for frame in [
+        Frame::Set(String::new(), 1),
+        Frame::Get("1x".into()),
+        Frame::Save("a:b".into()),
+        Frame::Restore("a\r\n".into()),
+        Frame::Named(Operator::Add, Operand::Name("8".into()), Operand::Number(1)),
+    ] {
+        assert!(frame.to_vec().is_err(), "{:?}", frame);
+    }
+}
+
+#[test]
+fn test_parse_accumulator() {
+    let mut cursor = Cursor::new(&b"A+5\r\nA*0x10\r\nR\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Accumulate(Operator::Add, 5))
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Accumulate(Operator::Mul, 16))
+    ));
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Result)));
+
+    for buf in [&b"A5\r\n"[..], b"A/5\r\n", b"A+1:2\r\n", b"R1\r\n"] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+
+    let buf = Frame::Accumulate(Operator::Sub, 7).to_vec().unwrap();
+    let mut cursor = Cursor::new(&buf[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Accumulate(Operator::Sub, 7))
+    ));
+}
+
+#[test]
+fn test_parse_hello() {
+    let mut cursor = Cursor::new(&b"h1\r\n"[..]);
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Hello(1))));
+
+    for buf in [&b"h\r\n"[..], b"h0\r\n", b"hx\r\n", b"h4294967296\r\n"] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_parse_modulo() {
+    let mut cursor = Cursor::new(&b"%17:0x5\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Modulo(17, 5))
+    ));
+    assert_eq!(Some(2), Frame::Modulo(17, 5).eval());
+    assert_eq!(None, Frame::Modulo(17, 0).eval());
+}
+
+#[test]
+fn test_parse_division() {
+    let mut cursor = Cursor::new(&b"/17:0x5\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Division(17, 5))
+    ));
+    assert_eq!(Some(3), Frame::Division(17, 5).eval());
+    assert_eq!(None, Frame::Division(17, 0).eval());
+}
+
+#[test]
+fn test_parse_aggregate() {
+    let mut cursor = Cursor::new(&b"a<3:1:2\r\na>7\r\na~\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Aggregate(Aggregate::Min, operands)) if operands == [3, 1, 2]
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Aggregate(Aggregate::Max, operands)) if operands == [7]
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Aggregate(Aggregate::Mean, operands)) if operands.is_empty()
+    ));
+
+    for buf in [&b"a=1:2\r\n"[..], b"a<1::2\r\n", b"a\r\n"] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+
+    use Aggregate::*;
+    let max = u64::MAX;
+    let cases = [
+        (Frame::Aggregate(Min, vec![3, 1, 2]), Some(1)),
+        (Frame::Aggregate(Max, vec![3, 1, 2]), Some(3)),
+        (Frame::Aggregate(Mean, vec![1, 2, 4]), Some(2)),
+        (
+            Frame::Aggregate(Mean, vec![max, max, max - 3]),
+            Some(max - 1),
+        ),
+        (Frame::Aggregate(Min, vec![5]), Some(5)),
+        (Frame::Aggregate(Max, vec![5]), Some(5)),
+        (Frame::Aggregate(Mean, vec![5]), Some(5)),
+        (Frame::Aggregate(Min, vec![]), None),
+        (Frame::Aggregate(Max, vec![]), None),
+        (Frame::Aggregate(Mean, vec![]), None),
+    ];
+    for (frame, expected) in cases {
+        assert_eq!(expected, frame.eval(), "{:?}", frame);
+        let encoded = frame.to_vec().unwrap();
+        let decoded = Frame::parse(&mut Cursor::new(&encoded[..])).unwrap();
+        assert_eq!(frame.to_vec().unwrap(), decoded.to_vec().unwrap());
+    }
+}
+
+#[test]
+fn test_parse_gcd_and_lcm() {
+    let mut cursor = Cursor::new(&b"g12:18\r\nl4:0x6\r\n"[..]);
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Gcd(12, 18))));
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Lcm(4, 6))));
+
+    let max = u64::MAX;
+    let cases = [
+        (Frame::Gcd(12, 18), Some(6)),
+        (Frame::Gcd(17, 5), Some(1)),
+        (Frame::Gcd(0, 7), Some(7)),
+        (Frame::Gcd(7, 0), Some(7)),
+        (Frame::Gcd(0, 0), Some(0)),
+        (Frame::Gcd(max, max), Some(max)),
+        (Frame::Lcm(4, 6), Some(12)),
+        (Frame::Lcm(0, 7), Some(0)),
+        (Frame::Lcm(0, 0), Some(0)),
+        (Frame::Lcm(max, max), Some(max)),
+        (Frame::Lcm(max, max - 1), None),
+        (Frame::Lcm(1 << 32, 1 << 31), Some(1 << 32)),
+    ];
+    for (frame, expected) in cases {
+        assert_eq!(expected, frame.eval(), "{:?}", frame);
+    }
+}
+
+#[test]
+fn test_parse_pow_and_factorial() {
+    let mut cursor = Cursor::new(&b"^2:0x10\r\nF20\r\n"[..]);
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Pow(2, 16))));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Factorial(20))
+    ));
+
+    for buf in [&b"^2\r\n"[..], b"F\r\n", b"F1:2\r\n"] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+
+    let cases = [
+        (Frame::Pow(2, 16), Some(65536)),
+        (Frame::Pow(2, 63), Some(1 << 63)),
+        (Frame::Pow(2, 64), None),
+        (Frame::Pow(0, 0), Some(1)),
+        (Frame::Pow(1, u64::MAX), Some(1)),
+        (Frame::Pow(0, u64::MAX), Some(0)),
+        (Frame::Pow(2, u64::MAX), None),
+        (Frame::Factorial(0), Some(1)),
+        (Frame::Factorial(5), Some(120)),
+        (Frame::Factorial(20), Some(2432902008176640000)),
+        (Frame::Factorial(21), None),
+        (Frame::Factorial(u64::MAX), None),
+    ];
+    for (frame, expected) in cases {
+        assert_eq!(expected, frame.eval(), "{:?}", frame);
+    }
+}
+
+#[test]
+fn test_parse_signed() {
+    let mut cursor = Cursor::new(&b"i*-3:4\r\ni--3:-0x5\r\ni=-12\r\ni+7:0\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Signed(Operator::Mul, -3, 4))
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Signed(Operator::Sub, -3, -5))
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::SignedResult(-12))
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Signed(Operator::Add, 7, 0))
+    ));
+
+    let mut cursor = Cursor::new(&b"i+-9223372036854775808:9223372036854775807\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Signed(Operator::Add, i64::MIN, i64::MAX))
+    ));
+
+    for buf in [
+        &b"i+9223372036854775808:1\r\n"[..],
+        b"i+--1:1\r\n",
+        b"i+-:1\r\n",
+        b"i/1:1\r\n",
+        b"i=\r\n",
+    ] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_parse_float() {
+    let mut cursor = Cursor::new(&b"f+1.5:-0.25\r\nf*3:2.0\r\nf=-1.25\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Float(Operator::Add, x, y)) if x == 1.5 && y == -0.25
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Float(Operator::Mul, x, y)) if x == 3.0 && y == 2.0
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::FloatResult(r)) if r == -1.25
+    ));
+
+    for buf in [
+        &b"f+1.5\r\n"[..],
+        b"f+.5:1\r\n",
+        b"f+1.:1\r\n",
+        b"f+1e3:1\r\n",
+        b"f+inf:1\r\n",
+        b"f+NaN:1\r\n",
+        b"f+1.2.3:1\r\n",
+        b"f/1:1\r\n",
+    ] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_parse_decimal() {
+    let mut cursor = Cursor::new(&b"d+12.50:-0.25\r\nd=12.25\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Decimal(Operator::Add, x, y))
+            if x == Decimal::new(1250, 2).unwrap() && y == Decimal::new(-25, 2).unwrap()
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::DecimalResult(r)) if r == Decimal::new(1225, 2).unwrap()
+    ));
+
+    // Trailing zeros are kept.
+    let frame = Frame::DecimalResult(Decimal::new(-100, 2).unwrap());
+    assert_eq!(b"d=-1.00\r\n", &frame.to_vec().unwrap()[..]);
+
+    for buf in [&b"d+1.5\r\n"[..], b"d+1e3:1\r\n", b"d/1:1\r\n"] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[cfg(feature = "bignum")]
+#[test]
+fn test_parse_big() {
+    let mut cursor = Cursor::new(&b"n*18446744073709551616:2\r\nn=36893488147419103232\r\n"[..]);
+    let x = BigUint::from(u64::MAX) + 1u32;
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Big(Operator::Mul, a, b)) if a == x && b == BigUint::from(2u32)
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::BigResult(r)) if r == x * 2u32
+    ));
+
+    let config = ParseConfig {
+        max_big_operand_digits: 3,
+        ..Default::default()
+    };
+    let mut cursor = Cursor::new(&b"n+999:1\r\nn+1000:1\r\nn=999999\r\nn=1000000\r\n"[..]);
+    assert!(Frame::parse_with(&mut cursor, &config).is_ok());
+    assert!(Frame::parse_with(&mut cursor, &config).is_err());
+    assert!(Frame::parse_with(&mut cursor, &config).is_ok());
+    assert!(Frame::parse_with(&mut cursor, &config).is_err());
+
+    for buf in [
+        &b"n+1_000:1\r\n"[..],
+        b"n+:1\r\n",
+        b"n+-1:1\r\n",
+        b"n/1:1\r\n",
+    ] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_parse_variadic() {
+    let mut cursor = Cursor::new(&b"+1:2:3:4\r\n*2:3:4\r\n+1:2\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Sum(operands)) if operands == [1, 2, 3, 4]
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Product(operands)) if operands == [2, 3, 4]
+    ));
+    // Two operands keep their own frame.
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Addition(1, 2))
+    ));
+    assert_eq!(Some(10), Frame::Sum(vec![1, 2, 3, 4]).eval());
+    assert_eq!(None, Frame::Product(vec![u64::MAX, 2, 1]).eval());
+
+    let config = ParseConfig {
+        max_operands: 3,
+        ..Default::default()
+    };
+    for buf in [&b"+1:2:3:4\r\n"[..], b"*1::3\r\n", b"+1:2:x\r\n"] {
+        let mut cursor = Cursor::new(buf);
+        assert!(
+            Frame::parse_with(&mut cursor, &config).is_err(),
+            "{:?}",
+            buf
+        );
+    }
+}
+
+#[test]
+fn test_parse_expr() {
+    let mut cursor = Cursor::new(&b"x2+3*4\r\nx(1 + 2) % 2\r\n"[..]);
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Expr(expr)) if expr == "2+3*4"));
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Expr(expr)) if expr == "(1 + 2) % 2"));
+
+    let mut cursor = Cursor::new(&b"x\xff\r\n"[..]);
+    assert!(Frame::parse(&mut cursor).is_err());
+}
+
+#[test]
+fn test_parse_tree() {
+    let buf = &b"t*2\r\n+1:2\r\nt-2\r\n+3:4\r\n%9:4\r\n"[..];
+    let mut cursor = Cursor::new(buf);
+    Frame::check(&mut cursor).unwrap();
+    assert_eq!(buf.len() as u64, cursor.position());
+
+    cursor.set_position(0);
+    match Frame::parse(&mut cursor) {
+        Ok(Frame::Tree(Operator::Mul, operands)) => assert!(matches!(
+            &operands[..],
+            [Frame::Addition(1, 2), Frame::Tree(Operator::Sub, inner)]
+                if matches!(&inner[..], [Frame::Addition(3, 4), Frame::Modulo(9, 4)])
+        )),
+        other => panic!("unexpected frame {:?}", other),
+    }
+
+    let mut cursor = Cursor::new(&buf[..buf.len() - 1]);
+    assert!(matches!(Frame::check(&mut cursor), Err(Error::Incomplete)));
+
+    for buf in [&b"t+0\r\n"[..], b"t/1\r\n+1:2\r\n"] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_nesting_depth_limit() {
+    let nested = |depth: usize| {
+        let mut buf = b"t+1\r\n".repeat(depth);
+        buf.extend_from_slice(b"p\r\n");
+        buf
+    };
+
+    let buf = nested(MAX_NESTING_DEPTH);
+    let mut cursor = Cursor::new(&buf[..]);
+    Frame::check(&mut cursor).unwrap();
+    cursor.set_position(0);
+    assert!(Frame::parse(&mut cursor).is_ok());
+
+    let buf = nested(MAX_NESTING_DEPTH + 1);
+    let mut cursor = Cursor::new(&buf[..]);
+    assert!(matches!(
+        Frame::check(&mut cursor),
+        Err(Error::NestingTooDeep)
+    ));
+    cursor.set_position(0);
+    assert!(Frame::parse(&mut cursor).is_err());
+
+    // Arrays count towards the same limit.
+    let mut buf = b"#1\r\n".repeat(MAX_NESTING_DEPTH + 1);
+    buf.extend_from_slice(b"p\r\n");
+    let mut cursor = Cursor::new(&buf[..]);
+    assert!(matches!(
+        Frame::check(&mut cursor),
+        Err(Error::NestingTooDeep)
+    ));
+}
+
+#[test]
+fn test_encode_round_trip() {
+    let frames = [
+        Frame::Addition(1, 2),
+        Frame::OpResult(u64::MAX),
+        Frame::Rpn(vec![Token::Number(3), Token::Ref(0), Token::Add]),
+        Frame::Error(ErrorCode::Overflow, "arithmetic overflow".into()),
+        Frame::Array(vec![Frame::Ping, Frame::Echo(b"a\r\nb".to_vec())]),
+    ];
+    let mut buf = BytesMut::new();
+    for frame in &frames {
+        frame.encode(&mut buf).unwrap();
+    }
+
+    let mut cursor = Cursor::new(&buf[..]);
+    for frame in &frames {
+        let parsed = Frame::parse(&mut cursor).unwrap();
+        assert_eq!(format!("{:?}", frame), format!("{:?}", parsed));
+        assert_eq!(frame.to_vec().unwrap(), parsed.to_vec().unwrap());
+    }
+    assert!(!cursor.has_remaining());
+
+    // A failed encoding leaves the buffer as it was.
+    let len = buf.len();
+    let invalid = Frame::Array(vec![Frame::Ping, Frame::Expr("1\n2".into())]);
+    assert!(invalid.encode(&mut buf).is_err());
+    assert_eq!(len, buf.len());
+}
+
+#[test]
+fn test_empty_encoding_is_rejected() {
+    // An encoding that writes nothing, as a broken operation could.
+    let mut buf = BytesMut::from(&b"p\r\n"[..]);
+    let err = encode_checked(&Frame::Ping, &mut buf, |_, _| Ok(())).unwrap_err();
+    assert!(err.to_string().contains("zero bytes"), "{}", err);
+    assert_eq!(&b"p\r\n"[..], &buf[..]);
+
+    encode_checked(&Frame::Ping, &mut buf, Frame::encode_into).unwrap();
+    assert_eq!(&b"p\r\np\r\n"[..], &buf[..]);
+}
+
+#[test]
+fn test_display() {
+    let tag = Tag { id: 7, priority: 1 };
+    let cases = [
+        (Frame::Addition(10, 32), "ADD 10 32"),
+        (Frame::OpResult(42), "RESULT 42"),
+        (Frame::Signed(Operator::Sub, -3, 5), "SUB -3 5"),
+        (Frame::Sum(vec![1, 2, 3]), "ADD 1 2 3"),
+        (
+            Frame::Rpn(vec![Token::Number(3), Token::Ref(0), Token::Mul]),
+            "RPN 3 $0 *",
+        ),
+        (
+            Frame::Array(vec![
+                Frame::Addition(1, 2),
+                Frame::Ping,
+                Frame::Array(vec![]),
+            ]),
+            "ARRAY [ADD 1 2, PING, ARRAY []]",
+        ),
+        (
+            Frame::Error(ErrorCode::Overflow, "arithmetic overflow".into()),
+            "ERROR Overflow arithmetic overflow",
+        ),
+        (Frame::Tagged(tag, Box::new(Frame::Pong)), "#7 PONG"),
+        (
+            Frame::Tree(Operator::Mul, vec![Frame::OpResult(2), Frame::Modulo(9, 4)]),
+            "MUL (RESULT 2, MOD 9 4)",
+        ),
+        (Frame::Echo(vec![0; 5]), "ECHO 5 bytes"),
+    ];
+    for (frame, expected) in cases {
+        assert_eq!(expected, frame.to_string());
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip() {
+    let tag = Tag { id: 7, priority: 1 };
+    let frame = Frame::Tagged(
+        tag,
+        Box::new(Frame::Array(vec![
+            Frame::Addition(10, 32),
+            Frame::Rpn(vec![Token::Number(3), Token::Ref(0), Token::Mul]),
+            Frame::Error(ErrorCode::Overflow, "arithmetic overflow".into()),
+            Frame::Signed(Operator::Sub, -3, 5),
+        ])),
+    );
+
+    let json = serde_json::to_string(&frame).unwrap();
+    let decoded: Frame = serde_json::from_str(&json).unwrap();
+    assert_eq!(format!("{:?}", frame), format!("{:?}", decoded));
+
+    let decoded: Frame = serde_json::from_str(r#"{"Addition":[1,2]}"#).unwrap();
+    assert!(matches!(decoded, Frame::Addition(1, 2)));
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_compressed_round_trip() {
+    let frames = (0..1000)
+        .map(|i| Frame::Addition(i, 1_000_000 + i))
+        .collect();
+    let frame = Frame::Compressed(Box::new(Frame::Array(frames)));
+    let encoded = frame.to_vec().unwrap();
+    let uncompressed = match &frame {
+        Frame::Compressed(frame) => frame.to_vec().unwrap(),
+        _ => unreachable!(),
+    };
+    assert!(encoded.len() * 2 < uncompressed.len());
+
+    let mut cursor = Cursor::new(&encoded[..]);
+    Frame::check(&mut cursor).unwrap();
+    assert_eq!(encoded.len() as u64, cursor.position());
+    cursor.set_position(0);
+    match Frame::parse(&mut cursor) {
+        Ok(Frame::Compressed(frame)) => match *frame {
+            Frame::Array(frames) => {
+                assert_eq!(1000, frames.len());
+                assert!(matches!(frames[999], Frame::Addition(999, 1000999)));
+            }
+            other => panic!("unexpected frame {:?}", other),
+        },
+        other => panic!("unexpected frame {:?}", other),
+    }
+
+    // The decompressed length is limited on its own.
+    let config = ParseConfig {
+        max_decompressed_len: uncompressed.len() - 1,
+        ..Default::default()
+    };
+    let mut cursor = Cursor::new(&encoded[..]);
+    assert!(Frame::parse_with(&mut cursor, &config).is_err());
+
+    let nested = Frame::Compressed(Box::new(Frame::Array(vec![frame.clone()])));
+    let truncated = compress(b"+1:2").unwrap();
+    let mut truncated_frame = format!("z{}\r\n", truncated.len()).into_bytes();
+    truncated_frame.extend_from_slice(&truncated);
+    for buf in [
+        nested.to_vec().unwrap(),
+        b"z3\r\nabc".to_vec(),
+        truncated_frame,
+    ] {
+        let mut cursor = Cursor::new(&buf[..]);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+}
+
+#[test]
+fn test_parse_named_operands() {
+    let mut cursor = Cursor::new(&b"o+x:8\r\no*0x2:total\r\n"[..]);
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Named(Operator::Add, Operand::Name(x), Operand::Number(8))) if x == "x"
+    ));
+    assert!(matches!(
+        Frame::parse(&mut cursor),
+        Ok(Frame::Named(Operator::Mul, Operand::Number(2), Operand::Name(y))) if y == "total"
+    ));
+
+    for buf in [
+        &b"o+x\r\n"[..],
+        b"o+:1\r\n",
+        b"o+x:\r\n",
+        b"o+x:y:z\r\n",
+        b"o/x:1\r\n",
+        b"o+1x:2\r\n",
+    ] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+
+    let frame = Frame::Named(Operator::Sub, Operand::Name("x".into()), Operand::Number(1));
+    assert_eq!("SUB x 1", frame.to_string());
+    let buf = frame.to_vec().unwrap();
+    assert_eq!(&b"o-x:1\r\n"[..], &buf[..]);
+}
+
+#[test]
+fn test_parse_admin_frames() {
+    let mut cursor = Cursor::new(&b"ksecret\r\nqsecret\r\nK1:2:3:4\r\n"[..]);
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Stats(token)) if token == "secret"));
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::Shutdown(token)) if token == "secret"));
+    let stats = ServerStats {
+        connections_active: 1,
+        connections_accepted: 2,
+        requests_served: 3,
+        protocol_errors: 4,
+    };
+    assert!(matches!(Frame::parse(&mut cursor), Ok(Frame::StatsInfo(parsed)) if parsed == stats));
+
+    for buf in [
+        &b"k\r\n"[..],
+        b"q\r\n",
+        b"K1:2:3\r\n",
+        b"K1:2:3:4:5\r\n",
+        b"K1:2:x:4\r\n",
+    ] {
+        let mut cursor = Cursor::new(buf);
+        assert!(Frame::parse(&mut cursor).is_err(), "{:?}", buf);
+    }
+
+    // The token is never shown, e.g. in logs.
+    assert_eq!("STATS", Frame::Stats("secret".into()).to_string());
+    assert_eq!("SHUTDOWN", Frame::Shutdown("secret".into()).to_string());
+    assert_eq!(
+        &b"K1:2:3:4\r\n"[..],
+        &Frame::StatsInfo(stats).to_vec().unwrap()[..]
+    );
 }
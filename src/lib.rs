@@ -2,12 +2,41 @@ pub mod frame;
 pub use frame::Frame;
 pub mod connection;
 pub use connection::Connection;
+pub mod binary;
+pub mod codec;
+pub use codec::FrameCodec;
 
 pub mod server;
 
+pub mod expr;
+
+pub mod decimal;
+
+pub mod proxy;
+
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+#[cfg(feature = "tls")]
+pub mod tls;
+
+pub mod op_log;
+
+pub mod audit;
+
+pub mod metrics;
+
+pub mod rate_limit;
+
+pub mod request_queue;
+
+pub mod circuit_breaker;
+pub use circuit_breaker::CircuitBreaker;
+
 pub mod clients;
-pub use clients::Client;
+pub use clients::{CachingClient, Client, ServerError};
 
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub mod error;
+pub use error::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,316 @@
+// Metrics of a server in the Prometheus text format.
+//
+// A `Metrics` registry is shared by the handlers of a server, see
+// `ServerHandle::metrics`. `Metrics::render` returns the exposition that a
+// Prometheus server scrapes, `serve` answers scrapes on a listener of its
+// own, see `ServerConfig::metrics_addr`.
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::{
+    frame::ServerStats,
+    server::{is_connection_error, Backoff, ServerConfig},
+};
+
+// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections_accepted: AtomicU64,
+    connections_active: AtomicU64,
+
+    // Request frames handled, see `ServerHandle::requests_served`.
+    requests_served: AtomicU64,
+
+    // Frames read, by `Frame::kind`. Sorted so the exposition is stable.
+    frames: Mutex<BTreeMap<&'static str, u64>>,
+
+    // Frames that could not be decoded.
+    protocol_errors: AtomicU64,
+
+    request_latency: Histogram,
+}
+
+// Counts of observations by bucket, Prometheus buckets are cumulative but
+// these are not, `render` sums them up.
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    // Observations above the largest bucket.
+    overflow: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+// Counts a connection as active until it is dropped, including when the
+// task serving the connection is aborted.
+#[derive(Debug)]
+pub(crate) struct ActiveConnection(Arc<Metrics>);
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn connections_accepted(&self) -> u64 {
+        self.connections_accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn connections_active(&self) -> u64 {
+        self.connections_active.load(Ordering::Relaxed)
+    }
+
+    pub fn requests_served(&self) -> u64 {
+        self.requests_served.load(Ordering::Relaxed)
+    }
+
+    // Frames of type `kind`, as named by `Frame::kind`, read so far.
+    pub fn frames(&self, kind: &str) -> u64 {
+        let frames = self.frames.lock().unwrap();
+        frames.get(kind).copied().unwrap_or(0)
+    }
+
+    pub fn protocol_errors(&self) -> u64 {
+        self.protocol_errors.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn connection_accepted(self: &Arc<Self>) -> ActiveConnection {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self.clone())
+    }
+
+    pub(crate) fn frame_read(&self, kind: &'static str) {
+        *self.frames.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    pub(crate) fn request_served(&self) {
+        self.requests_served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn request_handled(&self, latency: Duration) {
+        self.request_latency.observe(latency);
+    }
+
+    pub(crate) fn protocol_error(&self) {
+        self.protocol_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The counters answering a `Frame::Stats`.
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            connections_active: self.connections_active(),
+            connections_accepted: self.connections_accepted(),
+            requests_served: self.requests_served(),
+            protocol_errors: self.protocol_errors(),
+        }
+    }
+
+    // The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        // Writing to a `String` never fails.
+        self.write(&mut out).unwrap();
+        out
+    }
+
+    fn write(&self, out: &mut String) -> fmt::Result {
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP {} {}", name, help)?;
+            writeln!(out, "# TYPE {} counter", name)?;
+            writeln!(out, "{} {}", name, value)
+        };
+
+        counter(
+            out,
+            "calculator_connections_accepted_total",
+            "Connections accepted.",
+            self.connections_accepted(),
+        )?;
+        writeln!(
+            out,
+            "# HELP calculator_connections_active Connections open."
+        )?;
+        writeln!(out, "# TYPE calculator_connections_active gauge")?;
+        writeln!(
+            out,
+            "calculator_connections_active {}",
+            self.connections_active()
+        )?;
+        counter(
+            out,
+            "calculator_protocol_errors_total",
+            "Frames that could not be decoded.",
+            self.protocol_errors(),
+        )?;
+
+        writeln!(out, "# HELP calculator_frames_total Frames read, by type.")?;
+        writeln!(out, "# TYPE calculator_frames_total counter")?;
+        for (kind, count) in self.frames.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "calculator_frames_total{{type=\"{}\"}} {}",
+                kind, count
+            )?;
+        }
+
+        let name = "calculator_request_duration_seconds";
+        writeln!(out, "# HELP {} Time taken to handle a request.", name)?;
+        writeln!(out, "# TYPE {} histogram", name)?;
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&self.request_latency.buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative)?;
+        }
+        cumulative += self.request_latency.overflow.load(Ordering::Relaxed);
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative)?;
+        let sum = self.request_latency.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "{}_sum {}", name, sum)?;
+        writeln!(out, "{}_count {}", name, cumulative)
+    }
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound);
+        match bucket {
+            Some(i) => self.buckets[i].fetch_add(1, Ordering::Relaxed),
+            None => self.overflow.fetch_add(1, Ordering::Relaxed),
+        };
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Answer every HTTP request on `listener` with the metrics, whatever its
+// path. Meant for a Prometheus scraper, not for general HTTP clients.
+// Failures of the listener are retried like those of the server, see
+// `ServerConfig::accept_backoff`.
+pub async fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    config: Arc<ServerConfig>,
+) -> crate::Result<()> {
+    loop {
+        let socket = accept(&listener, &config).await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = scrape(socket, &metrics).await {
+                tracing::debug!(%err, "metrics scrape failed");
+            }
+        });
+    }
+}
+
+async fn accept(listener: &TcpListener, config: &ServerConfig) -> io::Result<TcpStream> {
+    let mut backoff = Backoff::new(config);
+    loop {
+        let err = match listener.accept().await {
+            Ok((socket, _)) => return Ok(socket),
+            Err(err) if is_connection_error(&err) => continue,
+            Err(err) => err,
+        };
+        match backoff.next_delay() {
+            Some(delay) => {
+                tracing::warn!(%err, ?delay, "failed to accept metrics scrape, retrying");
+                time::sleep(delay).await;
+            }
+            None => return Err(err),
+        }
+    }
+}
+
+async fn scrape(mut socket: TcpStream, metrics: &Metrics) -> crate::Result<()> {
+    // Read the request head, its contents do not matter.
+    let mut request = Vec::new();
+    let mut chunk = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") && request.len() < 8192 {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&chunk[..n]);
+    }
+
+    let body = metrics.render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+#[test]
+fn test_render() {
+    let metrics = Arc::new(Metrics::new());
+    let active = metrics.connection_accepted();
+    metrics.frame_read("Addition");
+    metrics.frame_read("Addition");
+    metrics.frame_read("Ping");
+    metrics.protocol_error();
+    metrics.request_handled(Duration::from_micros(300));
+    metrics.request_handled(Duration::from_secs(10));
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("calculator_connections_accepted_total 1\n"));
+    assert!(rendered.contains("calculator_connections_active 1\n"));
+    assert!(rendered.contains("calculator_frames_total{type=\"Addition\"} 2\n"));
+    assert!(rendered.contains("calculator_frames_total{type=\"Ping\"} 1\n"));
+    assert!(rendered.contains("calculator_protocol_errors_total 1\n"));
+    assert!(rendered.contains("calculator_request_duration_seconds_bucket{le=\"0.0001\"} 0\n"));
+    assert!(rendered.contains("calculator_request_duration_seconds_bucket{le=\"0.0005\"} 1\n"));
+    assert!(rendered.contains("calculator_request_duration_seconds_bucket{le=\"5\"} 1\n"));
+    assert!(rendered.contains("calculator_request_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+    assert!(rendered.contains("calculator_request_duration_seconds_count 2\n"));
+
+    drop(active);
+    assert_eq!(0, metrics.connections_active());
+    assert_eq!(1, metrics.connections_accepted());
+}
+
+#[tokio::test]
+async fn test_serve_scrape() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let metrics = Arc::new(Metrics::new());
+    metrics.frame_read("Addition");
+    tokio::spawn(serve(listener, metrics, Arc::default()));
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("\r\n\r\n# HELP"));
+    assert!(response.contains("calculator_frames_total{type=\"Addition\"} 1\n"));
+}
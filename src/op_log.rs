@@ -0,0 +1,116 @@
+use std::{
+    fmt,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{self, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+
+use crate::{frame::Aggregate, Frame};
+
+// Appends every completed operation to a file, one line per operation:
+//
+//   {unix millis} peer={addr} op={name} operands={operands} result={result}
+//
+// Lines are handed to a background task that owns the file, so a slow
+// disk never blocks a handler. The task flushes whenever it has no more
+// pending lines.
+#[derive(Clone, Debug)]
+pub struct OpLog {
+    lines: mpsc::UnboundedSender<String>,
+}
+
+impl OpLog {
+    // Open `path` for appending, creating it if needed, and start the
+    // writer task. The task ends once every `OpLog` clone is dropped.
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<OpLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        let (lines, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_lines(file, rx));
+
+        Ok(OpLog { lines })
+    }
+
+    // Record `request` answered with `response`. Only arithmetic requests
+    // that produced a result are logged.
+    pub fn record(&self, peer: &impl fmt::Display, request: &Frame, response: &Frame) {
+        let result = match response {
+            Frame::OpResult(result) => result,
+            _ => return,
+        };
+        let (op, operands) = match request {
+            Frame::Addition(x, y) => ("add", format!("{},{}", x, y)),
+            Frame::Subtraction(x, y) => ("sub", format!("{},{}", x, y)),
+            Frame::Multiplication(x, y) => ("mul", format!("{},{}", x, y)),
+            Frame::Modulo(x, y) => ("mod", format!("{},{}", x, y)),
+            Frame::Division(x, y) => ("div", format!("{},{}", x, y)),
+            Frame::Pow(x, y) => ("pow", format!("{},{}", x, y)),
+            Frame::Factorial(n) => ("fact", n.to_string()),
+            Frame::Gcd(x, y) => ("gcd", format!("{},{}", x, y)),
+            Frame::Lcm(x, y) => ("lcm", format!("{},{}", x, y)),
+            Frame::Sum(operands) => ("add", join(operands)),
+            Frame::Product(operands) => ("mul", join(operands)),
+            Frame::Aggregate(Aggregate::Min, operands) => ("min", join(operands)),
+            Frame::Aggregate(Aggregate::Max, operands) => ("max", join(operands)),
+            Frame::Aggregate(Aggregate::Mean, operands) => ("mean", join(operands)),
+            Frame::Expr(expr) => ("expr", expr.replace(' ', "")),
+            Frame::Rpn(tokens) => (
+                "rpn",
+                tokens
+                    .iter()
+                    .map(|token| token.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            _ => return,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        let line = format!(
+            "{} peer={} op={} operands={} result={}\n",
+            timestamp, peer, op, operands, result
+        );
+        // The writer task only stops when the file failed, the operation
+        // itself still succeeded so the line is dropped.
+        let _ = self.lines.send(line);
+    }
+}
+
+fn join(operands: &[u64]) -> String {
+    operands
+        .iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+async fn write_lines(file: File, mut lines: mpsc::UnboundedReceiver<String>) {
+    let mut file = BufWriter::new(file);
+
+    while let Some(line) = lines.recv().await {
+        let mut written = file.write_all(line.as_bytes()).await;
+        while written.is_ok() {
+            match lines.try_recv() {
+                Ok(line) => written = file.write_all(line.as_bytes()).await,
+                Err(_) => break,
+            }
+        }
+
+        if let Err(err) = written.and(file.flush().await) {
+            tracing::error!(%err, "failed writing the operation log");
+            return;
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tracing::Instrument;
+
+use crate::{
+    connection::{FrameReader, FrameWriter},
+    Connection, Frame,
+};
+
+// Which way a relayed frame travelled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    ToUpstream,
+    ToClient,
+}
+
+// Relays frames between clients and an upstream server.
+//
+// Every accepted client gets its own connection to the upstream server.
+// Frames are decoded and re-encoded in both directions, and every frame
+// is logged. When either side closes, the close is passed on to the
+// other side.
+#[derive(Debug)]
+pub struct Proxy {
+    listener: TcpListener,
+    upstream: SocketAddr,
+
+    // Receives a copy of every relayed frame, if set.
+    frame_log: Option<mpsc::UnboundedSender<(Direction, Frame)>>,
+}
+
+impl Proxy {
+    pub fn new(listener: TcpListener, upstream: SocketAddr) -> Proxy {
+        Proxy {
+            listener,
+            upstream,
+            frame_log: None,
+        }
+    }
+
+    // Send every relayed frame to `frame_log` in addition to printing it.
+    pub fn with_frame_log(mut self, frame_log: mpsc::UnboundedSender<(Direction, Frame)>) -> Proxy {
+        self.frame_log = Some(frame_log);
+        self
+    }
+
+    pub async fn run(self) -> crate::Result<()> {
+        loop {
+            let (client, peer) = self.listener.accept().await?;
+            let upstream = self.upstream;
+            let frame_log = self.frame_log.clone();
+
+            let span = tracing::info_span!("proxy", %peer);
+            tokio::spawn(
+                async move {
+                    if let Err(err) = proxy_connection(client, upstream, frame_log).await {
+                        tracing::warn!(%err, "proxy connection error");
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+}
+
+async fn proxy_connection(
+    client: TcpStream,
+    upstream: SocketAddr,
+    frame_log: Option<mpsc::UnboundedSender<(Direction, Frame)>>,
+) -> crate::Result<()> {
+    let upstream = TcpStream::connect(upstream).await?;
+
+    let (client_read, client_write) = Connection::new(client).into_split();
+    let (upstream_read, upstream_write) = Connection::new(upstream).into_split();
+
+    // Each direction ends when its source closes. Dropping the write half
+    // then closes the destination, which in turn ends the other direction.
+    let to_upstream = relay(
+        client_read,
+        upstream_write,
+        Direction::ToUpstream,
+        frame_log.clone(),
+    );
+    let to_client = relay(upstream_read, client_write, Direction::ToClient, frame_log);
+
+    let (to_upstream, to_client) = tokio::join!(to_upstream, to_client);
+    to_upstream.and(to_client)
+}
+
+async fn relay(
+    mut src: FrameReader,
+    mut dst: FrameWriter,
+    direction: Direction,
+    frame_log: Option<mpsc::UnboundedSender<(Direction, Frame)>>,
+) -> crate::Result<()> {
+    while let Some(frame) = src.read_frame().await? {
+        tracing::debug!(?direction, %frame, "relayed frame");
+        if let Some(frame_log) = &frame_log {
+            let _ = frame_log.send((direction, frame.clone()));
+        }
+        dst.write_frame(&frame).await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_round_trip() {
+    let server = crate::server::Server::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let (log_tx, mut log_rx) = mpsc::unbounded_channel();
+    tokio::spawn(Proxy::new(proxy, server_addr).with_frame_log(log_tx).run());
+
+    let mut client = crate::Client::connect(proxy_addr).await.unwrap();
+    assert!(matches!(client.addition().await, Ok(Frame::OpResult(42))));
+
+    // The handshake of `Client::connect` is relayed like any frame.
+    assert!(matches!(
+        log_rx.recv().await,
+        Some((Direction::ToUpstream, Frame::Hello(_)))
+    ));
+    assert!(matches!(
+        log_rx.recv().await,
+        Some((Direction::ToClient, Frame::Hello(_)))
+    ));
+
+    assert!(matches!(
+        log_rx.recv().await,
+        Some((Direction::ToUpstream, Frame::Addition(10, 32)))
+    ));
+    assert!(matches!(
+        log_rx.recv().await,
+        Some((Direction::ToClient, Frame::OpResult(42)))
+    ));
+
+    // A client closing its write side is passed on to the server, which
+    // closes the connection, and that close is passed back to the client.
+    let socket = TcpStream::connect(proxy_addr).await.unwrap();
+    let (mut read_half, write_half) = Connection::new(socket).into_split();
+    drop(write_half);
+    assert!(read_half.read_frame().await.unwrap().is_none());
+}
@@ -0,0 +1,167 @@
+use std::{
+    num::NonZeroU32,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use tokio::{
+    sync::Semaphore,
+    time::{self, Instant},
+};
+
+// Limits the number of requests served per second across all connections.
+//
+// Tokens are permits of a semaphore that a background task refills at a
+// steady pace, up to one second worth of burst. The semaphore hands out
+// permits in the order they were requested, and a handler waits for at
+// most one token at a time, so waiting connections are served round
+// robin. A connection sending many requests can not starve a connection
+// sending few, each waits behind at most one request of every other
+// connection.
+#[derive(Debug)]
+pub struct RateLimiter {
+    tokens: Arc<Semaphore>,
+}
+
+impl RateLimiter {
+    // Allow `per_second` requests per second. Must be called from within
+    // a runtime, the refill task stops when the limiter is dropped.
+    pub fn new(per_second: NonZeroU32) -> RateLimiter {
+        let burst = per_second.get() as usize;
+        let tokens = Arc::new(Semaphore::new(burst));
+        // Rounds to zero above a billion per second, an interval needs a
+        // period.
+        let period = (Duration::from_secs(1) / per_second.get()).max(Duration::from_nanos(1));
+        tokio::spawn(refill(Arc::downgrade(&tokens), burst, period));
+
+        RateLimiter { tokens }
+    }
+
+    // Wait for a token, it is consumed.
+    pub async fn acquire(&self) {
+        // The semaphore is never closed.
+        self.tokens.acquire().await.unwrap().forget();
+    }
+
+    // Consume a token if one is available, without waiting.
+    pub fn try_acquire(&self) -> bool {
+        match self.tokens.try_acquire() {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+async fn refill(tokens: Weak<Semaphore>, burst: usize, period: Duration) {
+    let mut interval = time::interval(period);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    // The first tick completes immediately, the bucket starts full.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let tokens = match tokens.upgrade() {
+            Some(tokens) => tokens,
+            None => return,
+        };
+        if tokens.available_permits() < burst {
+            tokens.add_permits(1);
+        }
+    }
+}
+
+// Limits the requests of a single connection, see
+// `ServerConfig::connection_rate_limit`.
+//
+// Unlike `RateLimiter` there is no task refilling the bucket, the tokens
+// for the time that passed are added whenever one is taken. Up to one
+// second worth of tokens are kept.
+#[derive(Debug)]
+pub struct TokenBucket {
+    per_second: NonZeroU32,
+    tokens: u32,
+    // When the tokens were last topped up, a partial token is carried
+    // over by not moving it all the way to now.
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    // Allow `per_second` requests per second, the bucket starts full.
+    pub fn new(per_second: NonZeroU32) -> TokenBucket {
+        TokenBucket {
+            per_second,
+            tokens: per_second.get(),
+            refilled: Instant::now(),
+        }
+    }
+
+    // Wait for a token, it is consumed.
+    pub async fn acquire(&mut self) {
+        while !self.try_acquire() {
+            time::sleep_until(self.refilled + self.period()).await;
+        }
+    }
+
+    // Consume a token if one is available, without waiting.
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    fn refill(&mut self) {
+        let burst = self.per_second.get();
+        let period = self.period();
+        let added = self.refilled.elapsed().as_nanos() / period.as_nanos();
+        if self.tokens as u128 + added >= burst as u128 {
+            self.tokens = burst;
+            self.refilled = Instant::now();
+        } else {
+            // Less than `burst`, it fits.
+            self.tokens += added as u32;
+            self.refilled += period * added as u32;
+        }
+    }
+
+    // Time it takes to add one token, rates beyond a token per nanosecond
+    // are rounded down to it.
+    fn period(&self) -> Duration {
+        (Duration::from_secs(1) / self.per_second.get()).max(Duration::from_nanos(1))
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_token_bucket() {
+    let mut bucket = TokenBucket::new(NonZeroU32::new(4).unwrap());
+    for _ in 0..4 {
+        assert!(bucket.try_acquire());
+    }
+    assert!(!bucket.try_acquire());
+
+    // A token is added every 250ms, partial tokens are not lost.
+    time::advance(Duration::from_millis(200)).await;
+    assert!(!bucket.try_acquire());
+    time::advance(Duration::from_millis(100)).await;
+    assert!(bucket.try_acquire());
+    assert!(!bucket.try_acquire());
+
+    let start = Instant::now();
+    bucket.acquire().await;
+    assert_eq!(Duration::from_millis(200), start.elapsed());
+
+    // No more than one second worth of tokens are kept.
+    time::advance(Duration::from_secs(10)).await;
+    for _ in 0..4 {
+        assert!(bucket.try_acquire());
+    }
+    assert!(!bucket.try_acquire());
+
+    let mut fast = TokenBucket::new(NonZeroU32::MAX);
+    assert!(fast.try_acquire());
+}
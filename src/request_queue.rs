@@ -0,0 +1,89 @@
+use std::{cmp::Ordering, collections::BinaryHeap, sync::Mutex};
+
+use tokio::sync::Notify;
+
+use crate::Frame;
+
+// Requests of a connection waiting for a worker.
+//
+// `pop` returns the queued request with the highest priority, requests
+// of the same priority are returned in the order they were pushed. Only
+// `Frame::Tagged` frames carry a priority, other frames have priority 0.
+#[derive(Debug, Default)]
+pub struct RequestQueue {
+    inner: Mutex<Inner>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    heap: BinaryHeap<Queued>,
+
+    // Order of the next pushed request.
+    next_seq: u64,
+}
+
+#[derive(Debug)]
+struct Queued {
+    priority: u8,
+    seq: u64,
+    frame: Frame,
+}
+
+impl RequestQueue {
+    pub fn new() -> RequestQueue {
+        RequestQueue::default()
+    }
+
+    pub fn push(&self, frame: Frame) {
+        let priority = match &frame {
+            Frame::Tagged(tag, _) => tag.priority,
+            _ => 0,
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.heap.push(Queued {
+            priority,
+            seq,
+            frame,
+        });
+        drop(inner);
+
+        self.notify.notify_one();
+    }
+
+    // Wait for a request.
+    pub async fn pop(&self) -> Frame {
+        loop {
+            if let Some(queued) = self.inner.lock().unwrap().heap.pop() {
+                return queued.frame;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+// Higher priority first, then the request pushed first.
+impl Ord for Queued {
+    fn cmp(&self, other: &Queued) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Queued) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Queued) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for Queued {}
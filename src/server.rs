@@ -1,115 +1,5320 @@
-use std::{result, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    num::NonZeroU32,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls;
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use tracing::{field, Instrument};
 
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
-    sync::Semaphore,
-    time,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, watch, OwnedSemaphorePermit, Semaphore,
+    },
+    task::JoinSet,
+    time::{self, Instant},
+};
+
+use crate::{
+    audit::{AuditLog, Outcome},
+    connection::{ConnectionConfig, Encoding, DEFAULT_MAX_FRAME_LEN},
+    expr,
+    frame::{self, ErrorCode, Operand, Operator, Token, MAX_NESTING_DEPTH, PROTOCOL_VERSION},
+    metrics::{self, ActiveConnection, Metrics},
+    op_log::OpLog,
+    rate_limit::{RateLimiter, TokenBucket},
+    request_queue::RequestQueue,
+    CircuitBreaker, Connection, Frame,
 };
 
-use crate::Connection;
+// Version reported in response to `Frame::Version`.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Backlog used by `TcpListener::bind`.
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+// How long a connection rejected by `ServerConfig::reject_when_busy` is
+// given to take the busy error frame.
+const BUSY_REJECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The shortest delay before retrying a failed accept, see
+// `ServerConfig::accept_backoff`.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(1);
+
+// How long a client is given to complete the TLS handshake when no
+// `ServerConfig::idle_timeout` is set.
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Options for running a `Server`.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    // Answer invalid frames and failed operations with an error frame and
+    // keep serving the connection. When unset the connection is closed.
+    // Arithmetic failures, e.g. an overflow, are always answered.
+    pub recover_on_protocol_error: bool,
+
+    // Close connections that have been open for longer than this. A request
+    // that is being handled when the deadline passes is still answered.
+    pub max_session_duration: Option<Duration>,
+
+    // Close connections that send no frame for this long.
+    pub idle_timeout: Option<Duration>,
+
+    // Close connections whose request is not computed within this long,
+    // e.g. a stuck computation. The client is sent an
+    // `ErrorCode::RequestTimeout` error frame. A tagged request of a
+    // worker is answered with that error instead, the connection stays
+    // open. Writing the responses is bound by `write_timeout`.
+    pub request_timeout: Option<Duration>,
+
+    // Reject requests with an operand larger than this.
+    pub max_operand: Option<u64>,
+
+    // Share the named registers, see `Frame::Set`, between all connections
+    // instead of giving every connection its own. The accumulator still
+    // belongs to a single connection. Snapshots are rejected, restoring
+    // one would replace the registers of every connection.
+    pub shared_registers: bool,
+
+    // Registers a connection may set, or all connections together with
+    // `shared_registers`. A connection may save as many snapshots.
+    pub max_registers: usize,
+
+    // Longest name of a register or snapshot, in bytes.
+    pub max_register_name_len: usize,
+
+    // Number of pending connections the OS queues for `bind`.
+    pub listen_backlog: u32,
+
+    // Connections served at the same time. A connection takes its place
+    // once its first frame is read, it waits for one while this many are
+    // served, unless `reject_when_busy` is set. Only served connections
+    // are counted as accepted in the metrics.
+    pub max_connections: usize,
+
+    // Failing to accept a connection is retried after this, doubling with
+    // every further failure, see `Backoff`. Once the delay would exceed
+    // `max_accept_backoff` the server stops with the error. A zero backoff
+    // is raised to `MIN_ACCEPT_BACKOFF`.
+    pub accept_backoff: Duration,
+    pub max_accept_backoff: Duration,
+
+    // Answer the first frame of connections beyond `max_connections`
+    // with an `ErrorCode::ServerBusy` error frame and close them, so
+    // clients learn right away that they should try again later.
+    pub reject_when_busy: bool,
+
+    // Connections that did not send their first frame yet, the
+    // handshake, including those waiting for a place among
+    // `max_connections`. Further connections are closed right away, so
+    // peers that stall during the handshake can not crowd out established
+    // connections.
+    pub max_handshakes: usize,
+
+    // Reject requests whose response would be an array with more
+    // elements than this.
+    pub max_response_array_len: usize,
+
+    // Append every completed operation to this file, see `OpLog`.
+    pub op_log_path: Option<PathBuf>,
+
+    // Requests per second shared by all connections, see `RateLimiter`.
+    pub global_rate_limit: Option<NonZeroU32>,
+
+    // Requests per second of every single connection, so one chatty
+    // client can not use up the global limit. What happens to requests
+    // over the limit is set by `rate_limit_exceeded`. The handshake,
+    // pings and the header of a streamed array are not limited.
+    pub connection_rate_limit: Option<NonZeroU32>,
+    pub rate_limit_exceeded: RateLimitExceeded,
+
+    // Log a warning when a connection has not read or written a frame for
+    // this long. Diagnostic only, the connection is left open.
+    pub watchdog_interval: Option<Duration>,
+
+    // Handle the tagged requests of a connection on this many concurrent
+    // workers, queued requests with a higher priority first. With 0
+    // workers every request is handled in the order it arrives.
+    pub request_workers: usize,
+
+    // Tagged requests of a connection that are read before their
+    // responses are written. Once this many are in flight the handler
+    // stops reading until a worker answers one, so a client that sends
+    // faster than the workers answer is slowed down instead of queueing
+    // requests without bound. Zero is taken as one, the handler would
+    // never read a request otherwise.
+    pub max_in_flight: usize,
+
+    // Compute requests whose estimated cost is at least this on the
+    // blocking thread pool, see `cost`, so a large factorial or bignum
+    // product does not stall the I/O of other connections. When unset
+    // every request is computed on the task of its connection.
+    pub blocking_cost_threshold: Option<u64>,
 
-const MAX_CONNECTIONS: usize = 250;
+    // Stop offloading requests to the blocking thread pool for
+    // `offload_cooldown` once this many offloaded requests failed in a
+    // row, see `CircuitBreaker`. An offloaded request fails when it panics
+    // or is not answered within `offload_timeout`, e.g. because the pool is
+    // busy with other requests. While the breaker is open costly requests
+    // are answered right away with `ErrorCode::Unavailable`.
+    pub offload_failure_threshold: Option<u32>,
+    pub offload_cooldown: Duration,
+    pub offload_timeout: Option<Duration>,
+
+    // How to handle a frame with an unknown type byte. When unset it is
+    // handled like any other invalid frame, see `recover_on_protocol_error`.
+    pub unknown_frame: Option<UnknownFrame>,
+
+    // Wire format of every connection, clients have to use the same.
+    pub encoding: Encoding,
+
+    // Close connections that do not start with a `Frame::Hello`.
+    pub require_handshake: bool,
+
+    // Oldest protocol version a client may negotiate with `Frame::Hello`.
+    pub min_protocol_version: u32,
+
+    // Close connections that send a frame longer than this, see
+    // `Connection::set_max_frame_len`.
+    pub max_frame_len: usize,
+
+    // Follow every frame with a checksum, see `Connection::set_checksum`.
+    // Clients have to enable it as well.
+    pub checksum: bool,
+
+    // Close connections that do not accept a response within this long,
+    // see `ConnectionConfig::write_timeout`.
+    pub write_timeout: Option<Duration>,
+
+    // Initial and largest kept capacity of the read buffer of every
+    // connection, see `ConnectionConfig::read_buffer_capacity` and
+    // `ConnectionConfig::max_read_buffer_capacity`.
+    pub read_buffer_capacity: usize,
+    pub max_read_buffer_capacity: usize,
+
+    // How long `Server::run_until` waits for connections to finish their
+    // requests in flight once shutdown starts. Connections that are still
+    // open afterwards are aborted.
+    pub drain_timeout: Duration,
+
+    // Answer Prometheus scrapes of `ServerHandle::metrics` on this
+    // address, see `metrics::serve`. `Server::run` returns right away if
+    // it can not be bound, like without the operation log.
+    pub metrics_addr: Option<SocketAddr>,
+
+    // Token that admin frames, `Frame::Stats` and `Frame::Shutdown`, have
+    // to carry. Without one every admin frame is rejected.
+    pub admin_token: Option<String>,
+}
+
+// Handling of requests over `ServerConfig::connection_rate_limit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimitExceeded {
+    // Wait until the connection is within its limit again before reading
+    // further requests.
+    Delay,
+
+    // Answer with an `ErrorCode::SlowDown` error frame instead, the
+    // request is not handled.
+    SlowDown,
+}
+
+// Handling of frames with an unknown type byte.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnknownFrame {
+    // Close the connection.
+    Reject,
+
+    // Drop the frame and continue with the next one.
+    Skip,
+
+    // Answer with an error frame and continue with the next frame.
+    ErrorFrame,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        let connection = ConnectionConfig::default();
+        ServerConfig {
+            recover_on_protocol_error: false,
+            max_session_duration: None,
+            idle_timeout: None,
+            request_timeout: None,
+            max_operand: None,
+            shared_registers: false,
+            max_registers: 1024,
+            max_register_name_len: 64,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            max_connections: 250,
+            reject_when_busy: false,
+            accept_backoff: Duration::from_secs(1),
+            max_accept_backoff: Duration::from_secs(64),
+            max_handshakes: 64,
+            max_response_array_len: 1024,
+            op_log_path: None,
+            global_rate_limit: None,
+            connection_rate_limit: None,
+            rate_limit_exceeded: RateLimitExceeded::Delay,
+            watchdog_interval: None,
+            request_workers: 0,
+            max_in_flight: 64,
+            blocking_cost_threshold: None,
+            offload_failure_threshold: None,
+            offload_cooldown: Duration::from_secs(10),
+            offload_timeout: None,
+            unknown_frame: None,
+            encoding: Encoding::Text,
+            require_handshake: false,
+            min_protocol_version: 1,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            checksum: false,
+            write_timeout: None,
+            read_buffer_capacity: connection.read_buffer_capacity,
+            max_read_buffer_capacity: connection.max_read_buffer_capacity,
+            drain_timeout: Duration::from_secs(30),
+            metrics_addr: None,
+            admin_token: None,
+        }
+    }
+}
+
+// Bind a listener to `addr` with the configured listen backlog.
+pub fn bind(addr: SocketAddr, config: &ServerConfig) -> io::Result<TcpListener> {
+    bind_socket(addr, config, false)
+}
+
+// Like `bind`. With `only_v6` an IPv6 listener does not accept IPv4
+// connections as well, so an IPv4 listener can bind the same port.
+fn bind_socket(addr: SocketAddr, config: &ServerConfig, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if only_v6 {
+        socket.set_only_v6(true)?;
+    }
+
+    // Same as `TcpListener::bind`, allow rebinding while old connections
+    // are in TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    let backlog = config.listen_backlog.min(i32::MAX as u32) as i32;
+    socket.listen(backlog)?;
+    TcpListener::from_std(socket.into())
+}
 
-// TODO: Add graceful shutdown logic
 // Per connection handler
 #[derive(Debug)]
-struct Handler {
-    connection: Connection,
+struct Handler<T = TcpStream> {
+    connection: Connection<T>,
+
+    config: Arc<ServerConfig>,
+
+    // Elements still expected of a streamed array, started with
+    // `Frame::ArrayStart`.
+    array_remaining: u64,
+
+    // Name the client gave itself with `Frame::Identify`, used in logs.
+    client_name: Option<String>,
+
+    peer: Peer,
+
+    // Of the connection, the client name is recorded on it once known.
+    span: tracing::Span,
+
+    op_log: Option<OpLog>,
+
+    audit: Option<AuditLog>,
+
+    rate_limiter: Option<Arc<RateLimiter>>,
+
+    // Shared by every connection, see `ServerConfig::offload_failure_threshold`.
+    offload_breaker: Option<Arc<CircuitBreaker>>,
+
+    // Limits this connection alone, see `ServerConfig::connection_rate_limit`.
+    connection_rate_limiter: Option<TokenBucket>,
+
+    // Last progress of the handler, checked by `watch_activity`.
+    activity: Arc<Mutex<Activity>>,
+
+    // Started by `run` when `ServerConfig::request_workers` is set.
+    workers: Option<Workers>,
+
+    registers: Registers,
+
+    // Protocol version agreed on with `Frame::Hello`. Without a handshake
+    // every frame is accepted.
+    version: Option<u32>,
+
+    // Of the server the connection belongs to, e.g. for its metrics.
+    handle: ServerHandle,
+
+    // Held until the first frame is read, see `ServerConfig::max_handshakes`.
+    handshake: Option<OwnedSemaphorePermit>,
+
+    // Place among the served connections, taken from `limit_connections`
+    // by `admit`, see `ServerConfig::max_connections`.
+    limit_connections: Arc<Semaphore>,
+    admitted: Option<(OwnedSemaphorePermit, ActiveConnection)>,
+
+    // Notified when the server shuts down, see `Server::run_until`.
+    shutdown: broadcast::Receiver<()>,
+}
+
+// Named registers of a connection, with snapshots that can be restored.
+// The values are shared by every connection with
+// `ServerConfig::shared_registers`, there are no snapshots then.
+#[derive(Debug)]
+struct Registers {
+    values: Arc<Mutex<HashMap<String, u64>>>,
+    shared: bool,
+    snapshots: HashMap<String, HashMap<String, u64>>,
+
+    // See `ServerConfig::max_registers` and `max_register_name_len`.
+    max_registers: usize,
+    max_name_len: usize,
+
+    // See `Frame::Accumulate`, not part of the snapshots.
+    accumulator: u64,
 }
 
-impl Handler {
-    async fn run(&mut self) -> crate::Result<()> {
-        let rframe = self.connection.read_frame().await;
+impl Registers {
+    fn new(config: &ServerConfig) -> Registers {
+        Registers {
+            values: Arc::default(),
+            shared: false,
+            snapshots: HashMap::new(),
+            max_registers: config.max_registers,
+            max_name_len: config.max_register_name_len,
+            accumulator: 0,
+        }
+    }
+
+    // Registers whose values are shared with other connections.
+    fn shared(values: Arc<Mutex<HashMap<String, u64>>>, config: &ServerConfig) -> Registers {
+        Registers {
+            values,
+            shared: true,
+            ..Registers::new(config)
+        }
+    }
 
-        let oframe = match rframe {
-            Ok(opt_frame) => opt_frame,
-            Err(e) => {
-                println!("Failed reading the frame error {}", e);
-                None
+    // Answer a register frame, `None` for any other frame.
+    fn apply(
+        &mut self,
+        frame: &Frame,
+        config: &ServerConfig,
+    ) -> Option<Result<Frame, ComputeError>> {
+        // Bound by the same limits as any other request.
+        if is_register(frame) {
+            if let Err(err) = check_limits(frame, config) {
+                return Some(Err(err));
             }
-        };
-        match oframe {
-            Some(frame) => {
-                let result = self.handle_frame(frame).await;
-                result.or_else(|e| Err(e))
+        }
+
+        let response = match frame {
+            Frame::Set(name, value) => self.set(name, *value),
+            Frame::Get(name) => self.get(name).map(Frame::OpResult),
+            Frame::Named(op, x, y) => {
+                let value = |operand: &Operand| match operand {
+                    Operand::Number(n) => Ok(*n),
+                    Operand::Name(name) => self.get(name),
+                };
+                value(x)
+                    .and_then(|x| apply_operator(*op, x, value(y)?))
+                    .map(Frame::OpResult)
+            }
+            Frame::Save(_) | Frame::Restore(_) if self.shared => Err(ComputeError::SharedRegisters),
+            Frame::Save(slot) => self.save(slot).map(|()| frame.clone()),
+            Frame::Restore(slot) => match self.snapshots.get(slot) {
+                Some(snapshot) => {
+                    *self.values.lock().unwrap() = snapshot.clone();
+                    Ok(frame.clone())
+                }
+                None => Err(ComputeError::UnknownName),
+            },
+            Frame::Accumulate(op, operand) => {
+                let value = apply_operator(*op, self.accumulator, *operand);
+                if let Ok(value) = value {
+                    self.accumulator = value;
+                }
+                value.map(Frame::OpResult)
             }
-            None => Ok(()),
+            Frame::Result => Ok(Frame::OpResult(self.accumulator)),
+            // Like any tagged request, the response carries the tag.
+            Frame::Tagged(tag, frame) => {
+                let response = self
+                    .apply(frame, config)?
+                    .unwrap_or_else(|err| Frame::Error(err.code(), err.to_string()));
+                Ok(Frame::Tagged(*tag, Box::new(response)))
+            }
+            _ => return None,
+        };
+        Some(response)
+    }
+
+    fn get(&self, name: &str) -> Result<u64, ComputeError> {
+        let values = self.values.lock().unwrap();
+        values.get(name).copied().ok_or(ComputeError::UnknownName)
+    }
+
+    fn set(&self, name: &str, value: u64) -> Result<Frame, ComputeError> {
+        self.check_name(name)?;
+        let mut values = self.values.lock().unwrap();
+        if values.len() >= self.max_registers && !values.contains_key(name) {
+            return Err(ComputeError::TooManyRegisters);
         }
+        values.insert(name.to_string(), value);
+        Ok(Frame::OpResult(value))
     }
 
-    async fn handle_frame(&mut self, frame: crate::Frame) -> Result<(), crate::Error> {
-        let op_result = match frame {
-            crate::Frame::Addition(x, y) => x + y,
-            crate::Frame::Subtraction(x, y) => x - y,
-            crate::Frame::Multiplication(x, y) => x * y,
-            crate::Frame::OpResult(r) => r,
-        };
-        let response = crate::Frame::OpResult(op_result);
-        println!("Respone: {:#?}", &response);
-        self.connection.write_frame(&response).await
+    fn save(&mut self, slot: &str) -> Result<(), ComputeError> {
+        self.check_name(slot)?;
+        if self.snapshots.len() >= self.max_registers && !self.snapshots.contains_key(slot) {
+            return Err(ComputeError::TooManyRegisters);
+        }
+        let values = self.values.lock().unwrap().clone();
+        self.snapshots.insert(slot.to_string(), values);
+        Ok(())
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), ComputeError> {
+        if !frame::is_valid_name(name) {
+            return Err(ComputeError::InvalidName);
+        }
+        if name.len() > self.max_name_len {
+            return Err(ComputeError::NameTooLong);
+        }
+        Ok(())
     }
 }
 
-pub async fn run(listener: TcpListener) {
-    let mut server = Listener {
-        listener,
-        limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
-    };
+// Compare in a time that does not depend on where the tokens differ, so
+// the token can not be guessed one byte at a time.
+fn token_matches(expected: &str, token: &str) -> bool {
+    let diff = expected
+        .bytes()
+        .zip(token.bytes())
+        .fold(0, |diff, (x, y)| diff | (x ^ y));
+    expected.len() == token.len() && diff == 0
+}
 
-    server.run().await;
+fn is_admin(frame: &Frame) -> bool {
+    matches!(frame, Frame::Stats(_) | Frame::Shutdown(_))
+}
+
+fn is_register(frame: &Frame) -> bool {
+    matches!(
+        frame,
+        Frame::Set(..)
+            | Frame::Get(_)
+            | Frame::Save(_)
+            | Frame::Restore(_)
+            | Frame::Accumulate(..)
+            | Frame::Result
+            | Frame::Named(..)
+    )
 }
 
+// Workers handling the tagged requests of a connection.
 #[derive(Debug)]
-struct Listener {
-    listener: TcpListener,
-    limit_connections: Arc<Semaphore>,
+struct Workers {
+    queue: Arc<RequestQueue>,
+
+    // Responses of the workers, written by the handler.
+    responses: mpsc::UnboundedReceiver<WorkerResponse>,
+
+    // Requests queued whose response was not written yet.
+    in_flight: usize,
+
+    // The workers are aborted when the handler is dropped.
+    _tasks: JoinSet<()>,
 }
 
-impl Listener {
-    // TODO: add logging library
-    async fn run(&mut self) -> crate::Result<()> {
-        println!("Incoming connection!");
+// A response of a worker, with the request it answers for the audit log.
+#[derive(Debug)]
+struct WorkerResponse {
+    request: Frame,
+    response: Frame,
+    latency: Duration,
+}
 
-        loop {
-            let permit = self
-                .limit_connections
-                .clone()
-                .acquire_owned()
-                .await
-                .unwrap();
+impl Workers {
+    fn spawn(
+        count: usize,
+        config: Arc<ServerConfig>,
+        breaker: Option<Arc<CircuitBreaker>>,
+    ) -> Workers {
+        let queue = Arc::new(RequestQueue::new());
+        let (tx, responses) = mpsc::unbounded_channel();
 
-            let socket = self.accept().await?;
+        let mut tasks = JoinSet::new();
+        for _ in 0..count {
+            tasks.spawn(work(
+                queue.clone(),
+                config.clone(),
+                breaker.clone(),
+                tx.clone(),
+            ));
+        }
 
-            let mut handler = Handler {
-                connection: Connection::new(socket),
-            };
+        Workers {
+            queue,
+            responses,
+            in_flight: 0,
+            _tasks: tasks,
+        }
+    }
+}
 
-            tokio::spawn(async move {
-                if let Err(error) = handler.run().await {
-                    eprintln!("Connection error {:#?}", error);
-                }
+async fn work(
+    queue: Arc<RequestQueue>,
+    config: Arc<ServerConfig>,
+    breaker: Option<Arc<CircuitBreaker>>,
+    responses: mpsc::UnboundedSender<WorkerResponse>,
+) {
+    loop {
+        let request = queue.pop().await;
+        let start = Instant::now();
+        let response = respond_offloaded(&request, &config, breaker.as_deref());
+        let response = match config.request_timeout {
+            Some(timeout) => time::timeout(timeout, response).await.ok(),
+            None => Some(response.await),
+        };
+        let response = match response {
+            Some(response) => {
+                response.unwrap_or_else(|err| Frame::Error(err.code(), err.to_string()))
+            }
+            None => timed_out(&request),
+        };
+        let response = WorkerResponse {
+            request,
+            response,
+            latency: start.elapsed(),
+        };
+        if responses.send(response).is_err() {
+            return;
+        }
+    }
+}
+
+// The response to a worker request that took longer than
+// `ServerConfig::request_timeout`, it carries the tag of the request.
+fn timed_out(request: &Frame) -> Frame {
+    let response = Frame::Error(ErrorCode::RequestTimeout, "request timed out".to_string());
+    match request {
+        Frame::Tagged(tag, _) => Frame::Tagged(*tag, Box::new(response)),
+        _ => response,
+    }
+}
 
-                drop(permit);
-            });
+// Completes with the next response of a worker, never completes without
+// workers. Fails when the workers stopped.
+async fn next_response(workers: &mut Option<Workers>) -> crate::Result<WorkerResponse> {
+    match workers {
+        Some(workers) => {
+            let response = workers
+                .responses
+                .recv()
+                .await
+                .ok_or_else(|| crate::Error::other("request workers stopped"))?;
+            workers.in_flight -= 1;
+            Ok(response)
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// The last frame a handler read or wrote.
+#[derive(Debug)]
+struct Activity {
+    at: Instant,
+    what: &'static str,
+}
+
+impl Activity {
+    fn new(what: &'static str) -> Activity {
+        Activity {
+            at: Instant::now(),
+            what,
+        }
+    }
+}
+
+impl<T> Handler<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // A handler of a connection accepted by no listener, for tests that
+    // drive a handler directly.
+    #[cfg(test)]
+    fn for_test(stream: T, config: ServerConfig) -> Handler<T> {
+        let offload_breaker = offload_breaker(&config);
+        let registers = Registers::new(&config);
+        Handler {
+            connection: Connection::new(stream),
+            config: Arc::new(config),
+            array_remaining: 0,
+            client_name: None,
+            peer: Peer::Tcp(([127, 0, 0, 1], 0).into()),
+            span: tracing::Span::none(),
+            op_log: None,
+            audit: None,
+            rate_limiter: None,
+            offload_breaker,
+            connection_rate_limiter: None,
+            activity: Arc::new(Mutex::new(Activity::new("accepted"))),
+            workers: None,
+            registers,
+            version: None,
+            handle: ServerHandle::new(),
+            handshake: None,
+            limit_connections: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            admitted: None,
+            shutdown: broadcast::channel(1).1,
         }
     }
 
-    async fn accept(&mut self) -> crate::Result<TcpStream> {
-        let mut backoff = 1;
+    // Process frames until the peer closes the connection, the session
+    // expires, the connection is idle for too long or the server shuts
+    // down.
+    async fn run(&mut self) -> crate::Result<()> {
+        let deadline = self
+            .config
+            .max_session_duration
+            .map(|duration| Instant::now() + duration);
+        let idle_timeout = self.config.idle_timeout;
+        let idle_deadline = || idle_timeout.map(|timeout| Instant::now() + timeout);
+        let mut idle = idle_deadline();
+
+        if self.config.request_workers > 0 && self.workers.is_none() {
+            self.workers = Some(Workers::spawn(
+                self.config.request_workers,
+                self.config.clone(),
+                self.offload_breaker.clone(),
+            ));
+        }
 
         loop {
-            match self.listener.accept().await {
-                Ok((socket, _)) => return Ok(socket),
-                Err(err) => {
-                    if backoff > 64 {
+            // Reading stops while the workers are saturated, responses
+            // still get written.
+            let saturated = matches!(
+                &self.workers,
+                Some(workers) if workers.in_flight >= self.config.max_in_flight.max(1)
+            );
+            let read = tokio::select! {
+                read = self.connection.read_frame(), if !saturated => read,
+                response = next_response(&mut self.workers) => {
+                    self.write_worker_response(response?).await?;
+                    continue;
+                }
+                _ = sleep_until_deadline(deadline) => {
+                    let notice = Frame::Error(
+                        ErrorCode::SessionExpired,
+                        "session expired, closing connection".to_string(),
+                    );
+                    return self.connection.write_frame(&notice).await;
+                }
+                _ = sleep_until_deadline(idle) => {
+                    // Not an error of the connection, logged as a regular
+                    // close.
+                    tracing::info!("closing idle connection");
+                    let notice = Frame::Error(
+                        ErrorCode::IdleTimeout,
+                        "connection idle, closing connection".to_string(),
+                    );
+                    return self.connection.write_frame(&notice).await;
+                }
+                _ = shutdown_notified(&mut self.shutdown) => {
+                    // Reading stopped between frames, the requests that
+                    // were read are still answered.
+                    self.finish_in_flight().await?;
+                    let notice = Frame::Error(
+                        ErrorCode::ShuttingDown,
+                        "server shutting down, closing connection".to_string(),
+                    );
+                    return self.connection.write_frame(&notice).await;
+                }
+            };
+            idle = idle_deadline();
+
+            let frame = match read {
+                Ok(Some(frame)) => {
+                    if self.handshake.is_some() && !self.admit().await {
+                        return Ok(());
+                    }
+                    self.record_activity("read a frame");
+                    self.handle.metrics.frame_read(frame.kind());
+                    frame
+                }
+                Ok(None) if self.array_remaining > 0 => return Err(crate::Error::ConnectionReset),
+                Ok(None) => return self.finish_in_flight().await,
+                // A frame that could not be decoded, as opposed to a failure
+                // of the underlying socket. The bytes of the frame were
+                // already dropped, reading continues with the next frame.
+                Err(crate::Error::Protocol(err)) => {
+                    tracing::warn!(%err, "failed to decode frame");
+                    self.handle.metrics.protocol_error();
+                    let unknown_type = matches!(err, frame::Error::InvalidTypeByte(_));
+                    let recover = match self.config.unknown_frame {
+                        // The rest of the frame is still unread.
+                        _ if matches!(err, frame::Error::FrameTooLarge(_)) => false,
+                        Some(UnknownFrame::Skip) if unknown_type => continue,
+                        Some(strategy) if unknown_type => strategy == UnknownFrame::ErrorFrame,
+                        _ => self.config.recover_on_protocol_error,
+                    };
+                    if !recover {
                         return Err(err.into());
                     }
+                    let response = Frame::Error(err.code(), err.to_string());
+                    self.connection.write_frame(&response).await?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            let start = Instant::now();
+            let handled = self.handle_request(&frame).await;
+            self.audit(&frame, &handled, start.elapsed());
+            match handled {
+                Handled::Replied(_) | Handled::Queued => {}
+                Handled::Closed(_, err) => return Err(err),
+                Handled::TimedOut => return self.close_timed_out().await,
+            }
+            self.record_activity("wrote a response");
+        }
+    }
+
+    // Take a place among the served connections once the first frame was
+    // read, ending the handshake. The handshake permit is held while
+    // waiting for a place. Returns `false` when the connection is to be
+    // closed instead, because it was rejected with
+    // `ServerConfig::reject_when_busy` or the server shuts down.
+    async fn admit(&mut self) -> bool {
+        let limit_connections = self.limit_connections.clone();
+        let permit = if self.config.reject_when_busy {
+            match limit_connections.try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    self.reject_busy().await;
+                    return false;
+                }
+            }
+        } else {
+            // The semaphore is never closed.
+            tokio::select! {
+                permit = limit_connections.acquire_owned() => permit.unwrap(),
+                _ = shutdown_notified(&mut self.shutdown) => return false,
+            }
+        };
+        self.admitted = Some((permit, self.handle.metrics.connection_accepted()));
+        self.handshake = None;
+        true
+    }
+
+    // Tell a client that sent its first frame while `max_connections` are
+    // served that the server is busy, then close the connection.
+    async fn reject_busy(&mut self) {
+        tracing::warn!("too many connections, rejecting connection");
+
+        let busy = Frame::Error(
+            ErrorCode::ServerBusy,
+            "server busy, try again later".to_string(),
+        );
+        // A client that does not read the frame in time only sees the
+        // close.
+        let rejected = async {
+            self.connection.write_frame(&busy).await?;
+            self.connection.shutdown().await
+        };
+        let _ = time::timeout(BUSY_REJECTION_TIMEOUT, rejected).await;
+    }
+
+    // Handle a request read from the peer, up to writing its reply.
+    async fn handle_request(&mut self, frame: &Frame) -> Handled {
+        // Checked before the global limit, a dropped request does not
+        // take a token from other connections.
+        let limited = !matches!(frame, Frame::Hello(_) | Frame::Ping | Frame::ArrayStart(_));
+        if let Some(rate_limiter) = self.connection_rate_limiter.as_mut().filter(|_| limited) {
+            match self.config.rate_limit_exceeded {
+                RateLimitExceeded::Delay => rate_limiter.acquire().await,
+                RateLimitExceeded::SlowDown if !rate_limiter.try_acquire() => {
+                    // The error answers the request, it still counts as
+                    // an element of a streamed array.
+                    self.array_remaining = self.array_remaining.saturating_sub(1);
+                    let response = Frame::Error(
+                        ErrorCode::SlowDown,
+                        "rate limit exceeded, slow down".to_string(),
+                    );
+                    return self.reply(Reply::Frame(response)).await;
                 }
+                RateLimitExceeded::SlowDown => {}
+            }
+        }
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+        // Counted before the response is written, so a client that saw
+        // the response also sees the count. The handshake is not a
+        // request.
+        if !matches!(frame, Frame::Hello(_)) {
+            self.handle.metrics.request_served();
+        }
+        let span = tracing::debug_span!(
+            "request",
+            frame = frame.kind(),
+            client = self.client_name.as_deref()
+        );
+        let request_timeout = self.config.request_timeout;
+        async {
+            let start = Instant::now();
+            // Only the computation is timed, writing the reply is bound by
+            // `ServerConfig::write_timeout`.
+            let reply = match request_timeout {
+                Some(timeout) => time::timeout(timeout, self.handle_frame(frame)).await.ok(),
+                None => Some(self.handle_frame(frame).await),
+            };
+            let handled = match reply {
+                Some(Ok(reply)) => self.reply(reply).await,
+                Some(Err(err)) => Handled::Closed(None, err),
+                None => Handled::TimedOut,
+            };
+            if let Handled::Replied(_) | Handled::Queued = handled {
+                let latency = start.elapsed();
+                self.handle.metrics.request_handled(latency);
+                tracing::debug!(?latency, "request handled");
+            }
+            handled
+        }
+        .instrument(span)
+        .await
+    }
+
+    // Write `reply` to the peer.
+    async fn reply(&mut self, reply: Reply) -> Handled {
+        let (response, close) = match reply {
+            Reply::Frame(response) => (response, None),
+            Reply::Close(err) => (Frame::Error(err.code(), err.to_string()), Some(err)),
+            Reply::Queued => return Handled::Queued,
+            Reply::None => return Handled::Replied(None),
+        };
+        if let Err(err) = self.connection.write_frame(&response).await {
+            return Handled::Closed(Some(response), err);
+        }
+        match close {
+            Some(err) => Handled::Closed(Some(response), err.into()),
+            None => Handled::Replied(Some(response)),
+        }
+    }
+
+    // Write the response of a worker, the request is recorded once its
+    // response is written.
+    async fn write_worker_response(&mut self, response: WorkerResponse) -> crate::Result<()> {
+        let handled = match self.connection.write_frame(&response.response).await {
+            Ok(()) => Handled::Replied(Some(response.response)),
+            Err(err) => Handled::Closed(Some(response.response), err),
+        };
+        self.audit(&response.request, &handled, response.latency);
+        if let Handled::Closed(_, err) = handled {
+            return Err(err);
+        }
+        self.record_activity("wrote a response");
+        Ok(())
+    }
+
+    // Record `request` in the audit log, if one is set. A request queued
+    // for a worker is recorded with the response of the worker.
+    fn audit(&self, request: &Frame, handled: &Handled, latency: Duration) {
+        let audit = match &self.audit {
+            Some(audit) => audit,
+            None => return,
+        };
+        let outcome = match handled {
+            Handled::Replied(Some(response)) => Outcome::Answered(response),
+            Handled::Replied(None) => Outcome::Unanswered,
+            Handled::Queued => return,
+            Handled::Closed(response, err) => Outcome::Failed(response.as_ref(), err),
+            Handled::TimedOut => Outcome::Failed(None, &"request timed out"),
+        };
+        let client = self.client_name.as_deref();
+        audit.record(&self.peer, client, request, outcome, latency);
+    }
+
+    // Close the connection after a request took longer than
+    // `ServerConfig::request_timeout` to compute.
+    async fn close_timed_out(&mut self) -> crate::Result<()> {
+        tracing::warn!("request timed out, closing connection");
+        let notice = Frame::Error(
+            ErrorCode::RequestTimeout,
+            "request timed out, closing connection".to_string(),
+        );
+        self.connection.write_frame(&notice).await
+    }
+
+    // Write the responses of the requests the workers are still handling.
+    async fn finish_in_flight(&mut self) -> crate::Result<()> {
+        while matches!(&self.workers, Some(workers) if workers.in_flight > 0) {
+            let response = next_response(&mut self.workers).await?;
+            self.write_worker_response(response).await?;
+        }
+        Ok(())
+    }
+
+    // Answer an admin frame, `None` for any other frame.
+    fn admin(&self, frame: &Frame) -> Option<Result<Frame, ComputeError>> {
+        // Like any tagged request, the response carries the tag.
+        if let Frame::Tagged(tag, frame) = frame {
+            let response = self
+                .admin(frame)?
+                .unwrap_or_else(|err| Frame::Error(err.code(), err.to_string()));
+            return Some(Ok(Frame::Tagged(*tag, Box::new(response))));
+        }
+
+        let token = match frame {
+            Frame::Stats(token) | Frame::Shutdown(token) => token,
+            _ => return None,
+        };
+        let authorized = match &self.config.admin_token {
+            Some(admin_token) => token_matches(admin_token, token),
+            None => false,
+        };
+        if !authorized {
+            tracing::warn!("admin frame with an invalid token");
+            return Some(Err(ComputeError::Unauthorized));
+        }
+
+        let response = match frame {
+            Frame::Stats(_) => Frame::StatsInfo(self.handle.metrics.stats()),
+            _ => {
+                tracing::info!("shutdown requested by an admin");
+                self.handle.shutdown();
+                frame.clone()
+            }
+        };
+        Some(Ok(response))
+    }
+
+    fn record_activity(&self, what: &'static str) {
+        *self.activity.lock().unwrap() = Activity::new(what);
+    }
+
+    // The reply to `frame`, an error closes the connection without one.
+    async fn handle_frame(&mut self, frame: &Frame) -> Result<Reply, crate::Error> {
+        // Start of a streamed array, its elements are answered one by one
+        // as they arrive, after the array header is echoed.
+        // A nested `ArrayStart` is an element that is answered with an error.
+        match frame {
+            Frame::ArrayStart(count) if self.array_remaining == 0 => {
+                self.array_remaining = *count;
+                return Ok(Reply::Frame(frame.clone()));
+            }
+            _ if self.array_remaining > 0 => self.array_remaining -= 1,
+            _ => {}
+        }
+
+        // Once a version is negotiated, frames of later versions are
+        // rejected and the version can not be changed anymore.
+        let checked = match frame {
+            Frame::Hello(_) if self.version.is_some() => Err(ComputeError::AlreadyNegotiated),
+            Frame::Hello(version) if *version < self.config.min_protocol_version => {
+                Err(ComputeError::VersionTooOld)
+            }
+            Frame::Hello(version) => {
+                let version = (*version).min(PROTOCOL_VERSION);
+                self.version = Some(version);
+                Ok(Some(Frame::Hello(version)))
+            }
+            _ if self.config.require_handshake && self.version.is_none() => {
+                Err(ComputeError::HandshakeRequired)
+            }
+            _ if frame.min_version() > self.version.unwrap_or(PROTOCOL_VERSION) => {
+                Err(ComputeError::UnsupportedVersion)
+            }
+            _ => Ok(None),
+        };
+        match checked {
+            Ok(Some(response)) => return Ok(Reply::Frame(response)),
+            Ok(None) => {}
+            Err(err) if self.config.recover_on_protocol_error => {
+                return Ok(Reply::Frame(Frame::Error(err.code(), err.to_string())));
+            }
+            // Reported before closing, otherwise the client could not tell
+            // a failed handshake from a dropped connection.
+            Err(err @ (ComputeError::HandshakeRequired | ComputeError::VersionTooOld)) => {
+                return Ok(Reply::Close(err));
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        // The peer reports a failure, answering it with another error
+        // could make both sides report errors to each other forever.
+        if let Frame::Error(code, message) = frame {
+            tracing::warn!(?code, %message, "peer reported an error");
+            return Ok(Reply::None);
+        }
+
+        // Heartbeats are answered right away, they never reach the
+        // arithmetic and are not logged.
+        if let Frame::Ping = frame {
+            return Ok(Reply::Frame(Frame::Pong));
+        }
+
+        if let Frame::Identify(name) = frame {
+            self.client_name = Some(name.clone());
+            self.span.record("client", field::display(name));
+            tracing::info!(client = %name, "identified");
+            return Ok(Reply::Frame(frame.clone()));
+        }
+
+        // Registers belong to the connection and admin frames act on the
+        // server, tagged requests of either are answered here instead of
+        // by a worker.
+        if matches!(frame, Frame::Tagged(_, request) if !is_register(request) && !is_admin(request))
+        {
+            if let Some(workers) = &mut self.workers {
+                workers.in_flight += 1;
+                workers.queue.push(frame.clone());
+                return Ok(Reply::Queued);
+            }
+        }
+
+        let response = match self
+            .admin(frame)
+            .or_else(|| self.registers.apply(frame, &self.config))
+        {
+            Some(response) => response,
+            None => {
+                let breaker = self.offload_breaker.as_deref();
+                respond_offloaded(frame, &self.config, breaker).await
+            }
+        };
+        let response = match response {
+            Ok(response) => response,
+            // A result that does not fit is a valid answer to a valid
+            // request, it never closes the connection. Neither does a
+            // request that may succeed when retried later.
+            Err(err)
+                if self.config.recover_on_protocol_error
+                    || err.is_arithmetic()
+                    || err == ComputeError::Unavailable =>
+            {
+                Frame::Error(err.code(), err.to_string())
             }
+            Err(err) => return Err(err.into()),
+        };
+        tracing::debug!(request = %frame, response = %response, "answered");
+        if let Some(op_log) = &self.op_log {
+            op_log.record(&self.peer, frame, &response);
+        }
+        Ok(Reply::Frame(response))
+    }
+}
+
+// What a request is answered with, see `Handler::handle_frame`.
+#[derive(Debug)]
+enum Reply {
+    // Write the frame.
+    Frame(Frame),
+
+    // Write the error, then close the connection.
+    Close(ComputeError),
+
+    // A worker answers the request.
+    Queued,
 
-            time::sleep(Duration::from_secs(backoff)).await;
+    // The frame needs no answer.
+    None,
+}
+
+// What became of a request, see `Handler::handle_request`.
+#[derive(Debug)]
+enum Handled {
+    // The reply was written, `None` when the frame needs no answer.
+    Replied(Option<Frame>),
+
+    // Handed to a worker, the request is recorded with its response.
+    Queued,
+
+    // The connection is closed with the error, after the reply was
+    // written or failed to be written.
+    Closed(Option<Frame>, crate::Error),
+
+    // The request took longer than `ServerConfig::request_timeout`.
+    TimedOut,
+}
+
+// Apply `op` to the results of `operands` from left to right, `depth` is
+// the number of trees the tree is nested in.
+fn eval_tree(op: Operator, operands: &[Frame], depth: usize) -> Result<u64, ComputeError> {
+    if depth >= MAX_NESTING_DEPTH {
+        return Err(ComputeError::NestingTooDeep);
+    }
+
+    let mut results = operands.iter().map(|operand| match operand {
+        Frame::Tree(op, operands) => eval_tree(*op, operands, depth + 1),
+        operand => match compute(operand)? {
+            Frame::OpResult(result) => Ok(result),
+            _ => Err(ComputeError::UnexpectedFrame),
+        },
+    });
+    let first = results.next().ok_or(ComputeError::InvalidSyntax)??;
+    results.try_fold(first, |acc, result| apply_operator(op, acc, result?))
+}
+
+fn apply_operator(op: Operator, x: u64, y: u64) -> Result<u64, ComputeError> {
+    match op {
+        Operator::Add => x.checked_add(y).ok_or(ComputeError::Overflow),
+        Operator::Sub => x.checked_sub(y).ok_or(ComputeError::Underflow),
+        Operator::Mul => x.checked_mul(y).ok_or(ComputeError::Overflow),
+    }
+}
+
+// `results` are the results of the earlier elements of the array the
+// expression is part of, `None` for an element that failed.
+fn eval_rpn(tokens: &[Token], results: &[Option<u64>]) -> Result<u64, ComputeError> {
+    let mut stack = Vec::new();
+
+    for token in tokens {
+        let value = match token {
+            Token::Number(n) => *n,
+            Token::Ref(index) => results
+                .get(*index)
+                .copied()
+                .flatten()
+                .ok_or(ComputeError::InvalidReference)?,
+            Token::Add => {
+                let (x, y) = pop_operands(&mut stack)?;
+                x.checked_add(y).ok_or(ComputeError::Overflow)?
+            }
+            Token::Sub => {
+                let (x, y) = pop_operands(&mut stack)?;
+                x.checked_sub(y).ok_or(ComputeError::Underflow)?
+            }
+            Token::Mul => {
+                let (x, y) = pop_operands(&mut stack)?;
+                x.checked_mul(y).ok_or(ComputeError::Overflow)?
+            }
+        };
+        stack.push(value);
+    }
+
+    match stack[..] {
+        [value] => Ok(value),
+        [] => Err(ComputeError::StackUnderflow),
+        _ => Err(ComputeError::LeftoverOperands),
+    }
+}
+
+// Pops the two operands of a binary operator, in the order they were pushed.
+fn pop_operands(stack: &mut Vec<u64>) -> Result<(u64, u64), ComputeError> {
+    match (stack.pop(), stack.pop()) {
+        (Some(y), Some(x)) => Ok((x, y)),
+        _ => Err(ComputeError::StackUnderflow),
+    }
+}
+
+// Warn through `warn` whenever the handler owning `activity` made no
+// progress for `interval`, once per stall. Ends with the handler.
+async fn watch_activity<F>(
+    id: u64,
+    activity: Weak<Mutex<Activity>>,
+    interval: Duration,
+    mut warn: F,
+) where
+    F: FnMut(String),
+{
+    let mut warned_at = None;
+
+    loop {
+        time::sleep(interval).await;
+
+        let activity = match activity.upgrade() {
+            Some(activity) => activity,
+            None => return,
+        };
+        let activity = activity.lock().unwrap();
+        let idle = activity.at.elapsed();
+        if idle >= interval && warned_at != Some(activity.at) {
+            warned_at = Some(activity.at);
+            warn(format!(
+                "connection {} made no progress for {:?}, last {}",
+                id, idle, activity.what
+            ));
+        }
+    }
+}
+
+// Completes once the server starts shutting down. A server that went away
+// without shutting down leaves its connections open.
+async fn shutdown_notified(shutdown: &mut broadcast::Receiver<()>) {
+    if let Err(RecvError::Closed) = shutdown.recv().await {
+        std::future::pending().await
+    }
+}
+
+// Completes at `deadline`, never completes without one.
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Reasons a request frame can not be answered.
+#[derive(Debug, PartialEq)]
+pub enum ComputeError {
+    // The result does not fit in a `u64`.
+    Overflow,
+
+    // The result would be negative.
+    Underflow,
+
+    // The divisor is zero.
+    DivisionByZero,
+
+    // An aggregate of an empty list of operands.
+    EmptyOperands,
+
+    // A postfix operator was applied to fewer than two operands.
+    StackUnderflow,
+
+    // A postfix expression did not reduce to exactly one value.
+    LeftoverOperands,
+
+    // An infix expression is malformed, see `expr`.
+    InvalidSyntax,
+
+    // An expression tree is nested deeper than `MAX_NESTING_DEPTH`.
+    NestingTooDeep,
+
+    // A register or snapshot that was never set.
+    UnknownName,
+
+    // The frame was introduced by a later protocol version than the
+    // negotiated one.
+    UnsupportedVersion,
+
+    // A `Frame::Hello` after the version was negotiated.
+    AlreadyNegotiated,
+
+    // A `Frame::Hello` with a version below
+    // `ServerConfig::min_protocol_version`.
+    VersionTooOld,
+
+    // A request before the `Frame::Hello`, see
+    // `ServerConfig::require_handshake`.
+    HandshakeRequired,
+
+    // A `$N` reference to an element that is not before it in the same
+    // array, or to an element that failed.
+    InvalidReference,
+
+    // An operand is larger than `ServerConfig::max_operand`.
+    OperandLimit,
+
+    // The response array would be longer than
+    // `ServerConfig::max_response_array_len`.
+    ResponseTooLarge,
+
+    // An admin frame without the admin token of the server, see
+    // `ServerConfig::admin_token`.
+    Unauthorized,
+
+    // The request was not computed, see
+    // `ServerConfig::offload_failure_threshold`.
+    Unavailable,
+
+    // A register beyond `ServerConfig::max_registers`, or a snapshot
+    // beyond as many snapshots.
+    TooManyRegisters,
+
+    // A register or snapshot name longer than
+    // `ServerConfig::max_register_name_len`.
+    NameTooLong,
+
+    // A register or snapshot name that can not be written back, e.g. one
+    // that starts with a digit.
+    InvalidName,
+
+    // A snapshot of registers shared by every connection, see
+    // `ServerConfig::shared_registers`.
+    SharedRegisters,
+
+    // The frame is not a request, e.g. a response frame.
+    UnexpectedFrame,
+}
+
+impl std::error::Error for ComputeError {}
+
+impl fmt::Display for ComputeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ComputeError::Overflow => "arithmetic overflow".fmt(fmt),
+            ComputeError::Underflow => "arithmetic underflow".fmt(fmt),
+            ComputeError::DivisionByZero => "division by zero".fmt(fmt),
+            ComputeError::EmptyOperands => "no operands".fmt(fmt),
+            ComputeError::StackUnderflow => "not enough operands for operator".fmt(fmt),
+            ComputeError::LeftoverOperands => "expression leaves unused operands".fmt(fmt),
+            ComputeError::InvalidSyntax => "invalid expression syntax".fmt(fmt),
+            ComputeError::NestingTooDeep => "expression nested too deeply".fmt(fmt),
+            ComputeError::InvalidReference => "invalid result reference".fmt(fmt),
+            ComputeError::UnknownName => "unknown register or snapshot".fmt(fmt),
+            ComputeError::UnsupportedVersion => {
+                "frame is not supported by the negotiated protocol version".fmt(fmt)
+            }
+            ComputeError::AlreadyNegotiated => "protocol version already negotiated".fmt(fmt),
+            ComputeError::VersionTooOld => {
+                "protocol version is older than the server supports".fmt(fmt)
+            }
+            ComputeError::HandshakeRequired => "expected a hello frame first".fmt(fmt),
+            ComputeError::OperandLimit => "operand exceeds the server limit".fmt(fmt),
+            ComputeError::ResponseTooLarge => "response exceeds the server limit".fmt(fmt),
+            ComputeError::Unauthorized => "invalid admin token".fmt(fmt),
+            ComputeError::Unavailable => {
+                "server can not compute the request, try again later".fmt(fmt)
+            }
+            ComputeError::TooManyRegisters => "too many registers or snapshots".fmt(fmt),
+            ComputeError::NameTooLong => "register or snapshot name too long".fmt(fmt),
+            ComputeError::InvalidName => "invalid register or snapshot name".fmt(fmt),
+            ComputeError::SharedRegisters => "snapshots of shared registers".fmt(fmt),
+            ComputeError::UnexpectedFrame => "unexpected frame".fmt(fmt),
+        }
+    }
+}
+
+impl ComputeError {
+    // The operation is well formed, its result is not representable.
+    pub fn is_arithmetic(&self) -> bool {
+        matches!(
+            self,
+            ComputeError::Overflow
+                | ComputeError::Underflow
+                | ComputeError::DivisionByZero
+                | ComputeError::EmptyOperands
+        )
+    }
+
+    // The code reported to the client for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ComputeError::Overflow => ErrorCode::Overflow,
+            ComputeError::Underflow => ErrorCode::Underflow,
+            ComputeError::DivisionByZero => ErrorCode::DivisionByZero,
+            ComputeError::EmptyOperands => ErrorCode::EmptyOperands,
+            ComputeError::StackUnderflow
+            | ComputeError::LeftoverOperands
+            | ComputeError::InvalidSyntax
+            | ComputeError::NestingTooDeep
+            | ComputeError::InvalidReference => ErrorCode::InvalidExpression,
+            ComputeError::OperandLimit => ErrorCode::OperandLimit,
+            ComputeError::ResponseTooLarge => ErrorCode::ResponseTooLarge,
+            ComputeError::UnknownName => ErrorCode::UnknownName,
+            ComputeError::UnsupportedVersion
+            | ComputeError::AlreadyNegotiated
+            | ComputeError::VersionTooOld
+            | ComputeError::HandshakeRequired => ErrorCode::UnsupportedVersion,
+            ComputeError::Unauthorized => ErrorCode::Unauthorized,
+            ComputeError::Unavailable => ErrorCode::Unavailable,
+            ComputeError::TooManyRegisters | ComputeError::NameTooLong => ErrorCode::RegisterLimit,
+            ComputeError::InvalidName => ErrorCode::InvalidName,
+            ComputeError::SharedRegisters | ComputeError::UnexpectedFrame => {
+                ErrorCode::UnexpectedFrame
+            }
+        }
+    }
+}
+
+// Compute the response for a request, enforcing the limits of the server.
+// A tagged request is always answered with a tagged response, a failure
+// is reported as a tagged error frame.
+pub(crate) fn respond(frame: &Frame, config: &ServerConfig) -> Result<Frame, ComputeError> {
+    if let Frame::Tagged(tag, frame) = frame {
+        let response =
+            respond(frame, config).unwrap_or_else(|err| Frame::Error(err.code(), err.to_string()));
+        return Ok(Frame::Tagged(*tag, Box::new(response)));
+    }
+    // The response is compressed as well, a failure is reported uncompressed.
+    #[cfg(feature = "compression")]
+    if let Frame::Compressed(frame) = frame {
+        return respond(frame, config).map(|response| Frame::Compressed(Box::new(response)));
+    }
+
+    check_limits(frame, config)?;
+    compute(frame)
+}
+
+// Like `respond`, on the blocking thread pool when the request costs at
+// least `ServerConfig::blocking_cost_threshold`. While `breaker` is open
+// such requests fail right away.
+async fn respond_offloaded(
+    frame: &Frame,
+    config: &Arc<ServerConfig>,
+    breaker: Option<&CircuitBreaker>,
+) -> Result<Frame, ComputeError> {
+    match config.blocking_cost_threshold {
+        Some(threshold) if cost(frame) >= threshold => {
+            if let Some(breaker) = breaker {
+                if !breaker.allow() {
+                    return Err(ComputeError::Unavailable);
+                }
+            }
+
+            let (request, config) = (frame.clone(), config.clone());
+            let timeout = config.offload_timeout;
+            let offloaded = tokio::task::spawn_blocking(move || respond(&request, &config));
+            let joined = match timeout {
+                Some(timeout) => time::timeout(timeout, offloaded).await.ok(),
+                None => Some(offloaded.await),
+            };
+            let joined = match joined {
+                Some(joined) => joined,
+                // The computation keeps running, its result is dropped.
+                None => {
+                    if let Some(breaker) = breaker {
+                        breaker.record_failure();
+                    }
+                    return Err(ComputeError::Unavailable);
+                }
+            };
+            match joined {
+                Ok(response) => {
+                    if let Some(breaker) = breaker {
+                        breaker.record_success();
+                    }
+                    response
+                }
+                // Blocking tasks are never cancelled, only a panic ends
+                // one early. It is raised on the handler as if the
+                // request had been computed there.
+                Err(err) => {
+                    if let Some(breaker) = breaker {
+                        breaker.record_failure();
+                    }
+                    std::panic::resume_unwind(err.into_panic())
+                }
+            }
+        }
+        _ => respond(frame, config),
+    }
+}
+
+// See `ServerConfig::offload_failure_threshold`.
+fn offload_breaker(config: &ServerConfig) -> Option<Arc<CircuitBreaker>> {
+    config
+        .offload_failure_threshold
+        .map(|threshold| Arc::new(CircuitBreaker::new(threshold, config.offload_cooldown)))
+}
+
+// Rough estimate of the work needed to compute a request, about one unit
+// per machine word operation. Only compared against
+// `ServerConfig::blocking_cost_threshold`.
+fn cost(frame: &Frame) -> u64 {
+    match frame {
+        // The computation overflows by 21, however large `n` is.
+        Frame::Factorial(n) => (*n).min(21),
+        Frame::Sum(operands)
+        | Frame::Product(operands)
+        | Frame::Sort(operands)
+        | Frame::Aggregate(_, operands) => operands.len() as u64,
+        Frame::Rpn(tokens) => tokens.len() as u64,
+        Frame::Expr(expr) => expr.len() as u64,
+        Frame::Array(frames) | Frame::Tree(_, frames) => frames.iter().map(cost).sum(),
+        Frame::Tagged(_, frame) => cost(frame),
+        #[cfg(feature = "compression")]
+        Frame::Compressed(frame) => cost(frame),
+        #[cfg(feature = "bignum")]
+        Frame::Big(op, x, y) => {
+            let (x, y) = (x.bits() / 64 + 1, y.bits() / 64 + 1);
+            match op {
+                Operator::Mul => x.saturating_mul(y),
+                Operator::Add | Operator::Sub => x.max(y),
+            }
+        }
+        _ => 1,
+    }
+}
+
+// Enforce the limits of the server before computing a request.
+fn check_limits(frame: &Frame, config: &ServerConfig) -> Result<(), ComputeError> {
+    // An array request is answered with an array of the same length, so
+    // an oversized response is rejected before it is built.
+    let response_len = match frame {
+        Frame::Array(frames) => frames.len(),
+        Frame::Sort(operands) => operands.len(),
+        _ => 0,
+    };
+    if response_len > config.max_response_array_len {
+        return Err(ComputeError::ResponseTooLarge);
+    }
+
+    // Nested requests are bound by the same limits.
+    match frame {
+        Frame::Array(frames) | Frame::Tree(_, frames) => {
+            return frames
+                .iter()
+                .try_for_each(|frame| check_limits(frame, config));
+        }
+        Frame::Tagged(_, frame) => return check_limits(frame, config),
+        #[cfg(feature = "compression")]
+        Frame::Compressed(frame) => return check_limits(frame, config),
+        _ => {}
+    }
+
+    let max = match config.max_operand {
+        Some(max) => max,
+        None => return Ok(()),
+    };
+
+    let within_limit = match frame {
+        Frame::Addition(x, y)
+        | Frame::Subtraction(x, y)
+        | Frame::Multiplication(x, y)
+        | Frame::Modulo(x, y)
+        | Frame::Division(x, y)
+        | Frame::Pow(x, y)
+        | Frame::Gcd(x, y)
+        | Frame::Lcm(x, y) => *x <= max && *y <= max,
+        Frame::Factorial(n) => *n <= max,
+        Frame::Set(_, x) | Frame::Accumulate(_, x) => *x <= max,
+        Frame::Named(_, x, y) => [x, y].into_iter().all(|operand| match operand {
+            Operand::Number(n) => *n <= max,
+            Operand::Name(_) => true,
+        }),
+        Frame::Signed(_, x, y) => x.unsigned_abs() <= max && y.unsigned_abs() <= max,
+        Frame::Float(_, x, y) => x.abs() <= max as f64 && y.abs() <= max as f64,
+        Frame::Decimal(_, x, y) => x.abs_le(max) && y.abs_le(max),
+        #[cfg(feature = "bignum")]
+        Frame::Big(_, x, y) => *x <= max.into() && *y <= max.into(),
+        Frame::Sum(operands)
+        | Frame::Product(operands)
+        | Frame::Sort(operands)
+        | Frame::Aggregate(_, operands) => operands.iter().all(|operand| *operand <= max),
+        Frame::Rpn(tokens) => tokens.iter().all(|token| match token {
+            Token::Number(n) => *n <= max,
+            _ => true,
+        }),
+        _ => true,
+    };
+
+    if within_limit {
+        Ok(())
+    } else {
+        Err(ComputeError::OperandLimit)
+    }
+}
+
+// Compute the response for a request frame.
+//
+// This is the pure part of request handling, no I/O is performed.
+pub fn compute(frame: &Frame) -> Result<Frame, ComputeError> {
+    compute_in_batch(frame, &[])
+}
+
+// Compute `frame` as an element of an array, `results` holds the results
+// of the elements before it.
+fn compute_in_batch(frame: &Frame, results: &[Option<u64>]) -> Result<Frame, ComputeError> {
+    let op_result = match frame {
+        // Control frames are answered directly, they are not
+        // arithmetic operations.
+        Frame::Ping => return Ok(Frame::Pong),
+        Frame::Identify(name) => return Ok(Frame::Identify(name.clone())),
+        Frame::Echo(payload) => return Ok(Frame::Echo(payload.clone())),
+        Frame::Version => return Ok(Frame::VersionInfo(VERSION.to_string())),
+        Frame::Addition(x, y) => x.checked_add(*y).ok_or(ComputeError::Overflow)?,
+        Frame::Subtraction(x, y) => x.checked_sub(*y).ok_or(ComputeError::Underflow)?,
+        Frame::Multiplication(x, y) => x.checked_mul(*y).ok_or(ComputeError::Overflow)?,
+        Frame::Modulo(x, y) => x.checked_rem(*y).ok_or(ComputeError::DivisionByZero)?,
+        Frame::Division(x, y) => x.checked_div(*y).ok_or(ComputeError::DivisionByZero)?,
+        Frame::Sum(_)
+        | Frame::Product(_)
+        | Frame::Pow(..)
+        | Frame::Factorial(_)
+        | Frame::Gcd(..)
+        | Frame::Lcm(..) => frame.eval().ok_or(ComputeError::Overflow)?,
+        Frame::Aggregate(..) => frame.eval().ok_or(ComputeError::EmptyOperands)?,
+        Frame::Rpn(tokens) => eval_rpn(tokens, results)?,
+        Frame::Expr(expr) => expr::eval(expr)?,
+        Frame::Tree(op, operands) => eval_tree(*op, operands, 0)?,
+        Frame::Signed(op, x, y) => {
+            let result = match op {
+                Operator::Add => x.checked_add(*y),
+                Operator::Sub => x.checked_sub(*y),
+                Operator::Mul => x.checked_mul(*y),
+            };
+            return result
+                .map(Frame::SignedResult)
+                .ok_or(ComputeError::Overflow);
+        }
+        // A result that is not finite is too large for the wire format.
+        Frame::Float(op, x, y) => {
+            let result = match op {
+                Operator::Add => x + y,
+                Operator::Sub => x - y,
+                Operator::Mul => x * y,
+            };
+            if !result.is_finite() {
+                return Err(ComputeError::Overflow);
+            }
+            return Ok(Frame::FloatResult(result));
+        }
+        // Exact, a result that would need more digits is an overflow.
+        Frame::Decimal(op, x, y) => {
+            let result = match op {
+                Operator::Add => x.checked_add(*y),
+                Operator::Sub => x.checked_sub(*y),
+                Operator::Mul => x.checked_mul(*y),
+            };
+            return result
+                .map(Frame::DecimalResult)
+                .ok_or(ComputeError::Overflow);
+        }
+        #[cfg(feature = "bignum")]
+        Frame::Big(op, x, y) => {
+            let result = match op {
+                Operator::Add => x + y,
+                Operator::Sub if x < y => return Err(ComputeError::Underflow),
+                Operator::Sub => x - y,
+                Operator::Mul => x * y,
+            };
+            return Ok(Frame::BigResult(result));
+        }
+        Frame::Tagged(tag, frame) => {
+            let response = compute_in_batch(frame, results)
+                .unwrap_or_else(|err| Frame::Error(err.code(), err.to_string()));
+            return Ok(Frame::Tagged(*tag, Box::new(response)));
+        }
+        Frame::Sort(operands) => {
+            let mut sorted = operands.clone();
+            sorted.sort_unstable();
+            return Ok(Frame::Array(
+                sorted.into_iter().map(Frame::OpResult).collect(),
+            ));
+        }
+        // Every element is answered, a failed element does not fail the
+        // other elements, only the ones that reference it.
+        Frame::Array(frames) => {
+            let mut results = Vec::with_capacity(frames.len());
+            let mut responses = Vec::with_capacity(frames.len());
+            for frame in frames {
+                let response = compute_in_batch(frame, &results)
+                    .unwrap_or_else(|err| Frame::Error(err.code(), err.to_string()));
+                results.push(match response {
+                    Frame::OpResult(result) => Some(result),
+                    _ => None,
+                });
+                responses.push(response);
+            }
+            return Ok(Frame::Array(responses));
+        }
+        Frame::OpResult(r) => *r,
+        // Registers are state of a connection, see `Registers`.
+        Frame::Set(..)
+        | Frame::Get(_)
+        | Frame::Save(_)
+        | Frame::Restore(_)
+        | Frame::Accumulate(..)
+        | Frame::Result
+        | Frame::Named(..) => return Err(ComputeError::UnexpectedFrame),
+        // Admin frames act on the server, see `Handler::admin`.
+        Frame::Stats(_) | Frame::Shutdown(_) => return Err(ComputeError::UnexpectedFrame),
+        // The version is negotiated per connection, see `Handler`.
+        Frame::Hello(_) => return Err(ComputeError::UnexpectedFrame),
+        Frame::Pong
+        | Frame::VersionInfo(_)
+        | Frame::StatsInfo(_)
+        | Frame::SignedResult(_)
+        | Frame::FloatResult(_)
+        | Frame::DecimalResult(_)
+        | Frame::ArrayStart(_)
+        | Frame::Error(..) => return Err(ComputeError::UnexpectedFrame),
+        #[cfg(feature = "bignum")]
+        Frame::BigResult(_) => return Err(ComputeError::UnexpectedFrame),
+        #[cfg(feature = "compression")]
+        Frame::Compressed(frame) => {
+            let response = compute_in_batch(frame, results)?;
+            return Ok(Frame::Compressed(Box::new(response)));
+        }
+    };
+    Ok(Frame::OpResult(op_result))
+}
+
+// The peer of a connection, as shown in logs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+
+    // Clients of a Unix domain socket are usually not bound to a path.
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+}
+
+// Where a `Server` accepts connections, e.g. a `TcpListener`.
+trait Accept: Send + Sync + fmt::Debug {
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Box<dyn Stream>, Peer)>>;
+
+    // The address of a TCP listener, `None` for any other.
+    fn tcp_addr(&self) -> Option<io::Result<SocketAddr>> {
+        None
+    }
+}
+
+// A calculator server bound to a listener.
+//
+// `Server::handle` returns a `ServerHandle` that can be used to control
+// the server once `run` has been called. Embedding applications and tests
+// usually bind port 0, learn the port the OS picked from `local_addr` and
+// stop the server with `run_until`:
+//
+//     let server = Server::bind("127.0.0.1:0".parse()?)?;
+//     let addr = server.local_addr()?;
+//     let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+//     tokio::spawn(server.run_until(stopped));
+#[derive(Debug)]
+pub struct Server {
+    // Connections of all listeners are served alike, see
+    // `Builder::bind_all`.
+    listeners: Vec<Box<dyn Accept>>,
+    config: ServerConfig,
+    handle: ServerHandle,
+
+    // See `Builder::audit_log`.
+    audit: Option<AuditLog>,
+
+    // Accept TLS on every connection, see `Builder::tls`.
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+// Builds a `Server` with options that go beyond the values of
+// `ServerConfig`, e.g. TLS certificates. The limits are set first, then
+// the server is built on a listener or bound to an address.
+#[derive(Debug, Default)]
+pub struct Builder {
+    config: ServerConfig,
+    audit: Option<AuditLog>,
+
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+// Controls a running `Server`. Cloning the handle is cheap, all clones
+// control the same server.
+#[derive(Clone, Debug)]
+pub struct ServerHandle {
+    // `true` while accepting new connections is paused.
+    paused: Arc<watch::Sender<bool>>,
+
+    // Counters of all connections, request frames handled among them.
+    metrics: Arc<Metrics>,
+
+    // Set to `true` to shut the server down, see `ServerHandle::shutdown`.
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl Server {
+    pub fn new(listener: TcpListener) -> Server {
+        Server::with_config(listener, ServerConfig::default())
+    }
+
+    pub fn with_config(listener: TcpListener, config: ServerConfig) -> Server {
+        Server::builder().config(config).build(listener)
+    }
+
+    // Bind `addr` with the default options, see `Builder::bind`.
+    pub fn bind(addr: SocketAddr) -> io::Result<Server> {
+        Server::builder().bind(addr)
+    }
+
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    // The address the server accepts connections on, the first one of a
+    // server with several listeners. Fails for a server on a Unix domain
+    // socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self
+            .listeners
+            .first()
+            .and_then(|listener| listener.tcp_addr())
+        {
+            Some(addr) => addr,
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "server is not bound to a TCP address",
+            )),
+        }
+    }
+
+    // The addresses of all TCP listeners, in the order they were given.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.tcp_addr())
+            .collect()
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    pub async fn run(self) {
+        self.run_until(std::future::pending::<()>()).await
+    }
+
+    // Serve connections until `shutdown` completes, e.g.
+    // `tokio::signal::ctrl_c()`.
+    //
+    // Shutting down stops accepting connections and tells every
+    // connection to stop reading requests. The requests that were read
+    // are answered, then the connection is sent a
+    // `ErrorCode::ShuttingDown` error and closed. Returns once every
+    // connection closed or `ServerConfig::drain_timeout` passed.
+    pub async fn run_until<F: std::future::Future>(self, shutdown: F) {
+        let op_log = match &self.config.op_log_path {
+            Some(path) => match OpLog::open(path).await {
+                Ok(op_log) => Some(op_log),
+                Err(err) => {
+                    tracing::error!(%err, "failed to open the operation log");
+                    return;
+                }
+            },
+            None => None,
+        };
+        let rate_limiter = self
+            .config
+            .global_rate_limit
+            .map(|per_second| Arc::new(RateLimiter::new(per_second)));
+
+        let config = Arc::new(self.config);
+        let scrapes = match config.metrics_addr {
+            Some(addr) => match TcpListener::bind(addr).await {
+                Ok(listener) => {
+                    let scrapes =
+                        metrics::serve(listener, self.handle.metrics.clone(), config.clone());
+                    Some(tokio::spawn(async move {
+                        if let Err(err) = scrapes.await {
+                            tracing::error!(%err, "failed to accept metrics scrape");
+                        }
+                    }))
+                }
+                Err(err) => {
+                    tracing::error!(%err, %addr, "failed to bind the metrics listener");
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        let shared_registers = config.shared_registers.then(Arc::default);
+        let offload_breaker = offload_breaker(&config);
+        let mut requested = self.handle.shutdown.subscribe();
+
+        let mut server = Listener {
+            listeners: self.listeners,
+            next_listener: 0,
+            limit_connections: Arc::new(Semaphore::new(config.max_connections)),
+            limit_handshakes: Arc::new(Semaphore::new(config.max_handshakes)),
+            config,
+            handle: self.handle,
+            op_log,
+            audit: self.audit,
+            rate_limiter,
+            offload_breaker,
+            next_id: 0,
+            connections: JoinSet::new(),
+            notify_shutdown: broadcast::channel(1).0,
+            shared_registers,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+        };
+
+        tokio::select! {
+            result = server.run() => {
+                if let Err(err) = result {
+                    tracing::error!(%err, "failed to accept connection");
+                }
+            }
+            _ = shutdown => tracing::info!("shutting down"),
+            _ = requested.wait_for(|requested| *requested) => {
+                tracing::info!("shutdown requested, shutting down");
+            }
+        }
+        server.drain().await;
+        if let Some(scrapes) = scrapes {
+            scrapes.abort();
+        }
+    }
+}
+
+impl Builder {
+    // Replace every option with those of `config`, the setters below
+    // change single options.
+    pub fn config(mut self, config: ServerConfig) -> Builder {
+        self.config = config;
+        self
+    }
+
+    // See `ServerConfig::max_connections`.
+    pub fn max_connections(mut self, connections: usize) -> Builder {
+        self.config.max_connections = connections;
+        self
+    }
+
+    // See `ServerConfig::max_handshakes`.
+    pub fn max_handshakes(mut self, handshakes: usize) -> Builder {
+        self.config.max_handshakes = handshakes;
+        self
+    }
+
+    // See `ServerConfig::listen_backlog`, used by `bind`.
+    pub fn listen_backlog(mut self, backlog: u32) -> Builder {
+        self.config.listen_backlog = backlog;
+        self
+    }
+
+    // See `ServerConfig::max_frame_len`.
+    pub fn max_frame_len(mut self, bytes: usize) -> Builder {
+        self.config.max_frame_len = bytes;
+        self
+    }
+
+    // See `ServerConfig::read_buffer_capacity`.
+    pub fn read_buffer_capacity(mut self, initial: usize, max: usize) -> Builder {
+        self.config.read_buffer_capacity = initial;
+        self.config.max_read_buffer_capacity = max;
+        self
+    }
+
+    // See `ServerConfig::idle_timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Builder {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    // See `ServerConfig::request_timeout`.
+    pub fn request_timeout(mut self, timeout: Duration) -> Builder {
+        self.config.request_timeout = Some(timeout);
+        self
+    }
+
+    // See `ServerConfig::write_timeout`.
+    pub fn write_timeout(mut self, timeout: Duration) -> Builder {
+        self.config.write_timeout = Some(timeout);
+        self
+    }
+
+    // See `ServerConfig::max_session_duration`.
+    pub fn max_session_duration(mut self, duration: Duration) -> Builder {
+        self.config.max_session_duration = Some(duration);
+        self
+    }
+
+    // See `ServerConfig::drain_timeout`.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Builder {
+        self.config.drain_timeout = timeout;
+        self
+    }
+
+    // See `ServerConfig::connection_rate_limit`.
+    pub fn connection_rate_limit(
+        mut self,
+        per_second: NonZeroU32,
+        exceeded: RateLimitExceeded,
+    ) -> Builder {
+        self.config.connection_rate_limit = Some(per_second);
+        self.config.rate_limit_exceeded = exceeded;
+        self
+    }
+
+    // See `ServerConfig::offload_failure_threshold`, `offload_cooldown`
+    // and `offload_timeout`.
+    pub fn offload_circuit_breaker(
+        mut self,
+        failure_threshold: u32,
+        cooldown: Duration,
+        timeout: Duration,
+    ) -> Builder {
+        self.config.offload_failure_threshold = Some(failure_threshold);
+        self.config.offload_cooldown = cooldown;
+        self.config.offload_timeout = Some(timeout);
+        self
+    }
+
+    // See `ServerConfig::accept_backoff` and
+    // `ServerConfig::max_accept_backoff`.
+    pub fn accept_backoff(mut self, initial: Duration, max: Duration) -> Builder {
+        self.config.accept_backoff = initial;
+        self.config.max_accept_backoff = max;
+        self
+    }
+
+    // See `ServerConfig::reject_when_busy`.
+    pub fn reject_when_busy(mut self, reject: bool) -> Builder {
+        self.config.reject_when_busy = reject;
+        self
+    }
+
+    // See `ServerConfig::shared_registers`.
+    pub fn shared_registers(mut self, shared: bool) -> Builder {
+        self.config.shared_registers = shared;
+        self
+    }
+
+    // See `ServerConfig::max_registers` and `max_register_name_len`.
+    pub fn register_limits(mut self, registers: usize, name_len: usize) -> Builder {
+        self.config.max_registers = registers;
+        self.config.max_register_name_len = name_len;
+        self
+    }
+
+    // See `ServerConfig::metrics_addr`.
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Builder {
+        self.config.metrics_addr = Some(addr);
+        self
+    }
+
+    // See `ServerConfig::admin_token`.
+    pub fn admin_token(mut self, token: impl Into<String>) -> Builder {
+        self.config.admin_token = Some(token.into());
+        self
+    }
+
+    // Accept TLS on every connection with the certificate chain in
+    // `cert_path` and its private key in `key_path`, see
+    // `tls::server_config`. Clients that do not start TLS are closed.
+    #[cfg(feature = "tls")]
+    pub fn tls(
+        self,
+        cert_path: impl AsRef<std::path::Path>,
+        key_path: impl AsRef<std::path::Path>,
+    ) -> crate::Result<Builder> {
+        Ok(self.tls_config(crate::tls::server_config(cert_path, key_path)?))
+    }
+
+    // Record every request in `audit`, see `AuditLog`.
+    pub fn audit_log(mut self, audit: AuditLog) -> Builder {
+        self.audit = Some(audit);
+        self
+    }
+
+    // Like `tls`, with a TLS configuration that was built by the caller.
+    #[cfg(feature = "tls")]
+    pub fn tls_config(mut self, config: Arc<rustls::ServerConfig>) -> Builder {
+        self.tls = Some(config);
+        self
+    }
+
+    pub fn build(self, listener: TcpListener) -> Server {
+        self.build_incoming(vec![Box::new(listener)])
+    }
+
+    // Serve the connections of every listener, e.g. of several ports.
+    // Fails without a listener, the server would never accept.
+    pub fn build_all(self, listeners: impl IntoIterator<Item = TcpListener>) -> io::Result<Server> {
+        let listeners: Vec<Box<dyn Accept>> = listeners
+            .into_iter()
+            .map(|listener| Box::new(listener) as _)
+            .collect();
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "server needs at least one listener",
+            ));
+        }
+        Ok(self.build_incoming(listeners))
+    }
+
+    // Serve clients of a Unix domain socket. The socket file is not
+    // removed when the server stops.
+    #[cfg(unix)]
+    pub fn build_unix(self, listener: UnixListener) -> Server {
+        self.build_incoming(vec![Box::new(listener)])
+    }
+
+    // Bind `addr` with the configured listen backlog and build the server
+    // on it.
+    pub fn bind(self, addr: SocketAddr) -> io::Result<Server> {
+        self.bind_all(&[addr])
+    }
+
+    // Like `bind`, for several addresses that are served alike. Binding
+    // `0.0.0.0:port` and `[::]:port` serves IPv4 and IPv6 clients on the
+    // same port, the IPv6 listener then only accepts IPv6 connections.
+    // Fails without an address, like `build_all`.
+    pub fn bind_all(self, addrs: &[SocketAddr]) -> io::Result<Server> {
+        let has_v4 = addrs.iter().any(SocketAddr::is_ipv4);
+        let listeners = addrs
+            .iter()
+            .map(|addr| bind_socket(*addr, &self.config, has_v4 && addr.is_ipv6()))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.build_all(listeners)
+    }
+
+    fn build_incoming(self, listeners: Vec<Box<dyn Accept>>) -> Server {
+        Server {
+            listeners,
+            config: self.config,
+            handle: ServerHandle::new(),
+            audit: self.audit,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+        }
+    }
+}
+
+impl ServerHandle {
+    fn new() -> ServerHandle {
+        ServerHandle {
+            paused: Arc::new(watch::Sender::new(false)),
+            metrics: Arc::default(),
+            shutdown: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    // Shut the server down as if the future passed to `Server::run_until`
+    // completed, e.g. for a `Frame::Shutdown` of an admin.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    // Stop accepting new connections. Connections that were already
+    // accepted keep being served.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    // Start accepting new connections again.
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    // Number of request frames the server handled so far.
+    pub fn requests_served(&self) -> u64 {
+        self.metrics.requests_served()
+    }
+
+    // Metrics of the server, shared with the running server.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+}
+
+#[derive(Debug)]
+struct Listener {
+    listeners: Vec<Box<dyn Accept>>,
+
+    // The listener polled first on the next accept, rotated so a busy
+    // listener can not starve the others.
+    next_listener: usize,
+
+    limit_connections: Arc<Semaphore>,
+    limit_handshakes: Arc<Semaphore>,
+
+    config: Arc<ServerConfig>,
+    handle: ServerHandle,
+    op_log: Option<OpLog>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    offload_breaker: Option<Arc<CircuitBreaker>>,
+
+    // Id of the next accepted connection, identifies the connection in
+    // watchdog warnings.
+    next_id: u64,
+
+    // The tasks serving the accepted connections.
+    connections: JoinSet<()>,
+
+    // Tells every handler that the server is shutting down.
+    notify_shutdown: broadcast::Sender<()>,
+
+    audit: Option<AuditLog>,
+
+    // Registers of every connection, see `ServerConfig::shared_registers`.
+    shared_registers: Option<Arc<Mutex<HashMap<String, u64>>>>,
+
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+// A stream the server serves a connection on, e.g. a `TcpStream`, a
+// `UnixStream` or a TLS stream on top of either.
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin + fmt::Debug {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Send + Unpin + fmt::Debug {}
+
+impl Accept for TcpListener {
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Box<dyn Stream>, Peer)>> {
+        let (socket, addr) = ready!(TcpListener::poll_accept(self, cx))?;
+        Poll::Ready(Ok((Box::new(socket), Peer::Tcp(addr))))
+    }
+
+    fn tcp_addr(&self) -> Option<io::Result<SocketAddr>> {
+        Some(self.local_addr())
+    }
+}
+
+#[cfg(unix)]
+impl Accept for UnixListener {
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Box<dyn Stream>, Peer)>> {
+        let (socket, addr) = ready!(UnixListener::poll_accept(self, cx))?;
+        let path = addr.as_pathname().map(PathBuf::from);
+        Poll::Ready(Ok((Box::new(socket), Peer::Unix(path))))
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => addr.fmt(fmt),
+            #[cfg(unix)]
+            Peer::Unix(Some(path)) => write!(fmt, "unix:{}", path.display()),
+            #[cfg(unix)]
+            Peer::Unix(None) => "unix".fmt(fmt),
+        }
+    }
+}
+
+impl Listener {
+    async fn run(&mut self) -> crate::Result<()> {
+        tracing::info!("accepting connections");
+
+        loop {
+            // Forget the connections that were closed.
+            while self.connections.try_join_next().is_some() {}
+
+            // Hold off accepting while the server is paused. The sender
+            // is owned by `self.handle`, so waiting can not fail.
+            let mut paused = self.handle.paused.subscribe();
+            let _ = paused.wait_for(|paused| !paused).await;
+
+            // Pausing while waiting for a connection abandons the accept,
+            // `biased` makes sure a pause wins over a ready connection.
+            let (socket, peer) = tokio::select! {
+                biased;
+                _ = paused.wait_for(|paused| *paused) => continue,
+                socket = self.accept() => socket?,
+            };
+
+            // The place among `max_connections` is only taken once the
+            // handshake completed, see `Handler::admit`.
+            let handshake = match self.limit_handshakes.clone().try_acquire_owned() {
+                Ok(handshake) => handshake,
+                Err(_) => {
+                    tracing::warn!(%peer, "too many handshakes in progress, closing connection");
+                    continue;
+                }
+            };
+
+            let id = self.next_id;
+            self.next_id += 1;
+
+            let connection_config = self.connection_config();
+            let config = self.config.clone();
+            let limit_connections = self.limit_connections.clone();
+            let op_log = self.op_log.clone();
+            let audit = self.audit.clone();
+            let rate_limiter = self.rate_limiter.clone();
+            let offload_breaker = self.offload_breaker.clone();
+            let connection_rate_limiter = self.config.connection_rate_limit.map(TokenBucket::new);
+            let registers = match &self.shared_registers {
+                Some(values) => Registers::shared(values.clone(), &self.config),
+                None => Registers::new(&self.config),
+            };
+            let handle = self.handle.clone();
+            let shutdown = self.notify_shutdown.subscribe();
+            #[cfg(feature = "tls")]
+            let tls = self.tls.clone();
+
+            let activity = Arc::new(Mutex::new(Activity::new("accepted")));
+            if let Some(interval) = self.config.watchdog_interval {
+                let activity = Arc::downgrade(&activity);
+                tokio::spawn(watch_activity(id, activity, interval, |warning| {
+                    tracing::warn!("{}", warning)
+                }));
+            }
+
+            let span = tracing::info_span!("connection", id, %peer, client = field::Empty);
+            let task = async move {
+                tracing::debug!("accepted connection");
+
+                // The TLS handshake is part of the handshake of the
+                // connection, it is limited by the same permit. A client
+                // that stalls it is closed after the idle timeout, and on
+                // shutdown.
+                #[cfg(feature = "tls")]
+                let mut shutdown = shutdown;
+                #[cfg(feature = "tls")]
+                let stream: Box<dyn Stream> = match tls {
+                    Some(tls) => {
+                        let timeout = config.idle_timeout.unwrap_or(TLS_HANDSHAKE_TIMEOUT);
+                        let accept = tokio_rustls::TlsAcceptor::from(tls).accept(socket);
+                        let accepted = tokio::select! {
+                            accepted = time::timeout(timeout, accept) => accepted,
+                            _ = shutdown.recv() => return,
+                        };
+                        match accepted {
+                            Ok(Ok(stream)) => Box::new(stream),
+                            Ok(Err(err)) => {
+                                tracing::warn!(%err, "TLS handshake failed");
+                                return;
+                            }
+                            Err(_) => {
+                                tracing::warn!("TLS handshake timed out");
+                                return;
+                            }
+                        }
+                    }
+                    None => socket,
+                };
+                #[cfg(not(feature = "tls"))]
+                let stream = socket;
+
+                let mut handler = Handler {
+                    connection: Connection::with_config(stream, connection_config),
+                    config,
+                    array_remaining: 0,
+                    client_name: None,
+                    peer,
+                    span: tracing::Span::current(),
+                    op_log,
+                    audit,
+                    rate_limiter,
+                    offload_breaker,
+                    connection_rate_limiter,
+                    activity,
+                    workers: None,
+                    registers,
+                    version: None,
+                    handle,
+                    handshake: Some(handshake),
+                    limit_connections,
+                    admitted: None,
+                    shutdown,
+                };
+
+                match handler.run().await {
+                    Ok(()) => tracing::debug!("connection closed"),
+                    Err(err) => tracing::warn!(%err, "connection error"),
+                }
+            };
+            self.connections.spawn(task.instrument(span));
+        }
+    }
+
+    fn connection_config(&self) -> ConnectionConfig {
+        ConnectionConfig {
+            encoding: self.config.encoding,
+            max_frame_len: self.config.max_frame_len,
+            checksum: self.config.checksum,
+            write_timeout: self.config.write_timeout,
+            read_buffer_capacity: self.config.read_buffer_capacity,
+            max_read_buffer_capacity: self.config.max_read_buffer_capacity,
+            ..ConnectionConfig::default()
+        }
+    }
+
+    // Notify the handlers of the shutdown and wait for them to close
+    // their connections, aborting those still open after the drain
+    // timeout.
+    async fn drain(&mut self) {
+        // Closing the listeners refuses new clients right away instead of
+        // leaving them in the backlog until the drain ends.
+        self.listeners.clear();
+
+        // Fails when no connection is open, there is nothing to drain then.
+        let _ = self.notify_shutdown.send(());
+
+        let closed = async { while self.connections.join_next().await.is_some() {} };
+        if time::timeout(self.config.drain_timeout, closed)
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                open = self.connections.len(),
+                drain_timeout = ?self.config.drain_timeout,
+                "aborting connections still open after the drain timeout"
+            );
+            self.connections.shutdown().await;
+        }
+    }
+
+    // Accept a connection on whichever listener has one first.
+    async fn accept_any(&mut self) -> io::Result<(Box<dyn Stream>, Peer)> {
+        let start = self.next_listener;
+        self.next_listener = (start + 1) % self.listeners.len().max(1);
+
+        let listeners = &self.listeners;
+        std::future::poll_fn(|cx| {
+            for i in 0..listeners.len() {
+                let listener = &listeners[(start + i) % listeners.len()];
+                if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                    return Poll::Ready(accepted);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    // Accept the next connection. Failures of the accepting socket, e.g.
+    // running out of file descriptors, are retried with `Backoff` until
+    // the backoff exceeds `ServerConfig::max_accept_backoff`, the error is
+    // returned then.
+    async fn accept(&mut self) -> crate::Result<(Box<dyn Stream>, Peer)> {
+        let mut backoff = Backoff::new(&self.config);
+
+        loop {
+            let err = match self.accept_any().await {
+                Ok(accepted) => return Ok(accepted),
+                // Only the connection that was being accepted failed, the
+                // next one can be accepted right away.
+                Err(err) if is_connection_error(&err) => {
+                    tracing::debug!(%err, "failed to accept connection");
+                    continue;
+                }
+                Err(err) => err,
+            };
+
+            match backoff.next_delay() {
+                Some(delay) => {
+                    tracing::warn!(%err, ?delay, "failed to accept connection, retrying");
+                    time::sleep(delay).await;
+                }
+                None => return Err(err.into()),
+            }
+        }
+    }
+}
+
+// Errors of `accept` that concern the connection being accepted, not the
+// listener.
+pub(crate) fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+    )
+}
+
+// Delays between retries of a failing accept, doubling from
+// `ServerConfig::accept_backoff`. Every delay is shortened by a random
+// amount of up to half, so servers that fail at the same time do not
+// retry in lockstep.
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(config: &ServerConfig) -> Backoff {
+        Backoff {
+            // A zero delay would never double, the accept loop would spin.
+            next: config.accept_backoff.max(MIN_ACCEPT_BACKOFF),
+            max: config.max_accept_backoff,
+        }
+    }
+
+    // The delay before the next retry, `None` once the backoff exceeds
+    // the maximum.
+    pub(crate) fn next_delay(&mut self) -> Option<Duration> {
+        if self.next > self.max {
+            return None;
+        }
+        let delay = self.next;
+        self.next = self.next.saturating_mul(2);
+        Some(jitter(delay))
+    }
+}
+
+// A random duration between half of `delay` and `delay`.
+fn jitter(delay: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    // Every `RandomState` is seeded differently, good enough to spread
+    // retries without depending on a random number generator.
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    let half = delay / 2;
+    half + half.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+#[tokio::test]
+async fn test_version_response() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Version).await.unwrap();
+
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::VersionInfo(version)) => assert_eq!(env!("CARGO_PKG_VERSION"), version),
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+// Every request on a connection is answered, not only the first one, and
+// the handler keeps serving until the peer closes the connection.
+#[tokio::test]
+async fn test_sequential_requests_on_one_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(listener);
+    let handle = server.handle();
+    tokio::spawn(server.run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    for n in 1..=5 {
+        connection
+            .write_frame(&Frame::Multiplication(n, 10))
+            .await
+            .unwrap();
+        match connection.read_frame().await.unwrap() {
+            Some(Frame::OpResult(result)) => assert_eq!(n * 10, result),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+    assert_eq!(5, handle.requests_served());
+
+    connection.shutdown().await.unwrap();
+    assert!(connection.read_frame().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_pause_and_resume_accepting() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(listener);
+    let handle = server.handle();
+    tokio::spawn(server.run());
+
+    handle.pause();
+    assert!(handle.is_paused());
+
+    // The OS completes the connect, but the server does not accept it.
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Version).await.unwrap();
+    let response = time::timeout(Duration::from_millis(200), connection.read_frame()).await;
+    assert!(response.is_err());
+
+    handle.resume();
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::VersionInfo(_)) => {}
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+#[test]
+fn test_compute() {
+    use ComputeError::*;
+    use Token::*;
+
+    let max = u64::MAX;
+    let cases = [
+        (Frame::Addition(10, 32), Ok(42)),
+        (Frame::Addition(0, 0), Ok(0)),
+        (Frame::Addition(max, 0), Ok(max)),
+        (Frame::Addition(max - 1, 1), Ok(max)),
+        (Frame::Addition(max, 1), Err(Overflow)),
+        (Frame::Addition(max, max), Err(Overflow)),
+        (Frame::Subtraction(42, 10), Ok(32)),
+        (Frame::Subtraction(10, 10), Ok(0)),
+        (Frame::Subtraction(max, max), Ok(0)),
+        (Frame::Subtraction(0, 1), Err(Underflow)),
+        (Frame::Subtraction(10, 42), Err(Underflow)),
+        (Frame::Multiplication(6, 7), Ok(42)),
+        (Frame::Multiplication(max, 0), Ok(0)),
+        (Frame::Multiplication(max, 1), Ok(max)),
+        (Frame::Multiplication(1 << 32, 1 << 31), Ok(1 << 63)),
+        (Frame::Multiplication(1 << 32, 1 << 32), Err(Overflow)),
+        (Frame::Multiplication(max, 2), Err(Overflow)),
+        (Frame::Modulo(17, 5), Ok(2)),
+        (Frame::Modulo(5, 17), Ok(5)),
+        (Frame::Modulo(max, max), Ok(0)),
+        (Frame::Modulo(17, 0), Err(DivisionByZero)),
+        (Frame::Division(17, 5), Ok(3)),
+        (Frame::Division(max, 1), Ok(max)),
+        (Frame::Division(0, 0), Err(DivisionByZero)),
+        (Frame::Pow(3, 4), Ok(81)),
+        (Frame::Pow(10, 20), Err(Overflow)),
+        (Frame::Factorial(10), Ok(3628800)),
+        (Frame::Factorial(25), Err(Overflow)),
+        (Frame::Gcd(12, 18), Ok(6)),
+        (Frame::Lcm(12, 18), Ok(36)),
+        (Frame::Lcm(max, 2), Err(Overflow)),
+        (
+            Frame::Aggregate(frame::Aggregate::Mean, vec![max, 1]),
+            Ok(max / 2 + 1),
+        ),
+        (
+            Frame::Aggregate(frame::Aggregate::Max, vec![]),
+            Err(EmptyOperands),
+        ),
+        (Frame::OpResult(7), Ok(7)),
+        (
+            Frame::Rpn(vec![Number(3), Number(4), Add, Number(2), Mul]),
+            Ok(14),
+        ),
+        (
+            Frame::Rpn(vec![Number(3), Number(4), Number(2), Mul, Sub]),
+            Err(Underflow),
+        ),
+        (
+            Frame::Rpn(vec![Number(9), Number(4), Number(2), Mul, Sub]),
+            Ok(1),
+        ),
+        (Frame::Rpn(vec![Number(7)]), Ok(7)),
+        (Frame::Rpn(vec![Number(3), Add]), Err(StackUnderflow)),
+        (Frame::Rpn(vec![Mul]), Err(StackUnderflow)),
+        (Frame::Rpn(vec![]), Err(StackUnderflow)),
+        (
+            Frame::Rpn(vec![Number(3), Number(4)]),
+            Err(LeftoverOperands),
+        ),
+        (Frame::Rpn(vec![Number(max), Number(1), Add]), Err(Overflow)),
+    ];
+
+    for (frame, expected) in cases {
+        let result = compute(&frame).map(|response| match response {
+            Frame::OpResult(r) => r,
+            other => panic!("unexpected response {:?} for {:?}", other, frame),
+        });
+        assert_eq!(expected, result, "{:?}", frame);
+    }
+
+    assert!(matches!(compute(&Frame::Ping), Ok(Frame::Pong)));
+    match compute(&Frame::Version) {
+        Ok(Frame::VersionInfo(version)) => assert_eq!(VERSION, version),
+        other => panic!("unexpected response {:?}", other),
+    }
+    assert_eq!(
+        Err(UnexpectedFrame),
+        compute(&Frame::VersionInfo(VERSION.to_string())).map(|_| ())
+    );
+    match compute(&Frame::Array(vec![
+        Frame::Addition(1, 2),
+        Frame::Subtraction(1, 2),
+    ])) {
+        Ok(Frame::Array(responses)) => assert!(matches!(
+            responses[..],
+            [Frame::OpResult(3), Frame::Error(ErrorCode::Underflow, _)]
+        )),
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_recover_from_compute_error() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        recover_on_protocol_error: true,
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection
+        .write_frame(&Frame::Addition(u64::MAX, 1))
+        .await
+        .unwrap();
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::Error(ErrorCode::Overflow, message)) => {
+            assert_eq!("arithmetic overflow", message)
+        }
+        other => panic!("unexpected response {:?}", other),
+    }
+
+    // The connection is still served after the error.
+    connection.write_frame(&Frame::Version).await.unwrap();
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::VersionInfo(_)) => {}
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_overflow_answered_with_error_frame() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // Without recovery, only protocol errors close the connection.
+    tokio::spawn(Server::new(listener).run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    for request in [
+        Frame::Addition(u64::MAX, 1),
+        Frame::Multiplication(u64::MAX, u64::MAX),
+        Frame::Sum(vec![u64::MAX, u64::MAX, 0]),
+    ] {
+        connection.write_frame(&request).await.unwrap();
+        assert!(
+            matches!(
+                connection.read_frame().await.unwrap(),
+                Some(Frame::Error(ErrorCode::Overflow, _))
+            ),
+            "{:?}",
+            request
+        );
+    }
+
+    connection
+        .write_frame(&Frame::Multiplication(u64::MAX, 1))
+        .await
+        .unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::OpResult(u64::MAX))
+    ));
+}
+
+#[tokio::test]
+async fn test_division_by_zero_keeps_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    for request in [
+        Frame::Division(1, 0),
+        Frame::Modulo(1, 0),
+        Frame::Expr("(2+3)/(1-1)".into()),
+    ] {
+        connection.write_frame(&request).await.unwrap();
+        match connection.read_frame().await.unwrap() {
+            Some(Frame::Error(ErrorCode::DivisionByZero, message)) => {
+                assert_eq!("division by zero", message)
+            }
+            other => panic!("unexpected response {:?} to {:?}", other, request),
+        }
+    }
+
+    // The connection is still served after the errors.
+    connection
+        .write_frame(&Frame::Division(42, 5))
+        .await
+        .unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::OpResult(8))
+    ));
+}
+
+#[tokio::test]
+async fn test_recover_from_invalid_frame() {
+    use tokio::io::AsyncWriteExt;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        recover_on_protocol_error: true,
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(b"?1:2\r\n+1:\r\nv\r\n").await.unwrap();
+    let mut connection = Connection::new(socket);
+
+    for _ in 0..2 {
+        match connection.read_frame().await.unwrap() {
+            Some(Frame::Error(ErrorCode::Protocol, _)) => {}
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::VersionInfo(_)) => {}
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_session_expires() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        max_session_duration: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let start = Instant::now();
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Version).await.unwrap();
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::VersionInfo(_)) => {}
+        other => panic!("unexpected response {:?}", other),
+    }
+
+    // The client stays connected without sending anything.
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::Error(ErrorCode::SessionExpired, _)) => {}
+        other => panic!("unexpected response {:?}", other),
+    }
+    assert!(connection.read_frame().await.unwrap().is_none());
+    assert!(start.elapsed() >= Duration::from_secs(60));
+}
+
+#[tokio::test]
+async fn test_interleaved_ping() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    for frame in [Frame::Addition(1, 2), Frame::Ping, Frame::Addition(3, 4)] {
+        connection.write_frame(&frame).await.unwrap();
+    }
+
+    let mut responses = Vec::new();
+    for _ in 0..3 {
+        responses.push(connection.read_frame().await.unwrap().unwrap());
+    }
+    assert!(matches!(
+        responses[..],
+        [Frame::OpResult(3), Frame::Pong, Frame::OpResult(7)]
+    ));
+}
+
+#[tokio::test]
+async fn test_operand_limit_and_overflow_codes() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        recover_on_protocol_error: true,
+        max_operand: Some(1 << 40),
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let requests = [
+        (Frame::Addition(u64::MAX, 1), ErrorCode::OperandLimit),
+        (Frame::Multiplication(1 << 40, 1 << 40), ErrorCode::Overflow),
+    ];
+    for (request, code) in requests {
+        connection.write_frame(&request).await.unwrap();
+        match connection.read_frame().await.unwrap() {
+            Some(Frame::Error(actual, _)) => assert_eq!(code, actual, "{:?}", request),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_bind_with_backlog() {
+    let config = ServerConfig {
+        listen_backlog: 16,
+        ..Default::default()
+    };
+    let listener = bind("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Ping).await.unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::Pong)
+    ));
+}
+
+#[tokio::test]
+async fn test_builder_limits() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::builder()
+        .max_connections(1)
+        .read_buffer_capacity(64, 256)
+        .build(listener);
+    tokio::spawn(server.run());
+
+    let mut first = Connection::new(TcpStream::connect(addr).await.unwrap());
+    first.write_frame(&Frame::Ping).await.unwrap();
+    assert!(matches!(
+        first.read_frame().await.unwrap(),
+        Some(Frame::Pong)
+    ));
+
+    // The second connection waits until the first one is closed.
+    let mut second = Connection::new(TcpStream::connect(addr).await.unwrap());
+    second.write_frame(&Frame::Ping).await.unwrap();
+    let waiting = time::timeout(Duration::from_millis(100), second.read_frame()).await;
+    assert!(waiting.is_err());
+    drop(first);
+    assert!(matches!(
+        second.read_frame().await.unwrap(),
+        Some(Frame::Pong)
+    ));
+
+    // The address is already in use.
+    assert!(Server::builder().bind(addr).is_err());
+}
+
+#[tokio::test]
+async fn test_streamed_array() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run());
+
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (mut read_half, mut write_half) = Connection::new(socket).into_split();
+
+    // Results come back while the rest of the array is still being sent.
+    write_half
+        .write_frame(&Frame::ArrayStart(1000))
+        .await
+        .unwrap();
+    write_half
+        .write_frame(&Frame::Addition(0, 1))
+        .await
+        .unwrap();
+    assert!(matches!(
+        read_half.read_frame().await.unwrap(),
+        Some(Frame::ArrayStart(1000))
+    ));
+    assert!(matches!(
+        read_half.read_frame().await.unwrap(),
+        Some(Frame::OpResult(1))
+    ));
+
+    let sender = tokio::spawn(async move {
+        for i in 1..1000 {
+            write_half
+                .write_frame(&Frame::Addition(i, 1))
+                .await
+                .unwrap();
+        }
+        write_half
+    });
+    for i in 1..1000 {
+        match read_half.read_frame().await.unwrap() {
+            Some(Frame::OpResult(r)) => assert_eq!(i + 1, r),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    // The array is complete, a new one can be started.
+    let mut write_half = sender.await.unwrap();
+    write_half.write_frame(&Frame::ArrayStart(0)).await.unwrap();
+    write_half.write_frame(&Frame::Ping).await.unwrap();
+    assert!(matches!(
+        read_half.read_frame().await.unwrap(),
+        Some(Frame::ArrayStart(0))
+    ));
+    assert!(matches!(
+        read_half.read_frame().await.unwrap(),
+        Some(Frame::Pong)
+    ));
+}
+
+#[tokio::test]
+async fn test_binary_encoding() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        encoding: Encoding::Binary,
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let mut connection = Connection::with_encoding(socket, Encoding::Binary);
+    connection
+        .write_frame(&Frame::Multiplication(6, 7))
+        .await
+        .unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::OpResult(42))
+    ));
+    connection.write_frame(&Frame::Ping).await.unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::Pong)
+    ));
+}
+
+#[tokio::test]
+async fn test_expression() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        recover_on_protocol_error: true,
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let mut client = crate::Client::connect(addr).await.unwrap();
+    let response = client.call(&Frame::Expr("2+3*4".into())).await.unwrap();
+    assert!(matches!(response, Frame::OpResult(14)));
+
+    let err = client.call(&Frame::Expr("2+".into())).await.unwrap_err();
+    assert!(matches!(
+        err,
+        crate::Error::Server(err) if err.code == ErrorCode::InvalidExpression
+    ));
+}
+
+#[tokio::test]
+async fn test_batched_array() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let batch = Frame::Array(vec![
+        Frame::Addition(2, 3),
+        Frame::Subtraction(1, 2),
+        Frame::Sort(vec![3, 1, 2]),
+    ]);
+    connection.write_frame(&batch).await.unwrap();
+
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::Array(responses)) => assert!(
+            matches!(
+                &responses[..],
+                [
+                    Frame::OpResult(5),
+                    Frame::Error(ErrorCode::Underflow, _),
+                    Frame::Array(sorted),
+                ] if matches!(
+                    &sorted[..],
+                    [Frame::OpResult(1), Frame::OpResult(2), Frame::OpResult(3)]
+                )
+            ),
+            "{:?}",
+            responses
+        ),
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_identify_names_client() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = TcpStream::connect(addr).await.unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+    let mut handler = Handler::for_test(socket, ServerConfig::default());
+    assert_eq!(None, handler.client_name);
+
+    let mut client = Connection::new(client);
+    client
+        .write_frame(&Frame::Identify("billing-service".to_string()))
+        .await
+        .unwrap();
+    let frame = handler.connection.read_frame().await.unwrap().unwrap();
+    handler.handle_request(&frame).await;
+
+    match client.read_frame().await.unwrap() {
+        Some(Frame::Identify(name)) => assert_eq!("billing-service", name),
+        other => panic!("unexpected response {:?}", other),
+    }
+    assert_eq!(Some("billing-service"), handler.client_name.as_deref());
+}
+
+#[tokio::test]
+async fn test_identify_names_client_in_logs() {
+    // Collects the log lines of the connection tasks, which run on this
+    // thread.
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let logs = Logs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _default = tracing::subscriber::set_default(subscriber);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run());
+
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let requests = [
+        Frame::Identify("billing-service".to_string()),
+        Frame::Addition(1, 2),
+    ];
+    for request in &requests {
+        client.write_frame(request).await.unwrap();
+        client.read_frame().await.unwrap();
+    }
+
+    // The request after the identification is logged with the name.
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let line = logs
+        .lines()
+        .find(|line| line.contains("request=ADD 1 2"))
+        .unwrap_or_else(|| panic!("no log line of the request in {:?}", logs));
+    assert!(line.contains("client=billing-service"), "{:?}", line);
+}
+
+// A handler serves any stream, not only a `TcpStream`.
+#[tokio::test]
+async fn test_handler_over_duplex() {
+    let (client, server) = tokio::io::duplex(1024);
+    let mut handler = Handler::for_test(server, ServerConfig::default());
+    tokio::spawn(async move { handler.run().await });
+
+    let mut client = crate::Client::handshake(client, PROTOCOL_VERSION)
+        .await
+        .unwrap();
+    assert_eq!(PROTOCOL_VERSION, client.version());
+    assert!(matches!(
+        client.call(&Frame::Multiplication(6, 7)).await,
+        Ok(Frame::OpResult(42))
+    ));
+}
+
+// Pings are answered by the handler before the rate limit and the
+// workers, so a heartbeat gets through while both hold back requests.
+#[test]
+fn test_ping_answered_by_handler() {
+    use crate::frame::Tag;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .max_blocking_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            request_workers: 1,
+            blocking_cost_threshold: Some(10),
+            connection_rate_limit: NonZeroU32::new(1),
+            rate_limit_exceeded: RateLimitExceeded::SlowDown,
+            ..Default::default()
+        };
+        tokio::spawn(Server::with_config(listener, config).run());
+
+        // The tagged factorial waits for the only blocking thread on the
+        // only worker, and takes the only token of the connection.
+        let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let (release, busy) = std::sync::mpsc::channel::<()>();
+        let blocker = tokio::task::spawn_blocking(move || busy.recv());
+        let tag = Tag { id: 1, priority: 0 };
+        let requests = [
+            Frame::Tagged(tag, Box::new(Frame::Factorial(20))),
+            Frame::Addition(1, 2),
+            Frame::Ping,
+        ];
+        for request in &requests {
+            connection.feed_frame(request).await.unwrap();
+        }
+        connection.flush().await.unwrap();
+
+        assert!(matches!(
+            connection.read_frame().await.unwrap(),
+            Some(Frame::Error(ErrorCode::SlowDown, _))
+        ));
+        assert!(matches!(
+            connection.read_frame().await.unwrap(),
+            Some(Frame::Pong)
+        ));
+
+        release.send(()).unwrap();
+        blocker.await.unwrap().unwrap();
+        match connection.read_frame().await.unwrap() {
+            Some(Frame::Tagged(actual, response)) => {
+                assert_eq!(tag, actual);
+                assert!(matches!(*response, Frame::OpResult(2432902008176640000)));
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    });
+}
+
+#[test]
+fn test_response_array_limit() {
+    let config = ServerConfig {
+        max_response_array_len: 4,
+        ..Default::default()
+    };
+
+    let request = Frame::Array(vec![Frame::Ping; 4]);
+    assert_eq!(Ok(()), check_limits(&request, &config));
+
+    let request = Frame::Array(vec![Frame::Ping; 5]);
+    assert_eq!(
+        Err(ComputeError::ResponseTooLarge),
+        check_limits(&request, &config)
+    );
+    assert_eq!(
+        ErrorCode::ResponseTooLarge,
+        ComputeError::ResponseTooLarge.code()
+    );
+}
+
+#[test]
+fn test_nested_operand_limit() {
+    use crate::frame::Tag;
+
+    let config = ServerConfig {
+        max_operand: Some(10),
+        ..Default::default()
+    };
+
+    let within = Frame::Array(vec![Frame::Addition(1, 2), Frame::Factorial(10)]);
+    assert_eq!(Ok(()), check_limits(&within, &config));
+
+    let tag = Tag { id: 1, priority: 0 };
+    let requests = [
+        Frame::Array(vec![Frame::Addition(1, 2), Frame::Addition(11, 2)]),
+        Frame::Array(vec![Frame::Array(vec![Frame::Sum(vec![1, 2, 11])])]),
+        Frame::Array(vec![Frame::Tagged(tag, Box::new(Frame::Factorial(11)))]),
+        Frame::Tagged(tag, Box::new(Frame::Array(vec![Frame::Pow(2, 11)]))),
+    ];
+    for request in requests {
+        assert_eq!(
+            Err(ComputeError::OperandLimit),
+            check_limits(&request, &config),
+            "{:?}",
+            request
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_op_log_appends_operations() {
+    let path = std::env::temp_dir().join(format!("op-log-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        op_log_path: Some(path.clone()),
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let peer = socket.local_addr().unwrap();
+    let mut connection = Connection::new(socket);
+    for frame in [
+        Frame::Addition(2, 3),
+        Frame::Subtraction(9, 4),
+        Frame::Multiplication(6, 7),
+        Frame::Ping,
+    ] {
+        connection.write_frame(&frame).await.unwrap();
+        connection.read_frame().await.unwrap().unwrap();
+    }
+
+    let expected = [
+        format!("peer={} op=add operands=2,3 result=5", peer),
+        format!("peer={} op=sub operands=9,4 result=5", peer),
+        format!("peer={} op=mul operands=6,7 result=42", peer),
+    ];
+
+    // Lines are written in the background, wait for the last one.
+    let mut contents = String::new();
+    for _ in 0..100 {
+        contents = std::fs::read_to_string(&path).unwrap_or_default();
+        if contents.lines().count() >= expected.len() {
+            break;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    std::fs::remove_file(&path).unwrap();
+
+    let lines: Vec<_> = contents.lines().collect();
+    assert_eq!(expected.len(), lines.len(), "{}", contents);
+    for (line, expected) in lines.iter().zip(&expected) {
+        let (timestamp, rest) = line.split_once(' ').unwrap();
+        assert!(timestamp.parse::<u128>().is_ok(), "{}", line);
+        assert_eq!(expected, rest);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_limit_serves_light_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        global_rate_limit: NonZeroU32::new(20),
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    // The greedy connection queues two seconds worth of requests, and
+    // drains the initial burst before the light connection shows up.
+    let mut greedy = Connection::new(TcpStream::connect(addr).await.unwrap());
+    for _ in 0..40 {
+        greedy.feed_frame(&Frame::Ping).await.unwrap();
+    }
+    greedy.flush().await.unwrap();
+    for _ in 0..22 {
+        assert!(matches!(
+            greedy.read_frame().await.unwrap(),
+            Some(Frame::Pong)
+        ));
+    }
+
+    let mut light = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let start = Instant::now();
+    light.write_frame(&Frame::Ping).await.unwrap();
+    assert!(matches!(
+        light.read_frame().await.unwrap(),
+        Some(Frame::Pong)
+    ));
+
+    // Served after about one greedy request instead of the 18 queued ones.
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+}
+
+#[tokio::test]
+async fn test_connection_rate_limit_slow_down() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::builder()
+        .connection_rate_limit(NonZeroU32::new(5).unwrap(), RateLimitExceeded::SlowDown)
+        .build(listener);
+    tokio::spawn(server.run());
+
+    // The burst of one second is served, the rest is dropped.
+    let mut chatty = Connection::new(TcpStream::connect(addr).await.unwrap());
+    for _ in 0..8 {
+        chatty.feed_frame(&Frame::Addition(1, 2)).await.unwrap();
+    }
+    chatty.flush().await.unwrap();
+    for _ in 0..5 {
+        assert!(matches!(
+            chatty.read_frame().await.unwrap(),
+            Some(Frame::OpResult(3))
+        ));
+    }
+    for _ in 0..3 {
+        assert!(matches!(
+            chatty.read_frame().await.unwrap(),
+            Some(Frame::Error(ErrorCode::SlowDown, _))
+        ));
+    }
+
+    // Other connections have limits of their own.
+    let mut quiet = Connection::new(TcpStream::connect(addr).await.unwrap());
+    quiet.write_frame(&Frame::Addition(1, 2)).await.unwrap();
+    assert!(matches!(
+        quiet.read_frame().await.unwrap(),
+        Some(Frame::OpResult(3))
+    ));
+}
+
+#[tokio::test]
+async fn test_connection_rate_limit_streamed_array() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::builder()
+        .connection_rate_limit(NonZeroU32::new(2).unwrap(), RateLimitExceeded::SlowDown)
+        .build(listener);
+    tokio::spawn(server.run());
+
+    // The header is always echoed, the element over the limit is answered
+    // with the error and still ends the array.
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.feed_frame(&Frame::ArrayStart(3)).await.unwrap();
+    for _ in 0..3 {
+        connection.feed_frame(&Frame::Addition(1, 2)).await.unwrap();
+    }
+    connection.feed_frame(&Frame::ArrayStart(1)).await.unwrap();
+    connection.feed_frame(&Frame::Addition(1, 2)).await.unwrap();
+    connection.flush().await.unwrap();
+
+    let mut responses = Vec::new();
+    for _ in 0..6 {
+        responses.push(connection.read_frame().await.unwrap().unwrap());
+    }
+    assert!(
+        matches!(
+            responses[..],
+            [
+                Frame::ArrayStart(3),
+                Frame::OpResult(3),
+                Frame::OpResult(3),
+                Frame::Error(ErrorCode::SlowDown, _),
+                Frame::ArrayStart(1),
+                Frame::Error(ErrorCode::SlowDown, _),
+            ]
+        ),
+        "{:?}",
+        responses
+    );
+}
+
+#[test]
+fn test_batch_result_references() {
+    use Token::*;
+
+    let batch = Frame::Array(vec![
+        Frame::Addition(2, 3),
+        Frame::Rpn(vec![Ref(0), Number(4), Mul]),
+        Frame::Subtraction(1, 2),
+        Frame::Rpn(vec![Ref(2), Number(1), Add]),
+        Frame::Rpn(vec![Ref(9)]),
+    ]);
+    match compute(&batch) {
+        Ok(Frame::Array(responses)) => assert!(
+            matches!(
+                responses[..],
+                [
+                    Frame::OpResult(5),
+                    Frame::OpResult(20),
+                    Frame::Error(ErrorCode::Underflow, _),
+                    Frame::Error(ErrorCode::InvalidExpression, _),
+                    Frame::Error(ErrorCode::InvalidExpression, _),
+                ]
+            ),
+            "{:?}",
+            responses
+        ),
+        other => panic!("unexpected response {:?}", other),
+    }
+
+    // There is nothing to reference outside of an array.
+    assert_eq!(
+        Err(ComputeError::InvalidReference),
+        compute(&Frame::Rpn(vec![Ref(0)])).map(|_| ())
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_watchdog_warns_stalled_handler() {
+    let activity = Arc::new(Mutex::new(Activity::new("read a frame")));
+    let (warnings, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let interval = Duration::from_secs(10);
+    tokio::spawn(watch_activity(
+        7,
+        Arc::downgrade(&activity),
+        interval,
+        move |warning| warnings.send(warning).unwrap(),
+    ));
+
+    // Stalled for one interval, warned once.
+    time::sleep(Duration::from_secs(25)).await;
+    let warning = rx.try_recv().unwrap();
+    assert!(
+        warning.starts_with("connection 7 made no progress"),
+        "{}",
+        warning
+    );
+    assert!(warning.ends_with("last read a frame"), "{}", warning);
+    assert!(rx.try_recv().is_err());
+
+    // Progress was made, the next stall is warned about again.
+    *activity.lock().unwrap() = Activity::new("wrote a response");
+    time::sleep(Duration::from_secs(5)).await;
+    assert!(rx.try_recv().is_err());
+    time::sleep(Duration::from_secs(20)).await;
+    assert!(rx.try_recv().unwrap().ends_with("last wrote a response"));
+
+    // The watchdog ends with the handler.
+    drop(activity);
+    time::sleep(interval).await;
+    assert!(rx.recv().await.is_none());
+}
+
+#[test]
+fn test_sort() {
+    match compute(&Frame::Sort(vec![3, 1, 2])) {
+        Ok(Frame::Array(responses)) => assert!(
+            matches!(
+                responses[..],
+                [Frame::OpResult(1), Frame::OpResult(2), Frame::OpResult(3)]
+            ),
+            "{:?}",
+            responses
+        ),
+        other => panic!("unexpected response {:?}", other),
+    }
+    assert!(matches!(
+        compute(&Frame::Sort(vec![])),
+        Ok(Frame::Array(responses)) if responses.is_empty()
+    ));
+
+    let config = ServerConfig {
+        max_response_array_len: 2,
+        ..Default::default()
+    };
+    assert_eq!(
+        Err(ComputeError::ResponseTooLarge),
+        check_limits(&Frame::Sort(vec![3, 1, 2]), &config)
+    );
+
+    // The limit applies to every array of the response, not only the
+    // outermost one.
+    let sort = || Box::new(Frame::Sort(vec![3, 1, 2]));
+    let requests = [
+        Frame::Array(vec![*sort()]),
+        Frame::Tagged(crate::frame::Tag { id: 1, priority: 0 }, sort()),
+        #[cfg(feature = "compression")]
+        Frame::Array(vec![Frame::Compressed(sort())]),
+    ];
+    for request in requests {
+        assert_eq!(
+            Err(ComputeError::ResponseTooLarge),
+            check_limits(&request, &config),
+            "{:?}",
+            request
+        );
+    }
+}
+
+#[test]
+fn test_workers_handle_higher_priority_first() {
+    use crate::frame::Tag;
+
+    async fn read_tagged(connection: &mut Connection) -> (Tag, Frame) {
+        match connection.read_frame().await.unwrap() {
+            Some(Frame::Tagged(tag, response)) => (tag, *response),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .max_blocking_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            request_workers: 1,
+            blocking_cost_threshold: Some(10),
+            ..Default::default()
+        };
+        tokio::spawn(Server::with_config(listener, config).run());
+
+        // The only worker waits for the only blocking thread with the
+        // offloaded factorial, it is taken first whenever the worker
+        // starts. The other requests queue up behind it.
+        let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let (release, busy) = std::sync::mpsc::channel::<()>();
+        let blocker = tokio::task::spawn_blocking(move || busy.recv());
+        let slow = Tag { id: 1, priority: 9 };
+        let low = Tag { id: 2, priority: 0 };
+        let high = Tag { id: 3, priority: 5 };
+        let requests = [
+            Frame::Tagged(slow, Box::new(Frame::Factorial(20))),
+            Frame::Tagged(low, Box::new(Frame::Addition(1, 1))),
+            Frame::Tagged(high, Box::new(Frame::Multiplication(6, 7))),
+            Frame::Ping,
+        ];
+        for request in &requests {
+            connection.feed_frame(request).await.unwrap();
+        }
+        connection.flush().await.unwrap();
+
+        // The handler answers the ping itself, after it queued the
+        // requests before it.
+        assert!(matches!(
+            connection.read_frame().await.unwrap(),
+            Some(Frame::Pong)
+        ));
+        release.send(()).unwrap();
+        blocker.await.unwrap().unwrap();
+
+        // The low priority request was sent before the high priority one
+        // but is answered last.
+        let (tag, response) = read_tagged(&mut connection).await;
+        assert_eq!(slow, tag);
+        assert!(matches!(response, Frame::OpResult(2432902008176640000)));
+        let (tag, response) = read_tagged(&mut connection).await;
+        assert_eq!(high, tag);
+        assert!(matches!(response, Frame::OpResult(42)));
+        let (tag, response) = read_tagged(&mut connection).await;
+        assert_eq!(low, tag);
+        assert!(matches!(response, Frame::OpResult(2)));
+
+        // A failed tagged request is answered with a tagged error.
+        connection
+            .write_frame(&Frame::Tagged(low, Box::new(Frame::Subtraction(1, 2))))
+            .await
+            .unwrap();
+        let (tag, response) = read_tagged(&mut connection).await;
+        assert_eq!(low, tag);
+        assert!(matches!(response, Frame::Error(ErrorCode::Underflow, _)));
+    });
+}
+
+#[tokio::test]
+async fn test_stopped_workers_fail_the_connection() {
+    let (_, responses) = mpsc::unbounded_channel();
+    let mut workers = Some(Workers {
+        queue: Arc::new(RequestQueue::new()),
+        responses,
+        in_flight: 1,
+        _tasks: JoinSet::new(),
+    });
+
+    // The request in flight is still counted.
+    assert!(next_response(&mut workers).await.is_err());
+    assert_eq!(1, workers.unwrap().in_flight);
+}
+
+#[tokio::test]
+async fn test_tagged_responses_carry_request_id() {
+    use crate::frame::Tag;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        request_workers: 2,
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let requests = [
+        Frame::Addition(1, 2),
+        Frame::Set("x".into(), 5),
+        Frame::Get("x".into()),
+        Frame::Get("y".into()),
+        Frame::Ping,
+    ];
+    for (id, request) in requests.iter().enumerate() {
+        let tag = Tag {
+            id: id as u64,
+            priority: 0,
+        };
+        connection
+            .feed_frame(&Frame::Tagged(tag, Box::new(request.clone())))
+            .await
+            .unwrap();
+    }
+    connection.flush().await.unwrap();
+
+    // Responses may arrive in any order, the id matches them up.
+    let mut responses = vec![None; requests.len()];
+    for _ in 0..requests.len() {
+        match connection.read_frame().await.unwrap() {
+            Some(Frame::Tagged(tag, response)) => responses[tag.id as usize] = Some(*response),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+    assert!(
+        matches!(
+            &responses[..],
+            [
+                Some(Frame::OpResult(3)),
+                Some(Frame::OpResult(5)),
+                Some(Frame::OpResult(5)),
+                Some(Frame::Error(ErrorCode::UnknownName, _)),
+                Some(Frame::Pong),
+            ]
+        ),
+        "{:?}",
+        responses
+    );
+}
+
+#[tokio::test]
+async fn test_pipelined_requests_beyond_max_in_flight() {
+    use crate::frame::Tag;
+
+    // Zero allows a single request in flight.
+    for max_in_flight in [4, 0] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            request_workers: 2,
+            max_in_flight,
+            ..Default::default()
+        };
+        tokio::spawn(Server::with_config(listener, config).run());
+
+        // Far more requests than may be in flight are sent before reading
+        // a single response, every one of them is answered.
+        let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let count = 100;
+        for id in 0..count {
+            let tag = Tag { id, priority: 0 };
+            connection
+                .feed_frame(&Frame::Tagged(tag, Box::new(Frame::Addition(id, 1))))
+                .await
+                .unwrap();
+        }
+        connection.flush().await.unwrap();
+
+        let mut answered = vec![false; count as usize];
+        for _ in 0..count {
+            match connection.read_frame().await.unwrap() {
+                Some(Frame::Tagged(tag, response)) => {
+                    assert!(matches!(*response, Frame::OpResult(sum) if sum == tag.id + 1));
+                    answered[tag.id as usize] = true;
+                }
+                other => panic!("unexpected response {:?}", other),
+            }
+        }
+        assert!(
+            answered.iter().all(|answered| *answered),
+            "{}",
+            max_in_flight
+        );
+    }
+}
+
+#[test]
+fn test_restore_register_snapshot() {
+    let config = ServerConfig::default();
+    let mut registers = Registers::new(&config);
+    let mut apply = |frame: Frame| registers.apply(&frame, &config).unwrap();
+
+    assert!(matches!(
+        apply(Frame::Set("x".into(), 1)),
+        Ok(Frame::OpResult(1))
+    ));
+    assert!(matches!(
+        apply(Frame::Save("before".into())),
+        Ok(Frame::Save(_))
+    ));
+    assert!(matches!(
+        apply(Frame::Set("x".into(), 2)),
+        Ok(Frame::OpResult(2))
+    ));
+    assert!(matches!(
+        apply(Frame::Set("y".into(), 3)),
+        Ok(Frame::OpResult(3))
+    ));
+    assert!(matches!(
+        apply(Frame::Get("x".into())),
+        Ok(Frame::OpResult(2))
+    ));
+
+    assert!(matches!(
+        apply(Frame::Restore("before".into())),
+        Ok(Frame::Restore(_))
+    ));
+    assert!(matches!(
+        apply(Frame::Get("x".into())),
+        Ok(Frame::OpResult(1))
+    ));
+    assert_eq!(
+        Err(ComputeError::UnknownName),
+        apply(Frame::Get("y".into())).map(|_| ())
+    );
+    assert_eq!(
+        Err(ComputeError::UnknownName),
+        apply(Frame::Restore("after".into())).map(|_| ())
+    );
+
+    assert!(registers.apply(&Frame::Ping, &config).is_none());
+}
+
+#[tokio::test]
+async fn test_reject_frames_above_negotiated_version() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        recover_on_protocol_error: true,
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Hello(1)).await.unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::Hello(1))
+    ));
+
+    // Postfix expressions were introduced by version 2.
+    let rpn = Frame::Rpn(vec![Token::Number(3), Token::Number(4), Token::Add]);
+    assert_eq!(2, rpn.min_version());
+    connection.write_frame(&rpn).await.unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::Error(ErrorCode::UnsupportedVersion, _))
+    ));
+
+    // The version can not be raised after the handshake.
+    connection.write_frame(&Frame::Hello(2)).await.unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::Error(ErrorCode::UnsupportedVersion, _))
+    ));
+
+    // Version 1 frames are still served.
+    connection
+        .write_frame(&Frame::Addition(3, 4))
+        .await
+        .unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::OpResult(7))
+    ));
+}
+
+#[tokio::test]
+async fn test_frame_too_large_closes_connection() {
+    use tokio::io::AsyncWriteExt;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        recover_on_protocol_error: true,
+        max_frame_len: 64,
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&[b'x'; 100]).await.unwrap();
+
+    // Unlike other invalid frames, the rest of the frame can not be
+    // skipped, so the connection is closed even though the server
+    // recovers from protocol errors.
+    let mut connection = Connection::new(stream);
+    assert!(!matches!(connection.read_frame().await, Ok(Some(_))));
+}
+
+#[tokio::test]
+async fn test_required_handshake() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        require_handshake: true,
+        min_protocol_version: 2,
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    // A request before the handshake is reported, then the connection
+    // is closed.
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection
+        .write_frame(&Frame::Addition(1, 2))
+        .await
+        .unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::Error(ErrorCode::UnsupportedVersion, message)) if message.contains("hello")
+    ));
+    assert!(connection.read_frame().await.unwrap().is_none());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.write_frame(&Frame::Hello(1)).await.unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::Error(ErrorCode::UnsupportedVersion, message)) if message.contains("older")
+    ));
+    assert!(connection.read_frame().await.unwrap().is_none());
+
+    let mut client = crate::Client::connect(addr).await.unwrap();
+    assert_eq!(PROTOCOL_VERSION, client.version());
+    assert!(matches!(
+        client.call(&Frame::Addition(1, 2)).await,
+        Ok(Frame::OpResult(3))
+    ));
+
+    let err = crate::Client::connect_with_version(addr, 1)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        crate::Error::Server(err) if err.code == ErrorCode::UnsupportedVersion
+    ));
+}
+
+#[tokio::test]
+async fn test_unknown_frame_strategies() {
+    async fn responses(strategy: UnknownFrame) -> Vec<Frame> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            unknown_frame: Some(strategy),
+            ..Default::default()
+        };
+        tokio::spawn(Server::with_config(listener, config).run());
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut socket, b"+1:2\r\n?3:4\r\n+5:6\r\n")
+            .await
+            .unwrap();
+
+        let mut connection = Connection::new(socket);
+        let mut responses = Vec::new();
+        while let Ok(Some(frame)) = connection.read_frame().await {
+            responses.push(frame);
+            if matches!(responses.last(), Some(Frame::OpResult(11))) {
+                break;
+            }
+        }
+        responses
+    }
+
+    let reject = responses(UnknownFrame::Reject).await;
+    assert!(matches!(reject[..], [Frame::OpResult(3)]), "{:?}", reject);
+
+    let skip = responses(UnknownFrame::Skip).await;
+    assert!(
+        matches!(skip[..], [Frame::OpResult(3), Frame::OpResult(11)]),
+        "{:?}",
+        skip
+    );
+
+    let error_frame = responses(UnknownFrame::ErrorFrame).await;
+    assert!(
+        matches!(
+            error_frame[..],
+            [
+                Frame::OpResult(3),
+                Frame::Error(ErrorCode::Protocol, _),
+                Frame::OpResult(11)
+            ]
+        ),
+        "{:?}",
+        error_frame
+    );
+}
+
+#[tokio::test]
+async fn test_handshake_limit() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        max_handshakes: 2,
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    async fn ping(connection: &mut Connection) -> bool {
+        connection.write_frame(&Frame::Ping).await.is_ok()
+            && matches!(connection.read_frame().await, Ok(Some(Frame::Pong)))
+    }
+
+    let mut established = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert!(ping(&mut established).await);
+
+    // Two connections stall during the handshake, the third one is closed.
+    let mut stalled = Vec::new();
+    for _ in 0..2 {
+        stalled.push(Connection::new(TcpStream::connect(addr).await.unwrap()));
+    }
+    let mut rejected = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert!(!matches!(rejected.read_frame().await, Ok(Some(_))));
+
+    assert!(ping(&mut established).await);
+
+    // Completing a handshake makes room for a new connection.
+    assert!(ping(&mut stalled[0]).await);
+    let mut accepted = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert!(ping(&mut accepted).await);
+
+    // Stalled connections do not take a place among `max_connections`,
+    // with as many places as stalled peers clients are still served.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        max_handshakes: 3,
+        max_connections: 2,
+        ..Default::default()
+    };
+    let server = Server::with_config(listener, config);
+    let metrics = server.handle().metrics();
+    tokio::spawn(server.run());
+
+    let mut stalled = Vec::new();
+    for _ in 0..2 {
+        stalled.push(Connection::new(TcpStream::connect(addr).await.unwrap()));
+    }
+    let mut established = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert!(ping(&mut established).await);
+    assert_eq!(1, metrics.connections_accepted());
+    assert_eq!(1, metrics.connections_active());
+}
+
+#[tokio::test]
+async fn test_peer_error_is_not_answered() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run());
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let error = Frame::Error(ErrorCode::Protocol, "bad response".to_string());
+    connection.write_frame(&error).await.unwrap();
+    connection.write_frame(&Frame::Ping).await.unwrap();
+
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::Pong)
+    ));
+}
+
+#[test]
+fn test_compute_signed() {
+    use Operator::*;
+
+    let cases = [
+        (Frame::Signed(Add, -3, 5), Ok(2)),
+        (Frame::Signed(Add, -3, -5), Ok(-8)),
+        (Frame::Signed(Sub, 3, 5), Ok(-2)),
+        (Frame::Signed(Sub, -3, -5), Ok(2)),
+        (Frame::Signed(Mul, -3, 4), Ok(-12)),
+        (Frame::Signed(Mul, -3, -4), Ok(12)),
+        (Frame::Signed(Add, i64::MAX, 1), Err(ComputeError::Overflow)),
+        (Frame::Signed(Sub, i64::MIN, 1), Err(ComputeError::Overflow)),
+        (
+            Frame::Signed(Mul, i64::MIN, -1),
+            Err(ComputeError::Overflow),
+        ),
+    ];
+    for (frame, expected) in cases {
+        let actual = compute(&frame).map(|response| match response {
+            Frame::SignedResult(result) => result,
+            other => panic!("unexpected response {:?}", other),
+        });
+        assert_eq!(expected, actual, "{:?}", frame);
+    }
+
+    let config = ServerConfig {
+        max_operand: Some(10),
+        ..Default::default()
+    };
+    assert_eq!(Ok(()), check_limits(&Frame::Signed(Add, -10, 10), &config));
+    assert_eq!(
+        Err(ComputeError::OperandLimit),
+        check_limits(&Frame::Signed(Add, -11, 0), &config)
+    );
+}
+
+#[test]
+fn test_compute_float() {
+    use Operator::*;
+
+    let cases = [
+        (Frame::Float(Add, 1.5, -0.25), Ok(1.25)),
+        (Frame::Float(Sub, 0.5, 2.0), Ok(-1.5)),
+        (Frame::Float(Mul, -3.0, 0.5), Ok(-1.5)),
+        (Frame::Float(Mul, 1e300, 1e300), Err(ComputeError::Overflow)),
+    ];
+    for (frame, expected) in cases {
+        let actual = compute(&frame).map(|response| match response {
+            Frame::FloatResult(result) => result,
+            other => panic!("unexpected response {:?}", other),
+        });
+        assert_eq!(expected, actual, "{:?}", frame);
+    }
+}
+
+#[test]
+fn test_compute_decimal() {
+    use crate::decimal::Decimal;
+    use Operator::*;
+
+    let decimal = |src: &str| Decimal::parse(src.as_bytes()).unwrap();
+    let cases = [
+        (
+            Frame::Decimal(Add, decimal("0.10"), decimal("0.20")),
+            Ok("0.30"),
+        ),
+        (
+            Frame::Decimal(Sub, decimal("5"), decimal("12.5")),
+            Ok("-7.5"),
+        ),
+        (
+            Frame::Decimal(Mul, decimal("19.99"), decimal("3")),
+            Ok("59.97"),
+        ),
+        (
+            Frame::Decimal(Mul, decimal("0.0000000001"), decimal("0.000000001")),
+            Err(ComputeError::Overflow),
+        ),
+    ];
+    for (frame, expected) in cases {
+        let actual = compute(&frame).map(|response| match response {
+            Frame::DecimalResult(result) => result.to_string(),
+            other => panic!("unexpected response {:?}", other),
+        });
+        assert_eq!(expected.map(str::to_string), actual, "{:?}", frame);
+    }
+
+    let config = ServerConfig {
+        max_operand: Some(10),
+        ..Default::default()
+    };
+    let frame = Frame::Decimal(Add, decimal("10.00"), decimal("-10.01"));
+    assert_eq!(
+        Err(ComputeError::OperandLimit),
+        check_limits(&frame, &config)
+    );
+}
+
+#[cfg(feature = "bignum")]
+#[test]
+fn test_compute_big() {
+    use num_bigint::BigUint;
+    use Operator::*;
+
+    let big = BigUint::from(u64::MAX);
+    let cases = [
+        (Frame::Big(Add, big.clone(), 1u32.into()), Ok(&big + 1u32)),
+        (Frame::Big(Mul, big.clone(), big.clone()), Ok(&big * &big)),
+        (Frame::Big(Sub, big.clone(), big.clone()), Ok(0u32.into())),
+        (
+            Frame::Big(Sub, 1u32.into(), big.clone()),
+            Err(ComputeError::Underflow),
+        ),
+    ];
+    for (frame, expected) in cases {
+        let actual = compute(&frame).map(|response| match response {
+            Frame::BigResult(result) => result,
+            other => panic!("unexpected response {:?}", other),
+        });
+        assert_eq!(expected, actual, "{:?}", frame);
+    }
+
+    let config = ServerConfig {
+        max_operand: Some(10),
+        ..Default::default()
+    };
+    assert_eq!(
+        Err(ComputeError::OperandLimit),
+        check_limits(&Frame::Big(Add, 11u32.into(), 0u32.into()), &config)
+    );
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_compute_compressed() {
+    let request = Frame::Compressed(Box::new(Frame::Array(vec![
+        Frame::Addition(1, 2),
+        Frame::Subtraction(1, 2),
+    ])));
+    match respond(&request, &ServerConfig::default()) {
+        Ok(Frame::Compressed(response)) => assert!(matches!(
+            &response.as_ref(),
+            Frame::Array(responses) if matches!(
+                responses[..],
+                [Frame::OpResult(3), Frame::Error(ErrorCode::Underflow, _)]
+            )
+        )),
+        other => panic!("unexpected response {:?}", other),
+    }
+
+    let config = ServerConfig {
+        max_response_array_len: 1,
+        ..Default::default()
+    };
+    assert_eq!(
+        Err(ComputeError::ResponseTooLarge),
+        respond(&request, &config).map(|_| ())
+    );
+}
+
+#[test]
+fn test_compute_variadic() {
+    let cases = [
+        (Frame::Sum(vec![1, 2, 3, 4]), Ok(10)),
+        (Frame::Product(vec![2, 3, 4]), Ok(24)),
+        (
+            Frame::Sum(vec![u64::MAX, 1, 0]),
+            Err(ComputeError::Overflow),
+        ),
+        (
+            Frame::Product(vec![1 << 32, 1 << 32, 0]),
+            Err(ComputeError::Overflow),
+        ),
+    ];
+    for (frame, expected) in cases {
+        let actual = compute(&frame).map(|response| match response {
+            Frame::OpResult(result) => result,
+            other => panic!("unexpected response {:?}", other),
+        });
+        assert_eq!(expected, actual, "{:?}", frame);
+    }
+}
+
+#[test]
+fn test_compute_tree() {
+    use Operator::*;
+
+    let tree = |op, operands| Frame::Tree(op, operands);
+    let cases = [
+        // (1+2) * ((3+4) - (9%4))
+        (
+            tree(
+                Mul,
+                vec![
+                    Frame::Addition(1, 2),
+                    tree(Sub, vec![Frame::Addition(3, 4), Frame::Modulo(9, 4)]),
+                ],
+            ),
+            Ok(18),
+        ),
+        (
+            tree(
+                Sub,
+                vec![Frame::OpResult(10), Frame::OpResult(4), Frame::OpResult(3)],
+            ),
+            Ok(3),
+        ),
+        (tree(Add, vec![Frame::Expr("2*3".into())]), Ok(6)),
+        (
+            tree(Sub, vec![Frame::OpResult(1), Frame::OpResult(2)]),
+            Err(ComputeError::Underflow),
+        ),
+        (
+            tree(Add, vec![Frame::Division(1, 0)]),
+            Err(ComputeError::DivisionByZero),
+        ),
+        (
+            tree(Add, vec![Frame::Ping]),
+            Err(ComputeError::UnexpectedFrame),
+        ),
+        (tree(Add, vec![]), Err(ComputeError::InvalidSyntax)),
+    ];
+    for (frame, expected) in cases {
+        let actual = compute(&frame).map(|response| match response {
+            Frame::OpResult(result) => result,
+            other => panic!("unexpected response {:?}", other),
+        });
+        assert_eq!(expected, actual, "{:?}", frame);
+    }
+
+    let mut deep = Frame::OpResult(1);
+    for _ in 0..MAX_NESTING_DEPTH {
+        deep = tree(Add, vec![deep]);
+    }
+    assert!(matches!(compute(&deep), Ok(Frame::OpResult(1))));
+    let deeper = tree(Add, vec![deep]);
+    assert!(matches!(
+        compute(&deeper),
+        Err(ComputeError::NestingTooDeep)
+    ));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket() {
+    let dir = std::env::temp_dir().join(format!("learn-tokio-frame-uds-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server.sock");
+    let _ = std::fs::remove_file(&path);
+
+    let server = Server::builder().build_unix(UnixListener::bind(&path).unwrap());
+    let handle = server.handle();
+    tokio::spawn(server.run());
+
+    let mut client = crate::Client::connect_unix(&path).await.unwrap();
+    assert!(matches!(
+        client.call(&Frame::Addition(40, 2)).await,
+        Ok(Frame::OpResult(42))
+    ));
+    assert_eq!(1, handle.requests_served());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_idle_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        idle_timeout: Some(Duration::from_secs(10)),
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    // Every frame restarts the idle period.
+    let start = Instant::now();
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    for _ in 0..3 {
+        time::sleep(Duration::from_secs(8)).await;
+        connection.write_frame(&Frame::Ping).await.unwrap();
+        assert!(matches!(
+            connection.read_frame().await.unwrap(),
+            Some(Frame::Pong)
+        ));
+    }
+
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::Error(ErrorCode::IdleTimeout, _)) => {}
+        other => panic!("unexpected response {:?}", other),
+    }
+    assert!(connection.read_frame().await.unwrap().is_none());
+    assert!(start.elapsed() >= Duration::from_secs(34));
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    use tokio::sync::oneshot;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        request_workers: 2,
+        ..Default::default()
+    };
+    let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+    let server = Server::with_config(listener, config);
+    let handle = server.handle();
+    let server = tokio::spawn(server.run_until(shutdown_rx));
+
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection
+        .write_frame(&Frame::Addition(1, 2))
+        .await
+        .unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::OpResult(3))
+    ));
+
+    // A request a worker is handling when the shutdown starts is still
+    // answered before the notice.
+    let tag = frame::Tag { id: 1, priority: 0 };
+    let request = Frame::Tagged(tag, Box::new(Frame::Addition(3, 4)));
+    connection.write_frame(&request).await.unwrap();
+    while handle.requests_served() < 2 {
+        tokio::task::yield_now().await;
+    }
+    shutdown.send(()).unwrap();
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::Tagged(_, response)) => assert!(matches!(*response, Frame::OpResult(7))),
+        other => panic!("unexpected response {:?}", other),
+    }
+    match connection.read_frame().await.unwrap() {
+        Some(Frame::Error(ErrorCode::ShuttingDown, _)) => {}
+        other => panic!("unexpected response {:?}", other),
+    }
+    assert!(connection.read_frame().await.unwrap().is_none());
+
+    // Returns once the connection closed, and no longer accepts.
+    server.await.unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_shutdown_aborts_after_drain_timeout() {
+    use tokio::{io::AsyncWriteExt, sync::oneshot};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        drain_timeout: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+    let server = tokio::spawn(Server::with_config(listener, config).run_until(shutdown_rx));
+
+    // A client that never reads its responses, the handler is stuck
+    // writing them once the socket buffers are full.
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut request = tokio_util::bytes::BytesMut::new();
+    Frame::Echo(vec![0; 512 * 1024])
+        .encode(&mut request)
+        .unwrap();
+    let client = tokio::spawn(async move { while stream.write_all(&request).await.is_ok() {} });
+    time::sleep(Duration::from_millis(200)).await;
+
+    shutdown.send(()).unwrap();
+
+    // New clients are refused while the stuck connection drains.
+    let refused = async {
+        while TcpStream::connect(addr).await.is_ok() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    time::timeout(Duration::from_millis(500), refused)
+        .await
+        .unwrap();
+    assert!(!server.is_finished());
+
+    time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap();
+    client.abort();
+}
+
+#[tokio::test]
+async fn test_metrics_addr_in_use() {
+    let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::builder()
+        .metrics_addr(taken.local_addr().unwrap())
+        .build(listener);
+
+    // The server stops instead of running without its metrics.
+    time::timeout(Duration::from_secs(5), server.run())
+        .await
+        .unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::builder().build(listener);
+    let handle = server.handle();
+    tokio::spawn(server.run());
+
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    client.write_frame(&Frame::Addition(1, 2)).await.unwrap();
+    client.write_frame(&Frame::Ping).await.unwrap();
+    client.read_frame().await.unwrap();
+    client.read_frame().await.unwrap();
+
+    let metrics = handle.metrics();
+    assert_eq!(1, metrics.connections_accepted());
+    assert_eq!(1, metrics.connections_active());
+    assert_eq!(1, metrics.frames("Addition"));
+    assert_eq!(1, metrics.frames("Ping"));
+    assert!(metrics
+        .render()
+        .contains("calculator_request_duration_seconds_count 2\n"));
+
+    // The invalid frame closes the connection.
+    let mut raw = TcpStream::connect(addr).await.unwrap();
+    raw.write_all(b"?\r\n").await.unwrap();
+    let mut rest = Vec::new();
+    raw.read_to_end(&mut rest).await.unwrap();
+    drop(client);
+    while metrics.connections_active() > 0 {
+        tokio::task::yield_now().await;
+    }
+    // Without a frame read the connection was never counted.
+    assert_eq!(1, metrics.connections_accepted());
+    assert_eq!(1, metrics.protocol_errors());
+}
+
+#[test]
+fn test_cost() {
+    assert_eq!(1, cost(&Frame::Addition(1, 2)));
+    assert_eq!(20, cost(&Frame::Factorial(20)));
+    assert_eq!(21, cost(&Frame::Factorial(u64::MAX)));
+    assert_eq!(3, cost(&Frame::Sum(vec![1, 2, 3])));
+    let batch = Frame::Array(vec![Frame::Factorial(5), Frame::Product(vec![2, 3])]);
+    assert_eq!(7, cost(&batch));
+}
+
+#[tokio::test]
+async fn test_offload_costly_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        blocking_cost_threshold: Some(10),
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    // Below and above the threshold, both computed the same.
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let requests = [
+        (Frame::Factorial(5), 120),
+        (Frame::Factorial(20), 2432902008176640000),
+        (Frame::Sum((1..=100).collect()), 5050),
+    ];
+    for (request, expected) in requests {
+        client.write_frame(&request).await.unwrap();
+        match client.read_frame().await.unwrap() {
+            Some(Frame::OpResult(result)) => assert_eq!(expected, result),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+}
+
+// The only blocking thread is kept busy, so offloaded requests time out
+// until the breaker opens and rejects them without waiting.
+#[test]
+fn test_offload_breaker_fails_fast() {
+    const OFFLOAD_TIMEOUT: Duration = Duration::from_millis(500);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .max_blocking_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::builder()
+            .config(ServerConfig {
+                blocking_cost_threshold: Some(10),
+                ..Default::default()
+            })
+            .offload_circuit_breaker(2, Duration::from_secs(60), OFFLOAD_TIMEOUT)
+            .build(listener);
+        tokio::spawn(server.run());
+
+        let mut client = crate::Client::connect(addr).await.unwrap();
+
+        // Occupy the only blocking thread, offloaded requests never start.
+        let (release, busy) = std::sync::mpsc::channel::<()>();
+        let blocker = tokio::task::spawn_blocking(move || busy.recv());
+        for open in [false, false, true] {
+            let start = Instant::now();
+            let err = client.call(&Frame::Factorial(20)).await.unwrap_err();
+            let crate::Error::Server(err) = err else {
+                panic!("unexpected error {:?}", err);
+            };
+            assert_eq!(ErrorCode::Unavailable, err.code);
+            assert_eq!(open, start.elapsed() < OFFLOAD_TIMEOUT);
+        }
+
+        // Cheap requests are not offloaded and still answered.
+        assert!(matches!(
+            client.call(&Frame::Factorial(5)).await,
+            Ok(Frame::OpResult(120))
+        ));
+
+        release.send(()).unwrap();
+        blocker.await.unwrap().unwrap();
+    });
+}
+
+#[tokio::test]
+async fn test_embedded_server() {
+    let server = Server::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = server.local_addr().unwrap();
+    assert_ne!(0, addr.port());
+    let handle = server.handle();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let running = tokio::spawn(server.run_until(stopped));
+
+    let mut client = crate::Client::connect(addr).await.unwrap();
+    assert!(matches!(
+        client.call(&Frame::Addition(40, 2)).await,
+        Ok(Frame::OpResult(42))
+    ));
+    assert_eq!(1, handle.requests_served());
+
+    stop.send(()).unwrap();
+    running.await.unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_multiple_listeners() {
+    let addrs = [
+        "127.0.0.1:0".parse().unwrap(),
+        "127.0.0.1:0".parse().unwrap(),
+    ];
+    let server = Server::builder().bind_all(&addrs).unwrap();
+    let addrs = server.local_addrs().unwrap();
+    assert_eq!(2, addrs.len());
+    assert_eq!(addrs[0], server.local_addr().unwrap());
+    let handle = server.handle();
+    tokio::spawn(server.run());
+
+    for (i, addr) in addrs.iter().enumerate() {
+        let mut client = crate::Client::connect(addr).await.unwrap();
+        assert!(matches!(
+            client.call(&Frame::Addition(i as u64, 1)).await,
+            Ok(Frame::OpResult(n)) if n == i as u64 + 1
+        ));
+    }
+    assert_eq!(2, handle.requests_served());
+}
+
+// Skipped on hosts without IPv6.
+#[tokio::test]
+async fn test_dual_stack_on_one_port() {
+    let v6 = match bind("[::1]:0".parse().unwrap(), &ServerConfig::default()) {
+        Ok(listener) => listener,
+        Err(_) => return,
+    };
+    let port = v6.local_addr().unwrap().port();
+    drop(v6);
+
+    let addrs = [
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port)),
+    ];
+    let server = Server::builder().bind_all(&addrs).unwrap();
+    tokio::spawn(server.run());
+
+    for addr in addrs {
+        let mut client = crate::Client::connect(addr).await.unwrap();
+        assert!(matches!(
+            client.call(&Frame::Addition(1, 2)).await,
+            Ok(Frame::OpResult(3))
+        ));
+    }
+}
+
+#[test]
+fn test_build_without_listeners() {
+    let err = Server::builder().bind_all(&[]).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    let err = Server::builder().build_all([]).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[tokio::test]
+async fn test_reject_when_busy() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::builder()
+        .max_connections(1)
+        .reject_when_busy(true)
+        .build(listener);
+    let metrics = server.handle().metrics();
+    tokio::spawn(server.run());
+
+    let mut first = Connection::new(TcpStream::connect(addr).await.unwrap());
+    first.write_frame(&Frame::Ping).await.unwrap();
+    assert!(matches!(
+        first.read_frame().await.unwrap(),
+        Some(Frame::Pong)
+    ));
+
+    // The second connection is rejected once it sent its first frame.
+    let mut second = Connection::new(TcpStream::connect(addr).await.unwrap());
+    second.write_frame(&Frame::Ping).await.unwrap();
+    assert!(matches!(
+        second.read_frame().await.unwrap(),
+        Some(Frame::Error(ErrorCode::ServerBusy, _))
+    ));
+    assert!(second.read_frame().await.unwrap().is_none());
+
+    // Once the first connection closed there is room again.
+    drop(first);
+    while metrics.connections_active() > 0 {
+        tokio::task::yield_now().await;
+    }
+    let mut third = Connection::new(TcpStream::connect(addr).await.unwrap());
+    third.write_frame(&Frame::Ping).await.unwrap();
+    assert!(matches!(
+        third.read_frame().await.unwrap(),
+        Some(Frame::Pong)
+    ));
+}
+
+// Fails with `errors`, the last one first, before accepting on
+// `listener`.
+#[cfg(test)]
+#[derive(Debug)]
+struct Failing {
+    listener: TcpListener,
+    errors: Mutex<Vec<io::ErrorKind>>,
+}
+
+#[cfg(test)]
+impl Accept for Failing {
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Box<dyn Stream>, Peer)>> {
+        match self.errors.lock().unwrap().pop() {
+            Some(kind) => Poll::Ready(Err(kind.into())),
+            None => Accept::poll_accept(&self.listener, cx),
+        }
+    }
+}
+
+// A server on a listener that fails with `errors`, the last one first.
+#[cfg(test)]
+async fn failing_server(builder: Builder, errors: Vec<io::ErrorKind>) -> (Server, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let errors = Mutex::new(errors);
+    let server = builder.build_incoming(vec![Box::new(Failing { listener, errors })]);
+    (server, addr)
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_accept_backoff() {
+    use io::ErrorKind::{ConnectionAborted, OutOfMemory};
+
+    // Three failures back off for 1, 2 and 4 seconds less jitter, the
+    // aborted connections are skipped right away.
+    let errors = vec![OutOfMemory, ConnectionAborted, OutOfMemory, OutOfMemory];
+    let builder = Server::builder().max_connections(1);
+    let (server, addr) = failing_server(builder, errors).await;
+    let start = Instant::now();
+    tokio::spawn(server.run());
+
+    // The failed accepts take no place, the single connection is served,
+    // and after it the next one.
+    for _ in 0..2 {
+        let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+        client.write_frame(&Frame::Ping).await.unwrap();
+        assert!(matches!(
+            client.read_frame().await.unwrap(),
+            Some(Frame::Pong)
+        ));
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(3500), "{:?}", elapsed);
+    assert!(elapsed <= Duration::from_secs(7), "{:?}", elapsed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_accept_gives_up_after_max_backoff() {
+    let errors = vec![io::ErrorKind::OutOfMemory; 10];
+    let builder = Server::builder().accept_backoff(Duration::from_secs(1), Duration::from_secs(2));
+    let (server, _) = failing_server(builder, errors).await;
+
+    // Retried after about 1 and 2 seconds, the third failure stops the
+    // server.
+    let start = Instant::now();
+    server.run().await;
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(1500), "{:?}", elapsed);
+    assert!(elapsed <= Duration::from_secs(3), "{:?}", elapsed);
+}
+
+#[test]
+fn test_backoff_jitter() {
+    let config = ServerConfig {
+        accept_backoff: Duration::from_millis(100),
+        max_accept_backoff: Duration::from_millis(400),
+        ..Default::default()
+    };
+    let mut backoff = Backoff::new(&config);
+    for max in [100, 200, 400] {
+        let delay = backoff.next_delay().unwrap();
+        let range = Duration::from_millis(max / 2)..=Duration::from_millis(max);
+        assert!(range.contains(&delay), "{:?}", delay);
+    }
+    assert_eq!(None, backoff.next_delay());
+
+    // A zero backoff still doubles up to the maximum.
+    let config = ServerConfig {
+        accept_backoff: Duration::ZERO,
+        max_accept_backoff: Duration::from_millis(4),
+        ..config
+    };
+    let mut backoff = Backoff::new(&config);
+    let delays = std::iter::from_fn(|| backoff.next_delay()).count();
+    assert_eq!(3, delays);
+}
+
+// The only blocking thread is busy, offloaded requests never finish
+// without a request timeout.
+#[test]
+fn test_request_timeout() {
+    use crate::frame::Tag;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .max_blocking_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            request_workers: 1,
+            request_timeout: Some(Duration::from_millis(100)),
+            blocking_cost_threshold: Some(10),
+            ..Default::default()
+        };
+        tokio::spawn(Server::with_config(listener, config).run());
+
+        let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let (release, busy) = std::sync::mpsc::channel::<()>();
+        let blocker = tokio::task::spawn_blocking(move || busy.recv());
+
+        // A worker answers with the error, the connection stays open.
+        let tag = Tag { id: 1, priority: 0 };
+        let request = Frame::Tagged(tag, Box::new(Frame::Factorial(20)));
+        client.write_frame(&request).await.unwrap();
+        match client.read_frame().await.unwrap() {
+            Some(Frame::Tagged(_, response)) => assert!(
+                matches!(*response, Frame::Error(ErrorCode::RequestTimeout, _)),
+                "{:?}",
+                response
+            ),
+            other => panic!("unexpected response {:?}", other),
+        }
+        client.write_frame(&Frame::Ping).await.unwrap();
+        assert!(matches!(
+            client.read_frame().await.unwrap(),
+            Some(Frame::Pong)
+        ));
+
+        // Otherwise the error closes the connection.
+        client.write_frame(&Frame::Factorial(20)).await.unwrap();
+        assert!(matches!(
+            client.read_frame().await.unwrap(),
+            Some(Frame::Error(ErrorCode::RequestTimeout, _))
+        ));
+        assert!(client.read_frame().await.unwrap().is_none());
+
+        release.send(()).unwrap();
+        blocker.await.unwrap().unwrap();
+    });
+}
+
+// An audit log writer the tests can read back while the writer thread
+// owns it.
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedAudit(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl std::io::Write for SharedAudit {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl SharedAudit {
+    // The lines written so far.
+    fn lines(&self) -> Vec<String> {
+        let written = self.0.lock().unwrap();
+        String::from_utf8_lossy(&written)
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+#[tokio::test]
+async fn test_audit_log() {
+    let written = SharedAudit::default();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        recover_on_protocol_error: true,
+        ..Default::default()
+    };
+    let server = Server::builder()
+        .config(config)
+        .audit_log(AuditLog::new(written.clone()))
+        .build(listener);
+    tokio::spawn(server.run());
+
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let local = socket.local_addr().unwrap();
+    let mut client = Connection::new(socket);
+    client.write_frame(&Frame::Addition(1, 2)).await.unwrap();
+    client.write_frame(&Frame::Subtraction(1, 2)).await.unwrap();
+    client.read_frame().await.unwrap();
+    client.read_frame().await.unwrap();
+
+    let lines = loop {
+        let lines = written.lines();
+        if lines.len() == 2 {
+            break lines;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    };
+    let mut lines = lines.iter();
+    let first = lines.next().unwrap();
+    assert!(first.contains(&format!("\"peer\":\"{}\"", local)));
+    assert!(first.contains("\"operation\":\"Addition\""));
+    assert!(first.ends_with("\"outcome\":\"ok\"}"));
+    let second = lines.next().unwrap();
+    assert!(second.contains("\"operation\":\"Subtraction\""));
+    assert!(second.ends_with("\"outcome\":\"error\",\"error_code\":\"Underflow\"}"));
+}
+
+// Requests are recorded however they end, including those that are not
+// answered or that close the connection.
+#[test]
+fn test_audit_log_outcomes() {
+    use crate::frame::Tag;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .max_blocking_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    let written = SharedAudit::default();
+    runtime.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::builder()
+            .config(ServerConfig {
+                request_workers: 1,
+                request_timeout: Some(Duration::from_millis(100)),
+                blocking_cost_threshold: Some(10),
+                ..Default::default()
+            })
+            .connection_rate_limit(NonZeroU32::new(4).unwrap(), RateLimitExceeded::SlowDown)
+            .audit_log(AuditLog::new(written.clone()))
+            .build(listener);
+        tokio::spawn(server.run());
+
+        let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let tag = Tag { id: 1, priority: 0 };
+        let requests = [
+            Frame::Identify("billing".to_string()),
+            Frame::Tagged(tag, Box::new(Frame::Addition(1, 2))),
+            Frame::Error(ErrorCode::Overflow, "overflow".to_string()),
+            Frame::Addition(1, 2),
+            Frame::Subtraction(1, 2),
+        ];
+        for request in &requests {
+            client.write_frame(request).await.unwrap();
+        }
+        // The error frame of the peer is not answered.
+        for _ in 0..4 {
+            client.read_frame().await.unwrap().unwrap();
+        }
+
+        // An unexpected frame closes the connection.
+        let mut closed = Connection::new(TcpStream::connect(addr).await.unwrap());
+        closed.write_frame(&Frame::Pong).await.unwrap();
+        assert!(matches!(closed.read_frame().await, Ok(None) | Err(_)));
+
+        // The only blocking thread is busy, the offloaded request times out.
+        let mut slow = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let (release, busy) = std::sync::mpsc::channel::<()>();
+        let blocker = tokio::task::spawn_blocking(move || busy.recv());
+        slow.write_frame(&Frame::Factorial(20)).await.unwrap();
+        assert!(matches!(
+            slow.read_frame().await.unwrap(),
+            Some(Frame::Error(ErrorCode::RequestTimeout, _))
+        ));
+        release.send(()).unwrap();
+        blocker.await.unwrap().unwrap();
+    });
+
+    let expected: [&[&str]; 7] = [
+        &[
+            "\"client\":\"billing\",\"operation\":\"Identify\"",
+            "\"outcome\":\"ok\"",
+        ],
+        &[
+            "\"client\":\"billing\",\"operation\":\"Tagged\"",
+            "\"outcome\":\"ok\"",
+        ],
+        &[
+            "\"operation\":\"Error\"",
+            "\"response\":null",
+            "\"outcome\":\"unanswered\"",
+        ],
+        &["\"operation\":\"Addition\"", "\"outcome\":\"ok\""],
+        &[
+            "\"operation\":\"Subtraction\"",
+            "\"error_code\":\"SlowDown\"",
+        ],
+        &[
+            "\"operation\":\"Pong\"",
+            "\"outcome\":\"failed\",\"error\":\"unexpected frame\"",
+        ],
+        &[
+            "\"operation\":\"Factorial\"",
+            "\"error\":\"request timed out\"",
+        ],
+    ];
+    let mut lines = written.lines();
+    for _ in 0..100 {
+        if lines.len() >= expected.len() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        lines = written.lines();
+    }
+    assert_eq!(expected.len(), lines.len(), "{:#?}", lines);
+    for parts in expected {
+        assert!(
+            lines
+                .iter()
+                .any(|line| parts.iter().all(|part| line.contains(part))),
+            "{:?} not in {:#?}",
+            parts,
+            lines
+        );
+    }
+}
+
+#[test]
+fn test_accumulator() {
+    let config = ServerConfig::default();
+    let mut registers = Registers::new(&config);
+    let mut apply = |frame: Frame| registers.apply(&frame, &config).unwrap();
+
+    assert!(matches!(apply(Frame::Result), Ok(Frame::OpResult(0))));
+    assert!(matches!(
+        apply(Frame::Accumulate(Operator::Add, 5)),
+        Ok(Frame::OpResult(5))
+    ));
+    assert!(matches!(
+        apply(Frame::Accumulate(Operator::Mul, 3)),
+        Ok(Frame::OpResult(15))
+    ));
+
+    // A failed operation leaves the accumulator as it was.
+    assert_eq!(
+        Err(ComputeError::Underflow),
+        apply(Frame::Accumulate(Operator::Sub, 16)).map(|_| ())
+    );
+    assert!(matches!(
+        apply(Frame::Accumulate(Operator::Sub, 15)),
+        Ok(Frame::OpResult(0))
+    ));
+    assert!(matches!(
+        apply(Frame::Accumulate(Operator::Add, 2)),
+        Ok(Frame::OpResult(2))
+    ));
+    assert!(matches!(apply(Frame::Result), Ok(Frame::OpResult(2))));
+}
+
+#[test]
+fn test_named_operands() {
+    let config = ServerConfig::default();
+    let mut registers = Registers::new(&config);
+    let mut apply = |frame: Frame| registers.apply(&frame, &config).unwrap();
+    let name = |name: &str| Operand::Name(name.into());
+
+    apply(Frame::Set("x".into(), 42)).unwrap();
+    apply(Frame::Set("y".into(), 2)).unwrap();
+    assert!(matches!(
+        apply(Frame::Named(Operator::Add, name("x"), Operand::Number(8))),
+        Ok(Frame::OpResult(50))
+    ));
+    assert!(matches!(
+        apply(Frame::Named(Operator::Mul, name("x"), name("y"))),
+        Ok(Frame::OpResult(84))
+    ));
+    assert_eq!(
+        Err(ComputeError::Underflow),
+        apply(Frame::Named(Operator::Sub, name("y"), name("x"))).map(|_| ())
+    );
+    assert_eq!(
+        Err(ComputeError::UnknownName),
+        apply(Frame::Named(Operator::Add, Operand::Number(1), name("z"))).map(|_| ())
+    );
+}
+
+#[tokio::test]
+async fn test_shared_registers() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        recover_on_protocol_error: true,
+        shared_registers: true,
+        ..Default::default()
+    };
+    tokio::spawn(Server::with_config(listener, config).run());
+
+    let mut first = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut second = Connection::new(TcpStream::connect(addr).await.unwrap());
+    first
+        .write_frame(&Frame::Set("x".into(), 42))
+        .await
+        .unwrap();
+    first.read_frame().await.unwrap();
+
+    let sum = Frame::Named(Operator::Add, Operand::Name("x".into()), Operand::Number(8));
+    second.write_frame(&sum).await.unwrap();
+    assert!(matches!(
+        second.read_frame().await.unwrap(),
+        Some(Frame::OpResult(50))
+    ));
+
+    // The accumulator is not shared.
+    first
+        .write_frame(&Frame::Accumulate(Operator::Add, 5))
+        .await
+        .unwrap();
+    first.read_frame().await.unwrap();
+    second.write_frame(&Frame::Result).await.unwrap();
+    assert!(matches!(
+        second.read_frame().await.unwrap(),
+        Some(Frame::OpResult(0))
+    ));
+
+    // Restoring a snapshot would replace the registers of both.
+    second.write_frame(&Frame::Save("a".into())).await.unwrap();
+    assert!(matches!(
+        second.read_frame().await.unwrap(),
+        Some(Frame::Error(ErrorCode::UnexpectedFrame, _))
+    ));
+}
+
+#[test]
+fn test_register_limits() {
+    let config = ServerConfig {
+        max_registers: 2,
+        max_register_name_len: 4,
+        ..Default::default()
+    };
+    let mut registers = Registers::new(&config);
+    let mut apply = |frame: Frame| registers.apply(&frame, &config).unwrap().map(|_| ());
+
+    apply(Frame::Set("x".into(), 1)).unwrap();
+    apply(Frame::Set("y".into(), 2)).unwrap();
+    assert_eq!(
+        Err(ComputeError::TooManyRegisters),
+        apply(Frame::Set("z".into(), 3))
+    );
+    // Replacing a register does not add one.
+    apply(Frame::Set("x".into(), 3)).unwrap();
+    assert_eq!(
+        Err(ComputeError::NameTooLong),
+        apply(Frame::Set("total".into(), 1))
+    );
+    assert_eq!(
+        Err(ComputeError::InvalidName),
+        apply(Frame::Set("1x".into(), 1))
+    );
+
+    apply(Frame::Save("a".into())).unwrap();
+    apply(Frame::Save("b".into())).unwrap();
+    assert_eq!(
+        Err(ComputeError::TooManyRegisters),
+        apply(Frame::Save("c".into()))
+    );
+    assert_eq!(
+        Err(ComputeError::NameTooLong),
+        apply(Frame::Save("first".into()))
+    );
+}
+
+#[test]
+fn test_register_operand_limit() {
+    use crate::frame::Tag;
+
+    let config = ServerConfig {
+        max_operand: Some(10),
+        ..Default::default()
+    };
+    let mut registers = Registers::new(&config);
+    let mut apply = |frame: Frame| registers.apply(&frame, &config).unwrap().map(|_| ());
+
+    apply(Frame::Set("x".into(), 10)).unwrap();
+    let name = || Operand::Name("x".into());
+    for request in [
+        Frame::Set("y".into(), 11),
+        Frame::Accumulate(Operator::Add, 11),
+        Frame::Named(Operator::Add, name(), Operand::Number(11)),
+        Frame::Named(Operator::Mul, Operand::Number(11), name()),
+    ] {
+        assert_eq!(
+            Err(ComputeError::OperandLimit),
+            apply(request.clone()),
+            "{:?}",
+            request
+        );
+    }
+
+    // Within the limit, a tagged request carries the error instead.
+    apply(Frame::Named(Operator::Add, name(), Operand::Number(10))).unwrap();
+    let tag = Tag { id: 1, priority: 0 };
+    let response = registers.apply(
+        &Frame::Tagged(tag, Box::new(Frame::Set("y".into(), 11))),
+        &config,
+    );
+    assert!(matches!(
+        response,
+        Some(Ok(Frame::Tagged(_, response))) if matches!(*response, Frame::Error(ErrorCode::OperandLimit, _))
+    ));
+}
+
+#[tokio::test]
+async fn test_admin_frames() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        recover_on_protocol_error: true,
+        admin_token: Some("secret".to_string()),
+        ..Default::default()
+    };
+    let server = Server::with_config(listener, config);
+    let running = tokio::spawn(server.run());
+
+    let mut client = crate::Client::connect(addr).await.unwrap();
+    client.call(&Frame::Addition(1, 2)).await.unwrap();
+    let err = client.server_stats("guess").await.unwrap_err();
+    let crate::Error::Server(err) = err else {
+        panic!("unexpected error {:?}", err);
+    };
+    assert_eq!(ErrorCode::Unauthorized, err.code);
+
+    let stats = client.server_stats("secret").await.unwrap();
+    assert_eq!(1, stats.connections_active);
+    assert_eq!(1, stats.connections_accepted);
+    assert_eq!(3, stats.requests_served);
+    assert_eq!(0, stats.protocol_errors);
+
+    client.shutdown_server("secret").await.unwrap();
+    running.await.unwrap();
+}
+
+#[test]
+fn test_token_matches() {
+    assert!(token_matches("secret", "secret"));
+    assert!(!token_matches("secret", "secreT"));
+    assert!(!token_matches("secret", "secret2"));
+    assert!(!token_matches("secret", ""));
+}
+
+// Admin frames are rejected unless the server has an admin token.
+#[test]
+fn test_admin_frames_need_a_token() {
+    let handler = Handler::for_test(tokio::io::duplex(64).0, ServerConfig::default());
+    assert!(matches!(
+        handler.admin(&Frame::Shutdown(String::new())),
+        Some(Err(ComputeError::Unauthorized))
+    ));
+    assert!(!*handler.handle.shutdown.borrow());
+    assert!(handler.admin(&Frame::Ping).is_none());
+}
+
+#[tokio::test]
+async fn test_tagged_admin_frames() {
+    use crate::frame::Tag;
+
+    let (client, server) = tokio::io::duplex(1024);
+    let config = ServerConfig {
+        admin_token: Some("secret".to_string()),
+        request_workers: 2,
+        ..Default::default()
+    };
+    let mut handler = Handler::for_test(server, config);
+    tokio::spawn(async move { handler.run().await });
 
-            backoff *= 2;
+    let mut client = Connection::new(client);
+    let tag = Tag { id: 7, priority: 0 };
+    let request = Frame::Tagged(tag, Box::new(Frame::Stats("secret".to_string())));
+    client.write_frame(&request).await.unwrap();
+    match client.read_frame().await.unwrap() {
+        Some(Frame::Tagged(tag, response)) => {
+            assert_eq!(7, tag.id);
+            assert!(matches!(*response, Frame::StatsInfo(_)), "{:?}", response);
         }
+        other => panic!("unexpected response {:?}", other),
     }
 }
@@ -0,0 +1,180 @@
+// TLS for connections and the server, enabled with the `tls` feature.
+//
+// Certificates and private keys are read from PEM files. The server is
+// configured with `server::Builder::tls`, a client connects with
+// `Connection::new_tls` and verifies the server against the certificate of
+// the server itself or of the CA that signed it.
+use std::{path::Path, sync::Arc};
+
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    client,
+    rustls::{
+        self,
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        RootCertStore,
+    },
+    TlsConnector,
+};
+
+use crate::Connection;
+
+impl Connection<client::TlsStream<TcpStream>> {
+    // Start TLS on `stream` and verify that the server is `server_name`,
+    // e.g. `localhost`, with the roots of `config`.
+    pub async fn new_tls(
+        stream: TcpStream,
+        config: Arc<rustls::ClientConfig>,
+        server_name: &str,
+    ) -> crate::Result<Self> {
+        let server_name =
+            ServerName::try_from(server_name.to_string()).map_err(crate::Error::other)?;
+        let stream = TlsConnector::from(config)
+            .connect(server_name, stream)
+            .await?;
+        Ok(Connection::new(stream))
+    }
+}
+
+// Server configuration with the certificate chain in `cert_path`, the
+// certificate of the server first, and its private key in `key_path`.
+pub fn server_config(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> crate::Result<Arc<rustls::ServerConfig>> {
+    let certs = load_certs(cert_path.as_ref())?;
+    let key = PrivateKeyDer::from_pem_file(key_path.as_ref()).map_err(|err| {
+        crate::Error::other(format!(
+            "invalid private key {:?}: {}",
+            key_path.as_ref(),
+            err
+        ))
+    })?;
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+// Client configuration that trusts the certificates in `ca_path`.
+pub fn client_config(ca_path: impl AsRef<Path>) -> crate::Result<Arc<rustls::ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path.as_ref())? {
+        roots.add(cert)?;
+    }
+
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> crate::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| crate::Error::other(format!("invalid certificates {:?}: {}", path, err)))?;
+    if certs.is_empty() {
+        return Err(crate::Error::other(format!(
+            "no certificates in {:?}",
+            path
+        )));
+    }
+    Ok(certs)
+}
+
+#[tokio::test]
+async fn test_tls_round_trip() {
+    use crate::{server::Server, Frame};
+    use tokio::net::TcpListener;
+
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("learn-tokio-frame-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified.signing_key.serialize_pem()).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::builder()
+        .tls(&cert_path, &key_path)
+        .unwrap()
+        .build(listener);
+    tokio::spawn(server.run());
+
+    let config = client_config(&cert_path).unwrap();
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut connection = Connection::new_tls(stream, config.clone(), "localhost")
+        .await
+        .unwrap();
+    connection
+        .write_frame(&Frame::Addition(1, 2))
+        .await
+        .unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::OpResult(3))
+    ));
+
+    // The certificate is not valid for another name.
+    let stream = TcpStream::connect(addr).await.unwrap();
+    assert!(Connection::new_tls(stream, config, "example.com")
+        .await
+        .is_err());
+
+    // A client that does not start TLS is closed.
+    let mut plain = Connection::new(TcpStream::connect(addr).await.unwrap());
+    plain.write_frame(&Frame::Addition(1, 2)).await.unwrap();
+    assert!(!matches!(
+        plain.read_frame().await,
+        Ok(Some(Frame::OpResult(3)))
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_tls_handshake_timeout() {
+    use crate::server::Server;
+    use std::time::Duration;
+    use tokio::{io::AsyncReadExt, net::TcpListener, time};
+
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir().join(format!(
+        "learn-tokio-frame-tls-timeout-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified.signing_key.serialize_pem()).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::builder()
+        .tls(&cert_path, &key_path)
+        .unwrap()
+        .max_handshakes(1)
+        .idle_timeout(Duration::from_millis(100))
+        .build(listener);
+    tokio::spawn(server.run());
+
+    // A client that never starts the handshake is closed once the idle
+    // timeout passes, it does not hold the only handshake permit forever.
+    let mut stalled = TcpStream::connect(addr).await.unwrap();
+    let mut buf = [0; 1];
+    let read = time::timeout(Duration::from_secs(5), stalled.read(&mut buf)).await;
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "{:?}", read);
+
+    let config = client_config(&cert_path).unwrap();
+    let stream = TcpStream::connect(addr).await.unwrap();
+    assert!(Connection::new_tls(stream, config, "localhost")
+        .await
+        .is_ok());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,165 @@
+use std::io::Cursor;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+use crate::{
+    frame::{self, ParseConfig},
+    server::{self, ServerConfig},
+    Frame,
+};
+
+// Send and receive `Frame` values over a WebSocket.
+//
+// Every binary message carries exactly one encoded frame, using the same
+// encoding as `Connection`. Text messages are rejected, control messages
+// are handled by the WebSocket stream.
+#[derive(Debug)]
+pub struct WsConnection<S> {
+    stream: WebSocketStream<S>,
+    parse_config: ParseConfig,
+}
+
+impl<S> WsConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: WebSocketStream<S>) -> WsConnection<S> {
+        WsConnection {
+            stream,
+            parse_config: ParseConfig::default(),
+        }
+    }
+
+    // Returns `None` once the peer closed the WebSocket.
+    pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        loop {
+            let message = match self.stream.next().await {
+                Some(message) => message?,
+                None => return Ok(None),
+            };
+
+            match message {
+                Message::Binary(data) => return parse_message(&data, &self.parse_config).map(Some),
+                Message::Close(_) => return Ok(None),
+                Message::Text(_) => {
+                    return Err(crate::Error::other(
+                        "websocket text messages are not supported",
+                    ))
+                }
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+            }
+        }
+    }
+
+    pub async fn write_frame(&mut self, frame: &Frame) -> crate::Result<()> {
+        let data = frame.to_vec()?;
+        self.stream.send(Message::Binary(data.into())).await?;
+        Ok(())
+    }
+}
+
+// Decode the single frame of a message, a message that holds a partial
+// frame or more than one frame is invalid.
+fn parse_message(data: &[u8], config: &ParseConfig) -> crate::Result<Frame> {
+    let mut cursor = Cursor::new(data);
+    match Frame::check_with(&mut cursor, config) {
+        Ok(()) => {}
+        Err(frame::Error::Incomplete) => {
+            return Err(crate::Error::other(
+                "websocket message holds a partial frame",
+            ))
+        }
+        Err(err) => return Err(err.into()),
+    }
+    if cursor.position() as usize != data.len() {
+        return Err(crate::Error::other(
+            "websocket message holds more than one frame",
+        ));
+    }
+
+    cursor.set_position(0);
+    Ok(Frame::parse_with(&mut cursor, config)?)
+}
+
+// Accept WebSocket connections on `listener` and answer their requests
+// the same way `server::Server` does.
+pub async fn run(listener: TcpListener, config: ServerConfig) {
+    let config = std::sync::Arc::new(config);
+
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(err) => {
+                tracing::error!(%err, "failed to accept connection");
+                return;
+            }
+        };
+
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve(socket, &config).await {
+                tracing::warn!(%err, "connection error");
+            }
+        });
+    }
+}
+
+async fn serve(socket: tokio::net::TcpStream, config: &ServerConfig) -> crate::Result<()> {
+    let mut connection = WsConnection::new(tokio_tungstenite::accept_async(socket).await?);
+
+    loop {
+        let frame = match connection.read_frame().await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(()),
+            Err(crate::Error::Protocol(err)) if config.recover_on_protocol_error => {
+                let response = Frame::Error(err.code(), err.to_string());
+                connection.write_frame(&response).await?;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        let response = match server::respond(&frame, config) {
+            Ok(response) => response,
+            Err(err) if config.recover_on_protocol_error => {
+                Frame::Error(err.code(), err.to_string())
+            }
+            Err(err) => return Err(err.into()),
+        };
+        connection.write_frame(&response).await?;
+    }
+}
+
+#[tokio::test]
+async fn test_addition_over_websocket() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(run(listener, ServerConfig::default()));
+
+    let (stream, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+        .await
+        .unwrap();
+    let mut connection = WsConnection::new(stream);
+
+    connection
+        .write_frame(&Frame::Addition(10, 32))
+        .await
+        .unwrap();
+    assert!(matches!(
+        connection.read_frame().await.unwrap(),
+        Some(Frame::OpResult(42))
+    ));
+}
+
+#[test]
+fn test_message_holds_one_frame() {
+    let config = ParseConfig::default();
+    assert!(matches!(parse_message(b"p\r\n", &config), Ok(Frame::Ping)));
+    assert!(parse_message(b"+1:", &config).is_err());
+    assert!(parse_message(b"p\r\np\r\n", &config).is_err());
+}